@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io;
 
@@ -87,7 +88,9 @@ pub async fn start_mycelium(peers: Vec<String>, tun_fd: i32, priv_key: Vec<u8>)
 
     let config = Config {
         node_key: secret_key,
+        previous_key: None,
         peers: endpoints,
+        peer_settings: HashMap::new(),
         no_tun: false,
         tcp_listen_port: DEFAULT_TCP_LISTEN_PORT,
         quic_listen_port: None,
@@ -102,6 +105,7 @@ pub async fn start_mycelium(peers: Vec<String>, tun_fd: i32, priv_key: Vec<u8>)
         metrics: NoMetrics,
         private_network_config: None,
         firewall_mark: None,
+        route_policy: Default::default(),
         #[cfg(any(
             target_os = "android",
             target_os = "ios",
@@ -109,6 +113,20 @@ pub async fn start_mycelium(peers: Vec<String>, tun_fd: i32, priv_key: Vec<u8>)
         ))]
         tun_fd: Some(tun_fd),
         update_workers: 1,
+        message_store: None,
+        message_spool: None,
+        peer_cache: None,
+        ipv4_overlay: None,
+        gateway_prefixes: Vec::new(),
+        trusted_gateways: Vec::new(),
+        require_signed_updates: false,
+        multipath_tolerance: None,
+        selection_hysteresis: mycelium::router::DEFAULT_SELECTION_HYSTERESIS,
+        route_damping: None,
+        leaf: false,
+        firewall: None,
+        preferred_cipher: None,
+        link_keys: Vec::new(),
     };
     let _node = match Node::new(config).await {
         Ok(node) => {
@@ -0,0 +1,260 @@
+use std::{net::Ipv6Addr, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::debug;
+
+use mycelium::{
+    crypto::PublicKey,
+    diagnostics::{ProbeOutcome, ProbeReply},
+    metrics::Metrics,
+};
+
+use super::HttpServerState;
+
+/// Amount of probes sent by a ping if it is not explicitly specified.
+const DEFAULT_PING_COUNT: u16 = 4;
+/// Maximum amount of probes which can be sent by a single ping.
+const MAX_PING_COUNT: u16 = 100;
+/// Time between sending subsequent probes of a ping.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Hop limit of probes sent by a ping.
+const PING_HOP_LIMIT: u8 = 64;
+/// Maximum amount of hops probed by a traceroute if it is not explicitly specified.
+const DEFAULT_MAX_HOPS: u8 = 30;
+/// Amount of seconds to wait for the reply to a probe if it is not explicitly specified.
+const DEFAULT_PROBE_TIMEOUT_SECS: u64 = 2;
+/// Maximum amount of seconds to wait for the reply to a probe.
+const MAX_PROBE_TIMEOUT_SECS: u64 = 30;
+
+/// Return a router which has diagnostic endpoints and their handlers mounted.
+pub fn diagnostics_router_v1<M>(server_state: HttpServerState<M>) -> Router
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/admin/ping", get(ping))
+        .route("/admin/traceroute", get(traceroute))
+        .with_state(server_state)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PingQuery {
+    /// Overlay IP or hex encoded public key of the node to ping.
+    destination: String,
+    count: Option<u16>,
+    timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TracerouteQuery {
+    /// Overlay IP or hex encoded public key of the node to trace.
+    destination: String,
+    max_hops: Option<u8>,
+    timeout: Option<u64>,
+}
+
+/// Result of a ping.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PingResponse {
+    /// Overlay IP of the pinged node.
+    pub destination: Ipv6Addr,
+    /// Amount of probes sent.
+    pub sent: u16,
+    /// Replies received, in the order the probes were sent.
+    pub replies: Vec<PingReply>,
+}
+
+/// A reply to a single probe of a ping.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PingReply {
+    /// Index of the probe this is a reply to, starting at 0.
+    pub sequence: u16,
+    /// Overlay IP of the node which replied.
+    pub from: Ipv6Addr,
+    /// What the node replied.
+    pub outcome: ProbeOutcome,
+    /// Round trip time in milliseconds.
+    pub rtt: f64,
+}
+
+/// Result of a traceroute.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TracerouteResponse {
+    /// Overlay IP of the traced node.
+    pub destination: Ipv6Addr,
+    /// Probed hops, starting with the first node after this one.
+    pub hops: Vec<TracerouteHop>,
+}
+
+/// A single hop in a traceroute.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TracerouteHop {
+    /// Distance of the hop from this node, starting at 1.
+    pub hop: u8,
+    /// Overlay IP of the node at this hop, if it replied.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<Ipv6Addr>,
+    /// What the node replied, if it replied.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<ProbeOutcome>,
+    /// Round trip time in milliseconds, if the node replied.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<f64>,
+}
+
+/// Ping a node, by sending it probes one at a time.
+async fn ping<M>(
+    State(state): State<HttpServerState<M>>,
+    Query(query): Query<PingQuery>,
+) -> Result<Json<PingResponse>, (StatusCode, String)>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    let destination = parse_destination(&query.destination)?;
+    let count = query.count.unwrap_or(DEFAULT_PING_COUNT);
+    if count == 0 || count > MAX_PING_COUNT {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("count must be between 1 and {MAX_PING_COUNT}"),
+        ));
+    }
+    let timeout = probe_timeout(query.timeout)?;
+
+    debug!(%destination, count, "Pinging node");
+
+    let mut replies = Vec::new();
+    for sequence in 0..count {
+        let started = Instant::now();
+        // The lock on the node is released before waiting for the reply.
+        let probe = state
+            .node
+            .lock()
+            .await
+            .send_probe(destination, PING_HOP_LIMIT)
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+        if let Some(reply) = probe.reply(timeout).await {
+            replies.push(PingReply::new(sequence, reply));
+        }
+        if sequence + 1 < count {
+            tokio::time::sleep_until(started + PING_INTERVAL).await;
+        }
+    }
+
+    Ok(Json(PingResponse {
+        destination,
+        sent: count,
+        replies,
+    }))
+}
+
+/// Trace the path to a node, by sending it probes with increasing hop limits.
+async fn traceroute<M>(
+    State(state): State<HttpServerState<M>>,
+    Query(query): Query<TracerouteQuery>,
+) -> Result<Json<TracerouteResponse>, (StatusCode, String)>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    let destination = parse_destination(&query.destination)?;
+    let max_hops = query.max_hops.unwrap_or(DEFAULT_MAX_HOPS);
+    if max_hops == 0 || max_hops == u8::MAX {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("maxHops must be between 1 and {}", u8::MAX - 1),
+        ));
+    }
+    let timeout = probe_timeout(query.timeout)?;
+
+    debug!(%destination, max_hops, "Tracing route to node");
+
+    let mut hops = Vec::new();
+    for hop in 1..=max_hops {
+        // A packet which arrives with a hop limit of 1 expires, so the hop limit needs to be one
+        // more than the amount of hops the probe may travel.
+        let probe = state
+            .node
+            .lock()
+            .await
+            .send_probe(destination, hop + 1)
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+        let Some(reply) = probe.reply(timeout).await else {
+            hops.push(TracerouteHop {
+                hop,
+                from: None,
+                outcome: None,
+                rtt: None,
+            });
+            continue;
+        };
+        hops.push(TracerouteHop {
+            hop,
+            from: Some(reply.from),
+            outcome: Some(reply.outcome),
+            rtt: Some(millis(reply.rtt)),
+        });
+        if reply.from == destination || reply.outcome != ProbeOutcome::TimeExceeded {
+            break;
+        }
+    }
+
+    Ok(Json(TracerouteResponse { destination, hops }))
+}
+
+/// Parse the destination of a probe, which is either an overlay IP or a hex encoded public key.
+fn parse_destination(destination: &str) -> Result<Ipv6Addr, (StatusCode, String)> {
+    if let Ok(ip) = destination.parse() {
+        return Ok(ip);
+    }
+    match PublicKey::try_from(destination) {
+        Ok(pk) => Ok(pk.address()),
+        Err(_) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("{destination} is not an overlay IP or public key"),
+        )),
+    }
+}
+
+/// Get the time to wait for the reply to a probe.
+fn probe_timeout(timeout: Option<u64>) -> Result<Duration, (StatusCode, String)> {
+    let timeout = timeout.unwrap_or(DEFAULT_PROBE_TIMEOUT_SECS);
+    if timeout == 0 || timeout > MAX_PROBE_TIMEOUT_SECS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("timeout must be between 1 and {MAX_PROBE_TIMEOUT_SECS} seconds"),
+        ));
+    }
+
+    Ok(Duration::from_secs(timeout))
+}
+
+impl PingReply {
+    fn new(sequence: u16, reply: ProbeReply) -> Self {
+        Self {
+            sequence,
+            from: reply.from,
+            outcome: reply.outcome,
+            rtt: millis(reply.rtt),
+        }
+    }
+}
+
+/// Convert a duration to fractional milliseconds.
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -1,29 +1,37 @@
 use core::fmt;
-use std::{net::IpAddr, net::SocketAddr, str::FromStr, sync::Arc};
+use std::{net::IpAddr, net::Ipv4Addr, net::SocketAddr, str::FromStr, sync::Arc};
 
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    routing::{delete, get},
+    routing::{delete, get, post},
     Json, Router,
 };
 use serde::{de, Deserialize, Deserializer, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tracing::{debug, error};
 
 use mycelium::{
     crypto::PublicKey,
     endpoint::Endpoint,
+    firewall::FirewallPolicy,
     metrics::Metrics,
-    peer_manager::{PeerExists, PeerNotFound, PeerStats},
+    peer_manager::{PeerExists, PeerNotFound, PeerSettings, PeerStats},
+    policy::RoutePolicy,
 };
 
 const INFINITE_STR: &str = "infinite";
 
+mod diagnostics;
+pub use diagnostics::{PingReply, PingResponse, TracerouteHop, TracerouteResponse};
+
 #[cfg(feature = "message")]
 mod message;
 #[cfg(feature = "message")]
-pub use message::{MessageDestination, MessageReceiveInfo, MessageSendInfo, PushMessageResponse};
+pub use message::{
+    MessageDestination, MessageReceiveInfo, MessageSendInfo, PushMessageResponse, TopicSubscribers,
+    TopicSubscription,
+};
 
 /// Http API server handle. The server is spawned in a background task. If this handle is dropped,
 /// the server is terminated.
@@ -33,31 +41,54 @@ pub struct Http {
     _cancel_tx: tokio::sync::oneshot::Sender<()>,
 }
 
+/// A request to reload the configuration of the node. The API does not know where the
+/// configuration comes from, so it hands the request to the owner of the node, which reports the
+/// outcome of the reload on the contained channel.
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
+
 #[derive(Clone)]
 /// Shared state accessible in HTTP endpoint handlers.
 struct HttpServerState<M> {
     /// Access to the (`node`)(mycelium::Node) state.
     node: Arc<Mutex<mycelium::Node<M>>>,
+    /// Channel to request a configuration reload, if the owner of the node supports it.
+    reload_tx: Option<mpsc::Sender<ReloadRequest>>,
 }
 
 impl Http {
     /// Spawns a new HTTP API server on the provided listening address.
-    pub fn spawn<M>(node: mycelium::Node<M>, listen_addr: SocketAddr) -> Self
+    ///
+    /// If `reload_tx` is set, configuration reloads requested through the API are sent on it.
+    pub fn spawn<M>(
+        node: Arc<Mutex<mycelium::Node<M>>>,
+        listen_addr: SocketAddr,
+        reload_tx: Option<mpsc::Sender<ReloadRequest>>,
+    ) -> Self
     where
         M: Metrics + Clone + Send + Sync + 'static,
     {
-        let server_state = HttpServerState {
-            node: Arc::new(Mutex::new(node)),
-        };
+        let server_state = HttpServerState { node, reload_tx };
         let admin_routes = Router::new()
             .route("/admin", get(get_info))
             .route("/admin/peers", get(get_peers).post(add_peer))
             .route("/admin/peers/:endpoint", delete(delete_peer))
             .route("/admin/routes/selected", get(get_selected_routes))
             .route("/admin/routes/fallback", get(get_fallback_routes))
+            .route("/admin/routes/damping", get(get_damped_routes))
+            .route("/admin/policy", get(get_route_policy).put(set_route_policy))
+            .route(
+                "/admin/firewall",
+                get(get_firewall_policy)
+                    .put(set_firewall_policy)
+                    .delete(disable_firewall),
+            )
+            .route("/admin/reload", post(reload_config))
             .route("/pubkey/:ip", get(get_pubk_from_ip))
             .with_state(server_state.clone());
-        let app = Router::new().nest("/api/v1", admin_routes);
+        let app = Router::new().nest("/api/v1", admin_routes).nest(
+            "/api/v1",
+            diagnostics::diagnostics_router_v1(server_state.clone()),
+        );
         #[cfg(feature = "message")]
         let app = app.nest("/api/v1", message::message_router_v1(server_state));
 
@@ -100,6 +131,9 @@ where
 pub struct AddPeer {
     /// The endpoint used to connect to the peer
     pub endpoint: String,
+    /// Link settings of the peer
+    #[serde(flatten)]
+    pub settings: PeerSettings,
 }
 
 /// Add a new peer to the system
@@ -119,7 +153,7 @@ where
         Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
     };
 
-    match state.node.lock().await.add_peer(endpoint) {
+    match state.node.lock().await.add_peer(endpoint, payload.settings) {
         Ok(()) => Ok(StatusCode::NO_CONTENT),
         Err(PeerExists) => Err((
             StatusCode::CONFLICT,
@@ -174,6 +208,12 @@ pub struct Route {
     pub metric: Metric,
     /// Sequence number of the route.
     pub seqno: u16,
+    /// Amount of data packets forwarded over the route.
+    #[serde(default)]
+    pub forwarded_packets: u64,
+    /// Amount of bytes of data forwarded over the route.
+    #[serde(default)]
+    pub forwarded_bytes: u64,
 }
 
 /// List all currently selected routes.
@@ -197,6 +237,8 @@ where
                 Metric::Value(sr.metric().into())
             },
             seqno: sr.seqno().into(),
+            forwarded_packets: sr.forwarded_packets(),
+            forwarded_bytes: sr.forwarded_bytes(),
         })
         .collect();
 
@@ -224,12 +266,154 @@ where
                 Metric::Value(sr.metric().into())
             },
             seqno: sr.seqno().into(),
+            forwarded_packets: sr.forwarded_packets(),
+            forwarded_bytes: sr.forwarded_bytes(),
+        })
+        .collect();
+
+    Json(routes)
+}
+
+/// Damping state of a route which flapped recently.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DampedRoute {
+    /// The subnet of the route.
+    pub subnet: String,
+    /// Public key of the router which announces the subnet, hex encoded.
+    pub router: String,
+    /// Current penalty of the route.
+    pub penalty: u32,
+    /// Whether the route is currently suppressed.
+    pub suppressed: bool,
+    /// Seconds until the route is no longer suppressed, if it does not flap again.
+    pub reuse_in: Option<u64>,
+}
+
+/// List the damping state of all routes which flapped recently.
+async fn get_damped_routes<M>(State(state): State<HttpServerState<M>>) -> Json<Vec<DampedRoute>>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Loading damped routes");
+    let routes = state
+        .node
+        .lock()
+        .await
+        .damped_routes()
+        .into_iter()
+        .map(|dr| DampedRoute {
+            subnet: dr.subnet.to_string(),
+            router: dr.router.to_string(),
+            penalty: dr.penalty,
+            suppressed: dr.suppressed,
+            reuse_in: dr.reuse_in.map(|reuse_in| reuse_in.as_secs()),
         })
         .collect();
 
     Json(routes)
 }
 
+/// Get the route policy currently applied by the node.
+async fn get_route_policy<M>(State(state): State<HttpServerState<M>>) -> Json<RoutePolicy>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Loading route policy");
+    Json(state.node.lock().await.route_policy())
+}
+
+/// Replace the route policy applied by the node.
+async fn set_route_policy<M>(
+    State(state): State<HttpServerState<M>>,
+    Json(policy): Json<RoutePolicy>,
+) -> StatusCode
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!(
+        rules = policy.rules.len(),
+        penalties = policy.penalties.len(),
+        "Replacing route policy"
+    );
+    state.node.lock().await.set_route_policy(policy);
+
+    StatusCode::NO_CONTENT
+}
+
+/// Get the firewall policy currently applied by the node.
+async fn get_firewall_policy<M>(
+    State(state): State<HttpServerState<M>>,
+) -> Result<Json<FirewallPolicy>, StatusCode>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Loading firewall policy");
+    match state.node.lock().await.firewall_policy() {
+        Some(policy) => Ok(Json(policy)),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Replace the firewall policy applied by the node, enabling the firewall if needed.
+async fn set_firewall_policy<M>(
+    State(state): State<HttpServerState<M>>,
+    Json(policy): Json<FirewallPolicy>,
+) -> StatusCode
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!(rules = policy.rules.len(), "Replacing firewall policy");
+    state.node.lock().await.set_firewall_policy(Some(policy));
+
+    StatusCode::NO_CONTENT
+}
+
+/// Disable the firewall of the node.
+async fn disable_firewall<M>(State(state): State<HttpServerState<M>>) -> StatusCode
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Disabling firewall");
+    state.node.lock().await.set_firewall_policy(None);
+
+    StatusCode::NO_CONTENT
+}
+
+/// Reload the configuration of the node.
+async fn reload_config<M>(
+    State(state): State<HttpServerState<M>>,
+) -> Result<StatusCode, (StatusCode, String)>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Requesting configuration reload");
+
+    let Some(reload_tx) = state.reload_tx else {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            "Configuration reload is not supported".to_string(),
+        ));
+    };
+
+    let (tx, rx) = oneshot::channel();
+    if reload_tx.send(tx).await.is_err() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Configuration reload handler is not running".to_string(),
+        ));
+    }
+
+    match rx.await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT),
+        Ok(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+        Err(_) => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Configuration reload handler stopped".to_string(),
+        )),
+    }
+}
+
 /// General info about a node.
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -238,6 +422,9 @@ pub struct Info {
     pub node_subnet: String,
     /// The public key of the node
     pub node_pubkey: PublicKey,
+    /// The IPv4 address of the node, if the IPv4 overlay is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_ipv4: Option<Ipv4Addr>,
 }
 
 /// Get general info about the node.
@@ -249,6 +436,7 @@ where
     Json(Info {
         node_subnet: info.node_subnet.to_string(),
         node_pubkey: info.node_pubkey,
+        node_ipv4: info.node_ipv4,
     })
 }
 
@@ -258,6 +446,9 @@ where
 pub struct PubKey {
     /// The public key from the node
     pub public_key: PublicKey,
+    /// The current key of the node, if it replaced the key owning the address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub successor: Option<PublicKey>,
 }
 
 /// Get public key from IP.
@@ -268,8 +459,17 @@ async fn get_pubk_from_ip<M>(
 where
     M: Metrics + Clone + Send + Sync + 'static,
 {
-    match state.node.lock().await.get_pubkey_from_ip(ip) {
-        Some(pubkey) => Ok(Json(PubKey { public_key: pubkey })),
+    let node = state.node.lock().await;
+    // If the node replaced its key, the previous key is known from the succession, even once the
+    // address is no longer announced.
+    match node
+        .get_pubkey_from_ip(ip)
+        .or_else(|| node.get_key_succession(ip).map(|s| s.previous()))
+    {
+        Some(pubkey) => Ok(Json(PubKey {
+            public_key: pubkey,
+            successor: node.get_successor_from_ip(ip),
+        })),
         None => Err(StatusCode::NOT_FOUND),
     }
 }
@@ -395,21 +595,48 @@ mod tests {
             subnet: "406:1d77:2438:aa7c::/64".to_string(),
             next_hop: "TCP [2a02:1811:d584:7400:c503:ff39:de03:9e44]:45694 <-> [2a01:4f8:212:fa6::2]:9651".to_string(),
             metric: Metric::Value(20),
-            seqno: 0
+            seqno: 0,
+            forwarded_packets: 0,
+            forwarded_bytes: 0,
         });
 
         assert_eq!(routes[1], Route {
             subnet: "407:8458:dbf5:4ed7::/64".to_string(),
             next_hop: "TCP [2a02:1811:d584:7400:c503:ff39:de03:9e44]:45694 <-> [2a01:4f8:212:fa6::2]:9651".to_string(),
             metric: Metric::Value(174),
-            seqno: 0
+            seqno: 0,
+            forwarded_packets: 0,
+            forwarded_bytes: 0,
         });
 
         assert_eq!(routes[2], Route {
             subnet: "408:7ba3:3a4d:808a::/64".to_string(),
             next_hop: "TCP [2a02:1811:d584:7400:c503:ff39:de03:9e44]:45694 <-> [2a01:4f8:212:fa6::2]:9651".to_string(),
             metric: Metric::Infinite,
-            seqno: 0
+            seqno: 0,
+            forwarded_packets: 0,
+            forwarded_bytes: 0,
         });
     }
+
+    #[test]
+    fn test_deserialize_add_peer() {
+        let add_peer: AddPeer =
+            serde_json::from_str(r#"{"endpoint":"tcp://188.40.132.242:9651"}"#).unwrap();
+        assert_eq!(add_peer.endpoint, "tcp://188.40.132.242:9651");
+        assert_eq!(add_peer.settings, PeerSettings::default());
+
+        let add_peer: AddPeer = serde_json::from_str(
+            r#"{"endpoint":"tcp://188.40.132.242:9651","cost":50,"penalty":10,"backup":true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            add_peer.settings,
+            PeerSettings {
+                cost: Some(50),
+                penalty: 10,
+                backup: true,
+            }
+        );
+    }
 }
@@ -1,13 +1,29 @@
-use std::{net::IpAddr, ops::Deref, time::Duration};
+use std::{
+    io,
+    net::IpAddr,
+    ops::Deref,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use axum::{
+    body::{Body, Bytes},
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
+use futures::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::oneshot,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{debug, warn};
 
 use mycelium::{
     crypto::PublicKey,
@@ -19,6 +35,21 @@ use super::HttpServerState;
 
 /// Default amount of time to try and send a message if it is not explicitly specified.
 const DEFAULT_MESSAGE_TRY_DURATION: Duration = Duration::from_secs(60 * 5);
+/// Default amount of time to try and send a streamed message if it is not explicitly specified.
+const DEFAULT_STREAM_TRY_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Header holding the id of a streamed message.
+const HEADER_MESSAGE_ID: HeaderName = HeaderName::from_static("x-message-id");
+/// Header holding the overlay ip of the sender of a streamed message.
+const HEADER_MESSAGE_SRC_IP: HeaderName = HeaderName::from_static("x-message-src-ip");
+/// Header holding the public key of the sender of a streamed message.
+const HEADER_MESSAGE_SRC_PK: HeaderName = HeaderName::from_static("x-message-src-pk");
+/// Header holding the overlay ip of the receiver of a streamed message.
+const HEADER_MESSAGE_DST_IP: HeaderName = HeaderName::from_static("x-message-dst-ip");
+/// Header holding the public key of the receiver of a streamed message.
+const HEADER_MESSAGE_DST_PK: HeaderName = HeaderName::from_static("x-message-dst-pk");
+/// Header holding the base64 encoded topic of a streamed message, if it has one.
+const HEADER_MESSAGE_TOPIC: HeaderName = HeaderName::from_static("x-message-topic");
 
 /// Return a router which has message endpoints and their handlers mounted.
 pub fn message_router_v1<M>(server_state: HttpServerState<M>) -> Router
@@ -29,6 +60,17 @@ where
         .route("/messages", get(get_message).post(push_message))
         .route("/messages/status/:id", get(message_status))
         .route("/messages/reply/:id", post(reply_message))
+        .route(
+            "/messages/stream",
+            get(get_stream_message).post(push_stream_message),
+        )
+        .route(
+            "/messages/topics/subscriptions",
+            get(topic_subscriptions)
+                .post(subscribe_topic)
+                .delete(unsubscribe_topic),
+        )
+        .route("/messages/topics/subscribers", get(topic_subscribers))
         .with_state(server_state)
 }
 
@@ -49,6 +91,8 @@ pub struct MessageSendInfo {
 pub enum MessageDestination {
     Ip(IpAddr),
     Pk(PublicKey),
+    /// All nodes which subscribed to the topic of the message.
+    Subscribers,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -65,14 +109,37 @@ pub struct MessageReceiveInfo {
     pub topic: Option<Vec<u8>>,
     #[serde(with = "base64::binary")]
     pub payload: Vec<u8>,
+    /// File holding the payload, if the message was too large to be kept in memory. In this case
+    /// the payload is empty.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spool_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicSubscription {
+    #[serde(with = "base64::binary")]
+    pub topic: Vec<u8>,
+    pub publisher: MessageDestination,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicSubscribers {
+    #[serde(with = "base64::binary")]
+    pub topic: Vec<u8>,
+    pub subscribers: Vec<IpAddr>,
 }
 
 impl MessageDestination {
-    /// Get the IP address of the destination.
-    fn ip(self) -> IpAddr {
+    /// Get the IP address of the destination. Returns [`None`] if the destination is not a single
+    /// node.
+    fn ip(self) -> Option<IpAddr> {
         match self {
-            MessageDestination::Ip(ip) => ip,
-            MessageDestination::Pk(pk) => IpAddr::V6(pk.address()),
+            MessageDestination::Ip(ip) => Some(ip),
+            MessageDestination::Pk(pk) => Some(IpAddr::V6(pk.address())),
+            MessageDestination::Subscribers => None,
         }
     }
 }
@@ -139,6 +206,7 @@ where
                 Some(m.topic)
             },
             payload: m.data,
+            spool_file: m.spool_file,
         })
     })
 }
@@ -182,7 +250,24 @@ async fn push_message<M>(
 where
     M: Metrics + Clone + Send + Sync + 'static,
 {
-    let dst = message_info.dst.ip();
+    let Some(dst) = message_info.dst.ip() else {
+        debug!(
+            message.len = message_info.payload.len(),
+            "Publishing new message to topic subscribers",
+        );
+
+        return match state.node.lock().await.publish_message(
+            message_info.payload,
+            message_info.topic.unwrap_or_default(),
+            DEFAULT_MESSAGE_TRY_DURATION,
+        ) {
+            Ok(id) => Ok((
+                StatusCode::CREATED,
+                Json(PushMessageResponse::Id(MessageIdReply { id })),
+            )),
+            Err(_) => Err(StatusCode::BAD_REQUEST),
+        };
+    };
     debug!(
         message.dst=%dst,
         message.len=message_info.payload.len(),
@@ -224,6 +309,7 @@ where
                             dst_pk: m.dst_pk,
                             topic: if m.topic.is_empty() { None } else { Some(m.topic.clone()) },
                             payload: m.data.clone(),
+                            spool_file: m.spool_file.clone(),
                         }))))
                     } else {
                         // This happens if a none value is send, which should not happen.
@@ -251,7 +337,9 @@ async fn reply_message<M>(
 where
     M: Metrics + Clone + Send + Sync + 'static,
 {
-    let dst = message_info.dst.ip();
+    let Some(dst) = message_info.dst.ip() else {
+        return StatusCode::BAD_REQUEST;
+    };
     debug!(
         message.id=id.as_hex(),
         message.dst=%dst,
@@ -287,6 +375,303 @@ where
         .map(Json)
 }
 
+async fn topic_subscriptions<M>(
+    State(state): State<HttpServerState<M>>,
+) -> Json<Vec<TopicSubscription>>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Fetching topic subscriptions");
+
+    Json(
+        state
+            .node
+            .lock()
+            .await
+            .topic_subscriptions()
+            .into_iter()
+            .map(|(topic, publisher)| TopicSubscription {
+                topic,
+                publisher: MessageDestination::Ip(publisher),
+            })
+            .collect(),
+    )
+}
+
+async fn subscribe_topic<M>(
+    State(state): State<HttpServerState<M>>,
+    Json(subscription): Json<TopicSubscription>,
+) -> StatusCode
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    let Some(publisher) = subscription.publisher.ip() else {
+        return StatusCode::BAD_REQUEST;
+    };
+    debug!(%publisher, "Subscribing to topic");
+
+    match state
+        .node
+        .lock()
+        .await
+        .subscribe_topic(subscription.topic, publisher)
+    {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+async fn unsubscribe_topic<M>(
+    State(state): State<HttpServerState<M>>,
+    Json(subscription): Json<TopicSubscription>,
+) -> StatusCode
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    let Some(publisher) = subscription.publisher.ip() else {
+        return StatusCode::BAD_REQUEST;
+    };
+    debug!(%publisher, "Unsubscribing from topic");
+
+    if state
+        .node
+        .lock()
+        .await
+        .unsubscribe_topic(subscription.topic, publisher)
+    {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn topic_subscribers<M>(
+    State(state): State<HttpServerState<M>>,
+) -> Json<Vec<TopicSubscribers>>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!("Fetching topic subscribers");
+
+    Json(
+        state
+            .node
+            .lock()
+            .await
+            .topic_subscribers()
+            .into_iter()
+            .map(|(topic, subscribers)| TopicSubscribers { topic, subscribers })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PushStreamQuery {
+    dst_ip: Option<IpAddr>,
+    dst_pk: Option<PublicKey>,
+    /// Optional topic of the message, base64 encoded.
+    #[serde(default)]
+    #[serde(with = "base64::optional_binary")]
+    topic: Option<Vec<u8>>,
+    /// Length of the message, required if the request does not have a content length.
+    length: Option<u64>,
+    /// Amount of seconds to try and send the message.
+    timeout: Option<u64>,
+}
+
+impl PushStreamQuery {
+    /// The destination of the message, if exactly one destination is set.
+    fn dst(&self) -> Option<IpAddr> {
+        match (self.dst_ip, self.dst_pk) {
+            (Some(ip), None) => Some(ip),
+            (None, Some(pk)) => Some(IpAddr::V6(pk.address())),
+            _ => None,
+        }
+    }
+
+    /// Amount of time to try and send the message.
+    fn try_duration(&self) -> Duration {
+        self.timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STREAM_TRY_DURATION)
+    }
+}
+
+/// Reader over a request body, which reports once `remaining` bytes have been read, or the body
+/// can't be read any further.
+struct BodyReader<R> {
+    inner: R,
+    remaining: u64,
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl<R> AsyncRead for BodyReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        match res {
+            Poll::Ready(Ok(())) => {
+                let read = (buf.filled().len() - filled) as u64;
+                self.remaining = self.remaining.saturating_sub(read);
+                if self.remaining == 0 || read == 0 {
+                    self.done.take();
+                }
+            }
+            Poll::Ready(Err(_)) => {
+                self.done.take();
+            }
+            Poll::Pending => {}
+        }
+        res
+    }
+}
+
+/// Push a message which is streamed from the request body. The response is sent once the full
+/// body has been read, or transmission of the message is aborted.
+async fn push_stream_message<M>(
+    State(state): State<HttpServerState<M>>,
+    Query(query): Query<PushStreamQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<MessageIdReply>), StatusCode>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    let Some(dst) = query.dst() else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let Some(len) = query.length.or_else(|| {
+        headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    }) else {
+        return Err(StatusCode::LENGTH_REQUIRED);
+    };
+
+    debug!(
+        message.dst=%dst,
+        message.len=len,
+        "Pushing new streamed message to message stack",
+    );
+
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+    let reader = BodyReader {
+        inner: StreamReader::new(body.into_data_stream().map_err(io::Error::other)),
+        remaining: len,
+        done: Some(done_tx),
+    };
+
+    let id = match state.node.lock().await.push_stream_message(
+        dst,
+        reader,
+        len,
+        query.topic.clone(),
+        query.try_duration(),
+    ) {
+        Ok(id) => id,
+        Err(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    // Either the full body is read, or the reader is dropped because the message is aborted.
+    let _ = done_rx.await;
+
+    Ok((StatusCode::CREATED, Json(MessageIdReply { id })))
+}
+
+/// Stream of the data in a spooled message file, which removes the file once it is dropped.
+struct SpoolFileStream {
+    inner: ReaderStream<tokio::fs::File>,
+    path: PathBuf,
+}
+
+impl Stream for SpoolFileStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Drop for SpoolFileStream {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), "Failed to remove spooled message: {e}");
+        }
+    }
+}
+
+/// Pop a message, and return its payload as the response body. Information about the message is
+/// returned in headers.
+async fn get_stream_message<M>(
+    State(state): State<HttpServerState<M>>,
+    Query(query): Query<GetMessageQuery>,
+) -> Result<Response, StatusCode>
+where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    debug!(
+        "Attempt to get streamed message, timeout {} seconds",
+        query.timeout_secs()
+    );
+
+    let m = tokio::time::timeout(
+        Duration::from_secs(query.timeout_secs()),
+        state.node.lock().await.get_message(true, query.topic),
+    )
+    .await
+    .or(Err(StatusCode::NO_CONTENT))?;
+
+    let (len, body) = if let Some(path) = m.spool_file {
+        let file = tokio::fs::File::open(&path).await.map_err(|e| {
+            warn!(path = %path.display(), "Failed to open spooled message: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .len();
+        let stream = SpoolFileStream {
+            inner: ReaderStream::new(file),
+            path,
+        };
+        (len, Body::from_stream(stream))
+    } else {
+        (m.data.len() as u64, Body::from(m.data))
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    headers.insert(HEADER_MESSAGE_ID, header_value(m.id.as_hex()));
+    headers.insert(HEADER_MESSAGE_SRC_IP, header_value(m.src_ip.to_string()));
+    headers.insert(HEADER_MESSAGE_SRC_PK, header_value(m.src_pk.to_string()));
+    headers.insert(HEADER_MESSAGE_DST_IP, header_value(m.dst_ip.to_string()));
+    headers.insert(HEADER_MESSAGE_DST_PK, header_value(m.dst_pk.to_string()));
+    if !m.topic.is_empty() {
+        headers.insert(HEADER_MESSAGE_TOPIC, header_value(base64::encode(&m.topic)));
+    }
+
+    Ok((headers, body).into_response())
+}
+
+/// Create a [`HeaderValue`] from a string which only contains visible ASCII characters.
+fn header_value(value: String) -> HeaderValue {
+    HeaderValue::try_from(value).expect("Header value only contains visible ASCII; qed")
+}
+
 /// Module to implement base64 decoding and encoding
 /// Sourced from https://users.rust-lang.org/t/serialize-a-vec-u8-to-json-as-base64/57781, with some
 /// addaptions to work with the new version of the base64 crate
@@ -299,6 +684,13 @@ mod base64 {
         GeneralPurposeConfig::new(),
     );
 
+    /// Encode data in standard base64.
+    pub fn encode(data: &[u8]) -> String {
+        use base64::Engine;
+
+        B64ENGINE.encode(data)
+    }
+
     pub mod binary {
         use super::B64ENGINE;
         use base64::Engine;
@@ -0,0 +1,114 @@
+use mycelium_api::{PingResponse, TracerouteResponse};
+use prettytable::{row, Table};
+use std::net::SocketAddr;
+
+use tracing::{debug, error};
+
+/// Ping a node identified by its overlay IP or public key, and print the replies.
+pub async fn ping(
+    server_addr: SocketAddr,
+    destination: String,
+    count: u16,
+    timeout: u64,
+    json_print: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request_url = format!(
+        "http://{server_addr}/api/v1/admin/ping?destination={}&count={count}&timeout={timeout}",
+        urlencoding::encode(&destination)
+    );
+    debug!("Pinging {destination}");
+    let resp = match reqwest::get(&request_url).await {
+        Err(e) => {
+            error!("Failed to ping {destination}");
+            return Err(e.into());
+        }
+        Ok(resp) => resp,
+    };
+    if !resp.status().is_success() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, resp.text().await?).into());
+    }
+
+    if json_print {
+        // API call returns the ping result in JSON format by default
+        let ping = resp.text().await?;
+        println!("{ping}");
+        return Ok(());
+    }
+
+    let ping: PingResponse = resp.json().await?;
+    for reply in ping.replies.iter() {
+        println!(
+            "{:?} from {}: seq={} time={:.3} ms",
+            reply.outcome, reply.from, reply.sequence, reply.rtt
+        );
+    }
+    let echo_replies = ping
+        .replies
+        .iter()
+        .filter(|reply| reply.from == ping.destination)
+        .count();
+    println!(
+        "--- {} ping statistics ---\n{} probes sent, {} replies received, {:.0}% loss",
+        ping.destination,
+        ping.sent,
+        echo_replies,
+        100. * (ping.sent as usize - echo_replies) as f64 / ping.sent as f64,
+    );
+
+    Ok(())
+}
+
+/// Trace the path to a node identified by its overlay IP or public key, and print the hops.
+pub async fn traceroute(
+    server_addr: SocketAddr,
+    destination: String,
+    max_hops: u8,
+    timeout: u64,
+    json_print: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request_url = format!(
+        "http://{server_addr}/api/v1/admin/traceroute?destination={}&maxHops={max_hops}&timeout={timeout}",
+        urlencoding::encode(&destination)
+    );
+    debug!("Tracing route to {destination}");
+    let resp = match reqwest::get(&request_url).await {
+        Err(e) => {
+            error!("Failed to trace route to {destination}");
+            return Err(e.into());
+        }
+        Ok(resp) => resp,
+    };
+    if !resp.status().is_success() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, resp.text().await?).into());
+    }
+
+    if json_print {
+        // API call returns the traceroute in JSON format by default
+        let traceroute = resp.text().await?;
+        println!("{traceroute}");
+        return Ok(());
+    }
+
+    let traceroute: TracerouteResponse = resp.json().await?;
+    let mut table = Table::new();
+    table.add_row(row!["Hop", "Address", "Reply", "RTT"]);
+    for hop in traceroute.hops.iter() {
+        match (hop.from, hop.outcome, hop.rtt) {
+            (Some(from), Some(outcome), Some(rtt)) => {
+                table.add_row(row![
+                    hop.hop,
+                    from,
+                    format!("{outcome:?}"),
+                    format!("{rtt:.3} ms")
+                ]);
+            }
+            _ => {
+                table.add_row(row![hop.hop, "*", "*", "*"]);
+            }
+        }
+    }
+
+    table.printstd();
+
+    Ok(())
+}
@@ -1,10 +1,18 @@
+mod diagnostics;
 mod inspect;
+mod key;
 #[cfg(feature = "message")]
 mod message;
 mod peer;
 mod routes;
 
+pub use diagnostics::{ping, traceroute};
 pub use inspect::inspect;
+pub use key::{
+    decrypt_secret, encrypt_secret, export_key, import_key, is_encrypted, key_passphrase,
+    new_key_passphrase, InvalidKeyEncoding, KeyDecryptionError, KeyFormat, KEY_PASSPHRASE_ENV,
+    NEW_KEY_PASSPHRASE_ENV,
+};
 #[cfg(feature = "message")]
 pub use message::{recv_msg, send_msg};
 pub use peer::{add_peers, list_peers, remove_peers};
@@ -1,4 +1,4 @@
-use mycelium::peer_manager::PeerStats;
+use mycelium::peer_manager::{PeerSettings, PeerStats};
 use mycelium_api::AddPeer;
 use prettytable::{row, Table};
 use std::net::SocketAddr;
@@ -37,7 +37,11 @@ pub async fn list_peers(
                             "Type",
                             "Connection",
                             "Rx total",
-                            "Tx total"
+                            "Tx total",
+                            "RTT",
+                            "Jitter",
+                            "Hello loss",
+                            "Link cost"
                         ]);
                         for peer in peers.iter() {
                             table.add_row(row![
@@ -47,6 +51,10 @@ pub async fn list_peers(
                                 peer.connection_state,
                                 format_bytes(peer.rx_bytes),
                                 format_bytes(peer.tx_bytes),
+                                format_optional(peer.rtt, "ms"),
+                                format_optional(peer.jitter, "ms"),
+                                format_optional(peer.hello_loss, "%"),
+                                format_link_cost(peer.link_cost, peer.settings),
                             ]);
                         }
                         table.printstd();
@@ -69,6 +77,24 @@ fn format_bytes(bytes: u64) -> String {
     )
 }
 
+/// Format an optional value with a unit, or a placeholder if there is no value.
+fn format_optional<T: std::fmt::Display>(value: Option<T>, unit: &str) -> String {
+    match value {
+        Some(value) => format!("{value} {unit}"),
+        None => "-".to_string(),
+    }
+}
+
+/// Format the link cost of a peer, marking peers which are only used as backup.
+fn format_link_cost(link_cost: Option<u16>, settings: PeerSettings) -> String {
+    let link_cost = link_cost.map_or_else(|| "-".to_string(), |cost| cost.to_string());
+    if settings.backup {
+        format!("{link_cost} (backup)")
+    } else {
+        link_cost
+    }
+}
+
 /// Remove peer(s) by (underlay) IP
 pub async fn remove_peers(
     server_addr: SocketAddr,
@@ -93,17 +119,21 @@ pub async fn remove_peers(
     Ok(())
 }
 
-/// Add peer(s) by (underlay) IP
+/// Add peer(s) by (underlay) IP, with the given link settings
 pub async fn add_peers(
     server_addr: SocketAddr,
     peers: Vec<String>,
+    settings: PeerSettings,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let client = reqwest::Client::new();
     for peer in peers.into_iter() {
         let request_url = format!("http://{server_addr}/api/v1/admin/peers");
         if let Err(e) = client
             .post(&request_url)
-            .json(&AddPeer { endpoint: peer })
+            .json(&AddPeer {
+                endpoint: peer,
+                settings,
+            })
             .send()
             .await
             .and_then(|res| res.error_for_status())
@@ -25,7 +25,9 @@ pub async fn list_selected_routes(
                 // Print routes in table format
                 let routes: Vec<Route> = resp.json().await?;
                 let mut table = Table::new();
-                table.add_row(row!["Subnet", "Next Hop", "Metric", "Seq No"]);
+                table.add_row(row![
+                    "Subnet", "Next Hop", "Metric", "Seq No", "Packets", "Bytes"
+                ]);
 
                 for route in routes.iter() {
                     table.add_row(row![
@@ -33,6 +35,8 @@ pub async fn list_selected_routes(
                         &route.next_hop,
                         route.metric,
                         route.seqno,
+                        route.forwarded_packets,
+                        route.forwarded_bytes,
                     ]);
                 }
 
@@ -65,7 +69,9 @@ pub async fn list_fallback_routes(
                 // Print routes in table format
                 let routes: Vec<Route> = resp.json().await?;
                 let mut table = Table::new();
-                table.add_row(row!["Subnet", "Next Hop", "Metric", "Seq No"]);
+                table.add_row(row![
+                    "Subnet", "Next Hop", "Metric", "Seq No", "Packets", "Bytes"
+                ]);
 
                 for route in routes.iter() {
                     table.add_row(row![
@@ -73,6 +79,8 @@ pub async fn list_fallback_routes(
                         &route.next_hop,
                         route.metric,
                         route.seqno,
+                        route.forwarded_packets,
+                        route.forwarded_bytes,
                     ]);
                 }
 
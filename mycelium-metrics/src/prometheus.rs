@@ -1,8 +1,9 @@
 use axum::{routing::get, Router};
-use mycelium::metrics::Metrics;
+use mycelium::{metrics::Metrics, peer_manager::LinkQuality};
 use prometheus::{
-    opts, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter,
-    IntCounterVec, IntGauge, TextEncoder,
+    opts, register_gauge_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
 };
 use tracing::{error, info};
 
@@ -15,6 +16,9 @@ pub struct PrometheusExporter {
     router_peer_added: IntCounter,
     router_peer_removed: IntCounter,
     router_peer_died: IntCounter,
+    router_peer_rtt: GaugeVec,
+    router_peer_jitter: GaugeVec,
+    router_peer_hello_loss: IntGaugeVec,
     router_route_selection_ran: IntCounter,
     router_source_key_expired: IntCounter,
     router_expired_routes: IntCounterVec,
@@ -30,6 +34,9 @@ pub struct PrometheusExporter {
     router_propage_selected_peers_time_spent: IntCounter,
     router_update_skipped_route_selection: IntCounter,
     router_update_denied_by_filter: IntCounter,
+    router_update_denied_by_policy: IntCounter,
+    router_update_invalid_signature: IntCounter,
+    router_update_unsigned: IntCounter,
     router_update_not_interested: IntCounter,
     peer_manager_peer_added: IntCounterVec,
     peer_manager_known_peers: IntGauge,
@@ -58,6 +65,30 @@ impl PrometheusExporter {
                 "mycelium_router_peer_died",
                 "Amount of times the router noticed a peer was dead, or the peer noticed itself and informed the router",
             ).expect("Can register int counter in default registry"),
+            router_peer_rtt: register_gauge_vec!(
+                opts!(
+                    "mycelium_router_peer_rtt_seconds",
+                    "Smoothed round trip time of the Hello/IHU exchanges with a peer, by peer connection"
+                ),
+                &["peer"],
+            )
+            .expect("Can register gauge vec in default registry"),
+            router_peer_jitter: register_gauge_vec!(
+                opts!(
+                    "mycelium_router_peer_jitter_seconds",
+                    "Smoothed mean deviation of the round trip time of the Hello/IHU exchanges with a peer, by peer connection"
+                ),
+                &["peer"],
+            )
+            .expect("Can register gauge vec in default registry"),
+            router_peer_hello_loss: register_int_gauge_vec!(
+                opts!(
+                    "mycelium_router_peer_hello_loss_percent",
+                    "Percentage of recent Hellos sent to a peer which were not answered, by peer connection"
+                ),
+                &["peer"],
+            )
+            .expect("Can register int gauge vec in default registry"),
             router_route_selection_ran: register_int_counter!(
                 "mycelium_router_route_selections",
                 "Amount of times a route selection procedure was ran as result of routes expiring or peers being disconnected. Does not include route selection after an update",
@@ -144,6 +175,21 @@ impl PrometheusExporter {
                 "Updates which were received and immediately denied by a configured filter",
             )
             .expect("Can register an int counter in default registry"),
+            router_update_denied_by_policy: register_int_counter!(
+                "mycelium_router_update_denied_by_policy",
+                "Updates which passed the configured filters, but were denied by the route policy",
+            )
+            .expect("Can register an int counter in default registry"),
+            router_update_invalid_signature: register_int_counter!(
+                "mycelium_router_update_invalid_signature",
+                "Updates which were denied because their origin signature is not valid for the announced route",
+            )
+            .expect("Can register an int counter in default registry"),
+            router_update_unsigned: register_int_counter!(
+                "mycelium_router_update_unsigned",
+                "Updates which were denied because they have no origin signature, while origin signatures are required",
+            )
+            .expect("Can register an int counter in default registry"),
             router_update_not_interested: register_int_counter!(
                 "mycelium_router_update_not_interested",
                 "Updates which were allowed by the configured filters, but not of interest as they were either not feasible, or retractions, for an unknown subnet",
@@ -262,6 +308,33 @@ impl Metrics for PrometheusExporter {
         self.router_peer_died.inc()
     }
 
+    #[inline]
+    fn router_peer_link_quality(&self, peer: &str, link_quality: LinkQuality) {
+        if let Some(rtt) = link_quality.rtt {
+            self.router_peer_rtt
+                .with_label_values(&[peer])
+                .set(rtt.as_secs_f64());
+        }
+        if let Some(jitter) = link_quality.jitter {
+            self.router_peer_jitter
+                .with_label_values(&[peer])
+                .set(jitter.as_secs_f64());
+        }
+        if let Some(hello_loss) = link_quality.hello_loss {
+            self.router_peer_hello_loss
+                .with_label_values(&[peer])
+                .set(hello_loss as i64);
+        }
+    }
+
+    #[inline]
+    fn router_peer_link_quality_removed(&self, peer: &str) {
+        // These only fail if the label was never set, which is fine.
+        let _ = self.router_peer_rtt.remove_label_values(&[peer]);
+        let _ = self.router_peer_jitter.remove_label_values(&[peer]);
+        let _ = self.router_peer_hello_loss.remove_label_values(&[peer]);
+    }
+
     #[inline]
     fn router_route_selection_ran(&self) {
         self.router_route_selection_ran.inc()
@@ -314,6 +387,13 @@ impl Metrics for PrometheusExporter {
             .inc()
     }
 
+    #[inline]
+    fn router_route_packet_transit_prohibited(&self) {
+        self.router_route_packet
+            .with_label_values(&["transit_prohibited"])
+            .inc()
+    }
+
     #[inline]
     fn router_seqno_request_reply_local(&self) {
         self.router_seqno_action
@@ -402,6 +482,21 @@ impl Metrics for PrometheusExporter {
         self.router_update_denied_by_filter.inc()
     }
 
+    #[inline]
+    fn router_update_denied_by_policy(&self) {
+        self.router_update_denied_by_policy.inc()
+    }
+
+    #[inline]
+    fn router_update_invalid_signature(&self) {
+        self.router_update_invalid_signature.inc()
+    }
+
+    #[inline]
+    fn router_update_unsigned(&self) {
+        self.router_update_unsigned.inc()
+    }
+
     #[inline]
     fn router_update_not_interested(&self) {
         self.router_update_not_interested.inc()
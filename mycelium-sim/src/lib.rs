@@ -0,0 +1,514 @@
+//! An in process network simulator for mycelium.
+//!
+//! A [`Network`] runs a number of [`Router`]s in the current tokio runtime, connected by in memory
+//! [`Link`]s. Links can be added and removed, and their delay and loss can be changed while the
+//! network is running. Parts of the network can be partitioned from the rest, and healed again.
+//!
+//! All timers of the routers and the links use the tokio clock, so simulations can run with
+//! paused time, e.g. with `#[tokio::test(start_paused = true)]`. Time then only advances once all
+//! nodes are idle, which makes the simulation deterministic and lets a simulated minute pass in an
+//! instant. Node keys and packet loss are derived from the seed of the network, so a simulation
+//! with the same seed always builds the same network.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
+
+use mycelium::{
+    crypto::{PublicKey, SecretKey},
+    filters::{self, RouteUpdateFilter},
+    link_auth::LinkKeys,
+    packet::DataPacket,
+    router::{Router, DEFAULT_SELECTION_HYSTERESIS},
+    subnet::Subnet,
+    GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN,
+};
+use mycelium_metrics::NoMetrics;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::debug;
+
+pub use link::{Link, LinkConfig, MemoryConnection};
+
+mod link;
+
+/// Interval between checks of the routing tables while waiting for the network to converge.
+const CONVERGENCE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Identifier of a node in a [`Network`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+/// Identifier of a [`Link`] in a [`Network`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LinkId(usize);
+
+/// Marker error to indicate a [`Network`] did not converge before the timeout expired.
+#[derive(Debug)]
+pub struct NotConverged;
+
+/// A simulated node.
+struct Node {
+    router: Router<NoMetrics>,
+    subnet: Subnet,
+    /// Leaf nodes don't forward traffic between other nodes.
+    leaf: bool,
+    /// Data packets the router delivers to the node. These are not used, but the channel must be
+    /// kept open.
+    _tun_rx: mpsc::UnboundedReceiver<DataPacket>,
+}
+
+/// A [`Link`] in the [`Network`], with the nodes it connects.
+struct NetworkLink {
+    nodes: (NodeId, NodeId),
+    link: Link,
+}
+
+/// A simulated network of mycelium nodes.
+pub struct Network {
+    nodes: Vec<Node>,
+    links: BTreeMap<LinkId, NetworkLink>,
+    /// The node at the remote end of every connection, by connection identifier.
+    remotes: HashMap<String, NodeId>,
+    next_link: usize,
+    rng: StdRng,
+}
+
+impl Network {
+    /// Create a new, empty `Network`, seeded with a fixed seed.
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    /// Create a new, empty `Network`, using the given seed to generate node keys and packet loss.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            nodes: Vec::new(),
+            links: BTreeMap::new(),
+            remotes: HashMap::new(),
+            next_link: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Add a new node to the network. The node is not connected to any other node.
+    ///
+    /// # Panics
+    ///
+    /// This panics if it is not called from within a tokio runtime.
+    pub fn add_node(&mut self) -> NodeId {
+        self.spawn_node(false)
+    }
+
+    /// Add a new leaf node to the network. Leaf nodes can reach and be reached by other nodes, but
+    /// don't forward traffic between them. The node is not connected to any other node.
+    ///
+    /// # Panics
+    ///
+    /// This panics if it is not called from within a tokio runtime.
+    pub fn add_leaf_node(&mut self) -> NodeId {
+        self.spawn_node(true)
+    }
+
+    /// Create a node and add it to the network.
+    fn spawn_node(&mut self, leaf: bool) -> NodeId {
+        let node_key = SecretKey::from(self.rng.gen::<[u8; 32]>());
+        let node_pub_key = PublicKey::from(&node_key);
+        let subnet = Subnet::new(
+            Subnet::new(node_pub_key.address().into(), 64)
+                .expect("64 is a valid IPv6 prefix size; qed")
+                .network(),
+            64,
+        )
+        .expect("64 is a valid IPv6 prefix size; qed");
+        let (tun_tx, tun_rx) = mpsc::unbounded_channel();
+
+        // Use the same filters as regular nodes, so the simulation routes like a real network.
+        let update_filter: Box<dyn RouteUpdateFilter + Send + Sync> =
+            Box::new(filters::AllOf(vec![
+                Box::new(filters::AllowedSubnet::new(
+                    Subnet::new(GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN)
+                        .expect("Global subnet is properly defined; qed"),
+                )),
+                Box::new(filters::MaxSubnetSize::<64>),
+                Box::new(filters::RouterIdOwnsSubnet),
+            ]));
+
+        let router = Router::new(
+            1,
+            tun_tx,
+            subnet,
+            vec![subnet],
+            (node_key, node_pub_key),
+            vec![update_filter],
+            false,
+            None,
+            DEFAULT_SELECTION_HYSTERESIS,
+            None,
+            leaf,
+            NoMetrics,
+        )
+        .expect("Can create a router for a simulated node");
+
+        let id = NodeId(self.nodes.len());
+        debug!(%id, %subnet, leaf, "Added node to simulated network");
+        self.nodes.push(Node {
+            router,
+            subnet,
+            leaf,
+            _tun_rx: tun_rx,
+        });
+
+        id
+    }
+
+    /// All nodes in the network.
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> {
+        (0..self.nodes.len()).map(NodeId)
+    }
+
+    /// The [`Router`] of a node.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the node is not part of the network.
+    pub fn router(&self, node: NodeId) -> &Router<NoMetrics> {
+        &self.nodes[node.0].router
+    }
+
+    /// The subnet of a node.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the node is not part of the network.
+    pub fn subnet(&self, node: NodeId) -> Subnet {
+        self.nodes[node.0].subnet
+    }
+
+    /// Connect 2 nodes with a new [`Link`].
+    ///
+    /// # Panics
+    ///
+    /// This panics if one of the nodes is not part of the network, or if both nodes are the same.
+    pub fn connect(&mut self, a: NodeId, b: NodeId, config: LinkConfig) -> LinkId {
+        assert!(
+            a.0 < self.nodes.len() && b.0 < self.nodes.len(),
+            "Unknown node"
+        );
+        assert_ne!(a, b, "Can't connect a node to itself");
+
+        let id = LinkId(self.next_link);
+        self.next_link += 1;
+        self.open_link(id, (a, b), config);
+
+        id
+    }
+
+    /// Get a [`Link`] in the network, to inspect it or change its config.
+    pub fn link(&self, link: LinkId) -> Option<&Link> {
+        self.links.get(&link).map(|nl| &nl.link)
+    }
+
+    /// Remove a [`Link`] from the network. Both nodes see the connection closing immediately.
+    pub fn disconnect(&mut self, link: LinkId) {
+        if let Some(nl) = self.links.remove(&link) {
+            debug!(%link, a = %nl.nodes.0, b = %nl.nodes.1, "Removed link from simulated network");
+        }
+    }
+
+    /// Partition the network in 2 parts: the given nodes, and all other nodes. All packets on
+    /// links between both parts are dropped. Nodes notice this once the peer on the other side
+    /// of the link times out. Partitions are not cumulative, this replaces a previous partition.
+    pub fn partition(&mut self, nodes: &[NodeId]) {
+        for nl in self.links.values() {
+            nl.link
+                .set_partitioned(nodes.contains(&nl.nodes.0) != nodes.contains(&nl.nodes.1));
+        }
+    }
+
+    /// Remove the partition of the network. Links which were closed by their nodes in the mean
+    /// time are reopened, like a peer manager would reconnect to the peer.
+    pub fn heal(&mut self) {
+        let closed = self
+            .links
+            .iter()
+            .filter_map(|(id, nl)| {
+                nl.link.set_partitioned(false);
+                nl.link
+                    .closed()
+                    .then_some((*id, nl.nodes, nl.link.config()))
+            })
+            .collect::<Vec<_>>();
+        for (id, nodes, config) in closed {
+            self.open_link(id, nodes, config);
+        }
+    }
+
+    /// Let the network run for the given duration.
+    pub async fn run_for(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Returns `true` if every node has a selected route to exactly the nodes it can reach over
+    /// open, unpartitioned links. Paths never pass through leaf nodes.
+    pub fn converged(&self) -> bool {
+        self.nodes().all(|node| {
+            let expected = self
+                .reachable(node)
+                .into_iter()
+                .filter(|other| *other != node)
+                .map(|other| self.subnet(other))
+                .collect::<HashSet<_>>();
+            let actual = self
+                .router(node)
+                .load_selected_routes()
+                .into_iter()
+                .filter(|re| !re.metric().is_infinite())
+                .map(|re| re.source().subnet())
+                .collect::<HashSet<_>>();
+            expected == actual
+        })
+    }
+
+    /// Wait until the network [`converged`](Self::converged), returning the time it took. If the
+    /// network did not converge before the timeout expires, [`NotConverged`] is returned.
+    pub async fn wait_for_convergence(&self, timeout: Duration) -> Result<Duration, NotConverged> {
+        let start = Instant::now();
+        loop {
+            if self.converged() {
+                return Ok(start.elapsed());
+            }
+            if start.elapsed() >= timeout {
+                return Err(NotConverged);
+            }
+            tokio::time::sleep(CONVERGENCE_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// The node `from` currently forwards packets for `to` to, if it has a selected route for it.
+    pub fn next_hop(&self, from: NodeId, to: NodeId) -> Option<NodeId> {
+        let subnet = self.subnet(to);
+        self.router(from)
+            .load_selected_routes()
+            .into_iter()
+            .find(|re| re.source().subnet() == subnet && !re.metric().is_infinite())
+            .and_then(|re| {
+                self.remotes
+                    .get(re.neighbour().connection_identifier())
+                    .copied()
+            })
+    }
+
+    /// The nodes packets from `from` to `to` currently travel through, including both ends. This
+    /// returns [`None`] if a node on the path has no route, or if the routes form a loop.
+    pub fn path(&self, from: NodeId, to: NodeId) -> Option<Vec<NodeId>> {
+        let mut path = vec![from];
+        let mut current = from;
+        while current != to {
+            current = self.next_hop(current, to)?;
+            if path.contains(&current) {
+                return None;
+            }
+            path.push(current);
+        }
+
+        Some(path)
+    }
+
+    /// Create a link and add its connections to the routers of the nodes.
+    fn open_link(&mut self, id: LinkId, nodes: (NodeId, NodeId), config: LinkConfig) {
+        let identifiers = (
+            format!("Sim {id} to {}", nodes.1),
+            format!("Sim {id} to {}", nodes.0),
+        );
+        let rngs = (
+            StdRng::seed_from_u64(self.rng.gen()),
+            StdRng::seed_from_u64(self.rng.gen()),
+        );
+        self.remotes.insert(identifiers.0.clone(), nodes.1);
+        self.remotes.insert(identifiers.1.clone(), nodes.0);
+
+        let (link, con_a, con_b) = Link::new(config, identifiers, rngs);
+        for (node, con) in [(nodes.0, con_a), (nodes.1, con_b)] {
+            self.router(node)
+                .add_connection(con, &LinkKeys::default())
+                .expect("Can add a memory connection to a router");
+        }
+        debug!(%id, a = %nodes.0, b = %nodes.1, "Opened link in simulated network");
+
+        self.links.insert(id, NetworkLink { nodes, link });
+    }
+
+    /// All nodes which can be reached from `node` over open, unpartitioned links, including
+    /// `node` itself. Leaf nodes can be reached, but are not used to reach other nodes.
+    fn reachable(&self, node: NodeId) -> HashSet<NodeId> {
+        let mut reachable = HashSet::from([node]);
+        let mut queue = vec![node];
+        while let Some(current) = queue.pop() {
+            if current != node && self.nodes[current.0].leaf {
+                continue;
+            }
+            for nl in self.links.values() {
+                if nl.link.partitioned() || nl.link.closed() {
+                    continue;
+                }
+                let next = match nl.nodes {
+                    (a, b) if a == current => b,
+                    (a, b) if b == current => a,
+                    _ => continue,
+                };
+                if reachable.insert(next) {
+                    queue.push(next);
+                }
+            }
+        }
+
+        reachable
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node {}", self.0)
+    }
+}
+
+impl fmt::Display for LinkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "link {}", self.0)
+    }
+}
+
+impl fmt::Display for NotConverged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Network did not converge before the timeout expired")
+    }
+}
+
+impl std::error::Error for NotConverged {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LinkConfig, Network};
+
+    /// Virtual time a network gets to converge.
+    const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(300);
+
+    #[tokio::test(start_paused = true)]
+    async fn line_converges() {
+        let mut network = Network::new();
+        let nodes = (0..4).map(|_| network.add_node()).collect::<Vec<_>>();
+        for pair in nodes.windows(2) {
+            network.connect(pair[0], pair[1], LinkConfig::new());
+        }
+
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(network.path(nodes[0], nodes[3]), Some(nodes.clone()));
+        assert_eq!(
+            network.path(nodes[3], nodes[0]),
+            Some(nodes.iter().rev().copied().collect())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reroute_around_removed_link() {
+        let mut network = Network::new();
+        let (a, b, c) = (network.add_node(), network.add_node(), network.add_node());
+        let direct = network.connect(a, b, LinkConfig::new());
+        network.connect(
+            a,
+            c,
+            LinkConfig::new().with_delay(Duration::from_millis(50)),
+        );
+        network.connect(
+            c,
+            b,
+            LinkConfig::new().with_delay(Duration::from_millis(50)),
+        );
+
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(network.next_hop(a, b), Some(b));
+
+        network.disconnect(direct);
+        network.run_for(Duration::from_secs(1)).await;
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(network.path(a, b), Some(vec![a, c, b]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partition_and_heal() {
+        let mut network = Network::new();
+        let (a, b, c) = (network.add_node(), network.add_node(), network.add_node());
+        network.connect(a, b, LinkConfig::new());
+        network.connect(b, c, LinkConfig::new().with_loss(0.1));
+
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+
+        network.partition(&[a]);
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(network.next_hop(a, c), None);
+        assert_eq!(network.next_hop(c, a), None);
+        assert_eq!(network.path(c, b), Some(vec![c, b]));
+
+        network.heal();
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(network.path(a, c), Some(vec![a, b, c]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaf_does_not_transit() {
+        let mut network = Network::new();
+        let (a, b, c) = (network.add_node(), network.add_node(), network.add_node());
+        let leaf = network.add_leaf_node();
+        network.connect(a, leaf, LinkConfig::new());
+        network.connect(leaf, b, LinkConfig::new());
+        network.connect(
+            a,
+            c,
+            LinkConfig::new().with_delay(Duration::from_millis(100)),
+        );
+        network.connect(
+            c,
+            b,
+            LinkConfig::new().with_delay(Duration::from_millis(100)),
+        );
+
+        network
+            .wait_for_convergence(CONVERGENCE_TIMEOUT)
+            .await
+            .unwrap();
+        // The path through the leaf is shorter, but the leaf does not forward traffic.
+        assert_eq!(network.path(a, b), Some(vec![a, c, b]));
+        assert_eq!(network.path(b, a), Some(vec![b, c, a]));
+        assert_eq!(network.path(a, leaf), Some(vec![a, leaf]));
+        assert_eq!(network.path(leaf, b), Some(vec![leaf, b]));
+    }
+}
@@ -0,0 +1,264 @@
+//! In memory links between simulated nodes.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use mycelium::{connection::Connection, packet};
+use rand::{rngs::StdRng, Rng};
+use tokio::{
+    io::{
+        AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf,
+        WriteHalf,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::trace;
+
+/// Size of the in memory buffers of a link, per direction.
+const LINK_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Static cost of a [`MemoryConnection`]. This is the same as the cost of a TCP connection over
+/// IPv6, so link costs in the simulation are in line with those of a real network.
+const LINK_STATIC_COST: u16 = 10;
+
+/// Properties of a [`Link`]. These apply to both directions of the link.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConfig {
+    /// One way delay of packets sent over the link.
+    pub delay: Duration,
+    /// Fraction of packets which are dropped, between 0 and 1.
+    pub loss: f64,
+}
+
+impl LinkConfig {
+    /// A link without delay or loss.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the one way delay of the link.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Set the fraction of packets which are dropped on the link.
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+}
+
+/// State of a [`Link`] which can be changed while the link is running.
+#[derive(Debug)]
+struct LinkState {
+    config: LinkConfig,
+    /// Set if the link is part of a partition. All packets are dropped in this case.
+    partitioned: bool,
+    /// Set once one of the nodes closed its connection. A closed link is never reopened.
+    closed: bool,
+}
+
+/// A bidirectional in memory link between 2 simulated nodes.
+///
+/// Packets sent on the link are delayed and dropped according to its [`LinkConfig`]. Packets are
+/// never reordered, and are always dropped as a whole, like a lossy datagram link would.
+pub struct Link {
+    state: Arc<Mutex<LinkState>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Link {
+    /// Create a new `Link`, returning the link and the connections for both ends. The identifiers
+    /// are the [`Connection::identifier`] of the connections.
+    pub(crate) fn new(
+        config: LinkConfig,
+        identifiers: (String, String),
+        rngs: (StdRng, StdRng),
+    ) -> (Self, MemoryConnection, MemoryConnection) {
+        let state = Arc::new(Mutex::new(LinkState {
+            config,
+            partitioned: false,
+            closed: false,
+        }));
+
+        let (a, a_relay) = tokio::io::duplex(LINK_BUFFER_SIZE);
+        let (b, b_relay) = tokio::io::duplex(LINK_BUFFER_SIZE);
+        let (a_rx, a_tx) = tokio::io::split(a_relay);
+        let (b_rx, b_tx) = tokio::io::split(b_relay);
+
+        let mut tasks = Vec::with_capacity(4);
+        tasks.extend(relay(a_rx, b_tx, state.clone(), rngs.0));
+        tasks.extend(relay(b_rx, a_tx, state.clone(), rngs.1));
+
+        (
+            Link { state, tasks },
+            MemoryConnection {
+                stream: a,
+                identifier: identifiers.0,
+            },
+            MemoryConnection {
+                stream: b,
+                identifier: identifiers.1,
+            },
+        )
+    }
+
+    /// Get the current [`LinkConfig`].
+    pub fn config(&self) -> LinkConfig {
+        self.state.lock().unwrap().config
+    }
+
+    /// Change the [`LinkConfig`]. Packets which are already in flight keep their delay.
+    pub fn set_config(&self, config: LinkConfig) {
+        self.state.lock().unwrap().config = config;
+    }
+
+    /// Returns `true` if the link is cut by a partition.
+    pub fn partitioned(&self) -> bool {
+        self.state.lock().unwrap().partitioned
+    }
+
+    pub(crate) fn set_partitioned(&self, partitioned: bool) {
+        self.state.lock().unwrap().partitioned = partitioned;
+    }
+
+    /// Returns `true` if one of the nodes closed its connection, for instance because it
+    /// considered the other node dead while the link was partitioned.
+    pub fn closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        // Stopping the relays drops their end of the streams, so both nodes see the connection
+        // closing.
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Forward packets written in `rx` to `tx`, according to the state of the link. This spawns a
+/// task which splits the stream in packets and decides their fate, and a task which delivers
+/// packets once their delay passed.
+fn relay(
+    mut rx: ReadHalf<DuplexStream>,
+    mut tx: WriteHalf<DuplexStream>,
+    state: Arc<Mutex<LinkState>>,
+    mut rng: StdRng,
+) -> [JoinHandle<()>; 2] {
+    let (in_flight_tx, mut in_flight_rx) = mpsc::unbounded_channel::<(Instant, Bytes)>();
+
+    let splitter = tokio::spawn(async move {
+        let mut buf = BytesMut::with_capacity(LINK_BUFFER_SIZE);
+        loop {
+            while let Some(size) = packet::wire_size(&buf).filter(|size| buf.len() >= *size) {
+                let packet = buf.split_to(size).freeze();
+                let (config, partitioned) = {
+                    let state = state.lock().unwrap();
+                    (state.config, state.partitioned)
+                };
+                if partitioned || rng.gen_bool(config.loss.clamp(0., 1.)) {
+                    trace!(size, "Dropping packet on simulated link");
+                    continue;
+                }
+                if in_flight_tx
+                    .send((Instant::now() + config.delay, packet))
+                    .is_err()
+                {
+                    // The receiving node closed its connection.
+                    state.lock().unwrap().closed = true;
+                    return;
+                }
+            }
+
+            match rx.read_buf(&mut buf).await {
+                Ok(0) | Err(_) => {
+                    state.lock().unwrap().closed = true;
+                    return;
+                }
+                Ok(_) => {}
+            }
+        }
+    });
+
+    let deliverer = tokio::spawn(async move {
+        while let Some((deadline, packet)) = in_flight_rx.recv().await {
+            tokio::time::sleep_until(deadline).await;
+            if tx.write_all(&packet).await.is_err() {
+                return;
+            }
+        }
+        // The sending node closed its connection, so close the connection of the receiving node
+        // as well.
+        let _ = tx.shutdown().await;
+    });
+
+    [splitter, deliverer]
+}
+
+/// One end of a [`Link`], implementing the [`Connection`] trait.
+pub struct MemoryConnection {
+    stream: DuplexStream,
+    identifier: String,
+}
+
+impl Connection for MemoryConnection {
+    fn identifier(&self) -> Result<String, io::Error> {
+        Ok(self.identifier.clone())
+    }
+
+    fn static_link_cost(&self) -> Result<u16, io::Error> {
+        Ok(LINK_STATIC_COST)
+    }
+
+    fn remote_address(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl AsyncRead for MemoryConnection {
+    #[inline]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryConnection {
+    #[inline]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
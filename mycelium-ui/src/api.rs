@@ -1,4 +1,5 @@
 use mycelium::endpoint::Endpoint;
+use mycelium::peer_manager::PeerSettings;
 use mycelium_api::AddPeer;
 use std::net::SocketAddr;
 use urlencoding::encode;
@@ -89,6 +90,7 @@ pub async fn add_peer(
         .post(request_url)
         .json(&AddPeer {
             endpoint: peer_endpoint,
+            settings: PeerSettings::default(),
         })
         .send()
         .await?
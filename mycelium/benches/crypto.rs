@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mycelium::crypto::{Cipher, PacketBuffer, PublicKey, SecretKey};
+
+/// Size of the payload of the benchmarked packets, which is close to a full packet on a link with
+/// a regular MTU.
+const PAYLOAD_SIZE: usize = 1400;
+
+fn packet() -> PacketBuffer {
+    let mut pb = PacketBuffer::new();
+    pb.buffer_mut()[..PAYLOAD_SIZE].fill(0xAA);
+    pb.set_size(PAYLOAD_SIZE);
+    pb
+}
+
+fn ciphers(c: &mut Criterion) {
+    let ss = SecretKey::new().shared_secret(&PublicKey::from(&SecretKey::new()));
+
+    let mut group = c.benchmark_group("cipher");
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+    for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+        group.bench_with_input(
+            BenchmarkId::new("encrypt", cipher),
+            &cipher,
+            |b, &cipher| {
+                b.iter_batched(
+                    packet,
+                    |pb| ss.encrypt_with_nonce(pb, cipher, [0; 12]),
+                    criterion::BatchSize::SmallInput,
+                )
+            },
+        );
+
+        let encrypted = ss.encrypt_with_nonce(packet(), cipher, [0; 12]);
+        group.bench_with_input(
+            BenchmarkId::new("decrypt", cipher),
+            &cipher,
+            |b, &cipher| {
+                b.iter_batched(
+                    || encrypted.clone(),
+                    |data| ss.decrypt_with(data, cipher).unwrap(),
+                    criterion::BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ciphers);
+criterion_main!(benches);
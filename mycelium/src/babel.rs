@@ -4,19 +4,26 @@
 //! our specific use case. For reference, the implementation is based on [this
 //! RFC](https://datatracker.ietf.org/doc/html/rfc8966).
 
-use std::io;
+use std::{collections::VecDeque, io, sync::OnceLock};
 
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 use tracing::trace;
 
+use crate::crypto::{Signature, SIGNATURE_SIZE};
+
 pub use self::{
     hello::Hello, ihu::Ihu, route_request::RouteRequest, seqno_request::SeqNoRequest,
     update::Update,
 };
 
+pub use self::update::ORIGIN_FLAG_DATA_SESSIONS;
+
+use self::update::UpdateDefaults;
+
 pub use self::tlv::Tlv;
 
+pub mod auth;
 mod hello;
 mod ihu;
 mod route_request;
@@ -32,6 +39,8 @@ const BABEL_VERSION: u8 = 2;
 /// Size of a babel header on the wire.
 const HEADER_WIRE_SIZE: usize = 4;
 
+/// TLV type for a Pad1 TLV, a single byte of padding without length field.
+const TLV_TYPE_PAD1: u8 = 0;
 /// TLV type for the [`Hello`] tlv
 const TLV_TYPE_HELLO: u8 = 4;
 /// TLV type for the [`Ihu`] tlv
@@ -52,21 +61,232 @@ const AE_IPV6: u8 = 2;
 /// Link-local IPv6 address, the value is 8 bytes long. This implies a `fe80::/64` prefix.
 const AE_IPV6_LL: u8 = 3;
 
+/// Sub-TLV type for a Pad1 sub-TLV, a single byte of padding without length field.
+const SUB_TLV_TYPE_PAD1: u8 = 0;
+/// Sub-TLV type for a timestamp sub-TLV, as defined in [RFC
+/// 9616](https://datatracker.ietf.org/doc/html/rfc9616#section-3).
+const SUB_TLV_TYPE_TIMESTAMP: u8 = 3;
+/// Sub-TLV type for a gateway signature sub-TLV, carrying the signature of the gateway over an
+/// announced prefix. This type is taken from the range reserved for experimental use, and is
+/// not mandatory.
+const SUB_TLV_TYPE_GATEWAY_SIGNATURE: u8 = 112;
+/// Sub-TLV type for an origin signature sub-TLV, carrying the signature of the router which
+/// originated a route over the announced prefix and sequence number. This type is taken from the
+/// range reserved for experimental use, and is not mandatory.
+const SUB_TLV_TYPE_ORIGIN_SIGNATURE: u8 = 113;
+/// Sub-TLV type for an origin flags sub-TLV, carrying flags of the router which originated a
+/// route. The flags are covered by the origin signature. This type is taken from the range
+/// reserved for experimental use, and is not mandatory.
+const SUB_TLV_TYPE_ORIGIN_FLAGS: u8 = 114;
+/// Bit set in the type of sub-TLVs which must be understood by the receiver. If a receiver does
+/// not understand such a sub-TLV, the enclosing TLV must be ignored.
+const SUB_TLV_MANDATORY_BIT: u8 = 0x80;
+
+/// Get the current timestamp, to be included in a timestamp sub-TLV.
+///
+/// Timestamps count microseconds since an arbitrary, fixed point in time, and wrap around after
+/// roughly 71 minutes. As such they are only meaningful when compared to other timestamps of this
+/// node, using wrapping arithmetic. The tokio clock is used, so timestamps follow paused time in
+/// tests.
+pub fn timestamp() -> u32 {
+    static EPOCH: OnceLock<tokio::time::Instant> = OnceLock::new();
+    EPOCH
+        .get_or_init(tokio::time::Instant::now)
+        .elapsed()
+        .as_micros() as u32
+}
+
+/// Bodies of the sub-TLVs of a TLV which are understood by this implementation.
+#[derive(Debug, Default)]
+struct SubTlvs {
+    /// Body of the timestamp sub-TLV, if there is one.
+    timestamp: Option<Bytes>,
+    /// Body of the gateway signature sub-TLV, if there is one.
+    gateway_signature: Option<Bytes>,
+    /// Body of the origin signature sub-TLV, if there is one.
+    origin_signature: Option<Bytes>,
+    /// Body of the origin flags sub-TLV, if there is one.
+    origin_flags: Option<Bytes>,
+}
+
+/// Read the sub-TLVs in the next `len` bytes of the buffer, which are the trailing bytes of a TLV
+/// body. Padding and unknown sub-TLVs are skipped.
+///
+/// [`None`] is returned if the enclosing TLV must be ignored, because the sub-TLVs are malformed
+/// or there is an unknown mandatory sub-TLV. Regardless of the outcome, `len` bytes are consumed.
+fn read_sub_tlvs(src: &mut BytesMut, len: usize) -> Option<SubTlvs> {
+    let mut sub_tlvs = src.split_to(len.min(src.remaining()));
+    let mut known = SubTlvs::default();
+
+    while sub_tlvs.has_remaining() {
+        let sub_tlv_type = sub_tlvs.get_u8();
+        if sub_tlv_type == SUB_TLV_TYPE_PAD1 {
+            continue;
+        }
+        if !sub_tlvs.has_remaining() {
+            trace!("Sub-TLV without length field, drop TLV");
+            return None;
+        }
+        let sub_tlv_len = sub_tlvs.get_u8() as usize;
+        if sub_tlvs.remaining() < sub_tlv_len {
+            trace!("Sub-TLV body exceeds TLV body, drop TLV");
+            return None;
+        }
+        let body = sub_tlvs.split_to(sub_tlv_len).freeze();
+        match sub_tlv_type {
+            SUB_TLV_TYPE_TIMESTAMP => known.timestamp = Some(body),
+            SUB_TLV_TYPE_GATEWAY_SIGNATURE => known.gateway_signature = Some(body),
+            SUB_TLV_TYPE_ORIGIN_SIGNATURE => known.origin_signature = Some(body),
+            SUB_TLV_TYPE_ORIGIN_FLAGS => known.origin_flags = Some(body),
+            t if t & SUB_TLV_MANDATORY_BIT != 0 => {
+                trace!(sub_tlv_type, "Unknown mandatory sub-TLV, drop TLV");
+                return None;
+            }
+            // PadN and unknown sub-TLVs are ignored.
+            _ => {}
+        }
+    }
+
+    Some(known)
+}
+
+/// The length of the babel packet at the start of the buffer, excluding a possible trailer.
+/// Returns [`None`] if the buffer does not hold a full babel header yet.
+pub fn packet_len(src: &[u8]) -> Option<usize> {
+    if src.len() < HEADER_WIRE_SIZE {
+        return None;
+    }
+
+    Some(HEADER_WIRE_SIZE + u16::from_be_bytes([src[2], src[3]]) as usize)
+}
+
+/// The TLV type of a [`Tlv`] on the wire.
+fn tlv_type(tlv: &Tlv) -> u8 {
+    match tlv {
+        Tlv::Hello(_) => TLV_TYPE_HELLO,
+        Tlv::Ihu(_) => TLV_TYPE_IHU,
+        Tlv::Update(_) => TLV_TYPE_UPDATE,
+        Tlv::RouteRequest(_) => TLV_TYPE_ROUTE_REQUEST,
+        Tlv::SeqNoRequest(_) => TLV_TYPE_SEQNO_REQUEST,
+    }
+}
+
+/// Write a timestamp sub-TLV containing the given timestamps.
+fn write_timestamp_sub_tlv(dst: &mut BytesMut, timestamps: &[u32]) {
+    dst.put_u8(SUB_TLV_TYPE_TIMESTAMP);
+    dst.put_u8((timestamps.len() * 4) as u8);
+    for ts in timestamps {
+        dst.put_u32(*ts);
+    }
+}
+
+/// Write a gateway signature sub-TLV containing the given signature.
+fn write_gateway_signature_sub_tlv(dst: &mut BytesMut, signature: &Signature) {
+    dst.put_u8(SUB_TLV_TYPE_GATEWAY_SIGNATURE);
+    dst.put_u8(SIGNATURE_SIZE as u8);
+    dst.put_slice(signature.as_bytes());
+}
+
+/// Write an origin signature sub-TLV containing the given signature.
+fn write_origin_signature_sub_tlv(dst: &mut BytesMut, signature: &Signature) {
+    dst.put_u8(SUB_TLV_TYPE_ORIGIN_SIGNATURE);
+    dst.put_u8(SIGNATURE_SIZE as u8);
+    dst.put_slice(signature.as_bytes());
+}
+
+/// Write an origin flags sub-TLV containing the given flags.
+fn write_origin_flags_sub_tlv(dst: &mut BytesMut, flags: u8) {
+    dst.put_u8(SUB_TLV_TYPE_ORIGIN_FLAGS);
+    dst.put_u8(1);
+    dst.put_u8(flags);
+}
+
 /// A codec which can send and receive whole babel packets on the wire.
+///
+/// A packet can hold multiple TLVs. The decoder returns them one by one, and encoding a [`Vec`]
+/// of TLVs writes them all in a single packet.
 #[derive(Debug, Clone)]
 pub struct Codec {
     header: Option<Header>,
+    /// TLVs of a decoded packet which have not been returned yet.
+    pending: VecDeque<Tlv>,
 }
 
 impl Codec {
     /// Create a new `BabelCodec`.
     pub fn new() -> Self {
-        Self { header: None }
+        Self {
+            header: None,
+            pending: VecDeque::new(),
+        }
     }
 
-    /// Resets the `BabelCodec` to its default state.
-    pub fn reset(&mut self) {
-        self.header = None;
+    /// Get the next TLV of a previously decoded packet, if there is one.
+    pub fn next_pending(&mut self) -> Option<Tlv> {
+        self.pending.pop_front()
+    }
+
+    /// Decode all TLVs in the body of a packet, and queue them to be returned. Unknown and
+    /// malformed TLVs are skipped.
+    fn decode_body(&mut self, mut body: BytesMut) {
+        // Defaults only apply within a single packet.
+        let mut defaults = UpdateDefaults::default();
+
+        while body.has_remaining() {
+            // TLV header
+            let tlv_type = body.get_u8();
+            if tlv_type == TLV_TYPE_PAD1 {
+                continue;
+            }
+            if !body.has_remaining() {
+                trace!("TLV without length field, drop remainder of packet");
+                return;
+            }
+            let tlv_len = body.get_u8();
+            if body.remaining() < tlv_len as usize {
+                trace!("TLV exceeds packet body, drop remainder of packet");
+                return;
+            }
+
+            // TLV payload. The TLV is decoded from its own buffer, so a malformed TLV can't read
+            // into the next one.
+            let mut tlv_body = body.split_to(tlv_len as usize);
+            let min_wire_size = match tlv_type {
+                TLV_TYPE_HELLO => Hello::MIN_WIRE_SIZE,
+                TLV_TYPE_IHU => Ihu::MIN_WIRE_SIZE,
+                TLV_TYPE_UPDATE => Update::MIN_WIRE_SIZE,
+                TLV_TYPE_ROUTE_REQUEST => RouteRequest::MIN_WIRE_SIZE,
+                TLV_TYPE_SEQNO_REQUEST => SeqNoRequest::MIN_WIRE_SIZE,
+                _ => {
+                    // unrecoginized body type, silently drop
+                    trace!(tlv_type, "Dropping unrecognized tlv");
+                    continue;
+                }
+            };
+            if tlv_body.remaining() < min_wire_size {
+                trace!(tlv_type, "TLV shorter than its minimum size, drop TLV");
+                continue;
+            }
+
+            let tlv = match tlv_type {
+                TLV_TYPE_HELLO => Hello::from_bytes(&mut tlv_body, tlv_len).map(From::from),
+                TLV_TYPE_IHU => Ihu::from_bytes(&mut tlv_body, tlv_len).map(From::from),
+                TLV_TYPE_UPDATE => {
+                    Update::from_bytes(&mut tlv_body, tlv_len, &mut defaults).map(From::from)
+                }
+                TLV_TYPE_ROUTE_REQUEST => {
+                    RouteRequest::from_bytes(&mut tlv_body, tlv_len).map(From::from)
+                }
+                TLV_TYPE_SEQNO_REQUEST => {
+                    SeqNoRequest::from_bytes(&mut tlv_body, tlv_len).map(From::from)
+                }
+                _ => unreachable!("Unrecognized TLV types are skipped above; qed"),
+            };
+
+            if let Some(tlv) = tlv {
+                self.pending.push_back(tlv);
+            }
+        }
     }
 }
 
@@ -90,6 +310,11 @@ impl Decoder for Codec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Return the remaining TLVs of the previous packet first.
+        if let Some(tlv) = self.pending.pop_front() {
+            return Ok(Some(tlv));
+        }
+
         // Read a header if we don't have one yet.
         let header = if let Some(header) = self.header.take() {
             trace!("Continue from stored header");
@@ -115,44 +340,22 @@ impl Decoder for Codec {
             return Ok(None);
         }
 
+        // at this point we have a whole body loaded in the buffer.
+        let body = src.split_to(header.body_length as usize);
+
         // Siltently ignore packets which don't have the correct values set, as defined in the
         // spec. Note that we consume the amount of bytes indentified so we leave the parser in the
         // correct state for the next packet.
         if header.magic != BABEL_MAGIC || header.version != BABEL_VERSION {
             trace!("Dropping babel packet with wrong magic or version");
-            src.advance(header.body_length as usize);
-            self.reset();
             return Ok(None);
         }
 
-        // at this point we have a whole body loaded in the buffer. We currently don't support sub
-        // TLV's
-
         trace!("Read babel TLV body");
 
-        // TODO: Technically we need to loop here as we can have multiple TLVs.
+        self.decode_body(body);
 
-        // TLV header
-        let tlv_type = src.get_u8();
-        let body_len = src.get_u8();
-        // TLV payload
-        let tlv = match tlv_type {
-            TLV_TYPE_HELLO => Some(Hello::from_bytes(src).into()),
-            TLV_TYPE_IHU => Ihu::from_bytes(src, body_len).map(From::from),
-            TLV_TYPE_UPDATE => Update::from_bytes(src, body_len).map(From::from),
-            TLV_TYPE_ROUTE_REQUEST => RouteRequest::from_bytes(src, body_len).map(From::from),
-            TLV_TYPE_SEQNO_REQUEST => SeqNoRequest::from_bytes(src, body_len).map(From::from),
-            _ => {
-                // unrecoginized body type, silently drop
-                trace!("Dropping unrecognized tlv");
-                // We already read 2 bytes
-                src.advance(header.body_length as usize - 2);
-                self.reset();
-                return Ok(None);
-            }
-        };
-
-        Ok(tlv)
+        Ok(self.pending.pop_front())
     }
 }
 
@@ -165,16 +368,8 @@ impl Encoder<Tlv> for Codec {
         dst.put_u8(BABEL_VERSION);
         dst.put_u16(item.wire_size() as u16 + 2); // tlv payload + tlv header
 
-        // Write TLV's, TODO: currently only 1 TLV/body
-
         // TLV header
-        match item {
-            Tlv::Hello(_) => dst.put_u8(TLV_TYPE_HELLO),
-            Tlv::Ihu(_) => dst.put_u8(TLV_TYPE_IHU),
-            Tlv::Update(_) => dst.put_u8(TLV_TYPE_UPDATE),
-            Tlv::RouteRequest(_) => dst.put_u8(TLV_TYPE_ROUTE_REQUEST),
-            Tlv::SeqNoRequest(_) => dst.put_u8(TLV_TYPE_SEQNO_REQUEST),
-        }
+        dst.put_u8(tlv_type(&item));
         dst.put_u8(item.wire_size());
         item.write_bytes(dst);
 
@@ -182,6 +377,53 @@ impl Encoder<Tlv> for Codec {
     }
 }
 
+impl Encoder<Vec<Tlv>> for Codec {
+    type Error = io::Error;
+
+    /// Encode all TLVs in a single packet. The prefix and router id of consecutive updates are
+    /// compressed. The caller must make sure the TLVs fit in a single packet.
+    fn encode(&mut self, items: Vec<Tlv>, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+
+        // Write header, the body length is filled in once the body is written.
+        dst.put_u8(BABEL_MAGIC);
+        dst.put_u8(BABEL_VERSION);
+        dst.put_u16(0);
+
+        let mut defaults = UpdateDefaults::default();
+        for item in items {
+            // TLV header, the length is filled in once the TLV is written.
+            dst.put_u8(tlv_type(&item));
+            let len_pos = dst.len();
+            dst.put_u8(0);
+            match &item {
+                Tlv::Update(update) => update.write_bytes_compressed(dst, &mut defaults),
+                _ => item.write_bytes(dst),
+            }
+            let Ok(tlv_len) = u8::try_from(dst.len() - len_pos - 1) else {
+                dst.truncate(start);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLV exceeds the maximum TLV size",
+                ));
+            };
+            dst[len_pos] = tlv_len;
+        }
+
+        let body_length = dst.len() - start - HEADER_WIRE_SIZE;
+        if body_length > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLVs don't fit in a single packet",
+            ));
+        }
+        dst[start + 2..start + HEADER_WIRE_SIZE]
+            .copy_from_slice(&(body_length as u16).to_be_bytes());
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{net::Ipv6Addr, time::Duration};
@@ -200,7 +442,7 @@ mod tests {
         let hello = super::Hello::new_unicast(15.into(), 400);
 
         sender
-            .send(hello.clone().into())
+            .send(super::Tlv::from(hello.clone()))
             .await
             .expect("Send on a non-networked buffer can never fail; qed");
         let recv_hello = receiver
@@ -220,7 +462,7 @@ mod tests {
         let ihu = super::Ihu::new(27.into(), 400, None);
 
         sender
-            .send(ihu.clone().into())
+            .send(super::Tlv::from(ihu.clone()))
             .await
             .expect("Send on a non-networked buffer can never fail; qed");
         let recv_ihu = receiver
@@ -251,7 +493,7 @@ mod tests {
         );
 
         sender
-            .send(update.clone().into())
+            .send(super::Tlv::from(update.clone()))
             .await
             .expect("Send on a non-networked buffer can never fail; qed");
         println!("Sent update packet");
@@ -282,7 +524,7 @@ mod tests {
         );
 
         sender
-            .send(snr.clone().into())
+            .send(super::Tlv::from(snr.clone()))
             .await
             .expect("Send on a non-networked buffer can never fail; qed");
         let recv_update = receiver
@@ -305,7 +547,7 @@ mod tests {
         ));
 
         sender
-            .send(rr.clone().into())
+            .send(super::Tlv::from(rr.clone()))
             .await
             .expect("Send on a non-networked buffer can never fail; qed");
         let recv_update = receiver
@@ -315,4 +557,95 @@ mod tests {
             .expect("Can decode the previously encoded value");
         assert_eq!(super::Tlv::from(rr), recv_update);
     }
+
+    #[tokio::test]
+    async fn codec_multiple_tlvs() {
+        let (tx, rx) = tokio::io::duplex(4096);
+        let mut sender = Framed::new(tx, super::Codec::new());
+        let mut receiver = Framed::new(rx, super::Codec::new());
+
+        let router_id = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+            25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40,
+        ]
+        .into();
+        let tlvs: Vec<super::Tlv> = vec![
+            super::Hello::new_unicast(15.into(), 400).into(),
+            super::Update::new(
+                Duration::from_secs(400),
+                16.into(),
+                25.into(),
+                Subnet::new(Ipv6Addr::new(0x400, 1, 2, 3, 0, 0, 0, 0).into(), 64)
+                    .expect("64 is a valid IPv6 prefix size; qed"),
+                router_id,
+            )
+            .into(),
+            super::Update::new(
+                Duration::from_secs(400),
+                17.into(),
+                26.into(),
+                Subnet::new(Ipv6Addr::new(0x400, 1, 2, 4, 0, 0, 0, 0).into(), 64)
+                    .expect("64 is a valid IPv6 prefix size; qed"),
+                router_id,
+            )
+            .into(),
+            super::RouteRequest::new(None).into(),
+        ];
+
+        sender
+            .send(tlvs.clone())
+            .await
+            .expect("Send on a non-networked buffer can never fail; qed");
+        for tlv in tlvs {
+            let recv_tlv = receiver
+                .next()
+                .await
+                .expect("Buffer isn't closed so this is always `Some`; qed")
+                .expect("Can decode the previously encoded value");
+            match (tlv, recv_tlv) {
+                // Flags differ since the updates are compressed.
+                (super::Tlv::Update(sent), super::Tlv::Update(received)) => {
+                    assert_eq!(sent.subnet(), received.subnet());
+                    assert_eq!(sent.router_id(), received.router_id());
+                    assert_eq!(sent.seqno(), received.seqno());
+                }
+                (sent, received) => assert_eq!(sent, received),
+            }
+        }
+    }
+
+    #[test]
+    fn codec_skips_truncated_tlvs() {
+        use bytes::BufMut;
+        use tokio_util::codec::Decoder;
+
+        let body: &[u8] = &[
+            // Valid hello.
+            4, 6, 0x80, 0, 0, 25, 1, 144, //
+            // Empty hello.
+            4, 0, //
+            // Update which is shorter than its fixed fields.
+            8, 3, 2, 0, 64, //
+            // Ihu which announces an IPv6 address, without the address.
+            5, 6, 2, 0, 0, 0, 0, 0, //
+            // Valid wildcard route request.
+            9, 2, 0, 0,
+        ];
+        let mut src = bytes::BytesMut::new();
+        src.put_u8(super::BABEL_MAGIC);
+        src.put_u8(super::BABEL_VERSION);
+        src.put_u16(body.len() as u16);
+        src.put_slice(body);
+
+        let mut codec = super::Codec::new();
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(super::Hello::new_unicast(25.into(), 400).into())
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(super::RouteRequest::new(None).into())
+        );
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+    }
 }
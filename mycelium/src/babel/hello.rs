@@ -11,30 +11,74 @@ const HELLO_FLAG_UNICAST: u16 = 0x8000;
 /// Mask to apply to [`Hello`] flags, leaving only valid flags.
 const FLAG_MASK: u16 = 0b10000000_00000000;
 
-/// Wire size of a [`Hello`] TLV without TLV header.
+/// Wire size of a [`Hello`] TLV without TLV header and sub-TLVs.
 const HELLO_WIRE_SIZE: u8 = 6;
 
+/// Wire size of the timestamp sub-TLV of a [`Hello`], including sub-TLV header.
+const HELLO_TIMESTAMP_WIRE_SIZE: u8 = 6;
+
 /// Hello TLV body as defined in https://datatracker.ietf.org/doc/html/rfc8966#section-4.6.5.
+///
+/// A `Hello` can optionally carry a timestamp sub-TLV, as defined in [RFC
+/// 9616](https://datatracker.ietf.org/doc/html/rfc9616#section-3.1), which is echoed by the
+/// [`Ihu`](super::Ihu) sent in reply.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Hello {
     flags: u16,
     seqno: SeqNo,
     interval: u16,
+    /// Time the `Hello` was sent, in the clock of the sender.
+    timestamp: Option<u32>,
+    /// Time the `Hello` was received, in the local clock. This is only set for received `Hello`s
+    /// which carry a timestamp.
+    received_at: Option<u32>,
 }
 
 impl Hello {
+    /// Minimum size of a hello TLV body on the wire.
+    pub(super) const MIN_WIRE_SIZE: usize = HELLO_WIRE_SIZE as usize;
+
     /// Create a new unicast hello packet.
     pub fn new_unicast(seqno: SeqNo, interval: u16) -> Self {
         Self {
             flags: HELLO_FLAG_UNICAST,
             seqno,
             interval,
+            timestamp: None,
+            received_at: None,
         }
     }
 
+    /// Add a timestamp to this `Hello`, marking the time it is sent.
+    pub fn with_timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// The time this `Hello` was sent, in the clock of the sender, if it carries a timestamp.
+    pub fn timestamp(&self) -> Option<u32> {
+        self.timestamp
+    }
+
+    /// The time this `Hello` was received, in the local clock, if it carries a timestamp.
+    pub fn received_at(&self) -> Option<u32> {
+        self.received_at
+    }
+
+    /// Remove the timestamp from this `Hello`, so it can be sent to a peer which does not
+    /// understand sub-TLVs.
+    pub fn clear_timestamp(&mut self) {
+        self.timestamp = None;
+    }
+
     /// Calculates the size on the wire of this `Hello`.
     pub fn wire_size(&self) -> u8 {
         HELLO_WIRE_SIZE
+            + if self.timestamp.is_some() {
+                HELLO_TIMESTAMP_WIRE_SIZE
+            } else {
+                0
+            }
     }
 
     /// Construct a `Hello` from wire bytes.
@@ -43,18 +87,34 @@ impl Hello {
     ///
     /// This function will panic if there are insufficient bytes present in the provided buffer to
     /// decode a complete `Hello`.
-    pub fn from_bytes(src: &mut bytes::BytesMut) -> Self {
+    pub fn from_bytes(src: &mut bytes::BytesMut, len: u8) -> Option<Self> {
         let flags = src.get_u16() & FLAG_MASK;
         let seqno = src.get_u16().into();
         let interval = src.get_u16();
 
+        let timestamp = match super::read_sub_tlvs(
+            src,
+            (len as usize).saturating_sub(HELLO_WIRE_SIZE as usize),
+        )?
+        .timestamp
+        {
+            Some(mut ts) if ts.len() == 4 => Some(ts.get_u32()),
+            Some(_) => {
+                trace!("Ignoring hello timestamp sub-TLV with invalid length");
+                None
+            }
+            None => None,
+        };
+
         trace!("Read hello tlv body");
 
-        Self {
+        Some(Self {
             flags,
             seqno,
             interval,
-        }
+            timestamp,
+            received_at: timestamp.map(|_| super::timestamp()),
+        })
     }
 
     /// Encode this `Hello` tlv as part of a packet.
@@ -62,6 +122,9 @@ impl Hello {
         dst.put_u16(self.flags);
         dst.put_u16(self.seqno.into());
         dst.put_u16(self.interval);
+        if let Some(ts) = self.timestamp {
+            super::write_timestamp_sub_tlv(dst, &[ts]);
+        }
     }
 }
 
@@ -77,6 +140,8 @@ mod tests {
             flags: 0,
             seqno: 25.into(),
             interval: 400,
+            timestamp: None,
+            received_at: None,
         };
 
         hello.write_bytes(&mut buf);
@@ -90,6 +155,8 @@ mod tests {
             flags: super::HELLO_FLAG_UNICAST,
             seqno: 16.into(),
             interval: 4000,
+            timestamp: None,
+            received_at: None,
         };
 
         hello.write_bytes(&mut buf);
@@ -106,9 +173,11 @@ mod tests {
             flags: super::HELLO_FLAG_UNICAST,
             seqno: 19.into(),
             interval: 513,
+            timestamp: None,
+            received_at: None,
         };
 
-        assert_eq!(super::Hello::from_bytes(&mut buf), hello);
+        assert_eq!(super::Hello::from_bytes(&mut buf, 6), Some(hello));
         assert_eq!(buf.remaining(), 0);
 
         let mut buf = bytes::BytesMut::from(&[0b00000000u8, 0b00000000, 1, 19, 200, 100][..]);
@@ -117,9 +186,11 @@ mod tests {
             flags: 0,
             seqno: 275.into(),
             interval: 51300,
+            timestamp: None,
+            received_at: None,
         };
 
-        assert_eq!(super::Hello::from_bytes(&mut buf), hello);
+        assert_eq!(super::Hello::from_bytes(&mut buf, 6), Some(hello));
         assert_eq!(buf.remaining(), 0);
     }
 
@@ -131,9 +202,11 @@ mod tests {
             flags: super::HELLO_FLAG_UNICAST,
             seqno: 100.into(),
             interval: 400,
+            timestamp: None,
+            received_at: None,
         };
 
-        assert_eq!(super::Hello::from_bytes(&mut buf), hello);
+        assert_eq!(super::Hello::from_bytes(&mut buf, 6), Some(hello));
         assert_eq!(buf.remaining(), 0);
 
         let mut buf = bytes::BytesMut::from(&[0b00001001u8, 0b00000000, 0, 100, 1, 144][..]);
@@ -142,9 +215,11 @@ mod tests {
             flags: 0,
             seqno: 100.into(),
             interval: 400,
+            timestamp: None,
+            received_at: None,
         };
 
-        assert_eq!(super::Hello::from_bytes(&mut buf), hello);
+        assert_eq!(super::Hello::from_bytes(&mut buf, 6), Some(hello));
         assert_eq!(buf.remaining(), 0);
     }
 
@@ -154,9 +229,53 @@ mod tests {
 
         let hello_src = super::Hello::new_unicast(16.into(), 400);
         hello_src.write_bytes(&mut buf);
-        let decoded = super::Hello::from_bytes(&mut buf);
+        let decoded = super::Hello::from_bytes(&mut buf, 6);
+
+        assert_eq!(Some(hello_src), decoded);
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn timestamp_roundtrip() {
+        let mut buf = bytes::BytesMut::new();
+
+        let hello_src = super::Hello::new_unicast(16.into(), 400).with_timestamp(0x01020304);
+        hello_src.write_bytes(&mut buf);
+
+        assert_eq!(hello_src.wire_size(), 12);
+        assert_eq!(buf[..], [128, 0, 0, 16, 1, 144, 3, 4, 1, 2, 3, 4]);
+
+        let decoded = super::Hello::from_bytes(&mut buf, 12).unwrap();
+
+        assert_eq!(decoded.seqno, hello_src.seqno);
+        assert_eq!(decoded.timestamp(), Some(0x01020304));
+        assert!(decoded.received_at().is_some());
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn decode_skips_padding_and_unknown_sub_tlvs() {
+        // Pad1, PadN of 2 bytes, unknown non mandatory sub-TLV 64 of 1 byte.
+        let mut buf =
+            bytes::BytesMut::from(&[128u8, 0, 0, 100, 1, 144, 0, 1, 2, 0, 0, 64, 1, 9][..]);
+
+        let hello = super::Hello {
+            flags: super::HELLO_FLAG_UNICAST,
+            seqno: 100.into(),
+            interval: 400,
+            timestamp: None,
+            received_at: None,
+        };
+
+        assert_eq!(super::Hello::from_bytes(&mut buf, 14), Some(hello));
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn decode_ignores_unknown_mandatory_sub_tlv() {
+        let mut buf = bytes::BytesMut::from(&[128u8, 0, 0, 100, 1, 144, 200, 1, 9][..]);
 
-        assert_eq!(hello_src, decoded);
+        assert_eq!(super::Hello::from_bytes(&mut buf, 9), None);
         assert_eq!(buf.remaining(), 0);
     }
 }
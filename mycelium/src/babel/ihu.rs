@@ -1,6 +1,9 @@
 //! The babel [IHU TLV](https://datatracker.ietf.org/doc/html/rfc8966#name-ihu).
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
 
 use bytes::{Buf, BufMut};
 use tracing::trace;
@@ -12,15 +15,46 @@ use super::{AE_IPV4, AE_IPV6, AE_IPV6_LL, AE_WILDCARD};
 /// Base wire size of an [`Ihu`] without variable length address encoding.
 const IHU_BASE_WIRE_SIZE: u8 = 6;
 
+/// Wire size of the timestamp sub-TLV of an [`Ihu`], including sub-TLV header.
+const IHU_TIMESTAMP_WIRE_SIZE: u8 = 14;
+
+/// Round trip times calculated from timestamps which exceed this value are considered bogus.
+/// This happens if timestamps wrapped around, or the clock of the remote is broken.
+const MAX_TIMESTAMP_RTT: Duration = Duration::from_secs(60);
+
 /// IHU TLV body as defined in https://datatracker.ietf.org/doc/html/rfc8966#name-ihu.
+///
+/// An `Ihu` can optionally carry a timestamp sub-TLV, which allows the receiver to calculate the
+/// round trip time of the link. This is similar to the timestamps defined in [RFC
+/// 9616](https://datatracker.ietf.org/doc/html/rfc9616#section-3.2), but since we don't bundle
+/// a [`Hello`](super::Hello) with the `Ihu`, the time the `Ihu` is sent is included as well.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Ihu {
     rx_cost: Metric,
     interval: u16,
     address: Option<IpAddr>,
+    timestamps: Option<IhuTimestamps>,
+    /// Time the `Ihu` was received, in the local clock. This is only set for received `Ihu`s
+    /// which carry timestamps.
+    received_at: Option<u32>,
+}
+
+/// Timestamps carried by an [`Ihu`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IhuTimestamps {
+    /// Timestamp of the [`Hello`](super::Hello) this `Ihu` replies to, in the clock of the
+    /// receiver of the `Ihu`.
+    origin: u32,
+    /// Time the `Hello` was received, in the clock of the sender of the `Ihu`.
+    receive: u32,
+    /// Time the `Ihu` was sent, in the clock of the sender of the `Ihu`.
+    transmit: u32,
 }
 
 impl Ihu {
+    /// Minimum size of an ihu TLV body on the wire.
+    pub(super) const MIN_WIRE_SIZE: usize = IHU_BASE_WIRE_SIZE as usize;
+
     /// Create a new `Ihu` to be transmitted.
     pub fn new(rx_cost: Metric, interval: u16, address: Option<IpAddr>) -> Self {
         // An interval of 0 is illegal according to the RFC, as this value is used by the receiver
@@ -32,7 +66,49 @@ impl Ihu {
             rx_cost,
             interval,
             address,
+            timestamps: None,
+            received_at: None,
+        }
+    }
+
+    /// Add timestamps to this `Ihu`. `origin` is the timestamp of the [`Hello`](super::Hello)
+    /// this `Ihu` replies to, `receive` the local time that `Hello` was received, and `transmit`
+    /// the local time this `Ihu` is sent.
+    pub fn with_timestamps(mut self, origin: u32, receive: u32, transmit: u32) -> Self {
+        self.timestamps = Some(IhuTimestamps {
+            origin,
+            receive,
+            transmit,
+        });
+        self
+    }
+
+    /// Remove the timestamps from this `Ihu`, so it can be sent to a peer which does not
+    /// understand sub-TLVs.
+    pub fn clear_timestamps(&mut self) {
+        self.timestamps = None;
+    }
+
+    /// Calculate the round trip time of the link from the timestamps in this received `Ihu`.
+    ///
+    /// The round trip time is the time between sending the [`Hello`](super::Hello) and receiving
+    /// this `Ihu`, minus the time the remote held on to the `Hello` before replying. Returns
+    /// [`None`] if the `Ihu` does not carry timestamps, or if the timestamps are not consistent.
+    pub fn rtt(&self) -> Option<Duration> {
+        let timestamps = self.timestamps?;
+        let total = self.received_at?.wrapping_sub(timestamps.origin);
+        let held = timestamps.transmit.wrapping_sub(timestamps.receive);
+        let rtt = Duration::from_micros(total.checked_sub(held)? as u64);
+
+        if rtt > MAX_TIMESTAMP_RTT {
+            trace!(
+                ?rtt,
+                "Ignoring bogus round trip time calculated from IHU timestamps"
+            );
+            return None;
         }
+
+        Some(rtt)
     }
 
     /// Calculates the size on the wire of this `Ihu`.
@@ -44,6 +120,11 @@ impl Ihu {
                 // TODO: link local should be encoded differently
                 Some(IpAddr::V6(_)) => 16,
             }
+            + if self.timestamps.is_some() {
+                IHU_TIMESTAMP_WIRE_SIZE
+            } else {
+                0
+            }
     }
 
     /// Construct a `Ihu` from wire bytes.
@@ -58,27 +139,39 @@ impl Ihu {
         let _ = src.get_u8();
         let rx_cost = src.get_u16().into();
         let interval = src.get_u16();
-        let address = match ae {
-            AE_WILDCARD => None,
+        let (address, address_len) = match ae {
+            AE_WILDCARD => (None, 0),
             AE_IPV4 => {
                 let mut raw_ip = [0; 4];
+                if src.remaining() < 4 {
+                    trace!("Ihu TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip.copy_from_slice(&src[..4]);
                 src.advance(4);
-                Some(Ipv4Addr::from(raw_ip).into())
+                (Some(Ipv4Addr::from(raw_ip).into()), 4)
             }
             AE_IPV6 => {
                 let mut raw_ip = [0; 16];
+                if src.remaining() < 16 {
+                    trace!("Ihu TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip.copy_from_slice(&src[..16]);
                 src.advance(16);
-                Some(Ipv6Addr::from(raw_ip).into())
+                (Some(Ipv6Addr::from(raw_ip).into()), 16)
             }
             AE_IPV6_LL => {
                 let mut raw_ip = [0; 16];
                 raw_ip[0] = 0xfe;
                 raw_ip[1] = 0x80;
+                if src.remaining() < 8 {
+                    trace!("Ihu TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[8..].copy_from_slice(&src[..8]);
                 src.advance(8);
-                Some(Ipv6Addr::from(raw_ip).into())
+                (Some(Ipv6Addr::from(raw_ip).into()), 8)
             }
             _ => {
                 // Invalid AE type, skip reamining data and ignore
@@ -88,12 +181,32 @@ impl Ihu {
             }
         };
 
+        let timestamps = match super::read_sub_tlvs(
+            src,
+            (len as usize).saturating_sub(IHU_BASE_WIRE_SIZE as usize + address_len),
+        )?
+        .timestamp
+        {
+            Some(mut ts) if ts.len() == 12 => Some(IhuTimestamps {
+                origin: ts.get_u32(),
+                receive: ts.get_u32(),
+                transmit: ts.get_u32(),
+            }),
+            Some(_) => {
+                trace!("Ignoring ihu timestamp sub-TLV with invalid length");
+                None
+            }
+            None => None,
+        };
+
         trace!("Read ihu tlv body");
 
         Some(Self {
             rx_cost,
             interval,
             address,
+            timestamps,
+            received_at: timestamps.map(|_| super::timestamp()),
         })
     }
 
@@ -113,12 +226,18 @@ impl Ihu {
             Some(IpAddr::V4(ip)) => dst.put_slice(&ip.octets()),
             Some(IpAddr::V6(ip)) => dst.put_slice(&ip.octets()),
         }
+        if let Some(ts) = self.timestamps {
+            super::write_timestamp_sub_tlv(dst, &[ts.origin, ts.receive, ts.transmit]);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
 
     use bytes::Buf;
 
@@ -130,6 +249,8 @@ mod tests {
             rx_cost: 25.into(),
             interval: 400,
             address: Some(Ipv4Addr::new(1, 1, 1, 1).into()),
+            timestamps: None,
+            received_at: None,
         };
 
         ihu.write_bytes(&mut buf);
@@ -143,6 +264,8 @@ mod tests {
             rx_cost: 100.into(),
             interval: 4000,
             address: Some(Ipv6Addr::new(2, 0, 1234, 2345, 3456, 4567, 5678, 1).into()),
+            timestamps: None,
+            received_at: None,
         };
 
         ihu.write_bytes(&mut buf);
@@ -162,6 +285,8 @@ mod tests {
             rx_cost: 1.into(),
             interval: 300,
             address: None,
+            timestamps: None,
+            received_at: None,
         };
 
         let buf_len = buf.len();
@@ -174,6 +299,8 @@ mod tests {
             rx_cost: 2.into(),
             interval: 44,
             address: Some(Ipv4Addr::new(3, 4, 5, 6).into()),
+            timestamps: None,
+            received_at: None,
         };
 
         let buf_len = buf.len();
@@ -190,6 +317,8 @@ mod tests {
             rx_cost: 2.into(),
             interval: 44,
             address: Some(Ipv6Addr::new(0x400, 0, 5, 6, 0x708, 0x90a, 0xb0c, 0xd0e).into()),
+            timestamps: None,
+            received_at: None,
         };
 
         let buf_len = buf.len();
@@ -202,6 +331,8 @@ mod tests {
             rx_cost: 258.into(),
             interval: 42,
             address: Some(Ipv6Addr::new(0xfe80, 0, 0, 0, 0x708, 0x90a, 0xb0c, 0xd0e).into()),
+            timestamps: None,
+            received_at: None,
         };
 
         let buf_len = buf.len();
@@ -243,4 +374,48 @@ mod tests {
         assert_eq!(Some(hello_src), decoded);
         assert_eq!(buf.remaining(), 0);
     }
+
+    #[test]
+    fn timestamp_roundtrip() {
+        let mut buf = bytes::BytesMut::new();
+
+        let ihu_src = super::Ihu::new(16.into(), 400, Some(Ipv4Addr::new(1, 1, 1, 1).into()))
+            .with_timestamps(1, 2, 3);
+        ihu_src.write_bytes(&mut buf);
+
+        assert_eq!(ihu_src.wire_size(), 24);
+        assert_eq!(buf[10..], [3, 12, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+
+        let buf_len = buf.len();
+        let decoded = super::Ihu::from_bytes(&mut buf, buf_len as u8).unwrap();
+
+        assert_eq!(decoded.timestamps, ihu_src.timestamps);
+        assert_eq!(decoded.address, ihu_src.address);
+        assert!(decoded.received_at.is_some());
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn rtt_from_timestamps() {
+        let mut ihu = super::Ihu::new(16.into(), 400, None).with_timestamps(1_000, 50, 2_050);
+        // Not a received Ihu.
+        assert_eq!(ihu.rtt(), None);
+
+        // Hello sent at 1000, Ihu received at 10000, and the remote held on to the Hello for 2000.
+        ihu.received_at = Some(10_000);
+        assert_eq!(ihu.rtt(), Some(Duration::from_micros(7_000)));
+
+        // Local clock wrapped around.
+        ihu.timestamps = Some(super::IhuTimestamps {
+            origin: u32::MAX - 999,
+            receive: 50,
+            transmit: 2_050,
+        });
+        ihu.received_at = Some(8_000);
+        assert_eq!(ihu.rtt(), Some(Duration::from_micros(7_000)));
+
+        // Remote claims to have held on to the Hello longer than the total round trip.
+        ihu.received_at = Some(500);
+        assert_eq!(ihu.rtt(), None);
+    }
 }
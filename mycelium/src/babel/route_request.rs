@@ -18,6 +18,9 @@ pub struct RouteRequest {
 }
 
 impl RouteRequest {
+    /// Minimum size of a route request TLV body on the wire.
+    pub(super) const MIN_WIRE_SIZE: usize = ROUTE_REQUEST_BASE_WIRE_SIZE as usize;
+
     /// Creates a new `RouteRequest` for the given [`prefix`]. If no [`prefix`] is given, a full
     /// route table dumb in requested.
     ///
@@ -60,6 +63,10 @@ impl RouteRequest {
                     return None;
                 }
                 let mut raw_ip = [0; 4];
+                if src.remaining() < prefix_size {
+                    trace!("Route request TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[..prefix_size].copy_from_slice(&src[..prefix_size]);
                 src.advance(prefix_size);
                 Some(Ipv4Addr::from(raw_ip).into())
@@ -69,6 +76,10 @@ impl RouteRequest {
                     return None;
                 }
                 let mut raw_ip = [0; 16];
+                if src.remaining() < prefix_size {
+                    trace!("Route request TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[..prefix_size].copy_from_slice(&src[..prefix_size]);
                 src.advance(prefix_size);
                 Some(Ipv6Addr::from(raw_ip).into())
@@ -80,6 +91,10 @@ impl RouteRequest {
                 let mut raw_ip = [0; 16];
                 raw_ip[0] = 0xfe;
                 raw_ip[1] = 0x80;
+                if src.remaining() < 8 {
+                    trace!("Route request TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[8..].copy_from_slice(&src[..8]);
                 src.advance(8);
                 Some(Ipv6Addr::from(raw_ip).into())
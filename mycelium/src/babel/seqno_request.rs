@@ -31,6 +31,9 @@ pub struct SeqNoRequest {
 }
 
 impl SeqNoRequest {
+    /// Minimum size of a seqno request TLV body on the wire.
+    pub(super) const MIN_WIRE_SIZE: usize = SEQNO_REQUEST_BASE_WIRE_SIZE as usize;
+
     /// Create a new `SeqNoRequest` for the given [prefix](Subnet) advertised by the [`RouterId`],
     /// with the required new [`SeqNo`].
     pub fn new(seqno: SeqNo, router_id: RouterId, prefix: Subnet) -> SeqNoRequest {
@@ -116,6 +119,10 @@ impl SeqNoRequest {
                     return None;
                 }
                 let mut raw_ip = [0; 4];
+                if src.remaining() < prefix_size {
+                    trace!("Seqno request TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[..prefix_size].copy_from_slice(&src[..prefix_size]);
                 src.advance(prefix_size);
                 Ipv4Addr::from(raw_ip).into()
@@ -125,6 +132,10 @@ impl SeqNoRequest {
                     return None;
                 }
                 let mut raw_ip = [0; 16];
+                if src.remaining() < prefix_size {
+                    trace!("Seqno request TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[..prefix_size].copy_from_slice(&src[..prefix_size]);
                 src.advance(prefix_size);
                 Ipv6Addr::from(raw_ip).into()
@@ -136,6 +147,10 @@ impl SeqNoRequest {
                 let mut raw_ip = [0; 16];
                 raw_ip[0] = 0xfe;
                 raw_ip[1] = 0x80;
+                if src.remaining() < 8 {
+                    trace!("Seqno request TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[8..].copy_from_slice(&src[..8]);
                 src.advance(8);
                 Ipv6Addr::from(raw_ip).into()
@@ -39,6 +39,21 @@ impl Tlv {
             Self::SeqNoRequest(seqno_request) => seqno_request.write_bytes(dst),
         }
     }
+
+    /// Remove the sub-TLVs from this `Tlv`, if there are any. This is needed before sending the
+    /// `Tlv` to a peer which does not understand sub-TLVs.
+    pub fn clear_sub_tlvs(&mut self) {
+        match self {
+            Self::Hello(hello) => hello.clear_timestamp(),
+            Self::Ihu(ihu) => ihu.clear_timestamps(),
+            Self::Update(update) => {
+                update.set_gateway_signature(None);
+                update.set_origin_signature(None);
+                update.set_origin_flags(0);
+            }
+            Self::RouteRequest(_) | Self::SeqNoRequest(_) => {}
+        }
+    }
 }
 
 impl From<SeqNoRequest> for Tlv {
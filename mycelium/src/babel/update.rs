@@ -8,21 +8,54 @@ use std::{
 use bytes::{Buf, BufMut};
 use tracing::trace;
 
-use crate::{metric::Metric, router_id::RouterId, sequence_number::SeqNo, subnet::Subnet};
+use crate::{
+    crypto::{Signature, SIGNATURE_SIZE},
+    metric::Metric,
+    router_id::RouterId,
+    sequence_number::SeqNo,
+    subnet::Subnet,
+};
 
 use super::{AE_IPV4, AE_IPV6, AE_IPV6_LL, AE_WILDCARD};
 
 /// Flag bit indicating an [`Update`] TLV establishes a new default prefix.
-#[allow(dead_code)]
 const UPDATE_FLAG_PREFIX: u8 = 0x80;
 /// Flag bit indicating an [`Update`] TLV establishes a new default router-id.
-#[allow(dead_code)]
 const UPDATE_FLAG_ROUTER_ID: u8 = 0x40;
 /// Mask to apply to [`Update`] flags, leaving only valid flags.
 const FLAG_MASK: u8 = 0b1100_0000;
 
 /// Base wire size of an [`Update`] without variable length address encoding.
 const UPDATE_BASE_WIRE_SIZE: u8 = 10 + RouterId::BYTE_SIZE as u8;
+/// Wire size of a gateway signature sub-TLV, including the sub-TLV type and length.
+const GATEWAY_SIGNATURE_WIRE_SIZE: u8 = 2 + SIGNATURE_SIZE as u8;
+/// Wire size of an origin signature sub-TLV, including the sub-TLV type and length.
+const ORIGIN_SIGNATURE_WIRE_SIZE: u8 = 2 + SIGNATURE_SIZE as u8;
+/// Wire size of an origin flags sub-TLV, including the sub-TLV type and length.
+const ORIGIN_FLAGS_WIRE_SIZE: u8 = 2 + 1;
+/// Origin flag indicating the origin of the route requires data packets to be encrypted with a
+/// session key.
+pub const ORIGIN_FLAG_DATA_SESSIONS: u8 = 0x01;
+/// Domain separation for the data signed by the origin of a route, so the signature can't be used
+/// in a different context.
+const ORIGIN_SIGNATURE_DOMAIN: &[u8] = b"mycelium route origin v1";
+
+/// Defaults established by previous [`Update`] TLVs in the same packet, used to compress the
+/// prefix and router id of later updates.
+///
+/// An update with [`UPDATE_FLAG_PREFIX`] set establishes its prefix as default for its address
+/// family, and later updates of the same family can omit the leading octets they share with it.
+/// An update with [`UPDATE_FLAG_ROUTER_ID`] set carries a router id which becomes the default.
+/// Once there is a default router id, updates without the flag omit their router id.
+#[derive(Debug, Default)]
+pub struct UpdateDefaults {
+    /// Default prefix for IPv4 updates.
+    ipv4_prefix: Option<[u8; 4]>,
+    /// Default prefix for IPv6 updates.
+    ipv6_prefix: Option<[u8; 16]>,
+    /// Default router id.
+    router_id: Option<RouterId>,
+}
 
 /// Update TLV body as defined in https://datatracker.ietf.org/doc/html/rfc8966#name-update.
 #[derive(Debug, Clone, PartialEq)]
@@ -41,9 +74,21 @@ pub struct Update {
     /// Router id of the sender. Importantly this is not part of the update itself, though we do
     /// transmit it for now as such.
     router_id: RouterId,
+    /// Signature of a gateway over the announced [`Subnet`], if the subnet is not part of the
+    /// overlay but routed by a gateway.
+    gateway_signature: Option<Signature>,
+    /// Signature of the router identified by the router id over the announced [`Subnet`] and
+    /// [`SeqNo`], proving the route was originated by that router.
+    origin_signature: Option<Signature>,
+    /// Flags set by the origin of the route, which are covered by the origin signature.
+    origin_flags: u8,
 }
 
 impl Update {
+    /// Minimum size of an update TLV body on the wire. The router id can be omitted if a default
+    /// router id was set earlier in the packet.
+    pub(super) const MIN_WIRE_SIZE: usize = UPDATE_BASE_WIRE_SIZE as usize - RouterId::BYTE_SIZE;
+
     /// Create a new `Update`.
     pub fn new(
         interval: Duration,
@@ -61,6 +106,9 @@ impl Update {
             metric,
             subnet,
             router_id,
+            gateway_signature: None,
+            origin_signature: None,
+            origin_flags: 0,
         }
     }
 
@@ -74,6 +122,11 @@ impl Update {
         self.metric
     }
 
+    /// Set the [`Metric`] for the route in this `Update`.
+    pub fn set_metric(&mut self, metric: Metric) {
+        self.metric = metric;
+    }
+
     /// Return the [`Subnet`] in this `Update.`
     pub fn subnet(&self) -> Subnet {
         self.subnet
@@ -84,10 +137,73 @@ impl Update {
         self.router_id
     }
 
+    /// Return the signature of the gateway over the announced [`Subnet`], if there is one.
+    pub fn gateway_signature(&self) -> Option<&Signature> {
+        self.gateway_signature.as_ref()
+    }
+
+    /// Set or remove the signature of the gateway over the announced [`Subnet`].
+    pub fn set_gateway_signature(&mut self, signature: Option<Signature>) {
+        self.gateway_signature = signature;
+    }
+
+    /// Return the signature of the origin of the route over the announced [`Subnet`] and
+    /// [`SeqNo`], if there is one.
+    pub fn origin_signature(&self) -> Option<&Signature> {
+        self.origin_signature.as_ref()
+    }
+
+    /// Set or remove the signature of the origin of the route.
+    pub fn set_origin_signature(&mut self, signature: Option<Signature>) {
+        self.origin_signature = signature;
+    }
+
+    /// Return the flags set by the origin of the route. These can only be trusted if the origin
+    /// signature is valid.
+    pub fn origin_flags(&self) -> u8 {
+        self.origin_flags
+    }
+
+    /// Set the flags of the origin of the route. This must happen before the update is signed.
+    pub fn set_origin_flags(&mut self, flags: u8) {
+        self.origin_flags = flags;
+    }
+
+    /// The data which is signed by the origin of the route. This binds the [`Subnet`], the
+    /// [`SeqNo`], the [`RouterId`] and the origin flags if any are set, but not the metric, as
+    /// that is changed by every router which propagates the route.
+    pub fn origin_signature_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(
+            ORIGIN_SIGNATURE_DOMAIN.len() + 16 + 1 + 2 + RouterId::BYTE_SIZE + 1,
+        );
+        data.extend_from_slice(ORIGIN_SIGNATURE_DOMAIN);
+        match self.subnet.network() {
+            IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+        }
+        data.push(self.subnet.prefix_len());
+        data.extend_from_slice(&u16::from(self.seqno).to_be_bytes());
+        data.extend_from_slice(&self.router_id.as_bytes());
+        if self.origin_flags != 0 {
+            data.push(self.origin_flags);
+        }
+        data
+    }
+
     /// Calculates the size on the wire of this `Update`.
     pub fn wire_size(&self) -> u8 {
         let address_bytes = (self.subnet.prefix_len() + 7) / 8;
-        UPDATE_BASE_WIRE_SIZE + address_bytes
+        let mut sub_tlv_bytes = 0;
+        if self.gateway_signature.is_some() {
+            sub_tlv_bytes += GATEWAY_SIGNATURE_WIRE_SIZE;
+        }
+        if self.origin_signature.is_some() {
+            sub_tlv_bytes += ORIGIN_SIGNATURE_WIRE_SIZE;
+            if self.origin_flags != 0 {
+                sub_tlv_bytes += ORIGIN_FLAGS_WIRE_SIZE;
+            }
+        }
+        UPDATE_BASE_WIRE_SIZE + address_bytes + sub_tlv_bytes
     }
 
     /// Get the time until a new `Update` for the [`Subnet`] is received at the latest.
@@ -96,22 +212,31 @@ impl Update {
         Duration::from_millis(self.interval as u64 * 10)
     }
 
-    /// Construct an `Update` from wire bytes.
+    /// Construct an `Update` from wire bytes. Omitted parts of the prefix and router id are taken
+    /// from the `defaults` of the packet, which are updated if the `Update` establishes new ones.
     ///
     /// # Panics
     ///
     /// This function will panic if there are insufficient bytes present in the provided buffer to
     /// decode a complete `Update`.
-    pub fn from_bytes(src: &mut bytes::BytesMut, len: u8) -> Option<Self> {
+    pub fn from_bytes(
+        src: &mut bytes::BytesMut,
+        len: u8,
+        defaults: &mut UpdateDefaults,
+    ) -> Option<Self> {
+        let start_remaining = src.remaining();
         let ae = src.get_u8();
         let flags = src.get_u8() & FLAG_MASK;
         let plen = src.get_u8();
-        // Read "omitted" value, we assume this is 0
-        let _ = src.get_u8();
+        let omitted = src.get_u8() as usize;
         let interval = src.get_u16();
         let seqno = src.get_u16().into();
         let metric = src.get_u16().into();
         let prefix_size = ((plen + 7) / 8) as usize;
+        if omitted > prefix_size {
+            trace!("Update omits more octets than the prefix has, drop TLV");
+            return None;
+        }
         let prefix = match ae {
             AE_WILDCARD => {
                 if prefix_size != 0 {
@@ -125,8 +250,22 @@ impl Update {
                     return None;
                 }
                 let mut raw_ip = [0; 4];
-                raw_ip[..prefix_size].copy_from_slice(&src[..prefix_size]);
-                src.advance(prefix_size);
+                if omitted > 0 {
+                    let Some(default) = defaults.ipv4_prefix else {
+                        trace!("Update omits octets without default IPv4 prefix, drop TLV");
+                        return None;
+                    };
+                    raw_ip[..omitted].copy_from_slice(&default[..omitted]);
+                }
+                if src.remaining() < prefix_size - omitted {
+                    trace!("Update TLV too short for its address, drop TLV");
+                    return None;
+                }
+                raw_ip[omitted..prefix_size].copy_from_slice(&src[..prefix_size - omitted]);
+                src.advance(prefix_size - omitted);
+                if flags & UPDATE_FLAG_PREFIX != 0 {
+                    defaults.ipv4_prefix = Some(raw_ip);
+                }
                 Ipv4Addr::from(raw_ip).into()
             }
             AE_IPV6 => {
@@ -134,8 +273,22 @@ impl Update {
                     return None;
                 }
                 let mut raw_ip = [0; 16];
-                raw_ip[..prefix_size].copy_from_slice(&src[..prefix_size]);
-                src.advance(prefix_size);
+                if omitted > 0 {
+                    let Some(default) = defaults.ipv6_prefix else {
+                        trace!("Update omits octets without default IPv6 prefix, drop TLV");
+                        return None;
+                    };
+                    raw_ip[..omitted].copy_from_slice(&default[..omitted]);
+                }
+                if src.remaining() < prefix_size - omitted {
+                    trace!("Update TLV too short for its address, drop TLV");
+                    return None;
+                }
+                raw_ip[omitted..prefix_size].copy_from_slice(&src[..prefix_size - omitted]);
+                src.advance(prefix_size - omitted);
+                if flags & UPDATE_FLAG_PREFIX != 0 {
+                    defaults.ipv6_prefix = Some(raw_ip);
+                }
                 Ipv6Addr::from(raw_ip).into()
             }
             AE_IPV6_LL => {
@@ -145,6 +298,10 @@ impl Update {
                 let mut raw_ip = [0; 16];
                 raw_ip[0] = 0xfe;
                 raw_ip[1] = 0x80;
+                if src.remaining() < 8 {
+                    trace!("Update TLV too short for its address, drop TLV");
+                    return None;
+                }
                 raw_ip[8..].copy_from_slice(&src[..8]);
                 src.advance(8);
                 Ipv6Addr::from(raw_ip).into()
@@ -159,11 +316,55 @@ impl Update {
 
         let subnet = Subnet::new(prefix, plen).ok()?;
 
-        let mut router_id_bytes = [0u8; RouterId::BYTE_SIZE];
-        router_id_bytes.copy_from_slice(&src[..RouterId::BYTE_SIZE]);
-        src.advance(RouterId::BYTE_SIZE);
+        let router_id = match defaults.router_id {
+            Some(router_id) if flags & UPDATE_FLAG_ROUTER_ID == 0 => router_id,
+            _ => {
+                if src.remaining() < RouterId::BYTE_SIZE {
+                    trace!("Update TLV too short for its router id, drop TLV");
+                    return None;
+                }
+                let mut router_id_bytes = [0u8; RouterId::BYTE_SIZE];
+                router_id_bytes.copy_from_slice(&src[..RouterId::BYTE_SIZE]);
+                src.advance(RouterId::BYTE_SIZE);
 
-        let router_id = RouterId::from(router_id_bytes);
+                let router_id = RouterId::from(router_id_bytes);
+                if flags & UPDATE_FLAG_ROUTER_ID != 0 {
+                    defaults.router_id = Some(router_id);
+                }
+                router_id
+            }
+        };
+
+        let sub_tlvs_len = (len as usize).saturating_sub(start_remaining - src.remaining());
+        let sub_tlvs = super::read_sub_tlvs(src, sub_tlvs_len)?;
+        let gateway_signature = match sub_tlvs.gateway_signature {
+            Some(sig) => match <[u8; SIGNATURE_SIZE]>::try_from(&sig[..]) {
+                Ok(sig) => Some(Signature::from(sig)),
+                Err(_) => {
+                    trace!("Ignoring update gateway signature sub-TLV with invalid length");
+                    None
+                }
+            },
+            None => None,
+        };
+        let origin_signature = match sub_tlvs.origin_signature {
+            Some(sig) => match <[u8; SIGNATURE_SIZE]>::try_from(&sig[..]) {
+                Ok(sig) => Some(Signature::from(sig)),
+                Err(_) => {
+                    trace!("Ignoring update origin signature sub-TLV with invalid length");
+                    None
+                }
+            },
+            None => None,
+        };
+        let origin_flags = match sub_tlvs.origin_flags {
+            Some(flags) if flags.len() == 1 => flags[0],
+            Some(_) => {
+                trace!("Ignoring update origin flags sub-TLV with invalid length");
+                0
+            }
+            None => 0,
+        };
 
         trace!("Read update tlv body");
 
@@ -174,6 +375,9 @@ impl Update {
             metric,
             subnet,
             router_id,
+            gateway_signature,
+            origin_signature,
+            origin_flags,
         })
     }
 
@@ -185,7 +389,7 @@ impl Update {
         });
         dst.put_u8(self.flags);
         dst.put_u8(self.subnet.prefix_len());
-        // Write "omitted" value, currently not used in our encoding scheme.
+        // Write "omitted" value, nothing is omitted without the defaults of the packet.
         dst.put_u8(0);
         dst.put_u16(self.interval);
         dst.put_u16(self.seqno.into());
@@ -195,8 +399,85 @@ impl Update {
             IpAddr::V4(ip) => dst.put_slice(&ip.octets()[..prefix_len]),
             IpAddr::V6(ip) => dst.put_slice(&ip.octets()[..prefix_len]),
         }
-        dst.put_slice(&self.router_id.as_bytes()[..])
+        dst.put_slice(&self.router_id.as_bytes()[..]);
+        self.write_sub_tlvs(dst);
     }
+
+    /// Encode this `Update` tlv as part of a packet, omitting the parts of the prefix and router
+    /// id which are already set in the `defaults` of the packet. The prefix and router id of this
+    /// `Update` become the new defaults.
+    pub fn write_bytes_compressed(&self, dst: &mut bytes::BytesMut, defaults: &mut UpdateDefaults) {
+        let prefix_len = ((self.subnet.prefix_len() + 7) / 8) as usize;
+        let (ae, omitted) = match self.subnet.address() {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                let omitted = common_octets(defaults.ipv4_prefix.as_ref(), &octets, prefix_len);
+                defaults.ipv4_prefix = Some(octets);
+                (AE_IPV4, omitted)
+            }
+            IpAddr::V6(ip) => {
+                let octets = ip.octets();
+                let omitted = common_octets(defaults.ipv6_prefix.as_ref(), &octets, prefix_len);
+                defaults.ipv6_prefix = Some(octets);
+                (AE_IPV6, omitted)
+            }
+        };
+        let mut flags = UPDATE_FLAG_PREFIX;
+        let omit_router_id = defaults.router_id == Some(self.router_id);
+        if !omit_router_id {
+            flags |= UPDATE_FLAG_ROUTER_ID;
+            defaults.router_id = Some(self.router_id);
+        }
+
+        dst.put_u8(ae);
+        dst.put_u8(flags);
+        dst.put_u8(self.subnet.prefix_len());
+        dst.put_u8(omitted as u8);
+        dst.put_u16(self.interval);
+        dst.put_u16(self.seqno.into());
+        dst.put_u16(self.metric.into());
+        match self.subnet.address() {
+            IpAddr::V4(ip) => dst.put_slice(&ip.octets()[omitted..prefix_len]),
+            IpAddr::V6(ip) => dst.put_slice(&ip.octets()[omitted..prefix_len]),
+        }
+        if !omit_router_id {
+            dst.put_slice(&self.router_id.as_bytes()[..]);
+        }
+        self.write_sub_tlvs(dst);
+    }
+
+    /// Encode the sub-TLVs of this `Update`.
+    fn write_sub_tlvs(&self, dst: &mut bytes::BytesMut) {
+        if let Some(signature) = &self.gateway_signature {
+            super::write_gateway_signature_sub_tlv(dst, signature);
+        }
+        if let Some(signature) = &self.origin_signature {
+            super::write_origin_signature_sub_tlv(dst, signature);
+            // The flags are only meaningful with the signature which covers them.
+            if self.origin_flags != 0 {
+                super::write_origin_flags_sub_tlv(dst, self.origin_flags);
+            }
+        }
+    }
+}
+
+/// The amount of leading octets of the first `prefix_len` octets of `octets` which are equal to
+/// the `default`.
+fn common_octets<const N: usize>(
+    default: Option<&[u8; N]>,
+    octets: &[u8; N],
+    prefix_len: usize,
+) -> usize {
+    let Some(default) = default else {
+        return 0;
+    };
+
+    default
+        .iter()
+        .zip(octets)
+        .take(prefix_len)
+        .take_while(|(a, b)| a == b)
+        .count()
 }
 
 #[cfg(test)]
@@ -206,7 +487,11 @@ mod tests {
         time::Duration,
     };
 
-    use crate::{router_id::RouterId, subnet::Subnet};
+    use crate::{
+        crypto::{PublicKey, SecretKey, Signature, SIGNATURE_SIZE},
+        router_id::RouterId,
+        subnet::Subnet,
+    };
     use bytes::Buf;
 
     #[test]
@@ -221,6 +506,9 @@ mod tests {
             subnet: Subnet::new(Ipv6Addr::new(512, 25, 26, 27, 28, 0, 0, 29).into(), 64)
                 .expect("64 is a valid IPv6 prefix size; qed"),
             router_id: RouterId::from([1u8; RouterId::BYTE_SIZE]),
+            gateway_signature: None,
+            origin_signature: None,
+            origin_flags: 0,
         };
 
         ihu.write_bytes(&mut buf);
@@ -245,6 +533,9 @@ mod tests {
             subnet: Subnet::new(Ipv4Addr::new(10, 101, 4, 1).into(), 23)
                 .expect("23 is a valid IPv4 prefix size; qed"),
             router_id: RouterId::from([2u8; RouterId::BYTE_SIZE]),
+            gateway_signature: None,
+            origin_signature: None,
+            origin_flags: 0,
         };
 
         ihu.write_bytes(&mut buf);
@@ -276,11 +567,14 @@ mod tests {
             subnet: Subnet::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
                 .expect("0 is a valid IPv6 prefix size; qed"),
             router_id: RouterId::from([3u8; RouterId::BYTE_SIZE]),
+            gateway_signature: None,
+            origin_signature: None,
+            origin_flags: 0,
         };
 
         let buf_len = buf.len();
         assert_eq!(
-            super::Update::from_bytes(&mut buf, buf_len as u8),
+            super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default()),
             Some(ihu)
         );
         assert_eq!(buf.remaining(), 0);
@@ -301,11 +595,14 @@ mod tests {
             subnet: Subnet::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 10, 20, 30, 40).into(), 64)
                 .expect("92 is a valid IPv6 prefix size; qed"),
             router_id: RouterId::from([4u8; RouterId::BYTE_SIZE]),
+            gateway_signature: None,
+            origin_signature: None,
+            origin_flags: 0,
         };
 
         let buf_len = buf.len();
         assert_eq!(
-            super::Update::from_bytes(&mut buf, buf_len as u8),
+            super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default()),
             Some(ihu)
         );
         assert_eq!(buf.remaining(), 0);
@@ -325,7 +622,10 @@ mod tests {
 
         let buf_len = buf.len();
 
-        assert_eq!(super::Update::from_bytes(&mut buf, buf_len as u8), None);
+        assert_eq!(
+            super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default()),
+            None
+        );
         // Decode function should still consume the required amount of bytes to leave parser in a
         // good state (assuming the length in the tlv preamble is good).
         assert_eq!(buf.remaining(), 0);
@@ -350,11 +650,14 @@ mod tests {
             subnet: Subnet::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 10, 20, 30, 40).into(), 64)
                 .expect("92 is a valid IPv6 prefix size; qed"),
             router_id: RouterId::from([4u8; RouterId::BYTE_SIZE]),
+            gateway_signature: None,
+            origin_signature: None,
+            origin_flags: 0,
         };
 
         let buf_len = buf.len();
         assert_eq!(
-            super::Update::from_bytes(&mut buf, buf_len as u8),
+            super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default()),
             Some(ihu)
         );
         assert_eq!(buf.remaining(), 0);
@@ -377,9 +680,232 @@ mod tests {
         );
         hello_src.write_bytes(&mut buf);
         let buf_len = buf.len();
-        let decoded = super::Update::from_bytes(&mut buf, buf_len as u8);
+        let decoded = super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default());
 
         assert_eq!(Some(hello_src), decoded);
         assert_eq!(buf.remaining(), 0);
     }
+
+    #[test]
+    fn roundtrip_with_gateway_signature() {
+        let mut buf = bytes::BytesMut::new();
+
+        let mut update_src = super::Update::new(
+            Duration::from_secs(64),
+            10.into(),
+            25.into(),
+            Subnet::new(Ipv4Addr::new(192, 168, 10, 0).into(), 24)
+                .expect("24 is a valid IPv4 prefix size; qed"),
+            RouterId::from([6; RouterId::BYTE_SIZE]),
+        );
+        update_src.set_gateway_signature(Some(Signature::from([7; SIGNATURE_SIZE])));
+        update_src.write_bytes(&mut buf);
+        assert_eq!(buf.len(), update_src.wire_size() as usize);
+
+        let buf_len = buf.len();
+        let decoded = super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default());
+
+        assert_eq!(Some(update_src), decoded);
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn roundtrip_with_origin_signature() {
+        let mut buf = bytes::BytesMut::new();
+
+        let sk = SecretKey::new();
+        let router_id = RouterId::new(PublicKey::from(&sk));
+        let mut update_src = super::Update::new(
+            Duration::from_secs(64),
+            10.into(),
+            25.into(),
+            Subnet::new(
+                Ipv6Addr::new(0x21f, 0x4025, 0xabcd, 0xdead, 0, 0, 0, 0).into(),
+                64,
+            )
+            .expect("64 is a valid IPv6 prefix size; qed"),
+            router_id,
+        );
+        update_src.set_gateway_signature(Some(Signature::from([7; SIGNATURE_SIZE])));
+        update_src.set_origin_signature(Some(sk.sign(&update_src.origin_signature_data())));
+        update_src.write_bytes(&mut buf);
+        assert_eq!(buf.len(), update_src.wire_size() as usize);
+
+        let buf_len = buf.len();
+        let decoded = super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default())
+            .expect("Can decode a valid update");
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(update_src, decoded);
+
+        let signature = decoded
+            .origin_signature()
+            .expect("Origin signature is decoded");
+        assert!(router_id
+            .to_pubkey()
+            .verify(&decoded.origin_signature_data(), signature)
+            .is_ok());
+
+        // The signature does not cover the metric, but it does cover the seqno.
+        let mut changed = decoded.clone();
+        changed.set_metric(50.into());
+        assert_eq!(
+            changed.origin_signature_data(),
+            decoded.origin_signature_data()
+        );
+        let changed = super::Update::new(
+            Duration::from_secs(64),
+            11.into(),
+            25.into(),
+            decoded.subnet(),
+            router_id,
+        );
+        assert!(router_id
+            .to_pubkey()
+            .verify(&changed.origin_signature_data(), signature)
+            .is_err());
+    }
+
+    #[test]
+    fn roundtrip_with_origin_flags() {
+        let mut buf = bytes::BytesMut::new();
+
+        let sk = SecretKey::new();
+        let router_id = RouterId::new(PublicKey::from(&sk));
+        let mut update_src = super::Update::new(
+            Duration::from_secs(64),
+            10.into(),
+            25.into(),
+            Subnet::new(
+                Ipv6Addr::new(0x21f, 0x4025, 0xabcd, 0xdead, 0, 0, 0, 0).into(),
+                64,
+            )
+            .expect("64 is a valid IPv6 prefix size; qed"),
+            router_id,
+        );
+        let unflagged_data = update_src.origin_signature_data();
+        update_src.set_origin_flags(super::ORIGIN_FLAG_DATA_SESSIONS);
+        // The flags are covered by the signature.
+        assert_ne!(unflagged_data, update_src.origin_signature_data());
+        update_src.set_origin_signature(Some(sk.sign(&update_src.origin_signature_data())));
+        update_src.write_bytes(&mut buf);
+        assert_eq!(buf.len(), update_src.wire_size() as usize);
+
+        let buf_len = buf.len();
+        let decoded = super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default())
+            .expect("Can decode a valid update");
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(update_src, decoded);
+        assert_eq!(decoded.origin_flags(), super::ORIGIN_FLAG_DATA_SESSIONS);
+        assert!(router_id
+            .to_pubkey()
+            .verify(
+                &decoded.origin_signature_data(),
+                decoded
+                    .origin_signature()
+                    .expect("Origin signature is decoded")
+            )
+            .is_ok());
+
+        // Without signature, the flags are not sent.
+        let mut unsigned = update_src.clone();
+        unsigned.set_origin_signature(None);
+        let mut buf = bytes::BytesMut::new();
+        unsigned.write_bytes(&mut buf);
+        assert_eq!(buf.len(), unsigned.wire_size() as usize);
+        let buf_len = buf.len();
+        let decoded = super::Update::from_bytes(&mut buf, buf_len as u8, &mut Default::default())
+            .expect("Can decode a valid update");
+        assert_eq!(decoded.origin_flags(), 0);
+    }
+
+    #[test]
+    fn roundtrip_compressed() {
+        let mut buf = bytes::BytesMut::new();
+
+        let router_id = RouterId::from([6; RouterId::BYTE_SIZE]);
+        let first = super::Update::new(
+            Duration::from_secs(64),
+            10.into(),
+            25.into(),
+            Subnet::new(
+                Ipv6Addr::new(0x400, 0x4025, 0xabcd, 0xdead, 0, 0, 0, 0).into(),
+                64,
+            )
+            .expect("64 is a valid IPv6 prefix size; qed"),
+            router_id,
+        );
+        let second = super::Update::new(
+            Duration::from_secs(64),
+            11.into(),
+            30.into(),
+            Subnet::new(
+                Ipv6Addr::new(0x400, 0x4025, 0xabcd, 0xbeef, 0, 0, 0, 0).into(),
+                64,
+            )
+            .expect("64 is a valid IPv6 prefix size; qed"),
+            router_id,
+        );
+        let third = super::Update::new(
+            Duration::from_secs(64),
+            12.into(),
+            35.into(),
+            Subnet::new(Ipv4Addr::new(10, 0, 0, 0).into(), 8)
+                .expect("8 is a valid IPv4 prefix size; qed"),
+            RouterId::from([7; RouterId::BYTE_SIZE]),
+        );
+
+        let mut defaults = super::UpdateDefaults::default();
+        let mut lens = vec![];
+        for update in [&first, &second, &third] {
+            let start = buf.len();
+            update.write_bytes_compressed(&mut buf, &mut defaults);
+            lens.push(buf.len() - start);
+        }
+        // The second update omits 6 octets of its prefix, and its router id.
+        assert_eq!(lens[0], first.wire_size() as usize);
+        assert_eq!(
+            lens[1],
+            second.wire_size() as usize - 6 - RouterId::BYTE_SIZE
+        );
+        assert_eq!(lens[2], third.wire_size() as usize);
+
+        let mut defaults = super::UpdateDefaults::default();
+        for (update, len) in [first, second, third].into_iter().zip(lens) {
+            let decoded = super::Update::from_bytes(&mut buf, len as u8, &mut defaults)
+                .expect("Can decode a compressed update");
+            assert_eq!(decoded.subnet(), update.subnet());
+            assert_eq!(decoded.router_id(), update.router_id());
+            assert_eq!(decoded.seqno(), update.seqno());
+            assert_eq!(decoded.metric(), update.metric());
+        }
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn decode_ignores_omitted_octets_without_default() {
+        let mut buf = bytes::BytesMut::new();
+        let update = super::Update::new(
+            Duration::from_secs(64),
+            10.into(),
+            25.into(),
+            Subnet::new(
+                Ipv6Addr::new(0x400, 0x4025, 0xabcd, 0xdead, 0, 0, 0, 0).into(),
+                64,
+            )
+            .expect("64 is a valid IPv6 prefix size; qed"),
+            RouterId::from([6; RouterId::BYTE_SIZE]),
+        );
+        let mut defaults = super::UpdateDefaults::default();
+        update.write_bytes_compressed(&mut buf, &mut defaults);
+        let start = buf.len();
+        update.write_bytes_compressed(&mut buf, &mut defaults);
+
+        // The second update omits its full prefix, which can't be decoded on its own.
+        let mut second = buf.split_off(start);
+        let len = second.len();
+        assert_eq!(
+            super::Update::from_bytes(&mut second, len as u8, &mut Default::default()),
+            None
+        );
+    }
 }
@@ -37,6 +37,10 @@ pub trait Connection: AsyncRead + AsyncWrite {
 
     /// The static cost of using this connection
     fn static_link_cost(&self) -> Result<u16, io::Error>;
+
+    /// The address of the remote end of this connection, if the connection is backed by a
+    /// network socket.
+    fn remote_address(&self) -> Option<SocketAddr>;
 }
 
 /// A wrapper around a quic send and quic receive stream, implementing the [`Connection`] trait.
@@ -71,6 +75,10 @@ impl Connection for TcpStream {
             SocketAddr::V6(_) => PACKET_PROCESSING_COST_IP6_TCP,
         })
     }
+
+    fn remote_address(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
 }
 
 impl AsyncRead for Quic {
@@ -141,6 +149,10 @@ impl Connection for Quic {
             SocketAddr::V6(_) => PACKET_PROCESSING_COST_IP6_QUIC,
         })
     }
+
+    fn remote_address(&self) -> Option<SocketAddr> {
+        Some(self.remote)
+    }
 }
 
 #[cfg(test)]
@@ -155,4 +167,8 @@ impl Connection for DuplexStream {
     fn static_link_cost(&self) -> Result<u16, io::Error> {
         Ok(1)
     }
+
+    fn remote_address(&self) -> Option<SocketAddr> {
+        None
+    }
 }
@@ -20,4 +20,8 @@ impl super::Connection for tokio_openssl::SslStream<TcpStream> {
             SocketAddr::V6(_) => super::PACKET_PROCESSING_COST_IP6_TCP,
         })
     }
+
+    fn remote_address(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr().ok()
+    }
 }
@@ -45,6 +45,11 @@ where
     fn static_link_cost(&self) -> Result<u16, std::io::Error> {
         self.con.static_link_cost()
     }
+
+    #[inline]
+    fn remote_address(&self) -> Option<std::net::SocketAddr> {
+        self.con.remote_address()
+    }
 }
 
 impl<C> AsyncRead for Tracked<C>
@@ -6,9 +6,20 @@ use std::{
     fmt::Display,
     net::Ipv6Addr,
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 
-use aes_gcm::{aead::OsRng, AeadCore, AeadInPlace, Aes256Gcm, Key, KeyInit};
+use aes_gcm::{
+    aead::{generic_array::GenericArray, OsRng},
+    AeadInPlace, Aes256Gcm, Key, KeyInit,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use curve25519_dalek::{
+    edwards::EdwardsPoint,
+    montgomery::MontgomeryPoint,
+    scalar::{clamp_integer, Scalar},
+};
+use rand::RngCore;
 use serde::{de::Visitor, Deserialize, Serialize};
 
 /// Default MTU for a packet. Ideally this would not be needed and the [`PacketBuffer`] takes a
@@ -17,18 +28,46 @@ use serde::{de::Visitor, Deserialize, Serialize};
 /// expression. This _is_ possible on nightly rust, with a feature gate (generic_const_exprs).
 const PACKET_SIZE: usize = 1400;
 
-/// Size of an AES_GCM tag in bytes.
+/// Size of an AES_GCM tag in bytes. ChaCha20-Poly1305 uses a tag of the same size.
 const AES_TAG_SIZE: usize = 16;
 
-/// Size of an AES_GCM nonce in bytes.
+/// Size of an AES_GCM nonce in bytes. ChaCha20-Poly1305 uses a nonce of the same size.
 const AES_NONCE_SIZE: usize = 12;
 
+/// A nonce used to encrypt a [`PacketBuffer`].
+pub type Nonce = [u8; AES_NONCE_SIZE];
+
 /// Size of user defined data header. This header will be part of the encrypted data.
 const DATA_HEADER_SIZE: usize = 4;
 
+/// Size of a [`Signature`] in bytes.
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Prefix for the hash used to derive the nonce of a signature. This is `hash_1` in the XEdDSA
+/// specification, and separates the nonce derivation from the challenge hash.
+const SIGNATURE_NONCE_PREFIX: [u8; 32] = {
+    let mut prefix = [0xff; 32];
+    prefix[0] = 0xfe;
+    prefix
+};
+
 /// Size of a `PacketBuffer`.
 const PACKET_BUFFER_SIZE: usize = PACKET_SIZE + AES_TAG_SIZE + AES_NONCE_SIZE + DATA_HEADER_SIZE;
 
+/// An AEAD cipher used to encrypt the content of data packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// AES-256 in Galois/Counter mode. This is fast on hardware with AES instructions, and always
+    /// used for packets which are not encrypted with a session key.
+    #[default]
+    #[serde(rename = "aes-gcm")]
+    Aes256Gcm,
+    /// ChaCha20-Poly1305. This is fast on all hardware, and much faster than AES-GCM on hardware
+    /// without AES instructions.
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
 /// A public key used as part of Diffie Hellman key exchange. It is derived from a [`SecretKey`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PublicKey(x25519_dalek::PublicKey);
@@ -47,6 +86,15 @@ pub struct SecretKey(x25519_dalek::StaticSecret);
 #[derive(Clone)]
 pub struct SharedSecret([u8; 32]);
 
+/// A signature over some data, created by a [`SecretKey`] and verified with the corresponding
+/// [`PublicKey`].
+///
+/// Since keys are X25519 keys, signatures are created following the
+/// [XEdDSA](https://signal.org/docs/specifications/xeddsa/) scheme, using BLAKE3 instead of
+/// SHA-512 as hash function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature([u8; SIGNATURE_SIZE]);
+
 /// A buffer for packets. This holds enough space to  encrypt a packet in place without
 /// reallocating.
 ///
@@ -69,6 +117,18 @@ pub struct PacketBufferHeaderMut<'a> {
     data: &'a mut [u8; DATA_HEADER_SIZE],
 }
 
+/// Error returned when parsing an unknown [`Cipher`].
+#[derive(Debug, Clone, Copy)]
+pub struct UnknownCipher;
+
+impl Display for UnknownCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Unknown cipher, expected aes-gcm or chacha20-poly1305")
+    }
+}
+
+impl Error for UnknownCipher {}
+
 /// Opaque type indicating decryption failed.
 #[derive(Debug, Clone, Copy)]
 pub struct DecryptionError;
@@ -81,6 +141,18 @@ impl Display for DecryptionError {
 
 impl Error for DecryptionError {}
 
+/// Opaque type indicating a [`Signature`] is not valid for the given data and [`PublicKey`].
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidSignature;
+
+impl Display for InvalidSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Signature is not valid for this data and key")
+    }
+}
+
+impl Error for InvalidSignature {}
+
 impl SecretKey {
     /// Generate a new `StaticSecret` using [`OsRng`] as an entropy source.
     pub fn new() -> Self {
@@ -97,6 +169,40 @@ impl SecretKey {
     pub fn shared_secret(&self, other: &PublicKey) -> SharedSecret {
         SharedSecret(self.0.diffie_hellman(&other.0).to_bytes())
     }
+
+    /// Sign some data with this `SecretKey`. The resulting [`Signature`] can be verified with the
+    /// [`PublicKey`] of this key.
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        let k = Scalar::from_bytes_mod_order(clamp_integer(self.0.to_bytes()));
+        // The Edwards form of the public key is defined to have a sign bit of 0, so negate the
+        // private scalar if the actual point has the sign bit set.
+        let mut public = EdwardsPoint::mul_base(&k).compress().to_bytes();
+        let a = if public[31] & 0x80 != 0 { -k } else { k };
+        public[31] &= 0x7f;
+
+        let mut random = [0; 64];
+        rand::thread_rng().fill_bytes(&mut random);
+        let r = hash_to_scalar(&[&SIGNATURE_NONCE_PREFIX, a.as_bytes(), data, &random]);
+        let big_r = EdwardsPoint::mul_base(&r).compress();
+        let h = hash_to_scalar(&[big_r.as_bytes(), &public, data]);
+        let s = r + h * a;
+
+        let mut signature = [0; SIGNATURE_SIZE];
+        signature[..32].copy_from_slice(big_r.as_bytes());
+        signature[32..].copy_from_slice(s.as_bytes());
+        Signature(signature)
+    }
+}
+
+/// Hash the given parts to a [`Scalar`].
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = blake3::Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut buf = [0; 64];
+    hasher.finalize_xof().fill(&mut buf);
+    Scalar::from_bytes_mod_order_wide(&buf)
 }
 
 impl Default for SecretKey {
@@ -132,6 +238,43 @@ impl PublicKey {
     pub fn as_bytes(&self) -> &[u8; 32] {
         self.0.as_bytes()
     }
+
+    /// Verify that a [`Signature`] over the given data was created by the [`SecretKey`] of this
+    /// `PublicKey`.
+    pub fn verify(&self, data: &[u8], signature: &Signature) -> Result<(), InvalidSignature> {
+        let a = MontgomeryPoint(self.to_bytes())
+            .to_edwards(0)
+            .ok_or(InvalidSignature)?;
+        let big_r = &signature.0[..32];
+        let s = Option::<Scalar>::from(Scalar::from_canonical_bytes(
+            signature.0[32..]
+                .try_into()
+                .expect("Signature is 64 bytes; qed"),
+        ))
+        .ok_or(InvalidSignature)?;
+
+        let h = hash_to_scalar(&[big_r, a.compress().as_bytes(), data]);
+        let expected_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &a, &s);
+        if expected_r.compress().as_bytes() == big_r {
+            Ok(())
+        } else {
+            Err(InvalidSignature)
+        }
+    }
+}
+
+impl Signature {
+    /// View this `Signature` as a byte array.
+    pub fn as_bytes(&self) -> &[u8; SIGNATURE_SIZE] {
+        &self.0
+    }
+}
+
+impl From<[u8; SIGNATURE_SIZE]> for Signature {
+    /// Load a signature from a byte array.
+    fn from(bytes: [u8; SIGNATURE_SIZE]) -> Signature {
+        Signature(bytes)
+    }
 }
 
 impl SharedSecret {
@@ -139,18 +282,38 @@ impl SharedSecret {
     ///
     /// Internally, a new random nonce will be generated using the OS's crypto rng generator. This
     /// nonce is appended to the encrypted data.
-    pub fn encrypt(&self, mut data: PacketBuffer) -> Vec<u8> {
-        let key: Key<Aes256Gcm> = self.0.into();
-        let nonce = Aes256Gcm::generate_nonce(OsRng);
+    pub fn encrypt(&self, data: PacketBuffer) -> Vec<u8> {
+        let mut nonce = [0; AES_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        self.encrypt_with_nonce(data, Cipher::Aes256Gcm, nonce)
+    }
 
-        let cipher = Aes256Gcm::new(&key);
-        let tag = cipher
-            .encrypt_in_place_detached(&nonce, &[], &mut data.buf[..data.size])
-            .expect("Encryption can't fail; qed.");
+    /// Encrypt a [`PacketBuffer`] using the `SharedSecret` as key for the given [`Cipher`], and
+    /// the given nonce. The nonce is appended to the encrypted data, exactly like
+    /// [`SharedSecret::encrypt`] does.
+    ///
+    /// The caller must make sure a nonce is never used twice with the same key.
+    pub fn encrypt_with_nonce(
+        &self,
+        mut data: PacketBuffer,
+        cipher: Cipher,
+        nonce: Nonce,
+    ) -> Vec<u8> {
+        let key: Key<Aes256Gcm> = self.0.into();
+        let nonce = GenericArray::from_slice(&nonce);
+
+        let buf = &mut data.buf[..data.size];
+        let tag = match cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(&key).encrypt_in_place_detached(nonce, &[], buf),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(&key).encrypt_in_place_detached(nonce, &[], buf)
+            }
+        }
+        .expect("Encryption can't fail; qed.");
 
         data.buf[data.size..data.size + AES_TAG_SIZE].clone_from_slice(tag.as_slice());
         data.buf[data.size + AES_TAG_SIZE..data.size + AES_TAG_SIZE + AES_NONCE_SIZE]
-            .clone_from_slice(&nonce);
+            .clone_from_slice(nonce);
 
         data.buf.truncate(data.size + AES_NONCE_SIZE + AES_TAG_SIZE);
 
@@ -164,7 +327,17 @@ impl SharedSecret {
     /// If the passed in data to decrypt does not contain a valid nonce, decryption fails and an
     /// opaque error is returned. As an extension to this, if the data is not of sufficient length
     /// to contain a valid nonce, an error is returned immediately.
-    pub fn decrypt(&self, mut data: Vec<u8>) -> Result<PacketBuffer, DecryptionError> {
+    pub fn decrypt(&self, data: Vec<u8>) -> Result<PacketBuffer, DecryptionError> {
+        self.decrypt_with(data, Cipher::Aes256Gcm)
+    }
+
+    /// Decrypt a message previously encrypted with an equivalent `SharedSecret` for the given
+    /// [`Cipher`], with [`SharedSecret::encrypt_with_nonce`].
+    pub fn decrypt_with(
+        &self,
+        mut data: Vec<u8>,
+        cipher: Cipher,
+    ) -> Result<PacketBuffer, DecryptionError> {
         // Make sure we have sufficient data (i.e. a nonce).
         if data.len() < AES_NONCE_SIZE + AES_TAG_SIZE + DATA_HEADER_SIZE {
             return Err(DecryptionError);
@@ -177,10 +350,19 @@ impl SharedSecret {
             let (data, nonce) = data.split_at_mut(data_len - AES_NONCE_SIZE);
             let (data, tag) = data.split_at_mut(data.len() - AES_TAG_SIZE);
 
-            let cipher = Aes256Gcm::new(&key);
-            cipher
-                .decrypt_in_place_detached((&*nonce).into(), &[], data, (&*tag).into())
-                .map_err(|_| DecryptionError)?;
+            let (nonce, tag) = (
+                GenericArray::from_slice(nonce),
+                GenericArray::from_slice(tag),
+            );
+            match cipher {
+                Cipher::Aes256Gcm => {
+                    Aes256Gcm::new(&key).decrypt_in_place_detached(nonce, &[], data, tag)
+                }
+                Cipher::ChaCha20Poly1305 => {
+                    ChaCha20Poly1305::new(&key).decrypt_in_place_detached(nonce, &[], data, tag)
+                }
+            }
+            .map_err(|_| DecryptionError)?;
         }
 
         Ok(PacketBuffer {
@@ -191,6 +373,97 @@ impl SharedSecret {
     }
 }
 
+impl Cipher {
+    /// Detect the fastest cipher for this hardware. This is AES-GCM if the CPU has AES
+    /// instructions, and ChaCha20-Poly1305 otherwise.
+    pub fn detect() -> Self {
+        if has_aes_instructions() {
+            Cipher::Aes256Gcm
+        } else {
+            Cipher::ChaCha20Poly1305
+        }
+    }
+
+    /// Version of the user data header in packets encrypted with this cipher. Since the header is
+    /// encrypted as well, this lets the receiver verify the cipher the sender intended to use.
+    pub fn header_version(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    /// Identifier of the cipher on the wire.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 0,
+            Cipher::ChaCha20Poly1305 => 1,
+        }
+    }
+
+    /// Get the cipher with the given identifier on the wire, if it is known.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Cipher::Aes256Gcm),
+            1 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// Check if the CPU has instructions which accelerate AES-GCM.
+fn has_aes_instructions() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cipher::Aes256Gcm => "aes-gcm",
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+        })
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = UnknownCipher;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(UnknownCipher),
+        }
+    }
+}
+
+/// Get the nonce of data encrypted by a [`SharedSecret`]. Returns [`None`] if the data is too
+/// short to be valid encrypted content.
+pub fn nonce(data: &[u8]) -> Option<Nonce> {
+    if data.len() < AES_NONCE_SIZE + AES_TAG_SIZE + DATA_HEADER_SIZE {
+        return None;
+    }
+
+    Some(
+        data[data.len() - AES_NONCE_SIZE..]
+            .try_into()
+            .expect("Nonce size constant is correct; qed"),
+    )
+}
+
 impl PacketBuffer {
     /// Create a new blank `PacketBuffer`.
     pub fn new() -> Self {
@@ -250,6 +523,13 @@ impl From<[u8; 32]> for SecretKey {
     }
 }
 
+impl From<[u8; 32]> for SharedSecret {
+    /// Use a byte array, usually derived from other secrets, as `SharedSecret`.
+    fn from(bytes: [u8; 32]) -> SharedSecret {
+        SharedSecret(bytes)
+    }
+}
+
 impl fmt::Display for PublicKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(&faster_hex::hex_string(self.as_bytes()))
@@ -369,7 +649,10 @@ impl fmt::Debug for PacketBuffer {
 
 #[cfg(test)]
 mod tests {
-    use super::{PacketBuffer, SecretKey, AES_NONCE_SIZE, AES_TAG_SIZE, DATA_HEADER_SIZE};
+    use super::{
+        Cipher, PacketBuffer, PublicKey, SecretKey, Signature, AES_NONCE_SIZE, AES_TAG_SIZE,
+        DATA_HEADER_SIZE,
+    };
 
     #[test]
     /// Test if encryption works in general. We just create some random value and encrypt it.
@@ -424,6 +707,43 @@ mod tests {
         assert_eq!(&*original, &data[..]);
     }
 
+    #[test]
+    /// Encrypting with an explicit nonce appends that nonce, and decrypts like a random nonce.
+    fn encrypt_with_nonce_roundtrip() {
+        let ss = SecretKey::new().shared_secret(&(&SecretKey::new()).into());
+
+        let data = b"some packet";
+        let mut pb = PacketBuffer::new();
+        pb.buffer_mut()[..data.len()].copy_from_slice(data);
+        pb.set_size(data.len());
+
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let res = ss.encrypt_with_nonce(pb, Cipher::Aes256Gcm, nonce);
+        assert_eq!(super::nonce(&res), Some(nonce));
+        assert_eq!(super::nonce(&res[..AES_NONCE_SIZE + AES_TAG_SIZE]), None);
+
+        let original = ss.decrypt(res).expect("Decryption works");
+        assert_eq!(&*original, &data[..]);
+    }
+
+    #[test]
+    /// Data encrypted with ChaCha20-Poly1305 roundtrips, and can't be decrypted as AES-GCM.
+    fn chacha20_poly1305_roundtrip() {
+        let ss = SecretKey::new().shared_secret(&(&SecretKey::new()).into());
+
+        let data = b"some packet";
+        let mut pb = PacketBuffer::new();
+        pb.buffer_mut()[..data.len()].copy_from_slice(data);
+        pb.set_size(data.len());
+
+        let res = ss.encrypt_with_nonce(pb, Cipher::ChaCha20Poly1305, [7; 12]);
+        assert!(ss.decrypt(res.clone()).is_err());
+        let original = ss
+            .decrypt_with(res, Cipher::ChaCha20Poly1305)
+            .expect("Decryption works");
+        assert_eq!(&*original, &data[..]);
+    }
+
     #[test]
     /// Test if PacketBufferHeaderMut actually modifies the PacketBuffer storage.
     fn modify_header() {
@@ -447,4 +767,30 @@ mod tests {
         assert_eq!(pb.buffer().len(), super::PACKET_SIZE);
         assert_eq!(pb.buffer_mut().len(), super::PACKET_SIZE);
     }
+
+    #[test]
+    fn signatures_verify_with_matching_key() {
+        for _ in 0..16 {
+            let sk = SecretKey::new();
+            let pk = PublicKey::from(&sk);
+
+            let signature = sk.sign(b"some data");
+            assert!(pk.verify(b"some data", &signature).is_ok());
+        }
+    }
+
+    #[test]
+    fn signatures_reject_other_data_and_keys() {
+        let sk = SecretKey::new();
+        let pk = PublicKey::from(&sk);
+        let other_pk = PublicKey::from(&SecretKey::new());
+
+        let signature = sk.sign(b"some data");
+        assert!(pk.verify(b"other data", &signature).is_err());
+        assert!(other_pk.verify(b"some data", &signature).is_err());
+
+        let mut tampered = *signature.as_bytes();
+        tampered[40] ^= 1;
+        assert!(pk.verify(b"some data", &Signature::from(tampered)).is_err());
+    }
 }
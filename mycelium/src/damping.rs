@@ -0,0 +1,286 @@
+//! Damping of routes which change often, following [RFC 2439](https://datatracker.ietf.org/doc/html/rfc2439).
+//!
+//! Every time the selected route for a source loses its route or switches to a different next
+//! hop, a penalty is added to the source. The penalty decays exponentially over time. Once the
+//! penalty exceeds the suppress threshold, the source is suppressed until the penalty decays below
+//! the reuse threshold. While a source is suppressed, the router keeps the current next hop as long
+//! as it is usable, and doesn't send triggered updates for changes in the metric of the route.
+//! Unlike in BGP, a suppressed route is still used, as it is often the only route to a node.
+
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{crypto::PublicKey, source_table::SourceKey, subnet::Subnet};
+
+/// Penalty below which the flap history of a source is forgotten.
+const FORGET_PENALTY: f64 = 1.;
+
+/// Parameters of route flap damping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DampingConfig {
+    /// Penalty added every time the route for a source flaps.
+    pub penalty: u32,
+    /// Penalty above which a source is suppressed.
+    pub suppress_threshold: u32,
+    /// Penalty below which a suppressed source is used normally again.
+    pub reuse_threshold: u32,
+    /// Time in seconds after which the penalty of a source is halved.
+    pub half_life: u64,
+    /// Maximum penalty of a source. This limits the time a source remains suppressed once it
+    /// stops flapping.
+    pub max_penalty: u32,
+}
+
+impl Default for DampingConfig {
+    fn default() -> Self {
+        // Defaults from RFC 2439, with a maximum suppression time of 1 hour.
+        Self {
+            penalty: 1000,
+            suppress_threshold: 2000,
+            reuse_threshold: 750,
+            half_life: 15 * 60,
+            max_penalty: 12000,
+        }
+    }
+}
+
+/// Error returned when the parameters of a [`DampingConfig`] can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDampingConfig;
+
+impl DampingConfig {
+    /// Check if the parameters can be used. The reuse threshold must be above 0 and below the
+    /// suppress threshold, the suppress threshold can't exceed the maximum penalty, and the half
+    /// life can't be 0.
+    pub fn validate(&self) -> Result<(), InvalidDampingConfig> {
+        if self.reuse_threshold == 0
+            || self.reuse_threshold >= self.suppress_threshold
+            || self.suppress_threshold > self.max_penalty
+            || self.half_life == 0
+        {
+            return Err(InvalidDampingConfig);
+        }
+
+        Ok(())
+    }
+}
+
+/// The damping state of a source which flapped recently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DampedRoute {
+    /// The subnet of the source.
+    pub subnet: Subnet,
+    /// The router which originates the subnet.
+    pub router: PublicKey,
+    /// The current penalty of the source.
+    pub penalty: u32,
+    /// Whether the source is currently suppressed.
+    pub suppressed: bool,
+    /// Time until the source is used normally again, if it is suppressed and does not flap
+    /// anymore.
+    pub reuse_in: Option<Duration>,
+}
+
+/// Tracks the flaps of the routes of all sources.
+pub(crate) struct RouteDamping {
+    config: DampingConfig,
+    sources: Mutex<HashMap<SourceKey, FlapState>>,
+}
+
+/// Flap history of a single source.
+#[derive(Debug, Clone, Copy)]
+struct FlapState {
+    /// Penalty at the time of the last update.
+    penalty: f64,
+    /// Time the penalty was last updated.
+    updated: Instant,
+    suppressed: bool,
+}
+
+impl RouteDamping {
+    /// Create a new `RouteDamping` with the given config.
+    pub fn new(config: DampingConfig) -> Self {
+        Self {
+            config,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a flap of the route for a source. Returns `true` if the source is suppressed after
+    /// the flap.
+    pub fn record_flap(&self, source: SourceKey) -> bool {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        // Forget sources which stopped flapping, so the history does not grow unbounded.
+        sources.retain(|_, state| {
+            self.update(state, now);
+            state.penalty >= FORGET_PENALTY
+        });
+
+        let state = sources.entry(source).or_insert(FlapState {
+            penalty: 0.,
+            updated: now,
+            suppressed: false,
+        });
+        state.penalty =
+            (state.penalty + self.config.penalty as f64).min(self.config.max_penalty as f64);
+        if !state.suppressed && state.penalty >= self.config.suppress_threshold as f64 {
+            state.suppressed = true;
+        }
+
+        state.suppressed
+    }
+
+    /// Returns `true` if the source is currently suppressed.
+    pub fn suppressed(&self, source: &SourceKey) -> bool {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        let Some(state) = sources.get_mut(source) else {
+            return false;
+        };
+        self.update(state, now);
+
+        state.suppressed
+    }
+
+    /// The damping state of all sources which flapped recently.
+    pub fn damped_routes(&self) -> Vec<DampedRoute> {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        sources.retain(|_, state| {
+            self.update(state, now);
+            state.penalty >= FORGET_PENALTY
+        });
+
+        sources
+            .iter()
+            .map(|(source, state)| DampedRoute {
+                subnet: source.subnet(),
+                router: source.router_id().to_pubkey(),
+                penalty: state.penalty as u32,
+                suppressed: state.suppressed,
+                reuse_in: state
+                    .suppressed
+                    .then(|| {
+                        Duration::try_from_secs_f64(
+                            self.config.half_life as f64
+                                * (state.penalty / self.config.reuse_threshold as f64)
+                                    .log2()
+                                    .max(0.),
+                        )
+                        .ok()
+                    })
+                    .flatten(),
+            })
+            .collect()
+    }
+
+    /// Decay the penalty of a source to the given time, and lift the suppression if it decayed
+    /// enough.
+    fn update(&self, state: &mut FlapState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.updated);
+        let half_life = Duration::from_secs(self.config.half_life.max(1));
+        state.penalty *= 0.5_f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        state.updated = now;
+        if state.suppressed && state.penalty < self.config.reuse_threshold as f64 {
+            state.suppressed = false;
+        }
+    }
+}
+
+impl fmt::Display for InvalidDampingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            "Invalid route damping config, expected 0 < reuse threshold < suppress threshold <= max penalty and a half life above 0",
+        )
+    }
+}
+
+impl std::error::Error for InvalidDampingConfig {}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv6Addr, time::Duration};
+
+    use crate::{crypto::SecretKey, router_id::RouterId, source_table::SourceKey, subnet::Subnet};
+
+    use super::{DampingConfig, InvalidDampingConfig, RouteDamping};
+
+    fn source() -> SourceKey {
+        let sk = SecretKey::new();
+        SourceKey::new(
+            Subnet::new(Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 0).into(), 64).unwrap(),
+            RouterId::new((&sk).into()),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn suppress_and_reuse() {
+        let damping = RouteDamping::new(DampingConfig::default());
+        let source = source();
+
+        assert!(!damping.record_flap(source));
+        assert!(!damping.suppressed(&source));
+        assert!(damping.record_flap(source));
+        assert!(damping.suppressed(&source));
+
+        let damped = damping.damped_routes();
+        assert_eq!(damped.len(), 1);
+        assert!(damped[0].suppressed);
+        // 2000 decays to 750 in log2(2000 / 750) half lives.
+        let reuse_in = damped[0].reuse_in.unwrap();
+        assert!(reuse_in > Duration::from_secs(15 * 60));
+        assert!(reuse_in < Duration::from_secs(30 * 60));
+
+        // After 1 half life the penalty is 1000, still above the reuse threshold.
+        tokio::time::advance(Duration::from_secs(15 * 60)).await;
+        assert!(damping.suppressed(&source));
+        // After 2 half lives the penalty is 500.
+        tokio::time::advance(Duration::from_secs(15 * 60)).await;
+        assert!(!damping.suppressed(&source));
+        assert!(!damping.damped_routes()[0].suppressed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn penalty_is_capped() {
+        let damping = RouteDamping::new(DampingConfig::default());
+        let source = source();
+
+        for _ in 0..100 {
+            damping.record_flap(source);
+        }
+        assert_eq!(damping.damped_routes()[0].penalty, 12000);
+
+        // The maximum penalty decays below the reuse threshold in 4 half lives.
+        tokio::time::advance(Duration::from_secs(4 * 15 * 60 + 1)).await;
+        assert!(!damping.suppressed(&source));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert_eq!(DampingConfig::default().validate(), Ok(()));
+        for config in [
+            DampingConfig {
+                reuse_threshold: 0,
+                ..DampingConfig::default()
+            },
+            DampingConfig {
+                reuse_threshold: 2000,
+                ..DampingConfig::default()
+            },
+            DampingConfig {
+                max_penalty: 1000,
+                ..DampingConfig::default()
+            },
+            DampingConfig {
+                half_life: 0,
+                ..DampingConfig::default()
+            },
+        ] {
+            assert_eq!(config.validate(), Err(InvalidDampingConfig));
+        }
+    }
+}
@@ -1,13 +1,33 @@
-use std::net::{IpAddr, Ipv6Addr};
-
-use etherparse::{icmpv6::DestUnreachableCode, Icmpv6Type, PacketBuilder};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use etherparse::{
+    icmpv4::DestUnreachableHeader, icmpv6::DestUnreachableCode, Icmpv4Type, Icmpv6Type,
+    PacketBuilder,
+};
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use tokio::sync::mpsc::UnboundedReceiver;
-use tracing::{debug, error, trace, warn};
-
-use crate::{crypto::PacketBuffer, metrics::Metrics, packet::DataPacket, router::Router};
-
-/// Current version of the user data header.
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    crypto::{Cipher, PacketBuffer, SharedSecret},
+    diagnostics::{self, Probe, ProbeTracker, UnknownDestination},
+    firewall::{Firewall, FirewallPolicy},
+    ipv4::{self, Ipv4Overlay, IPV4_MIN_HEADER_SIZE},
+    metrics::Metrics,
+    packet::DataPacket,
+    router::Router,
+    session::{Protection, ReceiveError, SessionManager},
+    succession::Succession,
+};
+
+/// Current version of the user data header, for packets encrypted with AES-256-GCM. Packets
+/// encrypted with a different [`Cipher`] use [`Cipher::header_version`] instead.
 const USER_DATA_VERSION: u8 = 1;
 
 /// Type value indicating L3 data in the user data header.
@@ -20,6 +40,18 @@ const USER_DATA_MESSAGE_TYPE: u8 = 1;
 /// intermediate nodes send back icmp data, as the original data is encrypted.
 const USER_DATA_OOB_ICMP: u8 = 2;
 
+/// Type value indicating an IPv4 packet in the user data header, carried over the IPv4 overlay.
+const USER_DATA_L3_IPV4_TYPE: u8 = 3;
+
+/// Type value indicating a session handshake message in the user data header. These are always
+/// encrypted with the static shared secret of the nodes.
+const USER_DATA_SESSION_TYPE: u8 = 4;
+
+/// Type value indicating a key succession in the user data header, which tells the receiver the
+/// sender replaced its key. These are always encrypted with the static shared secret of the
+/// previous key of the sender.
+const USER_DATA_SUCCESSION_TYPE: u8 = 5;
+
 /// Minimum size in bytes of an IPv6 header.
 const IPV6_MIN_HEADER_SIZE: usize = 40;
 
@@ -38,18 +70,54 @@ const IP_VERSION_MASK: u8 = 0b1111_0000;
 /// must be masked first.
 const IPV6_VERSION_BYTE: u8 = 0b0110_0000;
 
+/// Version byte of an IP header indicating IPv4. Since the version is only 4 bits, the lower bits
+/// must be masked first.
+const IPV4_VERSION_BYTE: u8 = 0b0100_0000;
+
+/// Maximum size of an ICMPv4 error message, as specified in
+/// https://datatracker.ietf.org/doc/html/rfc1812#section-4.3.2.3.
+const MAX_ICMP4_SIZE: usize = 576;
+
+/// Size of an ICMPv4 header.
+const ICMP4_HEADER_SIZE: usize = 8;
+
+/// IP protocol number of TCP.
+const IP_PROTO_TCP: u8 = 6;
+
+/// IP protocol number of UDP.
+const IP_PROTO_UDP: u8 = 17;
+
 /// Default hop limit for message packets. For now this is set to 64 hops.
 ///
 /// For regular l3 packets, we copy the hop limit from the packet itself. We can't do that here, so
 /// 64 is used as sane default.
 const MESSAGE_HOP_LIMIT: u8 = 64;
 
+/// Minimum time between key successions sent to the same node.
+const SUCCESSION_NOTICE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Amount of nodes we remember sending a key succession to, before forgetting those we did not
+/// send one to recently.
+const MAX_SUCCESSION_NOTICES: usize = 1024;
+
 /// The DataPlane manages forwarding/receiving of local data packets to the [`Router`], and the
 /// encryption/decryption of them.
 ///
 /// DataPlane itself can be cloned, but this is not cheap on the router and should be avoided.
 pub struct DataPlane<M> {
     router: Router<M>,
+    /// Address assignment for the IPv4 overlay, if it is enabled.
+    ipv4_overlay: Option<Arc<Ipv4Overlay>>,
+    /// Probes sent by this node which are waiting for a reply.
+    probes: Arc<ProbeTracker>,
+    /// Answer echo requests for the node address ourselves, since there is no host to do so.
+    answer_echo_requests: bool,
+    /// Filter for packets received from the overlay, before they are delivered to the host.
+    firewall: Arc<Firewall>,
+    /// Ephemeral session keys negotiated with other nodes.
+    sessions: Arc<SessionManager>,
+    /// Time we last sent our key succession to nodes which sent packets to our previous address.
+    succession_notices: Arc<Mutex<HashMap<Ipv6Addr, Instant>>>,
 }
 
 impl<M> DataPlane<M>
@@ -60,8 +128,18 @@ where
     ///
     /// `l3_packet_stream` is a stream of l3 packets from the host, usually read from a TUN interface.
     /// `l3_packet_sink` is a sink for l3 packets received from a romte, usually send to a TUN interface,
+    /// `ipv4_overlay` enables carrying IPv4 packets over the overlay if it is set.
+    /// `answer_echo_requests` makes the data plane reply to ICMPv6 echo requests for the node
+    /// address itself, which should be set if there is no host behind it.
+    /// `firewall_policy` enables filtering packets before they are sent to the `l3_packet_sink` if
+    /// it is set.
+    /// `preferred_cipher` is the [`Cipher`] proposed when negotiating session keys.
     pub fn new<S, T, U>(
         router: Router<M>,
+        ipv4_overlay: Option<Ipv4Overlay>,
+        answer_echo_requests: bool,
+        firewall_policy: Option<FirewallPolicy>,
+        preferred_cipher: Cipher,
         l3_packet_stream: S,
         l3_packet_sink: T,
         message_packet_sink: U,
@@ -74,7 +152,17 @@ where
         U: Sink<(PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
         U::Error: std::fmt::Display,
     {
-        let dp = Self { router };
+        let dp = Self {
+            router,
+            ipv4_overlay: ipv4_overlay.map(Arc::new),
+            probes: Arc::new(ProbeTracker::new()),
+            answer_echo_requests,
+            firewall: Arc::new(Firewall::new(firewall_policy)),
+            sessions: Arc::new(SessionManager::new(preferred_cipher)),
+            succession_notices: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        tokio::spawn(dp.sessions.clone().sweep_sessions());
 
         tokio::spawn(
             dp.clone()
@@ -94,6 +182,18 @@ where
         &self.router
     }
 
+    /// Get the [`FirewallPolicy`] applied to packets received from the overlay, if the firewall
+    /// is enabled.
+    pub fn firewall_policy(&self) -> Option<FirewallPolicy> {
+        self.firewall.policy()
+    }
+
+    /// Replace the [`FirewallPolicy`] applied to packets received from the overlay. Setting no
+    /// policy disables the firewall.
+    pub fn set_firewall_policy(&self, policy: Option<FirewallPolicy>) {
+        self.firewall.set_policy(policy)
+    }
+
     async fn inject_l3_packet_loop<S, T>(self, mut l3_packet_stream: S, mut l3_packet_sink: T)
     where
         // TODO: no result
@@ -113,6 +213,20 @@ where
 
             trace!("Received packet from tun");
 
+            if packet.first().map(|b| b & IP_VERSION_MASK) == Some(IPV4_VERSION_BYTE) {
+                match self.ipv4_overlay {
+                    Some(ref ipv4_overlay) => {
+                        if let Some(icmp) = self.route_ipv4_packet(ipv4_overlay, packet) {
+                            if let Err(e) = l3_packet_sink.send(icmp).await {
+                                error!("Could not forward icmp packet back to TUN interface {e}");
+                            }
+                        }
+                    }
+                    None => trace!("Packet is IPv4, but the IPv4 overlay is disabled"),
+                }
+                continue;
+            }
+
             // Parse an IPv6 header. We don't care about the full header in reality. What we want
             // to know is:
             // - This is an IPv6 header
@@ -147,9 +261,9 @@ where
             );
 
             trace!("Received packet from TUN with dest addr: {:?}", dst_ip);
-            // Check if the source address is part of 400::/7
+            // Check if the source address is part of 400::/7, or of a prefix we are a gateway for.
             let first_src_byte = src_ip.segments()[0] >> 8;
-            if !(0x04..0x06).contains(&first_src_byte) {
+            if !(0x04..0x06).contains(&first_src_byte) && !self.router.is_gateway_address(src_ip) {
                 let mut icmp_packet = PacketBuffer::new();
                 let host = self.router.node_public_key().address().octets();
                 let icmp = PacketBuilder::ipv6(host, src_ip.octets(), 64).icmpv6(
@@ -173,11 +287,17 @@ where
             // should not be a route for it, and therefore the route step will generate the
             // appropriate ICMP.
 
+            self.firewall.track_outbound(&packet);
+
+            let flow = ipv6_flow(&packet);
+
             let mut header = packet.header_mut();
             header[0] = USER_DATA_VERSION;
             header[1] = USER_DATA_L3_TYPE;
 
-            if let Some(icmp) = self.encrypt_and_route_packet(src_ip, dst_ip, hop_limit, packet) {
+            if let Some(icmp) =
+                self.encrypt_and_route_packet(src_ip, dst_ip, hop_limit, flow, packet)
+            {
                 if let Err(e) = l3_packet_sink.send(icmp).await {
                     error!("Could not forward icmp packet back to TUN interface {e}");
                 }
@@ -187,6 +307,70 @@ where
         warn!("Data inject loop from host to router ended");
     }
 
+    /// Route an IPv4 packet read from the host to the node owning the destination address.
+    ///
+    /// If the packet can't be routed, an ICMP packet is returned which should be sent back to the
+    /// host.
+    fn route_ipv4_packet(
+        &self,
+        ipv4_overlay: &Ipv4Overlay,
+        mut packet: PacketBuffer,
+    ) -> Option<PacketBuffer> {
+        // We only need the TTL, source address and destination address from the header, which
+        // are part of the fixed 20 byte header.
+        if packet.len() < IPV4_MIN_HEADER_SIZE {
+            trace!("Packet can't contain an IPv4 header");
+            return None;
+        }
+
+        let ttl = packet[8];
+        let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst_ip = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+
+        trace!("Received IPv4 packet from TUN with dest addr: {dst_ip}");
+
+        if src_ip != ipv4_overlay.local_address() {
+            trace!("Dropping IPv4 packet with source address {src_ip} which is not ours");
+            return None;
+        }
+
+        if !ipv4_overlay.range().contains_ip(dst_ip.into()) {
+            trace!("Dropping IPv4 packet for {dst_ip} outside of the IPv4 overlay range");
+            return None;
+        }
+
+        let Some(dst_overlay_ip) = ipv4_overlay.resolve(dst_ip, &self.router) else {
+            debug!("No node found for IPv4 destination address {dst_ip}, dropping packet");
+            return icmp4_host_unreachable(src_ip, &packet);
+        };
+
+        self.firewall.track_outbound(&packet);
+
+        let flow = ipv4_flow(&packet);
+
+        let mut header = packet.header_mut();
+        header[0] = USER_DATA_VERSION;
+        header[1] = USER_DATA_L3_IPV4_TYPE;
+
+        if self
+            .encrypt_and_route_packet(
+                self.router.node_public_key().address(),
+                dst_overlay_ip,
+                ttl,
+                flow,
+                packet,
+            )
+            .is_some()
+        {
+            // The route disappeared between resolving the address and encrypting the packet.
+            // The returned ICMP packet is an ICMPv6 packet which is useless for the host, so just
+            // drop it.
+            debug!("No route to IPv4 destination address {dst_ip}, dropping packet");
+        }
+
+        None
+    }
+
     /// Inject a new packet where the content is a `message` fragment.
     pub fn inject_message_packet(
         &self,
@@ -198,11 +382,38 @@ where
         header[0] = USER_DATA_VERSION;
         header[1] = USER_DATA_MESSAGE_TYPE;
 
-        self.encrypt_and_route_packet(src_ip, dst_ip, MESSAGE_HOP_LIMIT, packet);
+        // Messages are not part of a flow, so all fragments between 2 nodes follow the same route.
+        self.encrypt_and_route_packet(src_ip, dst_ip, MESSAGE_HOP_LIMIT, 0, packet);
+    }
+
+    /// Send a probe with the given hop limit to `dst_ip`. The returned [`Probe`] can be used to
+    /// wait for the reply.
+    pub fn send_probe(&self, dst_ip: Ipv6Addr, hop_limit: u8) -> Result<Probe, UnknownDestination> {
+        let src_ip = self.router.node_public_key().address();
+        let (probe, mut packet) = self
+            .probes
+            .new_probe(src_ip, dst_ip, hop_limit)
+            .ok_or(UnknownDestination)?;
+
+        let flow = ipv6_flow(&packet);
+
+        let mut header = packet.header_mut();
+        header[0] = USER_DATA_VERSION;
+        header[1] = USER_DATA_L3_TYPE;
+
+        if self
+            .encrypt_and_route_packet(src_ip, dst_ip, hop_limit, flow, packet)
+            .is_some()
+        {
+            return Err(UnknownDestination);
+        }
+
+        Ok(probe)
     }
 
     /// Encrypt the content of a packet based on the destination key, and then inject the packet
-    /// into the [`Router`] for processing.
+    /// into the [`Router`] for processing. The flow identifies the flow the packet is part of,
+    /// and must be derived from the packet before it is encrypted.
     ///
     /// If no key exists for the destination, the content can'be encrypted, the packet is not injected
     /// into the router, and a packet is returned containing an ICMP packet. Note that a return
@@ -212,6 +423,7 @@ where
         src_ip: Ipv6Addr,
         dst_ip: Ipv6Addr,
         hop_limit: u8,
+        flow: u8,
         packet: PacketBuffer,
     ) -> Option<PacketBuffer> {
         // Get shared secret from node and dest address
@@ -244,16 +456,84 @@ where
             }
         };
 
+        let (raw_data, handshake) = self.sessions.encrypt(&shared_secret, packet);
         self.router.route_packet(DataPacket {
             dst_ip,
             src_ip,
             hop_limit,
-            raw_data: shared_secret.encrypt(packet),
+            raw_data,
+            flow,
         });
+        if let Some(handshake) = handshake {
+            self.send_handshake(dst_ip, &shared_secret, handshake);
+        }
 
         None
     }
 
+    /// Send a session handshake message to `dst_ip`, encrypted with the static shared secret.
+    fn send_handshake(
+        &self,
+        dst_ip: Ipv6Addr,
+        shared_secret: &SharedSecret,
+        mut message: PacketBuffer,
+    ) {
+        let mut header = message.header_mut();
+        header[0] = USER_DATA_VERSION;
+        header[1] = USER_DATA_SESSION_TYPE;
+
+        self.router.route_packet(DataPacket {
+            dst_ip,
+            src_ip: self.router.node_public_key().address(),
+            hop_limit: MESSAGE_HOP_LIMIT,
+            raw_data: shared_secret.encrypt(message),
+            flow: 0,
+        });
+    }
+
+    /// Send the [`Succession`] of our previous key to `dst_ip`, so it can use our current key. This
+    /// is sent at most once every [`SUCCESSION_NOTICE_INTERVAL`] to the same node.
+    fn send_succession(&self, dst_ip: Ipv6Addr) {
+        {
+            let mut notices = self.succession_notices.lock().unwrap();
+            if notices
+                .get(&dst_ip)
+                .is_some_and(|sent| sent.elapsed() < SUCCESSION_NOTICE_INTERVAL)
+            {
+                return;
+            }
+            if notices.len() >= MAX_SUCCESSION_NOTICES {
+                notices.retain(|_, sent| sent.elapsed() < SUCCESSION_NOTICE_INTERVAL);
+            }
+            notices.insert(dst_ip, Instant::now());
+        }
+
+        let Some(remote) = self.router.get_pubkey(dst_ip.into()) else {
+            trace!("Not sending key succession to unknown node {dst_ip}");
+            return;
+        };
+        let Some((succession, shared_secret)) = self.router.previous_shared_secret(&remote) else {
+            return;
+        };
+
+        let succession_bytes = succession.to_bytes();
+        let mut packet = PacketBuffer::new();
+        packet.buffer_mut()[..succession_bytes.len()].copy_from_slice(&succession_bytes);
+        packet.set_size(succession_bytes.len());
+        let mut header = packet.header_mut();
+        header[0] = USER_DATA_VERSION;
+        header[1] = USER_DATA_SUCCESSION_TYPE;
+
+        debug!("Sending key succession to {dst_ip}");
+        self.router.route_packet(DataPacket {
+            dst_ip,
+            src_ip: succession.previous().address(),
+            hop_limit: MESSAGE_HOP_LIMIT,
+            raw_data: shared_secret.encrypt(packet),
+            flow: 0,
+        });
+    }
+
     async fn extract_packet_loop<T, U>(
         self,
         mut l3_packet_sink: T,
@@ -266,6 +546,13 @@ where
         U::Error: std::fmt::Display,
     {
         while let Some(data_packet) = host_packet_source.recv().await {
+            // The host no longer has the address of our previous key, so packets for it can't be
+            // delivered. Tell the sender about our current key instead.
+            if self.router.is_previous_address(data_packet.dst_ip.into()) {
+                self.send_succession(data_packet.src_ip);
+                continue;
+            }
+
             // decrypt & send to TUN interface
             let shared_secret = if let Some(ss) = self
                 .router
@@ -276,21 +563,49 @@ where
                 trace!("Received packet from unknown sender");
                 continue;
             };
-            let mut decrypted_packet = match shared_secret.decrypt(data_packet.raw_data) {
-                Ok(data) => data,
-                Err(_) => {
-                    debug!("Dropping data packet with invalid encrypted content");
-                    continue;
-                }
-            };
+            let (mut decrypted_packet, protection) =
+                match self.sessions.decrypt(&shared_secret, data_packet.raw_data) {
+                    Ok(data) => data,
+                    Err(ReceiveError::Replayed) => {
+                        debug!("Dropping replayed data packet from {}", data_packet.src_ip);
+                        continue;
+                    }
+                    Err(ReceiveError::Decryption) => {
+                        debug!("Dropping data packet with invalid encrypted content");
+                        // The sender might use a session we don't know about, so negotiate a new
+                        // one.
+                        if let Some(handshake) = self.sessions.initiate(&shared_secret) {
+                            self.send_handshake(data_packet.src_ip, &shared_secret, handshake);
+                        }
+                        continue;
+                    }
+                };
 
             // Check header
             let header = decrypted_packet.header();
-            if header[0] != USER_DATA_VERSION {
+            if header[0] != protection.cipher().header_version() {
                 trace!("Dropping decrypted packet with unknown header version");
                 continue;
             }
 
+            // Once the sender uses sessions, or announced that it requires them, packets
+            // encrypted with the static shared secret could be replayed, so only handshakes and
+            // ICMP from nodes on the path are accepted without session.
+            if protection == Protection::Static
+                && !matches!(
+                    header[1],
+                    USER_DATA_SESSION_TYPE | USER_DATA_SUCCESSION_TYPE | USER_DATA_OOB_ICMP
+                )
+                && (self.sessions.requires_session(&shared_secret)
+                    || self.router.requires_data_session(data_packet.src_ip.into()))
+            {
+                debug!(
+                    "Dropping data packet from {} without session key",
+                    data_packet.src_ip
+                );
+                continue;
+            }
+
             // Route based on packet type.
             match header[1] {
                 USER_DATA_L3_TYPE => {
@@ -301,13 +616,148 @@ where
                         );
                         continue;
                     }
+                    // Only accept packets with a source address the sender owns, so other nodes
+                    // can't be impersonated towards the host.
+                    let src_ip = Ipv6Addr::from(
+                        <&[u8] as TryInto<[u8; 16]>>::try_into(&real_packet[8..24])
+                            .expect("Static range bounds on slice are correct length"),
+                    );
+                    if !self.router.is_valid_source(data_packet.src_ip, src_ip) {
+                        debug!(
+                            "Dropping packet from {} with spoofed source address {src_ip}",
+                            data_packet.src_ip
+                        );
+                        continue;
+                    }
                     // Adjust the hop limit in the decrypted packet to the new value.
                     real_packet[7] = data_packet.hop_limit;
+                    if self.probes.handle_echo_reply(&decrypted_packet) {
+                        trace!("Received reply to probe from {}", data_packet.src_ip);
+                        continue;
+                    }
+                    if self.answer_echo_requests {
+                        let node_ip = self.router.node_public_key().address();
+                        if let Some(mut reply) = diagnostics::echo_reply(node_ip, &decrypted_packet)
+                        {
+                            let hop_limit = reply[7];
+                            let flow = ipv6_flow(&reply);
+                            let mut header = reply.header_mut();
+                            header[0] = USER_DATA_VERSION;
+                            header[1] = USER_DATA_L3_TYPE;
+                            self.encrypt_and_route_packet(
+                                node_ip,
+                                data_packet.src_ip,
+                                hop_limit,
+                                flow,
+                                reply,
+                            );
+                            continue;
+                        }
+                    }
+                    if !self
+                        .firewall
+                        .allows_inbound(data_packet.src_ip, &decrypted_packet)
+                    {
+                        trace!("Firewall dropped packet from {}", data_packet.src_ip);
+                        continue;
+                    }
+                    if let Err(e) = l3_packet_sink.send(decrypted_packet).await {
+                        error!("Failed to send packet on local TUN interface: {e}",);
+                        continue;
+                    }
+                }
+                USER_DATA_L3_IPV4_TYPE => {
+                    let Some(ref ipv4_overlay) = self.ipv4_overlay else {
+                        trace!("Dropping IPv4 packet, the IPv4 overlay is disabled");
+                        continue;
+                    };
+                    let real_packet = decrypted_packet.buffer_mut();
+                    if real_packet.len() < IPV4_MIN_HEADER_SIZE
+                        || real_packet[0] & IP_VERSION_MASK != IPV4_VERSION_BYTE
+                        || real_packet.len() < (real_packet[0] & 0x0f) as usize * 4
+                    {
+                        debug!("Decrypted packet is not a valid IPv4 packet");
+                        continue;
+                    }
+                    // Only accept packets from the IPv4 address of the sender, and for our own
+                    // address, so IPv4 addresses can't be spoofed.
+                    let src_ip = Ipv4Addr::new(
+                        real_packet[12],
+                        real_packet[13],
+                        real_packet[14],
+                        real_packet[15],
+                    );
+                    let dst_ip = Ipv4Addr::new(
+                        real_packet[16],
+                        real_packet[17],
+                        real_packet[18],
+                        real_packet[19],
+                    );
+                    if src_ip != ipv4_overlay.address_of(data_packet.src_ip)
+                        || !ipv4_overlay.is_owner(src_ip, data_packet.src_ip, &self.router)
+                    {
+                        debug!(
+                            "Dropping IPv4 packet from {} with spoofed source address {src_ip}",
+                            data_packet.src_ip
+                        );
+                        continue;
+                    }
+                    if dst_ip != ipv4_overlay.local_address() {
+                        debug!("Dropping IPv4 packet for {dst_ip} which is not our address");
+                        continue;
+                    }
+                    // Adjust the TTL in the decrypted packet to the new hop limit.
+                    ipv4::set_ttl(real_packet, data_packet.hop_limit);
+                    if !self
+                        .firewall
+                        .allows_inbound(data_packet.src_ip, &decrypted_packet)
+                    {
+                        trace!("Firewall dropped IPv4 packet from {}", data_packet.src_ip);
+                        continue;
+                    }
                     if let Err(e) = l3_packet_sink.send(decrypted_packet).await {
                         error!("Failed to send packet on local TUN interface: {e}",);
                         continue;
                     }
                 }
+                USER_DATA_SESSION_TYPE => {
+                    if protection != Protection::Static {
+                        trace!("Dropping session handshake sent on a session");
+                        continue;
+                    }
+                    if let Some(reply) = self
+                        .sessions
+                        .handle_handshake(&shared_secret, &decrypted_packet)
+                    {
+                        self.send_handshake(data_packet.src_ip, &shared_secret, reply);
+                    }
+                }
+                USER_DATA_SUCCESSION_TYPE => {
+                    let Some(succession) = Succession::from_bytes(&decrypted_packet) else {
+                        trace!("Dropping truncated key succession");
+                        continue;
+                    };
+                    // The packet is encrypted with the shared secret of the previous key, but the
+                    // succession must also be signed by it.
+                    if self.router.get_pubkey(data_packet.src_ip.into())
+                        != Some(succession.previous())
+                        || succession.verify().is_err()
+                    {
+                        debug!(
+                            "Dropping key succession from {} with invalid signature",
+                            data_packet.src_ip
+                        );
+                        continue;
+                    }
+                    if self.router.add_succession(succession) {
+                        info!(
+                            previous = %succession.previous(),
+                            successor = %succession.successor(),
+                            "Node {} replaced its key",
+                            data_packet.src_ip
+                        );
+                    }
+                }
                 USER_DATA_MESSAGE_TYPE => {
                     if let Err(e) = message_packet_sink
                         .send((
@@ -373,7 +823,10 @@ where
                     };
 
                     // Where are the leftover bytes coming from
-                    let orig_pb = match key.decrypt(body[..body.len()].to_vec()) {
+                    let orig_pb = match self
+                        .sessions
+                        .decrypt_sent(&key, body[..body.len()].to_vec())
+                    {
                         Ok(pb) => pb,
                         Err(e) => {
                             warn!("Failed to decrypt ICMP data body {e}");
@@ -381,6 +834,15 @@ where
                         }
                     };
 
+                    if self.probes.handle_icmp_error(
+                        data_packet.src_ip,
+                        &header.icmp_type,
+                        &orig_pb,
+                    ) {
+                        trace!("Received ICMP error for probe from {}", data_packet.src_ip);
+                        continue;
+                    }
+
                     let packet = etherparse::PacketBuilder::ipv6(
                         data_packet.src_ip.octets(),
                         data_packet.dst_ip.octets(),
@@ -413,6 +875,28 @@ where
     }
 }
 
+/// Build an ICMPv4 host unreachable packet for the given IPv4 packet, to be sent back to the
+/// host which sent it.
+fn icmp4_host_unreachable(host: Ipv4Addr, packet: &[u8]) -> Option<PacketBuffer> {
+    let mut pb = PacketBuffer::new();
+    // From self to self
+    let icmp = PacketBuilder::ipv4(host.octets(), host.octets(), 64).icmpv4(
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Host),
+    );
+    // Scale to max size if needed
+    let orig_buf_end = packet
+        .len()
+        .min(MAX_ICMP4_SIZE - IPV4_MIN_HEADER_SIZE - ICMP4_HEADER_SIZE);
+    pb.set_size(icmp.size(orig_buf_end));
+    let mut b = pb.buffer_mut();
+    if let Err(e) = icmp.write(&mut b, &packet[..orig_buf_end]) {
+        error!("Failed to construct host unreachable ICMP packet {e}");
+        return None;
+    }
+
+    Some(pb)
+}
+
 impl<M> Clone for DataPlane<M>
 where
     M: Clone,
@@ -420,6 +904,53 @@ where
     fn clone(&self) -> Self {
         Self {
             router: self.router.clone(),
+            ipv4_overlay: self.ipv4_overlay.clone(),
+            probes: self.probes.clone(),
+            answer_echo_requests: self.answer_echo_requests,
+            firewall: self.firewall.clone(),
+            sessions: self.sessions.clone(),
+            succession_notices: self.succession_notices.clone(),
         }
     }
 }
+
+/// Compute the flow identifier of an IPv6 packet. The flow label is used if the host set one,
+/// otherwise the ports of TCP and UDP packets without extension headers are used. Returns 0 if the
+/// flow can't be determined. The addresses are not included, as forwarding nodes already know
+/// them.
+fn ipv6_flow(packet: &[u8]) -> u8 {
+    let flow_label = u32::from_be_bytes([0, packet[1] & 0x0F, packet[2], packet[3]]);
+    if flow_label != 0 {
+        return flow_id(flow_label);
+    }
+
+    transport_flow(packet[6], &packet[IPV6_MIN_HEADER_SIZE..])
+}
+
+/// Compute the flow identifier of an IPv4 packet, from the ports of TCP and UDP packets. Returns 0
+/// if the flow can't be determined.
+fn ipv4_flow(packet: &[u8]) -> u8 {
+    let header_len = ((packet[0] & 0x0F) as usize) * 4;
+    if packet.len() < header_len {
+        return 0;
+    }
+
+    transport_flow(packet[9], &packet[header_len..])
+}
+
+/// Compute the flow identifier from the ports in a transport header. Returns 0 if the protocol
+/// does not have ports, or the header is truncated.
+fn transport_flow(protocol: u8, header: &[u8]) -> u8 {
+    if !matches!(protocol, IP_PROTO_TCP | IP_PROTO_UDP) || header.len() < 4 {
+        return 0;
+    }
+
+    flow_id((protocol, &header[..4]))
+}
+
+/// Reduce flow fields to a flow identifier. This is never 0, which indicates an unknown flow.
+fn flow_id(fields: impl Hash) -> u8 {
+    let mut hasher = DefaultHasher::new();
+    fields.hash(&mut hasher);
+    (hasher.finish() % 255) as u8 + 1
+}
@@ -0,0 +1,339 @@
+//! Probes to diagnose reachability of other nodes in the overlay.
+//!
+//! A probe is an ICMPv6 echo request which is injected directly in the
+//! [`DataPlane`](crate::data::DataPlane), so it works without a TUN interface. The reply, either
+//! an echo reply from the destination or an out of band ICMP error from a node on the path, is
+//! taken out of the packet stream before it reaches the host. By limiting the hop limit of a
+//! probe, the nodes on the path to the destination reveal themselves with a time exceeded error.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::Ipv6Addr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use etherparse::{Icmpv6Type, PacketBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::error;
+
+use crate::crypto::PacketBuffer;
+
+/// IP protocol number of ICMPv6.
+const IP_PROTO_ICMPV6: u8 = 58;
+
+/// ICMPv6 type of an echo request.
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+
+/// ICMPv6 type of an echo reply.
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Size of an IPv6 header followed by an ICMPv6 echo header.
+const ECHO_HEADERS_SIZE: usize = 48;
+
+/// Payload carried in probes.
+const PROBE_PAYLOAD: &[u8] = b"mycelium overlay probe";
+
+/// Hop limit of echo replies sent by the node itself.
+const ECHO_REPLY_HOP_LIMIT: u8 = 64;
+
+/// What a node replied to a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeOutcome {
+    /// The destination replied to the probe.
+    EchoReply,
+    /// The hop limit of the probe expired on a node on the path.
+    TimeExceeded,
+    /// A node on the path can't forward the probe to the destination.
+    Unreachable,
+}
+
+/// A reply to a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeReply {
+    /// Address of the node which replied.
+    pub from: Ipv6Addr,
+    /// What the node replied.
+    pub outcome: ProbeOutcome,
+    /// Time between sending the probe and receiving the reply.
+    pub rtt: Duration,
+}
+
+/// A probe which has been sent. Dropping it stops waiting for the reply.
+pub struct Probe {
+    sequence: u16,
+    reply: oneshot::Receiver<ProbeReply>,
+    tracker: Arc<ProbeTracker>,
+}
+
+/// Marker error to indicate a probe could not be sent since the destination is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownDestination;
+
+/// Keeps track of probes which are waiting for a reply.
+pub(crate) struct ProbeTracker {
+    /// ICMP identifier used by all probes of this node.
+    identifier: u16,
+    /// Sequence number of the next probe.
+    next_sequence: AtomicU16,
+    pending: Mutex<HashMap<u16, PendingProbe>>,
+}
+
+/// A probe waiting for a reply.
+struct PendingProbe {
+    destination: Ipv6Addr,
+    sent: Instant,
+    reply: oneshot::Sender<ProbeReply>,
+}
+
+impl Probe {
+    /// Wait at most `timeout` for the reply to the probe. Returns [`None`] if no reply arrived in
+    /// time.
+    pub async fn reply(mut self, timeout: Duration) -> Option<ProbeReply> {
+        tokio::time::timeout(timeout, &mut self.reply)
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        self.tracker.pending.lock().unwrap().remove(&self.sequence);
+    }
+}
+
+impl ProbeTracker {
+    /// Create a new `ProbeTracker` with a random identifier.
+    pub fn new() -> Self {
+        Self {
+            identifier: rand::random(),
+            next_sequence: AtomicU16::new(0),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register a new probe, and build the echo request for it. Returns [`None`] if the packet
+    /// could not be built.
+    pub fn new_probe(
+        self: &Arc<Self>,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        hop_limit: u8,
+    ) -> Option<(Probe, PacketBuffer)> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+
+        let icmp = PacketBuilder::ipv6(source.octets(), destination.octets(), hop_limit)
+            .icmpv6_echo_request(self.identifier, sequence);
+        let mut packet = PacketBuffer::new();
+        packet.set_size(icmp.size(PROBE_PAYLOAD.len()));
+        if let Err(e) = icmp.write(&mut packet.buffer_mut(), PROBE_PAYLOAD) {
+            error!("Failed to construct probe packet {e}");
+            return None;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(
+            sequence,
+            PendingProbe {
+                destination,
+                sent: Instant::now(),
+                reply: tx,
+            },
+        );
+
+        Some((
+            Probe {
+                sequence,
+                reply: rx,
+                tracker: self.clone(),
+            },
+            packet,
+        ))
+    }
+
+    /// Handle an IPv6 packet received from another node. Returns `true` if the packet is an echo
+    /// reply to a pending probe, in which case it must not be delivered to the host.
+    pub fn handle_echo_reply(&self, packet: &[u8]) -> bool {
+        let Some((sequence, source)) = self.parse_echo(packet, ICMPV6_ECHO_REPLY) else {
+            return false;
+        };
+
+        self.complete(sequence, source, |destination| {
+            (source == destination).then_some(ProbeOutcome::EchoReply)
+        })
+    }
+
+    /// Handle an ICMP error sent by `from` for the `original` packet. Returns `true` if the
+    /// original packet is a pending probe, in which case the error must not be delivered to the
+    /// host.
+    pub fn handle_icmp_error(
+        &self,
+        from: Ipv6Addr,
+        icmp_type: &Icmpv6Type,
+        original: &[u8],
+    ) -> bool {
+        let outcome = match icmp_type {
+            Icmpv6Type::TimeExceeded(_) => ProbeOutcome::TimeExceeded,
+            Icmpv6Type::DestinationUnreachable(_) => ProbeOutcome::Unreachable,
+            _ => return false,
+        };
+        let Some((sequence, _)) = self.parse_echo(original, ICMPV6_ECHO_REQUEST) else {
+            return false;
+        };
+
+        self.complete(sequence, from, |_| Some(outcome))
+    }
+
+    /// Get the sequence number and source address of an echo packet of the given ICMP type with
+    /// our identifier.
+    fn parse_echo(&self, packet: &[u8], icmp_type: u8) -> Option<(u16, Ipv6Addr)> {
+        if packet.len() < ECHO_HEADERS_SIZE
+            || packet[6] != IP_PROTO_ICMPV6
+            || packet[40] != icmp_type
+            || u16::from_be_bytes([packet[44], packet[45]]) != self.identifier
+        {
+            return None;
+        }
+
+        let source = Ipv6Addr::from(
+            <&[u8] as TryInto<[u8; 16]>>::try_into(&packet[8..24])
+                .expect("Static range bounds on slice are correct length"),
+        );
+
+        Some((u16::from_be_bytes([packet[46], packet[47]]), source))
+    }
+
+    /// Complete the pending probe with the given sequence number, if `outcome` returns one for
+    /// the destination of the probe.
+    fn complete(
+        &self,
+        sequence: u16,
+        from: Ipv6Addr,
+        outcome: impl FnOnce(Ipv6Addr) -> Option<ProbeOutcome>,
+    ) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(outcome) = pending
+            .get(&sequence)
+            .and_then(|probe| outcome(probe.destination))
+        else {
+            return false;
+        };
+        let probe = pending
+            .remove(&sequence)
+            .expect("Probe is present as we just looked it up; qed");
+        // The receiver might have stopped waiting already, that's fine.
+        let _ = probe.reply.send(ProbeReply {
+            from,
+            outcome,
+            rtt: probe.sent.elapsed(),
+        });
+
+        true
+    }
+}
+
+/// Build an echo reply from `address` if the packet is an echo request for it. This is used to
+/// answer probes when there is no host to answer them.
+pub(crate) fn echo_reply(address: Ipv6Addr, packet: &[u8]) -> Option<PacketBuffer> {
+    if packet.len() < ECHO_HEADERS_SIZE
+        || packet[6] != IP_PROTO_ICMPV6
+        || packet[40] != ICMPV6_ECHO_REQUEST
+        || packet[24..40] != address.octets()
+    {
+        return None;
+    }
+
+    let source: [u8; 16] = packet[8..24]
+        .try_into()
+        .expect("Static range bounds on slice are correct length");
+    let identifier = u16::from_be_bytes([packet[44], packet[45]]);
+    let sequence = u16::from_be_bytes([packet[46], packet[47]]);
+    let payload = &packet[ECHO_HEADERS_SIZE..];
+
+    let icmp = PacketBuilder::ipv6(address.octets(), source, ECHO_REPLY_HOP_LIMIT)
+        .icmpv6_echo_reply(identifier, sequence);
+    let mut reply = PacketBuffer::new();
+    reply.set_size(icmp.size(payload.len()));
+    if let Err(e) = icmp.write(&mut reply.buffer_mut(), payload) {
+        error!("Failed to construct echo reply {e}");
+        return None;
+    }
+
+    Some(reply)
+}
+
+impl fmt::Display for UnknownDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no route to destination")
+    }
+}
+
+impl std::error::Error for UnknownDestination {}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv6Addr, sync::Arc, time::Duration};
+
+    use etherparse::{icmpv6::TimeExceededCode, Icmpv6Type};
+
+    use super::{echo_reply, ProbeOutcome, ProbeTracker};
+
+    const SOURCE: Ipv6Addr = Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 1);
+    const DESTINATION: Ipv6Addr = Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 2);
+    const HOP: Ipv6Addr = Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 3);
+
+    #[tokio::test]
+    async fn echo_reply_completes_probe() {
+        let tracker = Arc::new(ProbeTracker::new());
+        let (probe, request) = tracker.new_probe(SOURCE, DESTINATION, 64).unwrap();
+
+        let reply = echo_reply(DESTINATION, &request).unwrap();
+        assert!(tracker.handle_echo_reply(&reply));
+
+        let reply = probe.reply(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(reply.from, DESTINATION);
+        assert_eq!(reply.outcome, ProbeOutcome::EchoReply);
+    }
+
+    #[tokio::test]
+    async fn time_exceeded_completes_probe() {
+        let tracker = Arc::new(ProbeTracker::new());
+        let (probe, request) = tracker.new_probe(SOURCE, DESTINATION, 2).unwrap();
+
+        assert!(tracker.handle_icmp_error(
+            HOP,
+            &Icmpv6Type::TimeExceeded(TimeExceededCode::HopLimitExceeded),
+            &request,
+        ));
+
+        let reply = probe.reply(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(reply.from, HOP);
+        assert_eq!(reply.outcome, ProbeOutcome::TimeExceeded);
+    }
+
+    #[test]
+    fn unrelated_packets_are_ignored() {
+        let tracker = Arc::new(ProbeTracker::new());
+        let (probe, request) = tracker.new_probe(SOURCE, DESTINATION, 64).unwrap();
+
+        // An echo reply from a different node than the destination.
+        let mut misdirected = request.to_vec();
+        misdirected[24..40].copy_from_slice(&HOP.octets());
+        let reply = echo_reply(HOP, &misdirected).unwrap();
+        assert!(!tracker.handle_echo_reply(&reply));
+        // The request itself is not a reply.
+        assert!(!tracker.handle_echo_reply(&request));
+
+        // Once the probe is dropped, replies are no longer intercepted.
+        drop(probe);
+        let reply = echo_reply(DESTINATION, &request).unwrap();
+        assert!(!tracker.handle_echo_reply(&reply));
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{babel, subnet::Subnet};
+use crate::{
+    babel,
+    gateway::{self, TrustedGateway},
+    subnet::Subnet,
+};
 
 /// This trait is used to filter incoming updates from peers. Only updates which pass all
 /// configured filters on the local [`Router`](crate::router::Router) will actually be forwarded
@@ -51,3 +55,60 @@ impl RouteUpdateFilter for RouterIdOwnsSubnet {
                 .contains_ip(update.router_id().to_pubkey().address().into())
     }
 }
+
+/// Allow subnets announced by trusted gateways, if the announcement is signed by the gateway.
+///
+/// Retractions of subnets in the trusted prefixes are allowed as well, similar to
+/// [`RouterIdOwnsSubnet`].
+pub struct TrustedGateways {
+    gateways: Vec<TrustedGateway>,
+}
+
+impl TrustedGateways {
+    /// Create a new `TrustedGateways` filter, which only allows updates from the given gateways
+    /// for the prefixes they are trusted for.
+    pub fn new(gateways: Vec<TrustedGateway>) -> Self {
+        Self { gateways }
+    }
+}
+
+impl RouteUpdateFilter for TrustedGateways {
+    fn allow(&self, update: &babel::Update) -> bool {
+        let subnet = update.subnet();
+        if update.metric().is_infinite() {
+            return self.gateways.iter().any(|gw| gw.trusted_for(&subnet));
+        }
+
+        let Some(signature) = update.gateway_signature() else {
+            return false;
+        };
+        let gateway_key = update.router_id().to_pubkey();
+        self.gateways
+            .iter()
+            .any(|gw| gw.public_key == gateway_key && gw.trusted_for(&subnet))
+            && gateway_key
+                .verify(
+                    &gateway::announcement_data(subnet, &gateway_key, update.seqno()),
+                    signature,
+                )
+                .is_ok()
+    }
+}
+
+/// Allow an update if all of the contained filters allow it.
+pub struct AllOf(pub Vec<Box<dyn RouteUpdateFilter + Send + Sync>>);
+
+impl RouteUpdateFilter for AllOf {
+    fn allow(&self, update: &babel::Update) -> bool {
+        self.0.iter().all(|filter| filter.allow(update))
+    }
+}
+
+/// Allow an update if any of the contained filters allows it.
+pub struct AnyOf(pub Vec<Box<dyn RouteUpdateFilter + Send + Sync>>);
+
+impl RouteUpdateFilter for AnyOf {
+    fn allow(&self, update: &babel::Update) -> bool {
+        self.0.iter().any(|filter| filter.allow(update))
+    }
+}
@@ -0,0 +1,513 @@
+//! Stateful packet filter for inbound overlay traffic.
+//!
+//! Every node in the overlay can send traffic to every other node, so a node effectively adds a
+//! public interface to the host. The [`FirewallPolicy`] decides which inbound packets are
+//! delivered to the host. Connections initiated by the host are tracked, so replies to them are
+//! always delivered, regardless of the rules.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use arc_swap::ArcSwapOption;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::{crypto::PublicKey, subnet::Subnet};
+
+/// Mask applied to the first byte of an IP header to extract the version.
+const IP_VERSION_MASK: u8 = 0b1111_0000;
+
+/// Version byte of an IP header indicating IPv6.
+const IPV6_VERSION_BYTE: u8 = 0b0110_0000;
+
+/// Version byte of an IP header indicating IPv4.
+const IPV4_VERSION_BYTE: u8 = 0b0100_0000;
+
+/// Size of the fixed IPv6 header.
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// Minimum size of an IPv4 header.
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+
+/// IP protocol number of ICMP.
+const IP_PROTO_ICMP: u8 = 1;
+
+/// IP protocol number of TCP.
+const IP_PROTO_TCP: u8 = 6;
+
+/// IP protocol number of UDP.
+const IP_PROTO_UDP: u8 = 17;
+
+/// IP protocol number of ICMPv6.
+const IP_PROTO_ICMPV6: u8 = 58;
+
+/// ICMPv6 error types: destination unreachable, packet too big, time exceeded and parameter
+/// problem.
+const ICMPV6_ERROR_TYPES: [u8; 4] = [1, 2, 3, 4];
+
+/// ICMPv6 echo request and reply types.
+const ICMPV6_ECHO_TYPES: [u8; 2] = [128, 129];
+
+/// ICMP echo reply and request types.
+const ICMP_ECHO_TYPES: [u8; 2] = [0, 8];
+
+/// ICMP error types: destination unreachable, time exceeded and parameter problem.
+const ICMP_ERROR_TYPES: [u8; 3] = [3, 11, 12];
+
+/// Time after which an idle TCP connection is forgotten.
+const TCP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Time after which an idle UDP or ICMP flow is forgotten.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+/// Maximum amount of tracked connections.
+const MAX_TRACKED_CONNECTIONS: usize = 1 << 16;
+
+/// Rules deciding which inbound packets from the overlay are delivered to the host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirewallPolicy {
+    /// Action to take for packets which don't match any rule.
+    #[serde(default, rename = "default")]
+    pub default_action: FirewallAction,
+    /// Rules to evaluate, in order. The first rule which matches decides the action.
+    #[serde(default)]
+    pub rules: Vec<FirewallRule>,
+}
+
+/// The action to take for a packet matched by a [`FirewallRule`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallAction {
+    /// Deliver the packet to the host.
+    Allow,
+    /// Drop the packet.
+    #[default]
+    Deny,
+}
+
+/// A transport protocol matched by a [`FirewallRule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallProtocol {
+    /// TCP, with IP protocol number 6.
+    Tcp,
+    /// UDP, with IP protocol number 17.
+    Udp,
+    /// ICMP for IPv4 packets, and ICMPv6 for IPv6 packets.
+    Icmp,
+}
+
+/// A single rule in a [`FirewallPolicy`]. A rule matches a packet if all of its configured
+/// selectors match. A rule without any selectors matches every packet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirewallRule {
+    /// Action to take if the rule matches.
+    pub action: FirewallAction,
+    /// Match packets sent by the node with this public key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PublicKey>,
+    /// Match packets sent from an overlay address in this subnet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<Subnet>,
+    /// Match packets of this protocol.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<FirewallProtocol>,
+    /// Match TCP and UDP packets for this destination port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+/// Applies a [`FirewallPolicy`] to inbound packets, and tracks connections initiated by the host.
+pub(crate) struct Firewall {
+    /// The policy to apply, if the firewall is enabled.
+    policy: ArcSwapOption<FirewallPolicy>,
+    /// Connections initiated by the host, and the time they were last active.
+    connections: Mutex<HashMap<Connection, Instant>>,
+}
+
+/// Identifies a connection, from the point of view of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Connection {
+    protocol: u8,
+    local: IpAddr,
+    local_port: u16,
+    remote: IpAddr,
+    remote_port: u16,
+}
+
+/// The fields of a packet used by the firewall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PacketInfo {
+    protocol: u8,
+    src: IpAddr,
+    dst: IpAddr,
+    /// Ports of TCP and UDP packets, or the identifier of ICMP echo messages.
+    src_port: u16,
+    dst_port: u16,
+    /// Set for ICMP messages.
+    icmp_type: Option<u8>,
+}
+
+impl FirewallPolicy {
+    /// Check if the policy allows a packet sent by `sender`, ignoring connection state.
+    fn allows(&self, sender: Ipv6Addr, packet: &PacketInfo) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(sender, packet))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default_action)
+            == FirewallAction::Allow
+    }
+}
+
+impl FirewallRule {
+    /// Check if this rule matches a packet sent by `sender`.
+    fn matches(&self, sender: Ipv6Addr, packet: &PacketInfo) -> bool {
+        if let Some(key) = self.key {
+            let node_subnet = Subnet::new(key.address().into(), 64)
+                .expect("64 is a valid subnet size for IPv6; qed");
+            if !node_subnet.contains_ip(sender.into()) {
+                return false;
+            }
+        }
+        if let Some(subnet) = self.subnet {
+            if !subnet.contains_ip(sender.into()) {
+                return false;
+            }
+        }
+        if let Some(protocol) = self.protocol {
+            if !protocol.matches(packet.protocol) {
+                return false;
+            }
+        }
+        if let Some(port) = self.port {
+            if !matches!(packet.protocol, IP_PROTO_TCP | IP_PROTO_UDP) || packet.dst_port != port {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl FirewallProtocol {
+    /// Check if this is the given IP protocol number.
+    fn matches(self, protocol: u8) -> bool {
+        match self {
+            FirewallProtocol::Tcp => protocol == IP_PROTO_TCP,
+            FirewallProtocol::Udp => protocol == IP_PROTO_UDP,
+            FirewallProtocol::Icmp => matches!(protocol, IP_PROTO_ICMP | IP_PROTO_ICMPV6),
+        }
+    }
+}
+
+impl Firewall {
+    /// Create a new `Firewall`, which is disabled if no policy is given.
+    pub fn new(policy: Option<FirewallPolicy>) -> Self {
+        Self {
+            policy: ArcSwapOption::new(policy.map(Arc::new)),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the policy currently applied, if the firewall is enabled.
+    pub fn policy(&self) -> Option<FirewallPolicy> {
+        self.policy.load().as_deref().cloned()
+    }
+
+    /// Replace the policy. Setting no policy disables the firewall.
+    pub fn set_policy(&self, policy: Option<FirewallPolicy>) {
+        if policy.is_none() {
+            self.connections.lock().unwrap().clear();
+        }
+        self.policy.store(policy.map(Arc::new));
+    }
+
+    /// Track an IPv6 or IPv4 packet sent by the host, so replies to it are allowed.
+    pub fn track_outbound(&self, packet: &[u8]) {
+        if self.policy.load().is_none() {
+            return;
+        }
+        let Some(info) = PacketInfo::parse(packet) else {
+            return;
+        };
+        // Only echo requests start a flow for ICMP, errors are always allowed.
+        if info.icmp_type.is_some() && !info.is_echo() {
+            return;
+        }
+
+        let connection = Connection {
+            protocol: info.protocol,
+            local: info.src,
+            local_port: info.src_port,
+            remote: info.dst,
+            remote_port: info.dst_port,
+        };
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+        if connections.len() >= MAX_TRACKED_CONNECTIONS && !connections.contains_key(&connection) {
+            connections.retain(|connection, last_active| !connection.expired(*last_active, now));
+            if connections.len() >= MAX_TRACKED_CONNECTIONS {
+                debug!("Connection table is full, not tracking outbound connection");
+                return;
+            }
+        }
+        connections.insert(connection, now);
+    }
+
+    /// Check if an IPv6 or IPv4 packet, sent by the node with overlay address `sender`, may be
+    /// delivered to the host.
+    pub fn allows_inbound(&self, sender: Ipv6Addr, packet: &[u8]) -> bool {
+        let Some(policy) = self.policy.load_full() else {
+            return true;
+        };
+        let Some(info) = PacketInfo::parse(packet) else {
+            // Not something we can reason about, so only the default action can apply.
+            return policy.default_action == FirewallAction::Allow;
+        };
+
+        if info.is_icmp_error() {
+            return true;
+        }
+
+        let connection = Connection {
+            protocol: info.protocol,
+            local: info.dst,
+            local_port: info.dst_port,
+            remote: info.src,
+            remote_port: info.src_port,
+        };
+        let now = Instant::now();
+        if let Some(last_active) = self.connections.lock().unwrap().get_mut(&connection) {
+            if !connection.expired(*last_active, now) {
+                *last_active = now;
+                return true;
+            }
+        }
+
+        policy.allows(sender, &info)
+    }
+}
+
+impl Connection {
+    /// Check if the connection expired, if it was last active at the given time.
+    fn expired(&self, last_active: Instant, now: Instant) -> bool {
+        let timeout = if self.protocol == IP_PROTO_TCP {
+            TCP_CONNECTION_TIMEOUT
+        } else {
+            CONNECTION_TIMEOUT
+        };
+        now.duration_since(last_active) > timeout
+    }
+}
+
+impl PacketInfo {
+    /// Parse an IPv6 or IPv4 packet. Extension headers and IPv4 fragments are not followed, so
+    /// the protocol of such packets is the one in the IP header, without ports.
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let (protocol, src, dst, transport) = match packet.first()? & IP_VERSION_MASK {
+            IPV6_VERSION_BYTE if packet.len() >= IPV6_HEADER_SIZE => (
+                packet[6],
+                IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&packet[8..24])
+                        .expect("Static range bounds on slice are correct length"),
+                )),
+                IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&packet[24..40])
+                        .expect("Static range bounds on slice are correct length"),
+                )),
+                &packet[IPV6_HEADER_SIZE..],
+            ),
+            IPV4_VERSION_BYTE if packet.len() >= IPV4_MIN_HEADER_SIZE => {
+                let header_len = ((packet[0] & 0x0F) as usize) * 4;
+                // Only the first fragment contains the transport header.
+                let fragment_offset = u16::from_be_bytes([packet[6] & 0x1F, packet[7]]);
+                let transport = if fragment_offset == 0 {
+                    packet.get(header_len..).unwrap_or_default()
+                } else {
+                    &[]
+                };
+                (
+                    packet[9],
+                    IpAddr::V4(Ipv4Addr::new(
+                        packet[12], packet[13], packet[14], packet[15],
+                    )),
+                    IpAddr::V4(Ipv4Addr::new(
+                        packet[16], packet[17], packet[18], packet[19],
+                    )),
+                    transport,
+                )
+            }
+            _ => return None,
+        };
+
+        let mut info = PacketInfo {
+            protocol,
+            src,
+            dst,
+            src_port: 0,
+            dst_port: 0,
+            icmp_type: None,
+        };
+        match protocol {
+            IP_PROTO_TCP | IP_PROTO_UDP if transport.len() >= 4 => {
+                info.src_port = u16::from_be_bytes([transport[0], transport[1]]);
+                info.dst_port = u16::from_be_bytes([transport[2], transport[3]]);
+            }
+            IP_PROTO_ICMP | IP_PROTO_ICMPV6 if !transport.is_empty() => {
+                info.icmp_type = Some(transport[0]);
+                // Echo messages are matched on their identifier.
+                if info.is_echo() && transport.len() >= 6 {
+                    let identifier = u16::from_be_bytes([transport[4], transport[5]]);
+                    info.src_port = identifier;
+                    info.dst_port = identifier;
+                }
+            }
+            _ => {}
+        }
+
+        Some(info)
+    }
+
+    /// Check if this is an ICMP echo request or reply.
+    fn is_echo(&self) -> bool {
+        match (self.protocol, self.icmp_type) {
+            (IP_PROTO_ICMPV6, Some(icmp_type)) => ICMPV6_ECHO_TYPES.contains(&icmp_type),
+            (IP_PROTO_ICMP, Some(icmp_type)) => ICMP_ECHO_TYPES.contains(&icmp_type),
+            _ => false,
+        }
+    }
+
+    /// Check if this is an ICMP error message. These are always allowed, as they are needed for
+    /// path MTU discovery and to report failures of connections initiated by the host.
+    fn is_icmp_error(&self) -> bool {
+        match (self.protocol, self.icmp_type) {
+            (IP_PROTO_ICMPV6, Some(icmp_type)) => ICMPV6_ERROR_TYPES.contains(&icmp_type),
+            (IP_PROTO_ICMP, Some(icmp_type)) => ICMP_ERROR_TYPES.contains(&icmp_type),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv6Addr, time::Duration};
+
+    use etherparse::PacketBuilder;
+
+    use crate::crypto::{PublicKey, SecretKey};
+
+    use super::{Firewall, FirewallAction, FirewallPolicy, FirewallProtocol, FirewallRule};
+
+    const LOCAL: Ipv6Addr = Ipv6Addr::new(0x400, 0, 0, 1, 0, 0, 0, 1);
+    const REMOTE: Ipv6Addr = Ipv6Addr::new(0x500, 0, 0, 1, 0, 0, 0, 1);
+
+    fn tcp(src: Ipv6Addr, dst: Ipv6Addr, src_port: u16, dst_port: u16) -> Vec<u8> {
+        let builder =
+            PacketBuilder::ipv6(src.octets(), dst.octets(), 64).tcp(src_port, dst_port, 0, 1024);
+        let mut packet = Vec::with_capacity(builder.size(0));
+        builder.write(&mut packet, &[]).unwrap();
+        packet
+    }
+
+    fn rule(action: FirewallAction) -> FirewallRule {
+        FirewallRule {
+            action,
+            key: None,
+            subnet: None,
+            protocol: None,
+            port: None,
+        }
+    }
+
+    #[test]
+    fn disabled_firewall_allows_everything() {
+        let firewall = Firewall::new(None);
+
+        assert!(firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 40000, 22)));
+    }
+
+    #[test]
+    fn default_deny_with_allow_rules() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let firewall = Firewall::new(Some(FirewallPolicy {
+            default_action: FirewallAction::Deny,
+            rules: vec![
+                FirewallRule {
+                    protocol: Some(FirewallProtocol::Tcp),
+                    port: Some(80),
+                    ..rule(FirewallAction::Allow)
+                },
+                FirewallRule {
+                    key: Some(pk),
+                    ..rule(FirewallAction::Allow)
+                },
+            ],
+        }));
+
+        assert!(firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 40000, 80)));
+        assert!(!firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 40000, 22)));
+        // Any address in the subnet of the key matches.
+        let pk_ip = pk.address();
+        assert!(firewall.allows_inbound(pk_ip, &tcp(pk_ip, LOCAL, 40000, 22)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_to_outbound_connections_are_allowed() {
+        let firewall = Firewall::new(Some(FirewallPolicy::default()));
+
+        assert!(!firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 443, 40000)));
+
+        firewall.track_outbound(&tcp(LOCAL, REMOTE, 40000, 443));
+        assert!(firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 443, 40000)));
+        // A different port on the same remote is not part of the connection.
+        assert!(!firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 444, 40000)));
+
+        tokio::time::advance(Duration::from_secs(7 * 60 * 60)).await;
+        assert!(!firewall.allows_inbound(REMOTE, &tcp(REMOTE, LOCAL, 443, 40000)));
+    }
+
+    #[test]
+    fn echo_replies_and_icmp_errors() {
+        let firewall = Firewall::new(Some(FirewallPolicy::default()));
+
+        let builder =
+            PacketBuilder::ipv6(LOCAL.octets(), REMOTE.octets(), 64).icmpv6_echo_request(1234, 1);
+        let mut request = Vec::new();
+        builder.write(&mut request, &[]).unwrap();
+        let builder =
+            PacketBuilder::ipv6(REMOTE.octets(), LOCAL.octets(), 64).icmpv6_echo_reply(1234, 1);
+        let mut reply = Vec::new();
+        builder.write(&mut reply, &[]).unwrap();
+
+        assert!(!firewall.allows_inbound(REMOTE, &reply));
+        firewall.track_outbound(&request);
+        assert!(firewall.allows_inbound(REMOTE, &reply));
+
+        let builder = PacketBuilder::ipv6(REMOTE.octets(), LOCAL.octets(), 64).icmpv6(
+            etherparse::Icmpv6Type::DestinationUnreachable(
+                etherparse::icmpv6::DestUnreachableCode::Port,
+            ),
+        );
+        let mut error = Vec::new();
+        builder.write(&mut error, &request).unwrap();
+        assert!(firewall.allows_inbound(REMOTE, &error));
+
+        // Unassigned ICMPv6 types below 128 are not errors.
+        let builder = PacketBuilder::ipv6(REMOTE.octets(), LOCAL.octets(), 64).icmpv6(
+            etherparse::Icmpv6Type::Unknown {
+                type_u8: 5,
+                code_u8: 0,
+                bytes5to8: [0; 4],
+            },
+        );
+        let mut unknown = Vec::new();
+        builder.write(&mut unknown, &request).unwrap();
+        assert!(!firewall.allows_inbound(REMOTE, &unknown));
+    }
+}
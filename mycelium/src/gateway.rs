@@ -0,0 +1,114 @@
+//! Gateways announce prefixes outside of the overlay.
+//!
+//! Normally, a node can only announce the subnet derived from its public key. A gateway is a node
+//! which additionally announces prefixes of a network it is connected to, for instance an internal
+//! network or a default route. Such announcements carry a signature of the gateway over the
+//! prefix, and are only accepted by nodes which explicitly trust the gateway for the prefix.
+
+use std::{fmt, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto::PublicKey, sequence_number::SeqNo, subnet::Subnet};
+
+/// Domain separation for the data signed by a gateway, so the signature can't be used in a
+/// different context.
+const ANNOUNCEMENT_DOMAIN: &[u8] = b"mycelium gateway announcement v2";
+
+/// A gateway which is trusted to announce some prefixes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedGateway {
+    /// The public key of the gateway.
+    pub public_key: PublicKey,
+    /// The prefixes the gateway is trusted for. Announcements of the gateway for subnets in one of
+    /// these prefixes are accepted.
+    pub prefixes: Vec<Subnet>,
+}
+
+impl TrustedGateway {
+    /// Checks if this gateway is trusted to announce the given subnet.
+    pub fn trusted_for(&self, subnet: &Subnet) -> bool {
+        self.prefixes.iter().any(|p| p.contains_subnet(subnet))
+    }
+}
+
+/// Error returned when a prefix can't be used for a gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidGatewayPrefix(pub Subnet);
+
+/// The data signed by a gateway to announce a prefix with the given sequence number. Binding the
+/// sequence number means a signature can't be replayed once the gateway announced a newer one.
+pub fn announcement_data(prefix: Subnet, gateway: &PublicKey, seqno: SeqNo) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ANNOUNCEMENT_DOMAIN.len() + 16 + 1 + 32 + 2);
+    data.extend_from_slice(ANNOUNCEMENT_DOMAIN);
+    match prefix.network() {
+        IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+    }
+    data.push(prefix.prefix_len());
+    data.extend_from_slice(gateway.as_bytes());
+    data.extend_from_slice(&u16::from(seqno).to_be_bytes());
+    data
+}
+
+impl fmt::Display for InvalidGatewayPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gateway prefix {} is not an IPv6 prefix, only IPv6 prefixes can be routed through a gateway",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidGatewayPrefix {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::{PublicKey, SecretKey},
+        subnet::Subnet,
+    };
+
+    use super::{announcement_data, TrustedGateway};
+
+    #[test]
+    fn trusted_for_contained_subnets() {
+        let gateway = TrustedGateway {
+            public_key: PublicKey::from(&SecretKey::new()),
+            prefixes: vec!["2001:db8::/48".parse().unwrap()],
+        };
+
+        assert!(gateway.trusted_for(&"2001:db8::/48".parse().unwrap()));
+        assert!(gateway.trusted_for(&"2001:db8:0:1::/64".parse().unwrap()));
+        assert!(!gateway.trusted_for(&"2001:db8::/32".parse().unwrap()));
+        assert!(!gateway.trusted_for(&"::/0".parse().unwrap()));
+    }
+
+    #[test]
+    fn announcement_is_bound_to_prefix_gateway_and_seqno() {
+        let sk = SecretKey::new();
+        let pk = PublicKey::from(&sk);
+        let other = PublicKey::from(&SecretKey::new());
+        let prefix: Subnet = "2001:db8::/48".parse().unwrap();
+        let seqno = 10.into();
+
+        let signature = sk.sign(&announcement_data(prefix, &pk, seqno));
+        assert!(pk
+            .verify(&announcement_data(prefix, &pk, seqno), &signature)
+            .is_ok());
+        assert!(pk
+            .verify(
+                &announcement_data("2001:db8::/32".parse().unwrap(), &pk, seqno),
+                &signature
+            )
+            .is_err());
+        assert!(pk
+            .verify(&announcement_data(prefix, &pk, 11.into()), &signature)
+            .is_err());
+        assert_ne!(
+            announcement_data(prefix, &pk, seqno),
+            announcement_data(prefix, &other, seqno)
+        );
+    }
+}
@@ -0,0 +1,346 @@
+//! Support for carrying IPv4 traffic over the overlay.
+//!
+//! When enabled, every node gets an IPv4 address in a configured private range. The address is
+//! derived from the `/64` overlay subnet of the node, so every node can calculate the IPv4 address
+//! of any other node it has a route to, as long as all nodes use the same range. IPv4 packets are
+//! encapsulated in the user data header, and sent to the node which owns the destination address.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{metrics::Metrics, router::Router, subnet::Subnet};
+
+/// Minimum size in bytes of an IPv4 header.
+pub const IPV4_MIN_HEADER_SIZE: usize = 20;
+
+/// The largest prefix length of a range which can be used for the IPv4 overlay. A range needs at
+/// least 2 usable addresses.
+const MAX_RANGE_PREFIX_LEN: u8 = 30;
+
+/// Offset of the time to live field in an IPv4 header.
+const TTL_OFFSET: usize = 8;
+
+/// Offset of the header checksum field in an IPv4 header.
+const CHECKSUM_OFFSET: usize = 10;
+
+/// Minimum time between 2 scans of the routing table to find the owner of an unknown IPv4
+/// address. Addresses which are not found are unknown until the next scan, so packets for unused
+/// addresses can't make the node scan the routing table for every packet.
+const MIN_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time after which the routing table is scanned again when an IPv4 address is resolved, so
+/// nodes which appeared since the last scan are taken into account for collisions.
+const MAX_SCAN_AGE: Duration = Duration::from_secs(30);
+
+/// Address assignment and lookup for the IPv4 overlay.
+pub struct Ipv4Overlay {
+    range: Subnet,
+    local_address: Ipv4Addr,
+    /// The IPv6 overlay subnet of the local node.
+    local_network: Ipv6Addr,
+    known: RwLock<KnownAddresses>,
+}
+
+/// The IPv4 addresses of the nodes we know about.
+#[derive(Default)]
+struct KnownAddresses {
+    /// IPv4 addresses mapped to the IPv6 overlay subnet of their owner, or [`None`] if multiple
+    /// subnets map to the address.
+    owners: HashMap<Ipv4Addr, Option<Ipv6Addr>>,
+    /// Time of the last scan of the routing table.
+    scanned: Option<Instant>,
+}
+
+/// Error returned when the configured IPv4 overlay range can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidIpv4Range;
+
+impl Ipv4Overlay {
+    /// Create a new `Ipv4Overlay` for the node owning `node_subnet`, assigning addresses in the
+    /// given range.
+    pub fn new(range: Subnet, node_subnet: Subnet) -> Result<Self, InvalidIpv4Range> {
+        let (IpAddr::V4(_), IpAddr::V6(node_network)) = (range.address(), node_subnet.network())
+        else {
+            return Err(InvalidIpv4Range);
+        };
+        if range.prefix_len() > MAX_RANGE_PREFIX_LEN {
+            return Err(InvalidIpv4Range);
+        }
+
+        Ok(Self {
+            range,
+            local_address: address_in_range(range, node_network),
+            local_network: node_network,
+            known: RwLock::new(KnownAddresses::default()),
+        })
+    }
+
+    /// The range in which IPv4 addresses are assigned.
+    pub fn range(&self) -> Subnet {
+        self.range
+    }
+
+    /// The IPv4 address of the local node.
+    pub fn local_address(&self) -> Ipv4Addr {
+        self.local_address
+    }
+
+    /// The IPv4 address of the node owning the given IPv6 overlay address.
+    pub fn address_of(&self, ip: Ipv6Addr) -> Ipv4Addr {
+        address_in_range(self.range, ip)
+    }
+
+    /// Find the IPv6 overlay address of the node owning the given IPv4 address.
+    ///
+    /// Returns [`None`] if there is no route to a node owning the address, or if multiple nodes
+    /// map to the address, since it is then not known which of them the address belongs to. The
+    /// local address is not resolved either.
+    pub fn resolve<M>(&self, ip: Ipv4Addr, router: &Router<M>) -> Option<Ipv6Addr>
+    where
+        M: Metrics + Clone + Send + 'static,
+    {
+        {
+            let known = self.known.read().unwrap();
+            let age = known.scanned.map(|scanned| scanned.elapsed());
+            if age.is_some_and(|age| age < MAX_SCAN_AGE) {
+                if let Some(owner) = known.owners.get(&ip) {
+                    return owner.filter(|network| *network != self.local_network);
+                }
+            }
+            if age.is_some_and(|age| age < MIN_RESCAN_INTERVAL) {
+                return None;
+            }
+        }
+
+        // Not known yet, calculate the IPv4 address of every node we have a route to.
+        let owners = owners(
+            self.range,
+            self.local_network,
+            router.load_selected_routes().iter().filter_map(|route| {
+                match route.source().subnet().network() {
+                    IpAddr::V6(network) => Some(network),
+                    IpAddr::V4(_) => None,
+                }
+            }),
+        );
+        let owner = owners
+            .get(&ip)
+            .copied()
+            .flatten()
+            .filter(|network| *network != self.local_network);
+        let mut known = self.known.write().unwrap();
+        known.owners = owners;
+        known.scanned = Some(Instant::now());
+
+        owner
+    }
+
+    /// Checks if the node owning the given IPv6 overlay address is the only known owner of the
+    /// given IPv4 address.
+    pub fn is_owner<M>(&self, ip: Ipv4Addr, owner: Ipv6Addr, router: &Router<M>) -> bool
+    where
+        M: Metrics + Clone + Send + 'static,
+    {
+        self.resolve(ip, router)
+            .is_some_and(|network| network.octets()[..8] == owner.octets()[..8])
+    }
+}
+
+/// Map the IPv4 addresses in `range` of the local node and the given IPv6 overlay subnets to
+/// their owner. Addresses to which multiple subnets map don't have an owner.
+fn owners(
+    range: Subnet,
+    local_network: Ipv6Addr,
+    networks: impl Iterator<Item = Ipv6Addr>,
+) -> HashMap<Ipv4Addr, Option<Ipv6Addr>> {
+    let mut owners = HashMap::new();
+    for network in std::iter::once(local_network).chain(networks) {
+        match owners.entry(address_in_range(range, network)) {
+            Entry::Vacant(entry) => {
+                entry.insert(Some(network));
+            }
+            Entry::Occupied(mut entry) => {
+                if entry
+                    .get()
+                    .is_some_and(|existing| existing.octets()[..8] != network.octets()[..8])
+                {
+                    debug!(
+                        ip = %entry.key(),
+                        %network,
+                        "Multiple subnets map to the same IPv4 overlay address"
+                    );
+                    entry.insert(None);
+                }
+            }
+        }
+    }
+
+    owners
+}
+
+/// Calculate the IPv4 address in `range` of the node owning the given IPv6 overlay address. Only
+/// the `/64` prefix of the address is used, so all addresses in the subnet of a node map to the
+/// same IPv4 address.
+///
+/// The network and broadcast address of the range are never returned.
+fn address_in_range(range: Subnet, ip: Ipv6Addr) -> Ipv4Addr {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&ip.octets()[..8]);
+    let mut buf = [0; 4];
+    hasher.finalize_xof().fill(&mut buf);
+
+    let host_mask = u32::MAX
+        .checked_shr(range.prefix_len() as u32)
+        .unwrap_or_default();
+    let mut host = u32::from_be_bytes(buf) & host_mask;
+    // Flipping the last bit turns the network address into the first host address, and the
+    // broadcast address into the last host address.
+    if host == 0 || host == host_mask {
+        host ^= 1;
+    }
+
+    let network = match range.network() {
+        IpAddr::V4(network) => u32::from(network),
+        IpAddr::V6(_) => unreachable!("IPv4 overlay range is an IPv4 subnet; qed"),
+    };
+
+    Ipv4Addr::from(network | host)
+}
+
+/// Set the time to live of an IPv4 packet, and update the header checksum accordingly.
+///
+/// # Panics
+///
+/// This function panics if the packet is smaller than the header length encoded in it.
+pub fn set_ttl(packet: &mut [u8], ttl: u8) {
+    let header_len = ((packet[0] & 0x0f) as usize * 4).max(IPV4_MIN_HEADER_SIZE);
+    packet[TTL_OFFSET] = ttl;
+    packet[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].copy_from_slice(&[0, 0]);
+
+    let mut sum = packet[..header_len]
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    packet[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+impl fmt::Display for InvalidIpv4Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IPv4 overlay range must be an IPv4 subnet with a prefix length of at most {MAX_RANGE_PREFIX_LEN}"
+        )
+    }
+}
+
+impl std::error::Error for InvalidIpv4Range {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::subnet::Subnet;
+
+    use super::{address_in_range, owners, set_ttl, InvalidIpv4Range, Ipv4Overlay};
+
+    fn node_subnet(ip: Ipv6Addr) -> Subnet {
+        Subnet::new(ip.into(), 64).unwrap()
+    }
+
+    #[test]
+    fn addresses_are_deterministic_and_in_range() {
+        let range: Subnet = "10.64.0.0/10".parse().unwrap();
+        let ip = Ipv6Addr::new(0x400, 1, 2, 3, 0, 0, 0, 1);
+
+        let overlay = Ipv4Overlay::new(range, node_subnet(ip)).unwrap();
+        let local = overlay.local_address();
+
+        assert!(range.contains_ip(local.into()));
+        assert_eq!(local, address_in_range(range, ip));
+        // Other addresses in the same /64 map to the same IPv4 address.
+        assert_eq!(
+            overlay.address_of(Ipv6Addr::new(0x400, 1, 2, 3, 4, 5, 6, 7)),
+            local
+        );
+    }
+
+    #[test]
+    fn network_and_broadcast_addresses_are_never_used() {
+        let range: Subnet = "192.168.0.0/30".parse().unwrap();
+        for i in 0..1000 {
+            let ip = Ipv6Addr::new(0x400, i, 0, 0, 0, 0, 0, 0);
+            let addr = address_in_range(range, ip);
+            assert!(addr == Ipv4Addr::new(192, 168, 0, 1) || addr == Ipv4Addr::new(192, 168, 0, 2));
+        }
+    }
+
+    #[test]
+    fn colliding_addresses_have_no_owner() {
+        let range: Subnet = "192.168.0.0/30".parse().unwrap();
+        let local = Ipv6Addr::new(0x400, 1, 0, 0, 0, 0, 0, 0);
+        let local_ip = address_in_range(range, local);
+        // Only 2 addresses are available, so some of these collide with the local node.
+        let networks = (2..40)
+            .map(|i| Ipv6Addr::new(0x400, i, 0, 0, 0, 0, 0, 0))
+            .collect::<Vec<_>>();
+        assert!(networks
+            .iter()
+            .any(|network| address_in_range(range, *network) == local_ip));
+
+        let known = owners(range, local, networks.into_iter());
+        assert_eq!(known.get(&local_ip), Some(&None));
+
+        // Without collision, the owner is known.
+        let range: Subnet = "10.0.0.0/8".parse().unwrap();
+        let remote = Ipv6Addr::new(0x400, 2, 0, 0, 0, 0, 0, 0);
+        let known = owners(range, local, std::iter::once(remote));
+        assert_eq!(
+            known.get(&address_in_range(range, remote)),
+            Some(&Some(remote))
+        );
+        assert_eq!(
+            known.get(&address_in_range(range, local)),
+            Some(&Some(local))
+        );
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        let node = node_subnet(Ipv6Addr::new(0x400, 1, 2, 3, 0, 0, 0, 1));
+        assert!(matches!(
+            Ipv4Overlay::new("10.0.0.0/31".parse().unwrap(), node),
+            Err(InvalidIpv4Range)
+        ));
+        assert!(matches!(
+            Ipv4Overlay::new("fd00::/8".parse().unwrap(), node),
+            Err(InvalidIpv4Range)
+        ));
+    }
+
+    #[test]
+    fn ttl_update_keeps_checksum_valid() {
+        // Example header from https://en.wikipedia.org/wiki/Internet_checksum
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+
+        set_ttl(&mut header, 0x40);
+        assert_eq!(header[10..12], [0xb8, 0x61]);
+
+        set_ttl(&mut header, 0x3f);
+        assert_eq!(header[8], 0x3f);
+        assert_eq!(header[10..12], [0xb9, 0x61]);
+    }
+}
@@ -1,28 +1,51 @@
-use std::net::{IpAddr, Ipv6Addr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 #[cfg(feature = "message")]
 use std::{future::Future, time::Duration};
+#[cfg(feature = "message")]
+use tokio::io::AsyncRead;
 
 use crate::tun::TunConfig;
 use bytes::BytesMut;
+use crypto::Cipher;
+use damping::{DampedRoute, DampingConfig};
 use data::DataPlane;
+use diagnostics::{Probe, UnknownDestination};
 use endpoint::Endpoint;
+use filters::RouteUpdateFilter;
+use firewall::FirewallPolicy;
+use gateway::{InvalidGatewayPrefix, TrustedGateway};
+use ipv4::Ipv4Overlay;
+use link_auth::LinkKey;
 #[cfg(feature = "message")]
 use message::{
-    MessageId, MessageInfo, MessagePushResponse, MessageStack, PushMessageError, ReceivedMessage,
+    MessageId, MessageInfo, MessagePushResponse, MessageSpool, MessageStack, MessageStore,
+    PushMessageError, ReceivedMessage,
 };
 use metrics::Metrics;
-use peer_manager::{PeerExists, PeerNotFound, PeerStats, PrivateNetworkKey};
+use peer_manager::{
+    PeerCache, PeerExists, PeerNotFound, PeerSettings, PeerStats, PrivateNetworkKey,
+};
+use policy::RoutePolicy;
 use routing_table::RouteEntry;
 use subnet::Subnet;
+use succession::{PreviousKey, Succession};
 use tracing::{error, info, warn};
 
 mod babel;
-mod connection;
+pub mod connection;
 pub mod crypto;
+pub mod damping;
 pub mod data;
+pub mod diagnostics;
 pub mod endpoint;
 pub mod filters;
+pub mod firewall;
+pub mod gateway;
 mod interval;
+pub mod ipv4;
+pub mod link_auth;
 #[cfg(feature = "message")]
 pub mod message;
 mod metric;
@@ -30,13 +53,16 @@ pub mod metrics;
 pub mod packet;
 mod peer;
 pub mod peer_manager;
+pub mod policy;
 pub mod router;
 mod router_id;
 mod routing_table;
 mod seqno_cache;
 mod sequence_number;
+mod session;
 mod source_table;
 pub mod subnet;
+pub mod succession;
 pub mod task;
 mod tun;
 
@@ -49,8 +75,15 @@ pub const GLOBAL_SUBNET_PREFIX_LEN: u8 = 7;
 pub struct Config<M> {
     /// The secret key of the node.
     pub node_key: crypto::SecretKey,
+    /// The key the node used before its current key, if the key was rotated. Until the end of the
+    /// grace period of the rotation, the subnet of the previous key is announced as well, and
+    /// nodes sending packets to it are told about the new key.
+    pub previous_key: Option<PreviousKey>,
     /// Statically configured peers.
     pub peers: Vec<Endpoint>,
+    /// Link settings of peers. Settings for endpoints which are not a static peer apply once the
+    /// peer is added, or when it is found in the peer cache.
+    pub peer_settings: HashMap<Endpoint, PeerSettings>,
     /// Tun interface should be disabled.
     pub no_tun: bool,
     /// Listen port for TCP connections.
@@ -75,6 +108,8 @@ pub struct Config<M> {
     pub metrics: M,
     /// Mark that's set on all packets that we send on the underlying network
     pub firewall_mark: Option<u32>,
+    /// Policy applied to routes received from peers. The default policy accepts all routes.
+    pub route_policy: RoutePolicy,
 
     // tun_fd is android, iOS, macos on appstore specific option
     // We can't create TUN device from the Rust code in android, iOS, and macos on appstore.
@@ -92,12 +127,85 @@ pub struct Config<M> {
     /// set this to a value which is higher than the amount of logical CPU cores available to the
     /// system.
     pub update_workers: usize,
+
+    /// Directory used to persist the message inbox and outbox. If this is not set, messages are
+    /// only kept in memory and are lost when the node stops. This has no effect if the `message`
+    /// feature is not enabled.
+    pub message_store: Option<PathBuf>,
+
+    /// Directory in which inbound messages which are too large to keep in memory are written. If
+    /// this is not set, all messages are kept in memory while they are received. This has no
+    /// effect if the `message` feature is not enabled.
+    pub message_spool: Option<PathBuf>,
+
+    /// File used to remember peers which are added at runtime, either through the API or link
+    /// local discovery. These peers are added again when the node restarts. If this is not set,
+    /// only the statically configured peers are known when the node starts.
+    pub peer_cache: Option<PathBuf>,
+
+    /// Private IPv4 range used for the IPv4 overlay. If this is set, the node gets a
+    /// deterministic IPv4 address in this range, and IPv4 packets to addresses in the range are
+    /// carried over the overlay. All nodes which want to communicate over IPv4 must use the same
+    /// range.
+    pub ipv4_overlay: Option<Subnet>,
+
+    /// Prefixes outside of the overlay which this node announces as a gateway. Only nodes which
+    /// trust this node for the prefixes will route traffic for them to this node. The node does
+    /// not forward the traffic itself, this is left to the host.
+    pub gateway_prefixes: Vec<Subnet>,
+
+    /// Gateways which are trusted to announce prefixes outside of the overlay. Signed
+    /// announcements from these gateways are accepted for the configured prefixes, and the
+    /// prefixes are routed over the TUN interface.
+    pub trusted_gateways: Vec<TrustedGateway>,
+
+    /// Only accept route announcements which are signed by the router which originated them.
+    /// Announcements are always signed by this node, and signatures are always verified if they
+    /// are present. Enabling this also rejects announcements of nodes which don't sign them yet.
+    pub require_signed_updates: bool,
+
+    /// Spread traffic to a destination over all loop free routes with a metric which is at most
+    /// this much higher than the metric of the selected route. Packets of the same flow are always
+    /// forwarded over the same route. If this is not set, only the selected route is used.
+    pub multipath_tolerance: Option<u16>,
+
+    /// The amount the metric of a route must improve before the router switches to it. Higher
+    /// values make route selection more stable, at the cost of sometimes using a slightly worse
+    /// route. The default is [`router::DEFAULT_SELECTION_HYSTERESIS`], and the value can't exceed
+    /// [`router::MAX_SELECTION_HYSTERESIS`].
+    pub selection_hysteresis: u16,
+
+    /// Damp routes which flap often. Suppressed routes keep their current next hop, and changes in
+    /// their metric are not propagated immediately. Route flap damping is disabled if this is not
+    /// set.
+    pub route_damping: Option<DampingConfig>,
+
+    /// Run as a leaf node. Leaf nodes only announce their own subnet, and don't forward traffic
+    /// between other nodes. This is useful for nodes on metered or slow connections.
+    pub leaf: bool,
+
+    /// Policy applied to packets received from the overlay before they are delivered to the host.
+    /// Replies to connections initiated by the host are always delivered. The firewall is
+    /// disabled if this is not set.
+    pub firewall: Option<FirewallPolicy>,
+
+    /// Cipher proposed when negotiating session keys with other nodes. ChaCha20-Poly1305 is used
+    /// if either side prefers it. If this is not set, AES-256-GCM is preferred when the CPU has
+    /// hardware support for it.
+    pub preferred_cipher: Option<Cipher>,
+
+    /// Secrets used to authenticate control packets on links with specific peers. If a private
+    /// network is configured, control packets on links with all other peers are authenticated
+    /// with a key derived from the network key.
+    pub link_keys: Vec<LinkKey>,
 }
 
 /// The Node is the main structure in mycelium. It governs the entire data flow.
 pub struct Node<M> {
     router: router::Router<M>,
+    node_ipv4: Option<Ipv4Addr>,
     peer_manager: peer_manager::PeerManager<M>,
+    data_plane: DataPlane<M>,
     #[cfg(feature = "message")]
     message_stack: message::MessageStack<M>,
 }
@@ -108,6 +216,8 @@ pub struct NodeInfo {
     pub node_subnet: Subnet,
     /// The public key of the node
     pub node_pubkey: crypto::PublicKey,
+    /// The IPv4 address of the node, if the IPv4 overlay is enabled.
+    pub node_ipv4: Option<Ipv4Addr>,
 }
 
 impl<M> Node<M>
@@ -126,6 +236,10 @@ where
                 .into());
             }
         }
+        if let Some(route_damping) = &config.route_damping {
+            route_damping.validate()?;
+        }
+        router::validate_selection_hysteresis(config.selection_hysteresis)?;
         let node_pub_key = crypto::PublicKey::from(&config.node_key);
         let node_addr = node_pub_key.address();
         let (tun_tx, tun_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -140,21 +254,61 @@ where
         )
         .expect("64 is a valid IPv6 prefix size; qed");
 
-        // Creating a new Router instance
-        let router = match router::Router::new(
-            config.update_workers,
-            tun_tx,
-            node_subnet,
-            vec![node_subnet],
-            (config.node_key, node_pub_key),
-            vec![
+        let ipv4_overlay = config
+            .ipv4_overlay
+            .map(|range| Ipv4Overlay::new(range, node_subnet))
+            .transpose()?;
+        let node_ipv4 = ipv4_overlay.as_ref().map(Ipv4Overlay::local_address);
+
+        // The routing table only holds IPv6 routes, so gateways can only be used for IPv6
+        // prefixes.
+        if let Some(prefix) = config
+            .gateway_prefixes
+            .iter()
+            .chain(config.trusted_gateways.iter().flat_map(|gw| &gw.prefixes))
+            .find(|prefix| !matches!(prefix.address(), IpAddr::V6(_)))
+        {
+            return Err(InvalidGatewayPrefix(*prefix).into());
+        }
+
+        // Regular routes must be in the global subnet, and be owned by the announcing node.
+        // Announcements of trusted gateways are accepted as well.
+        let overlay_filter: Box<dyn RouteUpdateFilter + Send + Sync> =
+            Box::new(filters::AllOf(vec![
                 Box::new(filters::AllowedSubnet::new(
                     Subnet::new(GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN)
                         .expect("Global subnet is properly defined; qed"),
                 )),
                 Box::new(filters::MaxSubnetSize::<64>),
                 Box::new(filters::RouterIdOwnsSubnet),
-            ],
+            ]));
+        let update_filter: Box<dyn RouteUpdateFilter + Send + Sync> =
+            if config.trusted_gateways.is_empty() {
+                overlay_filter
+            } else {
+                Box::new(filters::AnyOf(vec![
+                    overlay_filter,
+                    Box::new(filters::TrustedGateways::new(
+                        config.trusted_gateways.clone(),
+                    )),
+                ]))
+            };
+
+        // Creating a new Router instance
+        let router = match router::Router::new(
+            config.update_workers,
+            tun_tx,
+            node_subnet,
+            std::iter::once(node_subnet)
+                .chain(config.gateway_prefixes.iter().copied())
+                .collect(),
+            (config.node_key, node_pub_key),
+            vec![update_filter],
+            config.require_signed_updates,
+            config.multipath_tolerance,
+            config.selection_hysteresis,
+            config.route_damping,
+            config.leaf,
             config.metrics.clone(),
         ) {
             Ok(router) => {
@@ -170,6 +324,26 @@ where
             }
         };
 
+        if config.route_policy != RoutePolicy::default() {
+            router.set_route_policy(config.route_policy);
+        }
+
+        if let Some(previous_key) = config.previous_key {
+            if previous_key.succession.successor() != node_pub_key
+                || previous_key.succession.verify().is_err()
+            {
+                warn!("Ignoring previous key, its succession does not name the node key");
+            } else {
+                router.set_previous_key(previous_key);
+            }
+        }
+
+        let peer_cache = config
+            .peer_cache
+            .as_deref()
+            .map(PeerCache::open)
+            .transpose()?;
+
         // Creating a new PeerManager instance
         let pm = peer_manager::PeerManager::new(
             router.clone(),
@@ -181,6 +355,9 @@ where
             config.private_network_config,
             config.metrics,
             config.firewall_mark,
+            peer_cache,
+            config.link_keys,
+            config.peer_settings,
         )?;
         info!("Started peer manager");
 
@@ -193,10 +370,16 @@ where
         #[cfg(not(feature = "message"))]
         let msg_sender = futures::sink::drain();
 
-        let _data_plane = if config.no_tun {
+        let preferred_cipher = config.preferred_cipher.unwrap_or_else(Cipher::detect);
+        let data_plane = if config.no_tun {
             warn!("Starting data plane without TUN interface, L3 functionality disabled");
             DataPlane::new(
                 router.clone(),
+                ipv4_overlay,
+                // There is no host to answer echo requests for our address
+                true,
+                config.firewall,
+                preferred_cipher,
                 // No tun so create a dummy stream for L3 packets which never yields
                 tokio_stream::pending(),
                 // Similarly, create a sink which just discards every packet we would receive
@@ -234,6 +417,15 @@ where
                         .expect("64 is a valid subnet size for IPv6; qed"),
                     route_subnet: Subnet::new(GLOBAL_SUBNET_ADDRESS, GLOBAL_SUBNET_PREFIX_LEN)
                         .expect("Static configured TUN route is valid; qed"),
+                    ipv4_subnet: ipv4_overlay.as_ref().map(|overlay| {
+                        Subnet::new(overlay.local_address().into(), overlay.range().prefix_len())
+                            .expect("Prefix length of the IPv4 overlay range is valid; qed")
+                    }),
+                    gateway_routes: config
+                        .trusted_gateways
+                        .iter()
+                        .flat_map(|gw| gw.prefixes.iter().copied())
+                        .collect(),
                 };
                 #[cfg(any(
                     target_os = "android",
@@ -247,16 +439,43 @@ where
                 let (rxhalf, txhalf) = tun::new(tun_config).await?;
 
                 info!("Node overlay IP: {node_addr}");
-                DataPlane::new(router.clone(), rxhalf, txhalf, msg_sender, tun_rx)
+                if let Some(node_ipv4) = node_ipv4 {
+                    info!("Node overlay IPv4: {node_ipv4}");
+                }
+                DataPlane::new(
+                    router.clone(),
+                    ipv4_overlay,
+                    false,
+                    config.firewall,
+                    preferred_cipher,
+                    rxhalf,
+                    txhalf,
+                    msg_sender,
+                    tun_rx,
+                )
             }
         };
 
         #[cfg(feature = "message")]
-        let ms = MessageStack::new(_data_plane, msg_receiver);
+        let ms = {
+            let store = config
+                .message_store
+                .as_deref()
+                .map(MessageStore::open)
+                .transpose()?;
+            let spool = config
+                .message_spool
+                .as_deref()
+                .map(MessageSpool::open)
+                .transpose()?;
+            MessageStack::new(data_plane.clone(), msg_receiver, store, spool)
+        };
 
         Ok(Node {
             router,
+            node_ipv4,
             peer_manager: pm,
+            data_plane,
             #[cfg(feature = "message")]
             message_stack: ms,
         })
@@ -267,6 +486,7 @@ where
         NodeInfo {
             node_subnet: self.router.node_tun_subnet(),
             node_pubkey: self.router.node_public_key(),
+            node_ipv4: self.node_ipv4,
         }
     }
 
@@ -275,9 +495,29 @@ where
         self.peer_manager.peers()
     }
 
-    /// Add a new peer to the system identified by an [`Endpoint`].
-    pub fn add_peer(&self, endpoint: Endpoint) -> Result<(), PeerExists> {
-        self.peer_manager.add_peer(endpoint)
+    /// Add a new peer to the system identified by an [`Endpoint`], with the given link
+    /// [`settings`](PeerSettings).
+    pub fn add_peer(&self, endpoint: Endpoint, settings: PeerSettings) -> Result<(), PeerExists> {
+        self.peer_manager.add_peer(endpoint, settings)
+    }
+
+    /// Add a new peer from the configuration identified by an [`Endpoint`], with the given link
+    /// [`settings`](PeerSettings). The peer is not recorded in the peer cache.
+    pub fn add_static_peer(
+        &self,
+        endpoint: Endpoint,
+        settings: PeerSettings,
+    ) -> Result<(), PeerExists> {
+        self.peer_manager.add_static_peer(endpoint, settings)
+    }
+
+    /// Change the link [`settings`](PeerSettings) of a peer identified by an [`Endpoint`].
+    pub fn set_peer_settings(
+        &self,
+        endpoint: Endpoint,
+        settings: PeerSettings,
+    ) -> Result<(), PeerNotFound> {
+        self.peer_manager.set_peer_settings(&endpoint, settings)
     }
 
     /// Remove an existing peer identified by an [`Endpoint`] from the system.
@@ -295,10 +535,58 @@ where
         self.router.load_fallback_routes()
     }
 
+    /// List the damping state of all routes which flapped recently. This is empty if route flap
+    /// damping is disabled.
+    pub fn damped_routes(&self) -> Vec<DampedRoute> {
+        self.router.damped_routes()
+    }
+
+    /// Send a probe with the given hop limit to the node with the given overlay IP. The returned
+    /// [`Probe`] can be used to wait for the reply, which is either an echo reply from the
+    /// destination, or an ICMP error from a node on the path to it.
+    pub fn send_probe(&self, dst: Ipv6Addr, hop_limit: u8) -> Result<Probe, UnknownDestination> {
+        self.data_plane.send_probe(dst, hop_limit)
+    }
+
+    /// Get the [`FirewallPolicy`] applied to packets received from the overlay, if the firewall
+    /// is enabled.
+    pub fn firewall_policy(&self) -> Option<FirewallPolicy> {
+        self.data_plane.firewall_policy()
+    }
+
+    /// Replace the [`FirewallPolicy`] applied to packets received from the overlay. Setting no
+    /// policy disables the firewall.
+    pub fn set_firewall_policy(&self, policy: Option<FirewallPolicy>) {
+        self.data_plane.set_firewall_policy(policy)
+    }
+
     /// Get public key from the IP of `Node`
     pub fn get_pubkey_from_ip(&self, ip: IpAddr) -> Option<crypto::PublicKey> {
         self.router.get_pubkey(ip)
     }
+
+    /// Get the [`Succession`] of the key which owned the given address, if that key was replaced
+    /// and the rotation is known to this node.
+    pub fn get_key_succession(&self, ip: IpAddr) -> Option<Succession> {
+        self.router.key_succession(ip)
+    }
+
+    /// Get the current key of the node which owned the given address before it rotated its key.
+    /// If the node rotated its key multiple times, this is the most recent key known.
+    pub fn get_successor_from_ip(&self, ip: IpAddr) -> Option<crypto::PublicKey> {
+        self.router.successor(ip)
+    }
+
+    /// Get the [`RoutePolicy`] currently applied to routes received from peers.
+    pub fn route_policy(&self) -> RoutePolicy {
+        self.router.route_policy()
+    }
+
+    /// Replace the [`RoutePolicy`] applied to routes received from peers. Routes which are
+    /// denied by the new policy are retracted.
+    pub fn set_route_policy(&self, policy: RoutePolicy) {
+        self.router.set_route_policy(policy)
+    }
 }
 
 #[cfg(feature = "message")]
@@ -352,6 +640,71 @@ where
         )
     }
 
+    /// Push a new message to the message stack, which is streamed from `reader` instead of being
+    /// held in memory.
+    ///
+    /// The reader must provide exactly `len` bytes. Since only a small part of the message is
+    /// buffered at any time, `try_duration` must be long enough to transmit the full message.
+    pub fn push_stream_message<R>(
+        &self,
+        dst: IpAddr,
+        reader: R,
+        len: u64,
+        topic: Option<Vec<u8>>,
+        try_duration: Duration,
+    ) -> Result<MessageId, PushMessageError>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.message_stack.new_stream_message(
+            dst,
+            reader,
+            len,
+            topic.unwrap_or_default(),
+            try_duration,
+        )
+    }
+
+    /// Publish a message to all nodes which subscribed to `topic` at this node.
+    ///
+    /// A separate message is sent to every subscriber. The returned [`MessageId`] can be used
+    /// to get the status of the message for all subscribers with
+    /// [`message_status`](Self::message_status).
+    pub fn publish_message(
+        &self,
+        data: Vec<u8>,
+        topic: Vec<u8>,
+        try_duration: Duration,
+    ) -> Result<MessageId, PushMessageError> {
+        self.message_stack
+            .publish_message(data, topic, try_duration)
+    }
+
+    /// Subscribe to messages published to `topic` by the node with the given address.
+    pub fn subscribe_topic(
+        &self,
+        topic: Vec<u8>,
+        publisher: IpAddr,
+    ) -> Result<(), PushMessageError> {
+        self.message_stack.subscribe_topic(topic, publisher)
+    }
+
+    /// Remove a subscription to `topic` at the node with the given address. Returns `false` if
+    /// there was no such subscription.
+    pub fn unsubscribe_topic(&self, topic: Vec<u8>, publisher: IpAddr) -> bool {
+        self.message_stack.unsubscribe_topic(topic, publisher)
+    }
+
+    /// The topics this node subscribed to, and the address of the publisher.
+    pub fn topic_subscriptions(&self) -> Vec<(Vec<u8>, IpAddr)> {
+        self.message_stack.topic_subscriptions()
+    }
+
+    /// The topics other nodes subscribed to at this node, and the addresses of the subscribers.
+    pub fn topic_subscribers(&self) -> Vec<(Vec<u8>, Vec<IpAddr>)> {
+        self.message_stack.topic_subscribers()
+    }
+
     /// Get the status of a message sent previously.
     ///
     /// Returns [`Option::None`] if no message is found with the given id. Message info is only
@@ -0,0 +1,382 @@
+//! Authentication of control packets on peer links.
+//!
+//! Control packets sent over a link with a configured key carry a MAC, and a packet counter which
+//! must increase for every packet. Before the packets of a remote are accepted, the remote must
+//! answer a challenge, which proves its packet counters are not replayed from an earlier
+//! connection. This follows the design of [RFC 8967](https://datatracker.ietf.org/doc/html/rfc8967).
+//!
+//! Data packets are not authenticated on the link, as they are already encrypted and
+//! authenticated between their source and destination.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use bytes::BytesMut;
+use serde::Deserialize;
+use tracing::trace;
+
+use crate::{
+    babel::auth::{self, AuthTlvs, Index, Nonce},
+    peer_manager::PrivateNetworkKey,
+};
+
+/// Context used to derive a link key from a configured secret.
+const LINK_KEY_CONTEXT: &str = "mycelium 2024 babel link key from secret";
+/// Context used to derive a link key from the key of a private network.
+const NETWORK_LINK_KEY_CONTEXT: &str = "mycelium 2024 babel link key from private network key";
+
+/// Maximum amount of packets of the remote which are held until it answered our challenge.
+const MAX_PENDING_PACKETS: usize = 1024;
+
+/// A secret used to authenticate the control packets on links with a peer.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct LinkKey {
+    /// The IP address of the peer.
+    pub address: IpAddr,
+    /// The secret shared with the peer. The key used for the links with the peer is derived from
+    /// this.
+    pub secret: String,
+}
+
+impl fmt::Debug for LinkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the secret in logs.
+        f.debug_struct("LinkKey")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The keys used to authenticate the control packets on links.
+#[derive(Clone, Default)]
+pub struct LinkKeys {
+    /// Keys for links with specific peers.
+    peers: HashMap<IpAddr, [u8; 32]>,
+    /// Key used for links with all other peers, if there is one.
+    default: Option<[u8; 32]>,
+}
+
+impl LinkKeys {
+    /// Create a new `LinkKeys` from the configured link keys. If a private network key is set, a
+    /// key derived from it is used for all links without a configured key.
+    pub fn new(link_keys: &[LinkKey], network_key: Option<&PrivateNetworkKey>) -> Self {
+        Self {
+            peers: link_keys
+                .iter()
+                .map(|lk| {
+                    (
+                        lk.address.to_canonical(),
+                        blake3::derive_key(LINK_KEY_CONTEXT, lk.secret.as_bytes()),
+                    )
+                })
+                .collect(),
+            default: network_key.map(|key| blake3::derive_key(NETWORK_LINK_KEY_CONTEXT, key)),
+        }
+    }
+
+    /// The key to use for a link with a peer at the given address, if there is one.
+    pub fn key_for(&self, remote: Option<SocketAddr>) -> Option<[u8; 32]> {
+        remote
+            .and_then(|remote| self.peers.get(&remote.ip().to_canonical()))
+            .or(self.default.as_ref())
+            .copied()
+    }
+}
+
+/// Authentication state of a single link.
+pub struct LinkAuth {
+    key: [u8; 32],
+    /// Our index, which is random for every link.
+    index: Index,
+    /// Packet counter of the last packet we sent.
+    pc: u32,
+    /// Index and packet counter of the last accepted packet of the remote, once the remote
+    /// answered a challenge.
+    remote: Option<(Index, u32)>,
+    /// Nonce of the challenge for the remote, if it has not been answered yet.
+    challenge: Option<Nonce>,
+    /// Set once the challenge has been sent to the remote.
+    challenge_sent: bool,
+    /// Nonce of a challenge of the remote we still need to answer.
+    challenge_reply: Option<Nonce>,
+    /// Packets received before the remote answered our challenge, with their index and packet
+    /// counter.
+    pending: VecDeque<(Index, u32, BytesMut)>,
+    /// Packets which are accepted, and can be decoded.
+    accepted: VecDeque<BytesMut>,
+}
+
+impl LinkAuth {
+    /// Create a new `LinkAuth` for a link which uses the given key. The remote is challenged with
+    /// the first packet we send.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            index: rand::random(),
+            pc: 0,
+            remote: None,
+            challenge: Some(rand::random()),
+            challenge_sent: false,
+            challenge_reply: None,
+            pending: VecDeque::new(),
+            accepted: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` if a packet should be sent to the remote as soon as possible, because it
+    /// needs to be challenged, or it challenged us.
+    pub fn pending_send(&self) -> bool {
+        (self.challenge.is_some() && !self.challenge_sent) || self.challenge_reply.is_some()
+    }
+
+    /// Write an authenticated packet holding the given babel packet, which can be [`None`] to
+    /// only send the authentication TLVs.
+    pub fn seal(&mut self, packet: Option<&[u8]>, dst: &mut BytesMut) {
+        // The packet counter must never wrap, start a new sequence with a fresh index instead.
+        // The remote challenges the new index before it accepts our packets again.
+        if self.pc == u32::MAX {
+            self.index = rand::random();
+            self.pc = 0;
+        }
+        self.pc += 1;
+        let challenge_request = if self.challenge_sent {
+            None
+        } else {
+            self.challenge_sent = true;
+            self.challenge
+        };
+        let tlvs = AuthTlvs {
+            pc: self.pc,
+            index: self.index,
+            challenge_request,
+            challenge_reply: self.challenge_reply.take(),
+        };
+
+        auth::seal(&self.key, packet, &tlvs, dst);
+    }
+
+    /// Process an authenticated packet received from the remote. If the packet is accepted, the
+    /// babel packet it holds can be retrieved with [`LinkAuth::next_accepted`].
+    pub fn open(&mut self, packet: BytesMut, trailer: &[u8]) {
+        let Some((tlvs, packet)) = auth::open(&self.key, packet, trailer) else {
+            trace!("Dropping control packet which failed authentication");
+            return;
+        };
+
+        // Our own packets reflected back to us carry a valid MAC as well.
+        if tlvs.index == self.index {
+            trace!("Dropping reflected control packet");
+            return;
+        }
+
+        if let Some(nonce) = tlvs.challenge_request {
+            self.challenge_reply = Some(nonce);
+        }
+
+        if tlvs.challenge_reply.is_some() && tlvs.challenge_reply == self.challenge {
+            // The remote proved this index is fresh. Packets with this index which arrived
+            // before the reply are part of the same sequence, and can be accepted as well.
+            self.challenge = None;
+            let mut last = None;
+            for (index, pc, pending) in std::mem::take(&mut self.pending) {
+                if index == tlvs.index && pc < tlvs.pc && last.map_or(true, |last| pc > last) {
+                    last = Some(pc);
+                    self.accept(pending);
+                }
+            }
+            self.remote = Some((tlvs.index, tlvs.pc));
+            self.accept(packet);
+            return;
+        }
+
+        match self.remote {
+            Some((index, last)) if index == tlvs.index => {
+                if tlvs.pc > last {
+                    self.remote = Some((index, tlvs.pc));
+                    self.accept(packet);
+                } else {
+                    trace!(pc = tlvs.pc, last, "Dropping replayed control packet");
+                }
+            }
+            _ => {
+                // Unknown index, challenge the remote and hold the packet until it answered.
+                if self.challenge.is_none() {
+                    self.challenge = Some(rand::random());
+                    self.challenge_sent = false;
+                }
+                if self.pending.len() < MAX_PENDING_PACKETS {
+                    self.pending.push_back((tlvs.index, tlvs.pc, packet));
+                } else {
+                    trace!("Dropping control packet of unverified remote");
+                }
+            }
+        }
+    }
+
+    /// Get the next accepted babel packet, if there is one.
+    pub fn next_accepted(&mut self) -> Option<BytesMut> {
+        self.accepted.pop_front()
+    }
+
+    /// Mark a packet as accepted. Packets which only hold authentication TLVs are not passed on.
+    fn accept(&mut self, packet: BytesMut) {
+        if !packet.is_empty() {
+            self.accepted.push_back(packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use bytes::BytesMut;
+
+    use crate::babel::{auth::TRAILER_SIZE, packet_len};
+
+    use super::{LinkAuth, LinkKey, LinkKeys};
+
+    /// Babel packet with a single TLV, the last byte identifies the packet.
+    fn packet(id: u8) -> [u8; 7] {
+        [42, 2, 0, 3, 200, 1, id]
+    }
+
+    /// Send a packet from one side to the other.
+    fn send(from: &mut LinkAuth, to: &mut LinkAuth, packet: Option<&[u8]>) {
+        let mut buf = BytesMut::new();
+        from.seal(packet, &mut buf);
+        transfer(to, buf);
+    }
+
+    fn transfer(to: &mut LinkAuth, mut buf: BytesMut) {
+        let len = packet_len(&buf).unwrap();
+        assert_eq!(buf.len(), len + TRAILER_SIZE);
+        let sealed = buf.split_to(len);
+        to.open(sealed, &buf);
+    }
+
+    fn accepted(auth: &mut LinkAuth) -> Vec<u8> {
+        std::iter::from_fn(|| auth.next_accepted())
+            .map(|p| p[p.len() - 1])
+            .collect()
+    }
+
+    #[test]
+    fn packets_are_held_until_challenge_is_answered() {
+        let mut a = LinkAuth::new([1; 32]);
+        let mut b = LinkAuth::new([1; 32]);
+
+        // Both sides challenge the other with their first packet.
+        assert!(a.pending_send());
+        send(&mut a, &mut b, Some(&packet(1)));
+        send(&mut a, &mut b, Some(&packet(2)));
+        assert!(!a.pending_send());
+        assert!(accepted(&mut b).is_empty());
+
+        // The reply of b answers the challenge of a, the challenge of b is sent with it.
+        assert!(b.pending_send());
+        send(&mut b, &mut a, None);
+        assert!(accepted(&mut a).is_empty());
+
+        send(&mut a, &mut b, Some(&packet(3)));
+        assert_eq!(accepted(&mut b), vec![1, 2, 3]);
+        assert!(!a.pending_send());
+        assert!(!b.pending_send());
+
+        send(&mut b, &mut a, Some(&packet(4)));
+        assert_eq!(accepted(&mut a), vec![4]);
+    }
+
+    #[test]
+    fn replayed_and_reflected_packets_are_dropped() {
+        let mut a = LinkAuth::new([1; 32]);
+        let mut b = LinkAuth::new([1; 32]);
+        send(&mut a, &mut b, None);
+        send(&mut b, &mut a, None);
+        send(&mut a, &mut b, None);
+
+        let mut buf = BytesMut::new();
+        a.seal(Some(&packet(1)), &mut buf);
+        transfer(&mut b, buf.clone());
+        assert_eq!(accepted(&mut b), vec![1]);
+
+        // Replay of the same packet.
+        transfer(&mut b, buf.clone());
+        assert!(accepted(&mut b).is_empty());
+
+        // Reflection of the packet to its sender.
+        transfer(&mut a, buf);
+        assert!(accepted(&mut a).is_empty());
+    }
+
+    #[test]
+    fn index_is_rotated_before_packet_counter_wraps() {
+        let mut a = LinkAuth::new([1; 32]);
+        let mut b = LinkAuth::new([1; 32]);
+        send(&mut a, &mut b, None);
+        send(&mut b, &mut a, None);
+        send(&mut a, &mut b, None);
+
+        a.pc = u32::MAX - 1;
+        let index = a.index;
+        send(&mut a, &mut b, Some(&packet(1)));
+        assert_eq!(a.index, index);
+        assert_eq!(accepted(&mut b), vec![1]);
+
+        // The counter would wrap, so a new index is used, which b has to challenge.
+        send(&mut a, &mut b, Some(&packet(2)));
+        assert_ne!(a.index, index);
+        assert_eq!(a.pc, 1);
+        assert!(accepted(&mut b).is_empty());
+        assert!(b.pending_send());
+
+        send(&mut b, &mut a, None);
+        send(&mut a, &mut b, Some(&packet(3)));
+        assert_eq!(accepted(&mut b), vec![2, 3]);
+    }
+
+    #[test]
+    fn packets_with_wrong_key_are_dropped() {
+        let mut a = LinkAuth::new([1; 32]);
+        let mut b = LinkAuth::new([2; 32]);
+
+        send(&mut a, &mut b, Some(&packet(1)));
+        assert!(accepted(&mut b).is_empty());
+
+        // The challenge of a is never answered, since its packet is not authenticated.
+        send(&mut b, &mut a, None);
+        send(&mut a, &mut b, Some(&packet(2)));
+        assert!(a.challenge.is_some());
+        assert!(accepted(&mut b).is_empty());
+    }
+
+    #[test]
+    fn peer_keys_take_precedence() {
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let keys = LinkKeys::new(
+            &[LinkKey {
+                address: peer,
+                secret: "secret".into(),
+            }],
+            Some(&[3; 32]),
+        );
+
+        let peer_key = keys.key_for(Some(SocketAddr::new(peer, 9651))).unwrap();
+        let mapped = SocketAddr::new(IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped()), 1);
+        assert_eq!(keys.key_for(Some(mapped)), Some(peer_key));
+
+        let other = keys
+            .key_for(Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+                9651,
+            )))
+            .unwrap();
+        assert_ne!(peer_key, other);
+        assert_eq!(keys.key_for(None), Some(other));
+
+        assert_eq!(LinkKeys::default().key_for(None), None);
+    }
+}
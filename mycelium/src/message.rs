@@ -11,6 +11,7 @@ use std::{
     marker::PhantomData,
     net::IpAddr,
     ops::{Deref, DerefMut},
+    path::PathBuf,
     sync::{Arc, Mutex},
     time::{self, Duration},
 };
@@ -18,19 +19,39 @@ use std::{
 use futures::{Stream, StreamExt};
 use rand::Fill;
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
-use tokio::sync::watch;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::watch,
+};
 use tracing::{debug, error, trace, warn};
 
 use crate::{
     crypto::{PacketBuffer, PublicKey},
     data::DataPlane,
-    message::{chunk::MessageChunk, done::MessageDone, init::MessageInit},
+    message::{
+        chunk::MessageChunk,
+        done::MessageDone,
+        init::MessageInit,
+        spool::{SpoolFile, SPOOL_THRESHOLD},
+        store::StoredOutboundMessage,
+        stream::OutboundStream,
+        subscribe::MessageSubscribe,
+        topic::{TopicRegistry, SUBSCRIPTION_REFRESH_INTERVAL},
+    },
     metrics::Metrics,
 };
 
+pub use spool::MessageSpool;
+pub use store::MessageStore;
+
 mod chunk;
 mod done;
 mod init;
+mod spool;
+mod store;
+mod stream;
+mod subscribe;
+mod topic;
 
 /// The amount of time to try and send messages before we give up.
 const MESSAGE_SEND_WINDOW: Duration = Duration::from_secs(60 * 5);
@@ -72,6 +93,10 @@ const FLAG_MESSAGE_READ: u16 = 0b0000_1000_0000_0000;
 /// Flag indicating we are sending a reply to a received message. The message ID used is the same
 /// as the received message.
 const FLAG_MESSAGE_REPLY: u16 = 0b0000_0100_0000_0000;
+/// Flag indicating the sender subscribes to, or unsubscribes from, a topic published by the
+/// receiver. The body contains the topic. Subscribe messages are not acknowledged, instead they
+/// are periodically repeated by the subscriber.
+const FLAG_MESSAGE_SUBSCRIBE: u16 = 0b0000_0010_0000_0000;
 /// Flag acknowledging receipt of a packet. Once this has been received, the packet __should not__ be
 /// transmitted again by the sender.
 const FLAG_MESSAGE_ACK: u16 = 0b0000_0001_0000_0000;
@@ -97,10 +122,21 @@ pub struct MessageStack<M> {
     /// This takes an Option as value to avoid the hassle of constructing a dummy value when
     /// creating the watch channel.
     reply_subscribers: Arc<Mutex<HashMap<MessageId, watch::Sender<Option<ReceivedMessage>>>>>,
+    /// Optional persistent storage for the inbox and outbox.
+    store: Option<Arc<Mutex<MessageStore>>>,
+    /// Optional directory in which large inbound messages are written.
+    spool: Option<Arc<MessageSpool>>,
+    /// Topic subscriptions of this node and of remote nodes.
+    topics: Arc<Mutex<TopicRegistry>>,
+    /// Messages published to the subscribers of a topic, mapped to the ids of the messages sent to
+    /// the individual subscribers.
+    published: Arc<Mutex<HashMap<MessageId, Vec<MessageId>>>>,
 }
 
 struct MessageOutbox {
     msges: HashMap<MessageId, OutboundMessageInfo>,
+    /// Messages which are streamed from a reader.
+    streams: HashMap<MessageId, OutboundStream>,
 }
 
 struct MessageInbox {
@@ -122,7 +158,13 @@ struct ReceivedMessageInfo {
     len: u64,
     /// Optional topic of the message.
     topic: Vec<u8>,
+    /// Chunks received so far. This is empty if the message is spooled.
     chunks: Vec<Option<Chunk>>,
+    /// File in which the message is written, if it is too large to keep in memory. Writes to the
+    /// file happen on a blocking thread, without holding the inbox lock.
+    spool: Option<Arc<Mutex<SpoolFile>>>,
+    /// Set while the data of a spooled message is being verified.
+    verifying: bool,
 }
 
 #[derive(Clone)]
@@ -141,8 +183,11 @@ pub struct ReceivedMessage {
     pub dst_pk: PublicKey,
     /// The possible topic of the message.
     pub topic: Vec<u8>,
-    /// Actual message.
+    /// Actual message. This is empty if the message is spooled to a file.
     pub data: Vec<u8>,
+    /// File holding the message, if it was too large to be kept in memory. The file is owned by
+    /// the consumer of the message, who is responsible for removing it.
+    pub spool_file: Option<PathBuf>,
 }
 
 /// A chunk of a message. This represents individual data pieces on the receiver side.
@@ -193,6 +238,10 @@ enum TransmissionState {
 pub enum PushMessageError {
     /// The topic set in the message is too large.
     TopicTooLarge,
+    /// A topic is required, but the message does not have one.
+    NoTopic,
+    /// There are no subscribers for the topic the message is published to.
+    NoSubscribers,
 }
 
 impl MessageInbox {
@@ -210,6 +259,7 @@ impl MessageOutbox {
     fn new() -> Self {
         Self {
             msges: HashMap::new(),
+            streams: HashMap::new(),
         }
     }
 
@@ -226,7 +276,19 @@ where
     /// Create a new `MessageStack`. This uses the provided [`DataPlane`] to inject message
     /// packets. Received packets must be injected into the `MessageStack` through the provided
     /// [`Stream`].
-    pub fn new<S>(data_plane: DataPlane<M>, message_packet_stream: S) -> Self
+    ///
+    /// If a [`MessageStore`] is provided, the inbox and outbox are persisted in it. Messages which
+    /// were left in the store are loaded in the inbox, and outbound messages which are still
+    /// inside the send window resume transmission.
+    ///
+    /// If a [`MessageSpool`] is provided, inbound messages which are too large to keep in memory
+    /// are written to it.
+    pub fn new<S>(
+        data_plane: DataPlane<M>,
+        message_packet_stream: S,
+        store: Option<MessageStore>,
+        spool: Option<MessageSpool>,
+    ) -> Self
     where
         S: Stream<Item = (PacketBuffer, IpAddr, IpAddr)> + Send + Unpin + 'static,
     {
@@ -237,8 +299,14 @@ where
             outbox: Arc::new(Mutex::new(MessageOutbox::new())),
             subscriber,
             reply_subscribers: Arc::new(Mutex::new(HashMap::new())),
+            store: store.map(|store| Arc::new(Mutex::new(store))),
+            spool: spool.map(Arc::new),
+            topics: Arc::new(Mutex::new(TopicRegistry::new())),
+            published: Arc::new(Mutex::new(HashMap::new())),
         };
 
+        ms.load_stored_messages();
+
         tokio::task::spawn(
             ms.clone()
                 .handle_incoming_message_packets(message_packet_stream),
@@ -266,9 +334,84 @@ where
                 }
             });
         }
+
+        // task to refresh our topic subscriptions, and forget subscribers which went away
+        {
+            let ms = ms.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    tokio::time::sleep(SUBSCRIPTION_REFRESH_INTERVAL).await;
+
+                    let subscriptions = {
+                        let mut topics = ms.topics.lock().unwrap();
+                        topics.expire_subscribers(time::Instant::now());
+                        topics.subscriptions().cloned().collect::<Vec<_>>()
+                    };
+                    for (topic, publisher) in subscriptions {
+                        ms.send_subscribe(&topic, publisher, true);
+                    }
+
+                    // Published messages are kept as long as one of the individual messages is.
+                    let outbox = ms.outbox.lock().unwrap();
+                    ms.published.lock().unwrap().retain(|_, children| {
+                        children
+                            .iter()
+                            .any(|child| outbox.msges.contains_key(child))
+                    });
+                }
+            });
+        }
         ms
     }
 
+    /// Load the messages left in the [`MessageStore`], if there is one. Received messages are
+    /// added to the inbox. Outbound messages are resumed if they are still inside the send
+    /// window, and discarded otherwise.
+    fn load_stored_messages(&self) {
+        let Some(ref store) = self.store else {
+            return;
+        };
+
+        let (received, outbound) = store.lock().unwrap().take_loaded();
+
+        if !received.is_empty() {
+            let mut inbox = self.inbox.lock().unwrap();
+            inbox.complete_msges.extend(received);
+            inbox.notify.send_replace(());
+        }
+
+        let now = time::SystemTime::now();
+        for stored in outbound {
+            let send_window = (stored.created + MESSAGE_SEND_WINDOW)
+                .duration_since(now)
+                .unwrap_or_default();
+            if send_window.is_zero() {
+                debug!(
+                    message.id = stored.msg.id.as_hex(),
+                    "Discarding stored message which is outside of the send window"
+                );
+                store.lock().unwrap().remove_outbound(stored.msg.id);
+                continue;
+            }
+
+            debug!(
+                message.id = stored.msg.id.as_hex(),
+                "Resuming transmission of stored message"
+            );
+
+            let id = stored.msg.id;
+            self.outbox.lock().unwrap().insert(OutboundMessageInfo {
+                state: TransmissionState::Init,
+                created: stored.created,
+                deadline: stored.deadline,
+                len: stored.msg.data.len(),
+                msg: stored.msg,
+                chunks: vec![],
+            });
+            self.spawn_transmission(id, stored.reply, send_window);
+        }
+    }
+
     /// Handle incoming messages from the [`DataPlane`].
     async fn handle_incoming_message_packets<S>(self, mut message_packet_stream: S)
     where
@@ -319,6 +462,14 @@ where
                     })
                 }
                 message.chunks = chunks;
+            } else if let Some(stream) = outbox.streams.get_mut(&message_id) {
+                if stream.state != TransmissionState::Init {
+                    debug!("Dropping INIT ACK for message not in init state");
+                    return;
+                }
+                stream.state = TransmissionState::InProgress;
+                // Wake up the transmission task so it starts reading chunks.
+                stream.notify.notify_one();
             }
         } else if flags.chunk() {
             // ACK for a chunk, mark chunk as received so it is not retried again.
@@ -341,6 +492,16 @@ where
 
                 message.chunks[mc.chunk_idx() as usize].chunk_transmit_state =
                     ChunkTransmitState::Acked;
+            } else if let Some(stream) = outbox.streams.get_mut(&message_id) {
+                if stream.state != TransmissionState::InProgress {
+                    debug!("Dropping CHUNK ACK for message not being transmitted");
+                    return;
+                }
+                let mc = MessageChunk::new(mp);
+                if stream.ack(mc.chunk_idx()) {
+                    // There is space in the window, so more data can be read.
+                    stream.notify.notify_one();
+                }
             }
         } else if flags.done() {
             // ACK for full message.
@@ -351,6 +512,16 @@ where
                     return;
                 }
                 message.state = TransmissionState::Received;
+                // The remote has the full message, there is no need to resume it later.
+                if let Some(ref store) = self.store {
+                    store.lock().unwrap().remove_outbound(message_id);
+                }
+            } else if let Some(stream) = outbox.streams.get_mut(&message_id) {
+                if stream.state != TransmissionState::InProgress {
+                    debug!("Dropping DONE ACK for message which is not being transmitted");
+                    return;
+                }
+                stream.state = TransmissionState::Received;
             }
         } else if flags.read() {
             // Ack for a read flag. Since the original read flag is sent by the receiver, this
@@ -384,7 +555,28 @@ where
             let mi = MessageInit::new(mp);
             let expected_chunks =
                 (mi.length() as usize + AVERAGE_CHUNK_SIZE - 1) / AVERAGE_CHUNK_SIZE;
-            let chunks = vec![None; expected_chunks];
+            // Large messages are written to the spool if there is one, instead of keeping the
+            // chunks in memory.
+            let spool = match self.spool {
+                Some(ref spool) if mi.length() > SPOOL_THRESHOLD => {
+                    match spool.create(message_id, expected_chunks) {
+                        Ok(spool) => Some(Arc::new(Mutex::new(spool))),
+                        Err(e) => {
+                            warn!(
+                                message.id = message_id.as_hex(),
+                                "Failed to create spool file for message: {e}"
+                            );
+                            return;
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let chunks = if spool.is_some() {
+                vec![]
+            } else {
+                vec![None; expected_chunks]
+            };
             let message = ReceivedMessageInfo {
                 id: message_id,
                 is_reply,
@@ -393,6 +585,8 @@ where
                 len: mi.length(),
                 topic: mi.topic().into(),
                 chunks,
+                spool,
+                verifying: false,
             };
 
             if inbox.pending_msges.insert(message_id, message).is_some() {
@@ -433,6 +627,13 @@ where
                     );
                     return;
                 }
+                // Spooled chunks are written, and acknowledged, on a blocking thread.
+                if let Some(ref spool) = message.spool {
+                    let spool = spool.clone();
+                    drop(inbox);
+                    self.spawn_spool_write(spool, mc, message_id, src, dst);
+                    return;
+                }
                 // Finally check if we have sufficient space for our chunks.
                 if message.chunks.len() as u64 <= mc.chunk_idx() {
                     // TODO: optimize
@@ -454,6 +655,22 @@ where
             let md = MessageDone::new(mp);
             // At this point, we should have all message chunks. Verify length and reassemble them.
             if let Some(inbound_message) = inbox.pending_msges.get_mut(&message_id) {
+                if let Some(ref spool) = inbound_message.spool {
+                    // The data of a spooled message is verified on disk, which takes a while for
+                    // large messages. The sender repeats the DONE if it is not acknowledged, so
+                    // any DONE received in the meantime is dropped.
+                    if inbound_message.verifying {
+                        debug!("Dropping DONE for message which is already being verified");
+                        return;
+                    }
+                    inbound_message.verifying = true;
+                    let spool = spool.clone();
+                    let len = inbound_message.len;
+                    drop(inbox);
+                    self.spawn_spool_verification(spool, len, md, message_id, src, dst);
+                    return;
+                }
+
                 // Check if we have sufficient chunks
                 if md.chunk_count() != inbound_message.chunks.len() as u64 {
                     // TODO: report error to sender
@@ -501,53 +718,11 @@ where
                     return;
                 }
 
-                // Convert the IP's to PublicKeys.
-                let dp = self.data_plane.lock().unwrap();
-                let src_pubkey = if let Some(pk) = dp.router().get_pubkey(message.src) {
-                    pk
+                if self.deliver_message(&mut inbox, message_id, message.data, None) {
+                    Some(md.into_reply().into_inner())
                 } else {
-                    warn!("No public key entry for IP we just received a message chunk from");
-                    return;
-                };
-                // This always is our own key as we are receiving.
-                let dst_pubkey = dp.router().node_public_key();
-
-                let message = ReceivedMessage {
-                    id: message.id,
-                    is_reply: inbound_message.is_reply,
-                    src_ip: message.src,
-                    src_pk: src_pubkey,
-                    dst_ip: message.dst,
-                    dst_pk: dst_pubkey,
-                    topic: message.topic,
-                    data: message.data,
-                };
-
-                debug!("Message {} reception complete", message.id.as_hex());
-
-                // Check if we have any listeners and try to send the message to those first.
-                let mut subscribers = self.reply_subscribers.lock().unwrap();
-                // Use remove here since we are done with the subscriber
-                // TODO: only check this if the is_reply flag is set?
-                if let Some(sub) = subscribers.remove(&message.id) {
-                    if let Err(e) = sub.send(Some(message)) {
-                        debug!("Subscriber quit before we could send the reply");
-                        // Move message to be read if there were no subscribers.
-                        inbox.complete_msges.push_back(e.0.unwrap());
-                        // Notify subscribers we have a new message.
-                        inbox.notify.send_replace(());
-                    } else {
-                        debug!("Informed subscriber of message reply");
-                    }
-                } else {
-                    // Move message to be read if there were no subscribers.
-                    inbox.complete_msges.push_back(message);
-                    // Notify subscribers we have a new message.
-                    inbox.notify.send_replace(());
+                    None
                 }
-                inbox.pending_msges.remove(&message_id);
-
-                Some(md.into_reply().into_inner())
             } else {
                 None
             }
@@ -560,6 +735,13 @@ where
                 }
                 debug!("Receiver confirmed READ of message {}", message_id.as_hex());
                 message.state = TransmissionState::Read;
+            } else if let Some(stream) = outbox.streams.get_mut(&message_id) {
+                if stream.state != TransmissionState::Received {
+                    debug!("Got READ for message which is not in received state");
+                    return;
+                }
+                debug!("Receiver confirmed READ of message {}", message_id.as_hex());
+                stream.state = TransmissionState::Read;
             }
             None
         } else if flags.aborted() {
@@ -570,23 +752,234 @@ where
                 debug!("Dropping pending message because we received an ABORT");
             }
             None
+        } else if flags.subscribe() {
+            let ms = MessageSubscribe::new(mp);
+            let mut topics = self.topics.lock().unwrap();
+            if ms.subscribe() {
+                trace!(subscriber = %src, "Received topic subscription");
+                if !topics.add_subscriber(ms.topic().to_vec(), src, time::Instant::now()) {
+                    debug!(subscriber = %src, "Dropping topic subscription, too many subscriptions");
+                }
+            } else {
+                debug!(subscriber = %src, "Subscriber withdrew topic subscription");
+                topics.remove_subscriber(ms.topic(), src);
+            }
+            None
         } else {
             debug!("Received unknown message flags {:b}", flags);
             None
         };
         if let Some(reply) = reply {
-            // This is a reply, so SRC -> DST and DST -> SRC
-            // FIXME: this can be fixed once the dataplane accepts generic IpAddr addresses.
-            match (src, dst) {
-                (IpAddr::V6(src), IpAddr::V6(dst)) => {
-                    self.data_plane.lock().unwrap().inject_message_packet(
-                        dst,
-                        src,
-                        reply.into_inner(),
-                    );
+            self.send_reply(reply, src, dst);
+        }
+    }
+
+    /// Send a reply to a message packet received from `src` for `dst`.
+    fn send_reply(&self, reply: MessagePacket, src: IpAddr, dst: IpAddr) {
+        // This is a reply, so SRC -> DST and DST -> SRC
+        // FIXME: this can be fixed once the dataplane accepts generic IpAddr addresses.
+        match (src, dst) {
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                self.data_plane
+                    .lock()
+                    .unwrap()
+                    .inject_message_packet(dst, src, reply.into_inner());
+            }
+            _ => debug!("can only reply to message fragments if both src and dst are IPv6"),
+        }
+    }
+
+    /// Write a chunk of a spooled message on a blocking thread, and acknowledge the chunk once it
+    /// is written.
+    fn spawn_spool_write(
+        &self,
+        spool: Arc<Mutex<SpoolFile>>,
+        mc: MessageChunk,
+        message_id: MessageId,
+        src: IpAddr,
+        dst: IpAddr,
+    ) {
+        let stack = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut spool = spool.lock().unwrap();
+            if spool.is_finished() {
+                debug!("Dropping CHUNK for message which is already complete");
+                return;
+            }
+            // Don't acknowledge the chunk if it can't be written, so it is sent again.
+            if let Err(e) = spool.write_chunk(mc.chunk_idx() as usize, mc.chunk_offset(), mc.data())
+            {
+                warn!(
+                    message.id = message_id.as_hex(),
+                    "Failed to write message chunk to spool file: {e}"
+                );
+                return;
+            }
+            drop(spool);
+
+            stack.send_reply(mc.into_reply().into_inner(), src, dst);
+        });
+    }
+
+    /// Verify the data of a spooled message on a blocking thread, and deliver the message if it
+    /// is valid.
+    fn spawn_spool_verification(
+        &self,
+        spool: Arc<Mutex<SpoolFile>>,
+        len: u64,
+        md: MessageDone,
+        message_id: MessageId,
+        src: IpAddr,
+        dst: IpAddr,
+    ) {
+        let stack = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // The spool file stays locked until the message is delivered, so no chunk can be
+            // written after the data has been verified.
+            let mut spool_file = spool.lock().unwrap();
+            let valid = verify_spool(&mut spool_file, len, &md, message_id);
+
+            let mut inbox = stack.inbox.lock().unwrap();
+            // The message might have been aborted or restarted in the meantime.
+            match inbox.pending_msges.get_mut(&message_id) {
+                Some(message)
+                    if message
+                        .spool
+                        .as_ref()
+                        .is_some_and(|pending| Arc::ptr_eq(pending, &spool)) =>
+                {
+                    message.verifying = false;
                 }
-                _ => debug!("can only reply to message fragments if both src and dst are IPv6"),
+                _ => return,
             }
+            if !valid {
+                return;
+            }
+
+            if stack.deliver_message(&mut inbox, message_id, vec![], Some(&mut spool_file)) {
+                drop(inbox);
+                drop(spool_file);
+                stack.send_reply(md.into_reply().into_inner(), src, dst);
+            }
+        });
+    }
+
+    /// Hand a complete and verified pending message to its consumer. `spool` is the file holding
+    /// the message data if the message is spooled, in which case `data` is empty. Returns `false`
+    /// if the message could not be delivered.
+    fn deliver_message(
+        &self,
+        inbox: &mut MessageInbox,
+        message_id: MessageId,
+        data: Vec<u8>,
+        spool: Option<&mut SpoolFile>,
+    ) -> bool {
+        let Some(inbound_message) = inbox.pending_msges.get(&message_id) else {
+            return false;
+        };
+
+        // Convert the IP's to PublicKeys.
+        let dp = self.data_plane.lock().unwrap();
+        let src_pubkey = if let Some(pk) = dp.router().get_pubkey(inbound_message.src) {
+            pk
+        } else {
+            warn!("No public key entry for IP we just received a message chunk from");
+            return false;
+        };
+        // This always is our own key as we are receiving.
+        let dst_pubkey = dp.router().node_public_key();
+
+        // The message is complete, hand over the spooled data to the consumer.
+        let spool_file = match spool.map(SpoolFile::finish) {
+            Some(Ok(path)) => Some(path),
+            Some(Err(e)) => {
+                warn!(
+                    message.id = message_id.as_hex(),
+                    "Failed to complete spooled message: {e}"
+                );
+                return false;
+            }
+            None => None,
+        };
+
+        let message = ReceivedMessage {
+            id: inbound_message.id,
+            is_reply: inbound_message.is_reply,
+            src_ip: inbound_message.src,
+            src_pk: src_pubkey,
+            dst_ip: inbound_message.dst,
+            dst_pk: dst_pubkey,
+            topic: inbound_message.topic.clone(),
+            data,
+            spool_file,
+        };
+
+        debug!("Message {} reception complete", message.id.as_hex());
+
+        // Check if we have any listeners and try to send the message to those first.
+        let mut subscribers = self.reply_subscribers.lock().unwrap();
+        // Use remove here since we are done with the subscriber
+        // TODO: only check this if the is_reply flag is set?
+        if let Some(sub) = subscribers.remove(&message.id) {
+            if let Err(e) = sub.send(Some(message)) {
+                debug!("Subscriber quit before we could send the reply");
+                let message = e.0.unwrap();
+                if let Some(ref store) = self.store {
+                    store.lock().unwrap().insert_received(&message);
+                }
+                // Move message to be read if there were no subscribers.
+                inbox.complete_msges.push_back(message);
+                // Notify subscribers we have a new message.
+                inbox.notify.send_replace(());
+            } else {
+                debug!("Informed subscriber of message reply");
+            }
+        } else {
+            if let Some(ref store) = self.store {
+                store.lock().unwrap().insert_received(&message);
+            }
+            // Move message to be read if there were no subscribers.
+            inbox.complete_msges.push_back(message);
+            // Notify subscribers we have a new message.
+            inbox.notify.send_replace(());
+        }
+        inbox.pending_msges.remove(&message_id);
+
+        true
+    }
+}
+
+/// Verify that a spool file holds the complete data of a message, as described by its DONE
+/// packet.
+fn verify_spool(spool: &mut SpoolFile, len: u64, md: &MessageDone, message_id: MessageId) -> bool {
+    if md.chunk_count() != spool.chunk_count() as u64 {
+        debug!("Message has invalid amount of chunks");
+        return false;
+    }
+    if !spool.complete() {
+        debug!("DONE received for incomplete message");
+        return false;
+    }
+    match spool.checksum(len) {
+        Ok(Some(checksum)) if checksum == md.checksum() => true,
+        Ok(Some(checksum)) => {
+            debug!(
+                "Message has wrong checksum, got {} expected {}",
+                md.checksum().to_hex(),
+                checksum.to_hex()
+            );
+            false
+        }
+        Ok(None) => {
+            debug!("Message has invalid size");
+            false
+        }
+        Err(e) => {
+            warn!(
+                message.id = message_id.as_hex(),
+                "Failed to verify spooled message: {e}"
+            );
+            false
         }
     }
 }
@@ -608,6 +1001,53 @@ where
         self.push_message(None, dst, data, topic, try_duration, subscribe_reply)
     }
 
+    /// Push a new message which is streamed from `reader`, instead of being held in memory. The
+    /// reader must provide exactly `len` bytes. Only a small window of the message is buffered
+    /// while it is being transmitted, so `try_duration` must be long enough to transmit the full
+    /// message. Streamed messages are not persisted in the [`MessageStore`].
+    pub fn new_stream_message<R>(
+        &self,
+        dst: IpAddr,
+        reader: R,
+        len: u64,
+        topic: Vec<u8>,
+        try_duration: Duration,
+    ) -> Result<MessageId, PushMessageError>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        if topic.len() > 255 {
+            return Err(PushMessageError::TopicTooLarge);
+        }
+
+        let (src, dst): (IpAddr, IpAddr) = {
+            let data_plane = self.data_plane.lock().unwrap();
+            let router = data_plane.router();
+            // If the receiver replaced the key owning the address, send to its current key.
+            let dst = router
+                .successor(dst)
+                .map_or(dst, |successor| successor.address().into());
+            (router.node_public_key().address().into(), dst)
+        };
+
+        let id = MessageId::new();
+        let created = time::SystemTime::now();
+        let deadline = created + try_duration;
+
+        self.outbox
+            .lock()
+            .expect("Outbox lock isn't poisoned; qed")
+            .streams
+            .insert(
+                id,
+                OutboundStream::new(src, dst, topic, created, deadline, len),
+            );
+
+        self.spawn_stream_transmission(id, reader, try_duration);
+
+        Ok(id)
+    }
+
     /// Push a new message which is a reply to the message with [the provided id](MessageId).
     pub fn reply_message(
         &self,
@@ -621,6 +1061,118 @@ where
             .0
     }
 
+    /// Publish a message to all nodes which subscribed to the given topic. A separate message is
+    /// sent to every subscriber, these can be tracked together with the returned
+    /// [message id](MessageId).
+    pub fn publish_message(
+        &self,
+        data: Vec<u8>,
+        topic: Vec<u8>,
+        try_duration: Duration,
+    ) -> Result<MessageId, PushMessageError> {
+        if topic.is_empty() {
+            return Err(PushMessageError::NoTopic);
+        }
+        if topic.len() > 255 {
+            return Err(PushMessageError::TopicTooLarge);
+        }
+
+        let subscribers = self
+            .topics
+            .lock()
+            .unwrap()
+            .subscribers(&topic, time::Instant::now());
+        if subscribers.is_empty() {
+            return Err(PushMessageError::NoSubscribers);
+        }
+
+        let mut children = Vec::with_capacity(subscribers.len());
+        for dst in subscribers {
+            let (id, _) =
+                self.push_message(None, dst, data.clone(), topic.clone(), try_duration, false)?;
+            children.push(id);
+        }
+
+        let id = MessageId::new();
+        debug!(
+            message.id = id.as_hex(),
+            recipients = children.len(),
+            "Published message to topic subscribers"
+        );
+        self.published.lock().unwrap().insert(id, children);
+
+        Ok(id)
+    }
+
+    /// Subscribe to messages published to a topic by the node with the given address. The
+    /// subscription is announced to the publisher, and periodically refreshed until
+    /// [`unsubscribe_topic`](Self::unsubscribe_topic) is called.
+    pub fn subscribe_topic(
+        &self,
+        topic: Vec<u8>,
+        publisher: IpAddr,
+    ) -> Result<(), PushMessageError> {
+        if topic.is_empty() {
+            return Err(PushMessageError::NoTopic);
+        }
+        if topic.len() > 255 {
+            return Err(PushMessageError::TopicTooLarge);
+        }
+
+        self.send_subscribe(&topic, publisher, true);
+        self.topics.lock().unwrap().subscribe(topic, publisher);
+
+        Ok(())
+    }
+
+    /// Remove a subscription to a topic at a publisher. The publisher is informed the
+    /// subscription is withdrawn. Returns `false` if there was no such subscription.
+    pub fn unsubscribe_topic(&self, topic: Vec<u8>, publisher: IpAddr) -> bool {
+        if topic.len() > 255 {
+            return false;
+        }
+
+        self.send_subscribe(&topic, publisher, false);
+        self.topics.lock().unwrap().unsubscribe(topic, publisher)
+    }
+
+    /// The topic subscriptions of this node, as topic and the address of the publisher.
+    pub fn topic_subscriptions(&self) -> Vec<(Vec<u8>, IpAddr)> {
+        self.topics
+            .lock()
+            .unwrap()
+            .subscriptions()
+            .cloned()
+            .collect()
+    }
+
+    /// The topics other nodes subscribed to at this node, and the addresses of the subscribers.
+    pub fn topic_subscribers(&self) -> Vec<(Vec<u8>, Vec<IpAddr>)> {
+        self.topics
+            .lock()
+            .unwrap()
+            .all_subscribers(time::Instant::now())
+    }
+
+    /// Send a subscribe message for a topic to a publisher. If `subscribe` is false, the
+    /// subscription is withdrawn instead.
+    fn send_subscribe(&self, topic: &[u8], publisher: IpAddr, subscribe: bool) {
+        let mut mp = MessagePacket::new(PacketBuffer::new());
+        mp.header_mut().set_message_id(MessageId::new());
+        let mut ms = MessageSubscribe::new(mp);
+        ms.set_subscribe(subscribe);
+        ms.set_topic(topic);
+
+        let data_plane = self.data_plane.lock().unwrap();
+        let src = data_plane.router().node_public_key().address();
+        match publisher {
+            IpAddr::V6(dst) => {
+                data_plane.inject_message_packet(src, dst, ms.into_inner().into_inner())
+            }
+            IpAddr::V4(_) => debug!("Can only send messages between two IPv6 addresses"),
+        }
+    }
+
     /// Subscribe to a new message with the given ID. In practice, this will be a reply.
     pub fn subscribe_id(&self, id: MessageId) -> watch::Receiver<Option<ReceivedMessage>> {
         let mut subscribers = self.reply_subscribers.lock().unwrap();
@@ -649,14 +1201,15 @@ where
             return Err(PushMessageError::TopicTooLarge);
         }
 
-        let src = self
-            .data_plane
-            .lock()
-            .unwrap()
-            .router()
-            .node_public_key()
-            .address()
-            .into();
+        let (src, dst): (IpAddr, IpAddr) = {
+            let data_plane = self.data_plane.lock().unwrap();
+            let router = data_plane.router();
+            // If the receiver replaced the key owning the address, send to its current key.
+            let dst = router
+                .successor(dst)
+                .map_or(dst, |successor| successor.address().into());
+            (router.node_public_key().address().into(), dst)
+        };
 
         let (id, reply) = if let Some(id) = id {
             (id, true)
@@ -702,6 +1255,13 @@ where
         mi.set_length(len as u64);
         mi.set_topic(&obmi.msg.topic);
 
+        if let Some(ref store) = self.store {
+            store
+                .lock()
+                .unwrap()
+                .insert_outbound(&obmi.msg, reply, created, deadline);
+        }
+
         self.outbox
             .lock()
             .expect("Outbox lock isn't poisoned; qed")
@@ -719,15 +1279,25 @@ where
             _ => debug!("Can only send messages between two IPv6 addresses"),
         }
 
+        self.spawn_transmission(id, reply, MESSAGE_SEND_WINDOW);
+
+        Ok((id, subscription))
+    }
+
+    /// Spawn a task which transmits the outbound message with the given id, and retransmits
+    /// unacknowledged parts. If the message is not received after `send_window`, it is aborted.
+    fn spawn_transmission(&self, id: MessageId, reply: bool, send_window: Duration) {
         // Clone message stack so it can be injected in the task.
         let message_stack = self.clone();
         tokio::task::spawn(async move {
-            let mut deadline = tokio::time::interval(MESSAGE_SEND_WINDOW);
+            let mut deadline = tokio::time::interval_at(
+                tokio::time::Instant::now() + send_window,
+                MESSAGE_SEND_WINDOW,
+            );
             let mut interval = tokio::time::interval(RETRANSMISSION_DELAY);
             // Avoid a send burst if the system is slow.
             interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-            // intervals tick immediately, so consume one tick each
-            deadline.tick().await;
+            // intervals tick immediately, so consume one tick
             interval.tick().await;
 
             let mut aborted = false;
@@ -749,7 +1319,7 @@ where
                                     }
 
                                     let mut mi = MessageInit::new(mp);
-                                    mi.set_length(len as u64);
+                                    mi.set_length(msg.len as u64);
                                     mi.set_topic(&msg.msg.topic);
                                     match (msg.msg.src, msg.msg.dst) {
                                         (IpAddr::V6(src), IpAddr::V6(dst)) => {
@@ -902,6 +1472,9 @@ where
                             if let Some(msg) = message_stack.outbox.lock().unwrap().msges.get_mut(&id) {
                                 if matches!(msg.state, TransmissionState::Init | TransmissionState::InProgress) {
                                     msg.state = TransmissionState::Aborted;
+                                    if let Some(ref store) = message_stack.store {
+                                        store.lock().unwrap().remove_outbound(id);
+                                    }
 
                                     // Inform receiver of message abortion.
                                     let mut mp = MessagePacket::new(PacketBuffer::new());
@@ -937,50 +1510,234 @@ where
                 }
             }
         });
+    }
 
-        Ok((id, subscription))
+    /// Spawn a task which reads the streamed message with the given id from `reader`, and
+    /// transmits it. If the message is not received after `send_window`, it is aborted.
+    fn spawn_stream_transmission<R>(&self, id: MessageId, reader: R, send_window: Duration)
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        // Clone message stack so it can be injected in the task.
+        let message_stack = self.clone();
+        tokio::task::spawn(async move {
+            // The reader is released as soon as the message is aborted.
+            let mut reader = Some(reader);
+
+            let Some(notify) = message_stack
+                .outbox
+                .lock()
+                .unwrap()
+                .streams
+                .get(&id)
+                .map(|stream| stream.notify.clone())
+            else {
+                return;
+            };
+
+            let mut deadline = tokio::time::interval_at(
+                tokio::time::Instant::now() + send_window,
+                MESSAGE_SEND_WINDOW,
+            );
+            // The first tick of the interval sends the init packet.
+            let mut interval = tokio::time::interval(RETRANSMISSION_DELAY);
+            // Avoid a send burst if the system is slow.
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+            let mut aborted = false;
+
+            loop {
+                tokio::select! {
+                    _ = notify.notified() => {
+                        if aborted {
+                            continue
+                        }
+                        // Read chunks as long as there is space in the window.
+                        loop {
+                            let size = match message_stack.outbox.lock().unwrap().streams.get(&id) {
+                                Some(stream) => stream.next_chunk_size(),
+                                None => return,
+                            };
+                            let (Some(size), Some(source)) = (size, reader.as_mut()) else {
+                                break
+                            };
+
+                            let mut data = vec![0; size];
+                            if let Err(e) = source.read_exact(&mut data).await {
+                                warn!(message.id = id.as_hex(), "Failed to read streamed message: {e}");
+                                message_stack.abort_stream(id);
+                                aborted = true;
+                                reader = None;
+                                break
+                            }
+
+                            if let Some(stream) = message_stack.outbox.lock().unwrap().streams.get_mut(&id) {
+                                stream.push_chunk(data);
+                            }
+                        }
+                        if !aborted {
+                            message_stack.send_stream(id);
+                        }
+                    },
+                    _ = interval.tick() => {
+                        if aborted {
+                            continue
+                        }
+                        if !message_stack.send_stream(id) {
+                            // If the message is gone, just exit
+                            return
+                        }
+                    },
+                    _ = deadline.tick() => {
+                        // The first time we get a tick to abort, abort the message if it is not
+                        // received yet.
+                        // The second time, clean up the storage.
+                        if !aborted {
+                            aborted = true;
+                            message_stack.abort_stream(id);
+                            reader = None;
+                            continue
+                        }
+
+                        message_stack.outbox.lock().unwrap().streams.remove(&id);
+                        return
+                    }
+                }
+            }
+        });
+    }
+
+    /// Send the packets of the streamed message with the given id which are due, depending on
+    /// its state. Returns `false` if the message does not exist (anymore).
+    fn send_stream(&self, id: MessageId) -> bool {
+        let mut outbox = self.outbox.lock().unwrap();
+        let Some(stream) = outbox.streams.get_mut(&id) else {
+            return false;
+        };
+        let (IpAddr::V6(src), IpAddr::V6(dst)) = (stream.src, stream.dst) else {
+            debug!("Can only send messages between two IPv6 addresses");
+            return true;
+        };
+
+        let data_plane = self.data_plane.lock().unwrap();
+        match stream.state {
+            TransmissionState::Init => {
+                let mut mp = MessagePacket::new(PacketBuffer::new());
+                mp.header_mut().set_message_id(id);
+
+                let mut mi = MessageInit::new(mp);
+                mi.set_length(stream.len);
+                mi.set_topic(&stream.topic);
+                data_plane.inject_message_packet(src, dst, mi.into_inner().into_inner());
+            }
+            TransmissionState::InProgress => {
+                // If the full message is read and acked, send the done packet.
+                if stream.all_acked() {
+                    let mut mp = MessagePacket::new(PacketBuffer::new());
+                    mp.header_mut().set_message_id(id);
+
+                    let mut md = MessageDone::new(mp);
+                    md.set_chunk_count(stream.chunk_count());
+                    md.set_checksum(stream.checksum());
+                    data_plane.inject_message_packet(src, dst, md.into_inner().into_inner());
+                    return true;
+                }
+
+                for chunk in stream.due_chunks() {
+                    let mut mp = MessagePacket::new(PacketBuffer::new());
+                    mp.header_mut().set_message_id(id);
+
+                    let mut mc = MessageChunk::new(mp);
+                    mc.set_chunk_idx(chunk.idx);
+                    mc.set_chunk_offset(chunk.offset);
+                    if let Err(e) = mc.set_chunk_data(&chunk.data) {
+                        error!("Failed to generate and send chunk: {e}");
+                    };
+                    data_plane.inject_message_packet(src, dst, mc.into_inner().into_inner());
+                }
+            }
+            TransmissionState::Received | TransmissionState::Read | TransmissionState::Aborted => {
+                // Nothing to do once the message is received, read or aborted.
+            }
+        }
+
+        true
+    }
+
+    /// Abort the streamed message with the given id, if it is still being transmitted, and
+    /// inform the receiver.
+    fn abort_stream(&self, id: MessageId) {
+        let mut outbox = self.outbox.lock().unwrap();
+        let Some(stream) = outbox.streams.get_mut(&id) else {
+            return;
+        };
+        if !matches!(
+            stream.state,
+            TransmissionState::Init | TransmissionState::InProgress
+        ) {
+            return;
+        }
+        stream.state = TransmissionState::Aborted;
+
+        let mut mp = MessagePacket::new(PacketBuffer::new());
+        mp.header_mut().set_message_id(id);
+        mp.header_mut().flags_mut().set_aborted();
+
+        match (stream.src, stream.dst) {
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                self.data_plane
+                    .lock()
+                    .unwrap()
+                    .inject_message_packet(src, dst, mp.into_inner());
+            }
+            _ => debug!("Can only send messages between two IPv6 addresses"),
+        }
     }
 
     /// Get information about the status of an outbound message.
+    ///
+    /// For a message published to a topic, the state of the message sent to every subscriber is
+    /// included, and the overall state is derived from those.
     pub fn message_info(&self, id: MessageId) -> Option<MessageInfo> {
         let outbox = self.outbox.lock().unwrap();
+        if let Some(children) = self.published.lock().unwrap().get(&id) {
+            let first = children.iter().find_map(|child| outbox.msges.get(child))?;
+            let recipients = children
+                .iter()
+                .filter_map(|child| outbox.msges.get(child))
+                .map(|mi| RecipientInfo {
+                    dst: mi.msg.dst,
+                    state: progress(mi),
+                })
+                .collect::<Vec<_>>();
+            return Some(MessageInfo {
+                dst: None,
+                state: aggregate_progress(&recipients),
+                created: unix_timestamp(first.created),
+                deadline: unix_timestamp(first.deadline),
+                msg_len: first.len,
+                recipients,
+            });
+        }
+
+        if let Some(stream) = outbox.streams.get(&id) {
+            return Some(MessageInfo {
+                dst: Some(stream.dst),
+                state: stream.progress(),
+                created: unix_timestamp(stream.created),
+                deadline: unix_timestamp(stream.deadline),
+                msg_len: stream.len as usize,
+                recipients: vec![],
+            });
+        }
+
         outbox.msges.get(&id).map(|mi| MessageInfo {
-            dst: mi.msg.dst,
-            state: match mi.state {
-                TransmissionState::Init => TransmissionProgress::Pending,
-                TransmissionState::InProgress => {
-                    let (pending, sent, acked) = mi.chunks.iter().fold(
-                        (0, 0, 0),
-                        |(mut pending, mut sent, mut acked), chunk| {
-                            match chunk.chunk_transmit_state {
-                                ChunkTransmitState::Started => pending += 1,
-                                ChunkTransmitState::Sent(_) => sent += 1,
-                                ChunkTransmitState::Acked => acked += 1,
-                            };
-                            (pending, sent, acked)
-                        },
-                    );
-                    TransmissionProgress::Sending {
-                        pending,
-                        sent,
-                        acked,
-                    }
-                }
-                TransmissionState::Received => TransmissionProgress::Received,
-                TransmissionState::Read => TransmissionProgress::Read,
-                TransmissionState::Aborted => TransmissionProgress::Aborted,
-            },
-            created: mi
-                .created
-                .duration_since(time::UNIX_EPOCH)
-                .expect("Message was created after the epoch")
-                .as_secs() as i64,
-            deadline: mi
-                .deadline
-                .duration_since(time::UNIX_EPOCH)
-                .expect("Message expires after the epoch")
-                .as_secs() as i64,
+            dst: Some(mi.msg.dst),
+            state: progress(mi),
+            created: unix_timestamp(mi.created),
+            deadline: unix_timestamp(mi.deadline),
             msg_len: mi.len,
+            recipients: vec![],
         })
     }
 
@@ -1005,7 +1762,11 @@ where
                         .enumerate()
                         .find(|(_, v)| &v.topic == topic)
                     {
-                        return inbox.complete_msges.remove(idx).unwrap();
+                        let msg = inbox.complete_msges.remove(idx).unwrap();
+                        if let Some(ref store) = self.store {
+                            store.lock().unwrap().remove_received(msg.id);
+                        }
+                        return msg;
                     } else {
                         break 'check;
                     }
@@ -1015,6 +1776,11 @@ where
                 } else {
                     inbox.complete_msges.front().cloned()
                 } {
+                    if pop {
+                        if let Some(ref store) = self.store {
+                            store.lock().unwrap().remove_received(msg.id);
+                        }
+                    }
                     self.notify_read(&msg);
                     return msg;
                 };
@@ -1051,6 +1817,94 @@ where
     }
 }
 
+/// The [`TransmissionProgress`] of an outbound message.
+fn progress(mi: &OutboundMessageInfo) -> TransmissionProgress {
+    match mi.state {
+        TransmissionState::Init => TransmissionProgress::Pending,
+        TransmissionState::InProgress => {
+            let (pending, sent, acked) =
+                mi.chunks
+                    .iter()
+                    .fold((0, 0, 0), |(mut pending, mut sent, mut acked), chunk| {
+                        match chunk.chunk_transmit_state {
+                            ChunkTransmitState::Started => pending += 1,
+                            ChunkTransmitState::Sent(_) => sent += 1,
+                            ChunkTransmitState::Acked => acked += 1,
+                        };
+                        (pending, sent, acked)
+                    });
+            TransmissionProgress::Sending {
+                pending,
+                sent,
+                acked,
+            }
+        }
+        TransmissionState::Received => TransmissionProgress::Received,
+        TransmissionState::Read => TransmissionProgress::Read,
+        TransmissionState::Aborted => TransmissionProgress::Aborted,
+    }
+}
+
+/// The overall [`TransmissionProgress`] of a message sent to multiple recipients.
+///
+/// As long as the message is still being sent to a recipient, the message is pending if none of
+/// these recipients acknowledged the init message yet, and sending otherwise, with the chunk
+/// counts of all recipients added up. Once transmission finished for all recipients, the message
+/// is read if all recipients read it, received if all recipients received it, and aborted
+/// otherwise.
+fn aggregate_progress(recipients: &[RecipientInfo]) -> TransmissionProgress {
+    let (mut in_flight, mut all_pending, mut all_received, mut all_read) =
+        (false, true, true, true);
+    let (mut pending, mut sent, mut acked) = (0, 0, 0);
+    for recipient in recipients {
+        match recipient.state {
+            TransmissionProgress::Pending => in_flight = true,
+            TransmissionProgress::Sending {
+                pending: p,
+                sent: s,
+                acked: a,
+            } => {
+                in_flight = true;
+                all_pending = false;
+                pending += p;
+                sent += s;
+                acked += a;
+            }
+            TransmissionProgress::Received => all_read = false,
+            TransmissionProgress::Read => {}
+            TransmissionProgress::Aborted => {
+                all_read = false;
+                all_received = false;
+            }
+        }
+    }
+
+    if in_flight {
+        if all_pending {
+            TransmissionProgress::Pending
+        } else {
+            TransmissionProgress::Sending {
+                pending,
+                sent,
+                acked,
+            }
+        }
+    } else if all_read {
+        TransmissionProgress::Read
+    } else if all_received {
+        TransmissionProgress::Received
+    } else {
+        TransmissionProgress::Aborted
+    }
+}
+
+/// Convert a [`SystemTime`](time::SystemTime) to seconds since the unix epoch.
+fn unix_timestamp(ts: time::SystemTime) -> i64 {
+    ts.duration_since(time::UNIX_EPOCH)
+        .expect("Message timestamps are after the epoch")
+        .as_secs() as i64
+}
+
 impl<M> Clone for MessageStack<M> {
     fn clone(&self) -> Self {
         Self {
@@ -1059,6 +1913,10 @@ impl<M> Clone for MessageStack<M> {
             outbox: self.outbox.clone(),
             subscriber: self.subscriber.clone(),
             reply_subscribers: self.reply_subscribers.clone(),
+            store: self.store.clone(),
+            spool: self.spool.clone(),
+            topics: self.topics.clone(),
+            published: self.published.clone(),
         }
     }
 }
@@ -1066,8 +1924,9 @@ impl<M> Clone for MessageStack<M> {
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageInfo {
-    /// The receiver of this message.
-    pub dst: IpAddr,
+    /// The receiver of this message. This is not set for messages published to a topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst: Option<IpAddr>,
     /// Transmission state of the message.
     pub state: TransmissionProgress,
     /// Time the message was created (received) by the system.
@@ -1076,6 +1935,19 @@ pub struct MessageInfo {
     pub deadline: i64,
     /// Size of the message in bytes.
     pub msg_len: usize,
+    /// The individual recipients of a message published to a topic.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<RecipientInfo>,
+}
+
+/// Transmission state of a message published to a topic, for a single subscriber.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipientInfo {
+    /// The subscriber receiving the message.
+    pub dst: IpAddr,
+    /// Transmission state of the message to this subscriber.
+    pub state: TransmissionProgress,
 }
 
 #[derive(Serialize)]
@@ -1265,6 +2137,11 @@ impl<'a> Flags<'a> {
         self.flags & FLAG_MESSAGE_REPLY != 0
     }
 
+    /// Check if the MESSAGE_SUBSCRIBE flag is set on the header.
+    fn subscribe(&self) -> bool {
+        self.flags & FLAG_MESSAGE_SUBSCRIBE != 0
+    }
+
     /// Check if the MESSAGE_ACK flag is set on the header.
     fn ack(&self) -> bool {
         self.flags & FLAG_MESSAGE_ACK != 0
@@ -1324,6 +2201,11 @@ impl FlagsMut<'_, '_> {
         self.flags |= FLAG_MESSAGE_REPLY;
     }
 
+    /// Sets the MESSAGE_SUBSCRIBE flag on the header.
+    fn set_subscribe(&mut self) {
+        self.flags |= FLAG_MESSAGE_SUBSCRIBE;
+    }
+
     /// Sets the MESSAGE_ACK flag on the header.
     fn set_ack(&mut self) {
         self.flags |= FLAG_MESSAGE_ACK;
@@ -1467,6 +2349,8 @@ impl fmt::Display for PushMessageError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::TopicTooLarge => f.write_str("topic too large, topic is limited to 255 bytes"),
+            Self::NoTopic => f.write_str("a topic is required to publish a message"),
+            Self::NoSubscribers => f.write_str("there are no subscribers for the topic"),
         }
     }
 }
@@ -1475,8 +2359,12 @@ impl std::error::Error for PushMessageError {}
 
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv6Addr;
 
-    use super::{MessagePacketHeaderMut, MESSAGE_HEADER_SIZE};
+    use super::{
+        aggregate_progress, MessagePacketHeaderMut, RecipientInfo, TransmissionProgress,
+        MESSAGE_HEADER_SIZE,
+    };
 
     #[test]
     fn set_init_flag() {
@@ -1538,6 +2426,16 @@ mod tests {
         assert_eq!(buf_mut.header[8], 0b0000_0100);
     }
 
+    #[test]
+    fn set_subscribe_flag() {
+        let mut buf = [0; MESSAGE_HEADER_SIZE];
+        let mut buf_mut = MessagePacketHeaderMut { header: &mut buf };
+        buf_mut.flags_mut().set_subscribe();
+
+        assert!(buf_mut.flags().subscribe());
+        assert_eq!(buf_mut.header[8], 0b0000_0010);
+    }
+
     #[test]
     fn set_ack_flag() {
         let mut buf = [0; MESSAGE_HEADER_SIZE];
@@ -1558,4 +2456,64 @@ mod tests {
         assert!(buf_mut.flags().ack() && buf_mut.flags().init());
         assert_eq!(buf_mut.header[8], 0b1000_0001);
     }
+
+    fn recipients(states: Vec<TransmissionProgress>) -> Vec<RecipientInfo> {
+        states
+            .into_iter()
+            .enumerate()
+            .map(|(i, state)| RecipientInfo {
+                dst: Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, i as u16).into(),
+                state,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aggregate_progress_in_flight() {
+        use TransmissionProgress::*;
+
+        assert!(matches!(
+            aggregate_progress(&recipients(vec![Pending, Read, Pending])),
+            Pending
+        ));
+        assert!(matches!(
+            aggregate_progress(&recipients(vec![
+                Pending,
+                Sending {
+                    pending: 1,
+                    sent: 2,
+                    acked: 3
+                },
+                Aborted,
+                Sending {
+                    pending: 0,
+                    sent: 1,
+                    acked: 4
+                },
+            ])),
+            Sending {
+                pending: 1,
+                sent: 3,
+                acked: 7
+            }
+        ));
+    }
+
+    #[test]
+    fn aggregate_progress_finished() {
+        use TransmissionProgress::*;
+
+        assert!(matches!(
+            aggregate_progress(&recipients(vec![Read, Read])),
+            Read
+        ));
+        assert!(matches!(
+            aggregate_progress(&recipients(vec![Read, Received])),
+            Received
+        ));
+        assert!(matches!(
+            aggregate_progress(&recipients(vec![Received, Aborted])),
+            Aborted
+        ));
+    }
 }
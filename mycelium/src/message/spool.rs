@@ -0,0 +1,210 @@
+//! Spooling of large inbound messages to disk.
+//!
+//! Inbound messages are normally reassembled in memory. Messages larger than
+//! [`SPOOL_THRESHOLD`] are instead written to a file in the spool directory as their chunks come
+//! in, so the size of a message is not limited by the available memory of the receiver. Once a
+//! message is complete, the file is handed over to the consumer of the message.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, warn};
+
+use super::{MessageChecksum, MessageId};
+
+/// Inbound messages larger than this amount of bytes are spooled to disk, if a spool directory is
+/// configured.
+pub const SPOOL_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// File extension of messages which are still being received.
+const PARTIAL_EXTENSION: &str = "part";
+/// File extension of completely received messages.
+const MESSAGE_EXTENSION: &str = "msg";
+
+/// A directory in which large inbound messages are written while they are received.
+pub struct MessageSpool {
+    dir: PathBuf,
+}
+
+/// A file holding the data of a single message which is being received.
+pub(super) struct SpoolFile {
+    path: PathBuf,
+    file: File,
+    /// Chunks of the message which have been written to the file.
+    received: Vec<bool>,
+    /// Set once the message is complete, after which the file is no longer removed when this is
+    /// dropped.
+    finished: bool,
+}
+
+impl MessageSpool {
+    /// Open the spool in the given directory, creating the directory if it does not exist yet.
+    /// Partially received messages left behind by a previous run are removed.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION) {
+                debug!(path = %path.display(), "Removing partially received message");
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!(path = %path.display(), "Failed to remove partially received message: {e}");
+                }
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Create a new file for the message with the given id, which is expected to consist of
+    /// `chunks` chunks.
+    pub(super) fn create(&self, id: MessageId, chunks: usize) -> io::Result<SpoolFile> {
+        let path = self
+            .dir
+            .join(format!("{}.{PARTIAL_EXTENSION}", id.as_hex()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(SpoolFile {
+            path,
+            file,
+            received: vec![false; chunks],
+            finished: false,
+        })
+    }
+}
+
+impl SpoolFile {
+    /// Write the data of a chunk at the given offset in the message.
+    pub(super) fn write_chunk(&mut self, idx: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+
+        if self.received.len() <= idx {
+            self.received.resize(idx + 1, false);
+        }
+        self.received[idx] = true;
+
+        Ok(())
+    }
+
+    /// The amount of chunks the message consists of, as far as we know.
+    pub(super) fn chunk_count(&self) -> usize {
+        self.received.len()
+    }
+
+    /// Check if every chunk of the message has been written.
+    pub(super) fn complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+
+    /// Calculate the [`MessageChecksum`] of the data in the file. Returns [`None`] if the file
+    /// does not hold exactly `len` bytes.
+    pub(super) fn checksum(&mut self, len: u64) -> io::Result<Option<MessageChecksum>> {
+        self.file.flush()?;
+        if self.file.metadata()?.len() != len {
+            return Ok(None);
+        }
+
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(&mut self.file)?;
+
+        Ok(Some(hasher.finalize()))
+    }
+
+    /// Mark the message as complete, returning the path of the file holding the message data.
+    /// From this point, the file is owned by whoever consumes the message.
+    pub(super) fn finish(&mut self) -> io::Result<PathBuf> {
+        let path = self.path.with_extension(MESSAGE_EXTENSION);
+        fs::rename(&self.path, &path)?;
+        self.path.clone_from(&path);
+        self.finished = true;
+
+        Ok(path)
+    }
+
+    /// Check if the message has been marked as complete.
+    pub(super) fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), "Failed to remove partially received message: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{MessageSpool, PARTIAL_EXTENSION};
+    use crate::message::MessageId;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mycelium-message-spool-{name}-{}",
+            MessageId::new().as_hex()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn chunks_are_written_at_their_offset() {
+        let dir = test_dir("offset");
+        let spool = MessageSpool::open(&dir).unwrap();
+        let mut file = spool.create(MessageId::new(), 2).unwrap();
+
+        file.write_chunk(1, 5, b"world").unwrap();
+        assert!(!file.complete());
+        file.write_chunk(0, 0, b"hello").unwrap();
+        assert!(file.complete());
+
+        assert_eq!(file.checksum(9).unwrap(), None);
+        assert_eq!(
+            file.checksum(10).unwrap(),
+            Some(blake3::hash(b"helloworld"))
+        );
+
+        let path = file.finish().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unfinished_files_are_removed() {
+        let dir = test_dir("unfinished");
+        let spool = MessageSpool::open(&dir).unwrap();
+
+        let mut file = spool.create(MessageId::new(), 1).unwrap();
+        file.write_chunk(0, 0, b"data").unwrap();
+        drop(file);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Leftovers of a previous run are removed when the spool is opened.
+        std::fs::write(dir.join(format!("leftover.{PARTIAL_EXTENSION}")), b"data").unwrap();
+        MessageSpool::open(&dir).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
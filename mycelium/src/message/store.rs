@@ -0,0 +1,713 @@
+//! Persistent storage for the message inbox and outbox.
+//!
+//! The store is a directory holding a set of append-only segment files, one set for the inbox
+//! and one set for the outbox. Every change to either of them is appended as a record to the
+//! currently active segment. When the store is opened, all existing segments are replayed to
+//! reconstruct the last known state. The messages which are still live are then written to a
+//! fresh segment, after which the old segments are removed.
+//!
+//! Records are written by a dedicated thread, so persisting a message never blocks the caller
+//! on disk I/O. The thread writes all pending records before syncing the segments.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{debug, warn};
+
+use crate::crypto::PublicKey;
+
+use super::{Message, MessageId, ReceivedMessage, MESSAGE_ID_SIZE};
+
+/// Size after which a new segment is started.
+const MAX_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// File extension used for segment files.
+const SEGMENT_EXTENSION: &str = "seg";
+/// Prefix of inbox segment files.
+const INBOX_PREFIX: &str = "inbox";
+/// Prefix of outbox segment files.
+const OUTBOX_PREFIX: &str = "outbox";
+
+/// Record kind indicating a message is added.
+const RECORD_INSERT: u8 = 1;
+/// Record kind indicating a message is removed.
+const RECORD_REMOVE: u8 = 2;
+/// Size of a record header: 1 byte kind, 4 bytes payload length and 4 bytes checksum.
+const RECORD_HEADER_SIZE: usize = 9;
+
+/// Persistent storage for the inbox and outbox of a [`MessageStack`](super::MessageStack).
+pub struct MessageStore {
+    /// Channel to the writer thread, and the handle to join it when the store is dropped.
+    writer: Option<(mpsc::Sender<WriteRequest>, JoinHandle<()>)>,
+    /// Inbox messages loaded when the store was opened, which have not been claimed yet.
+    loaded_inbox: Vec<ReceivedMessage>,
+    /// Outbox messages loaded when the store was opened, which have not been claimed yet.
+    loaded_outbox: Vec<StoredOutboundMessage>,
+}
+
+/// An outbound message as it is kept in the store.
+pub(super) struct StoredOutboundMessage {
+    pub msg: Message,
+    /// The message is a reply to a message with the same id.
+    pub reply: bool,
+    pub created: SystemTime,
+    pub deadline: SystemTime,
+}
+
+/// A set of append-only segment files sharing the same prefix.
+struct SegmentLog {
+    dir: PathBuf,
+    prefix: &'static str,
+    /// Index of the active segment.
+    idx: u64,
+    /// Handle to the active segment.
+    file: File,
+    /// Amount of bytes written in the active segment.
+    size: u64,
+    /// Records were appended which have not been synced yet.
+    dirty: bool,
+}
+
+/// The log a record is appended to.
+#[derive(Clone, Copy)]
+enum LogKind {
+    Inbox,
+    Outbox,
+}
+
+/// A record which is appended by the writer thread.
+struct WriteRequest {
+    log: LogKind,
+    record: Vec<u8>,
+    /// Id of the message the record is about, used to report failures.
+    id: MessageId,
+    /// Description of the change, used to report failures.
+    action: &'static str,
+}
+
+/// A single decoded record from a segment.
+enum Record<'a> {
+    Insert(&'a [u8]),
+    Remove(MessageId),
+}
+
+impl MessageStore {
+    /// Open the store in the given directory, creating the directory if it does not exist yet.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let (inbox_segments, inbox_records) = SegmentLog::replay(dir, INBOX_PREFIX)?;
+        let mut inbox = Vec::<ReceivedMessage>::new();
+        for record in inbox_records {
+            match decode_record(&record) {
+                Some(Record::Insert(payload)) => match decode_received(payload) {
+                    Some(msg) => {
+                        inbox.retain(|m| m.id != msg.id);
+                        inbox.push(msg);
+                    }
+                    None => warn!("Skipping malformed inbox record"),
+                },
+                Some(Record::Remove(id)) => inbox.retain(|m| m.id != id),
+                None => warn!("Skipping malformed inbox record"),
+            }
+        }
+
+        let (outbox_segments, outbox_records) = SegmentLog::replay(dir, OUTBOX_PREFIX)?;
+        let mut outbox = HashMap::<MessageId, StoredOutboundMessage>::new();
+        for record in outbox_records {
+            match decode_record(&record) {
+                Some(Record::Insert(payload)) => match decode_outbound(payload) {
+                    Some(msg) => {
+                        outbox.insert(msg.msg.id, msg);
+                    }
+                    None => warn!("Skipping malformed outbox record"),
+                },
+                Some(Record::Remove(id)) => {
+                    outbox.remove(&id);
+                }
+                None => warn!("Skipping malformed outbox record"),
+            }
+        }
+        let mut outbox = outbox.into_values().collect::<Vec<_>>();
+        outbox.sort_by_key(|msg| msg.created);
+
+        let inbox_log = SegmentLog::compact(
+            dir,
+            INBOX_PREFIX,
+            inbox_segments,
+            inbox.iter().map(encode_received),
+        )?;
+        let outbox_log = SegmentLog::compact(
+            dir,
+            OUTBOX_PREFIX,
+            outbox_segments,
+            outbox
+                .iter()
+                .map(|s| encode_outbound(&s.msg, s.reply, s.created, s.deadline)),
+        )?;
+
+        debug!(
+            inbox = inbox.len(),
+            outbox = outbox.len(),
+            "Loaded messages from message store"
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("message-store".into())
+            .spawn(move || write_records(rx, inbox_log, outbox_log))?;
+
+        Ok(Self {
+            writer: Some((tx, handle)),
+            loaded_inbox: inbox,
+            loaded_outbox: outbox,
+        })
+    }
+
+    /// Take the messages which were found in the store when it was opened. Inbox messages are
+    /// returned in the order they were received, outbox messages in the order they were created.
+    pub(super) fn take_loaded(&mut self) -> (Vec<ReceivedMessage>, Vec<StoredOutboundMessage>) {
+        (
+            std::mem::take(&mut self.loaded_inbox),
+            std::mem::take(&mut self.loaded_outbox),
+        )
+    }
+
+    /// Persist a completed inbound message.
+    pub(super) fn insert_received(&mut self, msg: &ReceivedMessage) {
+        self.write(
+            LogKind::Inbox,
+            encode_received(msg),
+            msg.id,
+            "persist received message",
+        );
+    }
+
+    /// Remove an inbound message from the store, typically because it has been popped.
+    pub(super) fn remove_received(&mut self, id: MessageId) {
+        self.write(
+            LogKind::Inbox,
+            encode_remove(id),
+            id,
+            "remove received message",
+        );
+    }
+
+    /// Persist an outbound message which is about to be transmitted.
+    pub(super) fn insert_outbound(
+        &mut self,
+        msg: &Message,
+        reply: bool,
+        created: SystemTime,
+        deadline: SystemTime,
+    ) {
+        self.write(
+            LogKind::Outbox,
+            encode_outbound(msg, reply, created, deadline),
+            msg.id,
+            "persist outbound message",
+        );
+    }
+
+    /// Remove an outbound message from the store, because it no longer needs to be transmitted.
+    pub(super) fn remove_outbound(&mut self, id: MessageId) {
+        self.write(
+            LogKind::Outbox,
+            encode_remove(id),
+            id,
+            "remove outbound message",
+        );
+    }
+
+    /// Hand a record to the writer thread.
+    fn write(&self, log: LogKind, record: Vec<u8>, id: MessageId, action: &'static str) {
+        let Some((ref tx, _)) = self.writer else {
+            return;
+        };
+        if tx
+            .send(WriteRequest {
+                log,
+                record,
+                id,
+                action,
+            })
+            .is_err()
+        {
+            warn!(
+                message.id = id.as_hex(),
+                "Failed to {action}: writer stopped"
+            );
+        }
+    }
+}
+
+impl Drop for MessageStore {
+    fn drop(&mut self) {
+        // Closing the channel stops the writer once all pending records are written.
+        if let Some((tx, handle)) = self.writer.take() {
+            drop(tx);
+            if handle.join().is_err() {
+                warn!("Message store writer panicked");
+            }
+        }
+    }
+}
+
+/// Append the records received on the channel to the logs, until the channel is closed. All
+/// records which are pending are written before the logs are synced.
+fn write_records(rx: mpsc::Receiver<WriteRequest>, mut inbox: SegmentLog, mut outbox: SegmentLog) {
+    while let Ok(first) = rx.recv() {
+        for req in std::iter::once(first).chain(rx.try_iter()) {
+            let log = match req.log {
+                LogKind::Inbox => &mut inbox,
+                LogKind::Outbox => &mut outbox,
+            };
+            if let Err(e) = log.append(&req.record) {
+                warn!(
+                    message.id = req.id.as_hex(),
+                    "Failed to {}: {e}", req.action
+                );
+            }
+        }
+        for (log, name) in [(&mut inbox, "inbox"), (&mut outbox, "outbox")] {
+            if let Err(e) = log.sync() {
+                warn!("Failed to sync message store {name}: {e}");
+            }
+        }
+    }
+}
+
+impl SegmentLog {
+    /// Read all records in the existing segments with the given prefix, in the order they were
+    /// written. The paths of the segments which were read are returned as well.
+    fn replay(dir: &Path, prefix: &str) -> io::Result<(Vec<(u64, PathBuf)>, Vec<Vec<u8>>)> {
+        let mut segments = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if let Some(idx) = segment_index(&path, prefix) {
+                segments.push((idx, path));
+            }
+        }
+        segments.sort();
+
+        let mut records = vec![];
+        for (_, path) in &segments {
+            let mut data = vec![];
+            File::open(path)?.read_to_end(&mut data)?;
+            let mut buf = &data[..];
+            while !buf.is_empty() {
+                if buf.len() < RECORD_HEADER_SIZE {
+                    warn!(segment = %path.display(), "Ignoring truncated record at end of segment");
+                    break;
+                }
+                let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+                if buf.len() < RECORD_HEADER_SIZE + len {
+                    warn!(segment = %path.display(), "Ignoring truncated record at end of segment");
+                    break;
+                }
+                records.push(buf[..RECORD_HEADER_SIZE + len].to_vec());
+                buf = &buf[RECORD_HEADER_SIZE + len..];
+            }
+        }
+
+        Ok((segments, records))
+    }
+
+    /// Start a new segment containing only the provided records, and remove the old segments.
+    fn compact(
+        dir: &Path,
+        prefix: &'static str,
+        old_segments: Vec<(u64, PathBuf)>,
+        records: impl Iterator<Item = Vec<u8>>,
+    ) -> io::Result<Self> {
+        let idx = old_segments.last().map(|(idx, _)| idx + 1).unwrap_or(0);
+        let mut log = Self::create(dir, prefix, idx)?;
+        for record in records {
+            log.file.write_all(&record)?;
+            log.size += record.len() as u64;
+        }
+        log.file.sync_all()?;
+
+        for (_, path) in old_segments {
+            fs::remove_file(path)?;
+        }
+
+        Ok(log)
+    }
+
+    /// Create a new, empty, segment with the given index.
+    fn create(dir: &Path, prefix: &'static str, idx: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(dir.join(format!("{prefix}-{idx:016}.{SEGMENT_EXTENSION}")))?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            prefix,
+            idx,
+            file,
+            size: 0,
+            dirty: false,
+        })
+    }
+
+    /// Append a record to the active segment, starting a new segment if the active one is full.
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.size >= MAX_SEGMENT_SIZE {
+            self.sync()?;
+            *self = Self::create(&self.dir, self.prefix, self.idx + 1)?;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Flush the records appended since the last sync to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Extract the index of a segment from its path, if the path is a segment with the given prefix.
+fn segment_index(path: &Path, prefix: &str) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix(prefix)?
+        .strip_prefix('-')?
+        .parse()
+        .ok()
+}
+
+/// Frame a payload as a record of the given kind.
+fn encode_record(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.push(kind);
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&blake3::hash(payload).as_bytes()[..4]);
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Decode a framed record, verifying its checksum.
+fn decode_record(record: &[u8]) -> Option<Record<'_>> {
+    let payload = record.get(RECORD_HEADER_SIZE..)?;
+    if blake3::hash(payload).as_bytes()[..4] != record[5..RECORD_HEADER_SIZE] {
+        return None;
+    }
+    match record[0] {
+        RECORD_INSERT => Some(Record::Insert(payload)),
+        RECORD_REMOVE => Some(Record::Remove(MessageId(payload.try_into().ok()?))),
+        _ => None,
+    }
+}
+
+fn encode_remove(id: MessageId) -> Vec<u8> {
+    encode_record(RECORD_REMOVE, &id.0)
+}
+
+fn encode_received(msg: &ReceivedMessage) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(128 + msg.topic.len() + msg.data.len());
+    buf.extend_from_slice(&msg.id.0);
+    buf.push(msg.is_reply as u8);
+    put_ip(&mut buf, msg.src_ip);
+    buf.extend_from_slice(msg.src_pk.as_bytes());
+    put_ip(&mut buf, msg.dst_ip);
+    buf.extend_from_slice(msg.dst_pk.as_bytes());
+    put_bytes(&mut buf, &msg.topic);
+    put_bytes(&mut buf, &msg.data);
+    // The spool file is optional, records written before spooling existed end here.
+    if let Some(ref path) = msg.spool_file {
+        put_bytes(&mut buf, path.to_string_lossy().as_bytes());
+    }
+    encode_record(RECORD_INSERT, &buf)
+}
+
+fn decode_received(payload: &[u8]) -> Option<ReceivedMessage> {
+    let mut r = Reader { buf: payload };
+    let mut msg = ReceivedMessage {
+        id: r.message_id()?,
+        is_reply: r.u8()? != 0,
+        src_ip: r.ip()?,
+        src_pk: r.pubkey()?,
+        dst_ip: r.ip()?,
+        dst_pk: r.pubkey()?,
+        topic: r.bytes()?,
+        data: r.bytes()?,
+        spool_file: None,
+    };
+    if !r.buf.is_empty() {
+        let path = String::from_utf8(r.bytes()?).ok()?;
+        msg.spool_file = Some(PathBuf::from(path));
+    }
+    r.buf.is_empty().then_some(msg)
+}
+
+fn encode_outbound(
+    msg: &Message,
+    reply: bool,
+    created: SystemTime,
+    deadline: SystemTime,
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64 + msg.topic.len() + msg.data.len());
+    buf.extend_from_slice(&msg.id.0);
+    buf.push(reply as u8);
+    put_ip(&mut buf, msg.src);
+    put_ip(&mut buf, msg.dst);
+    buf.extend_from_slice(&unix_millis(created).to_be_bytes());
+    buf.extend_from_slice(&unix_millis(deadline).to_be_bytes());
+    put_bytes(&mut buf, &msg.topic);
+    put_bytes(&mut buf, &msg.data);
+    encode_record(RECORD_INSERT, &buf)
+}
+
+fn decode_outbound(payload: &[u8]) -> Option<StoredOutboundMessage> {
+    let mut r = Reader { buf: payload };
+    let id = r.message_id()?;
+    let reply = r.u8()? != 0;
+    let src = r.ip()?;
+    let dst = r.ip()?;
+    let created = UNIX_EPOCH + Duration::from_millis(r.u64()?);
+    let deadline = UNIX_EPOCH + Duration::from_millis(r.u64()?);
+    let msg = StoredOutboundMessage {
+        msg: Message {
+            id,
+            src,
+            dst,
+            topic: r.bytes()?,
+            data: r.bytes()?,
+        },
+        reply,
+        created,
+        deadline,
+    };
+    r.buf.is_empty().then_some(msg)
+}
+
+/// Milliseconds since the unix epoch of a given time.
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn put_ip(buf: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Helper to decode the fields of a record payload.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (data, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn message_id(&mut self) -> Option<MessageId> {
+        Some(MessageId(self.take(MESSAGE_ID_SIZE)?.try_into().ok()?))
+    }
+
+    fn pubkey(&mut self) -> Option<PublicKey> {
+        Some(PublicKey::from(<[u8; 32]>::try_from(self.take(32)?).ok()?))
+    }
+
+    fn ip(&mut self) -> Option<IpAddr> {
+        match self.u8()? {
+            4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(self.take(4)?).ok()?).into()),
+            6 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(self.take(16)?).ok()?).into()),
+            _ => None,
+        }
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Some(self.take(len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::crypto::{PublicKey, SecretKey};
+
+    use super::{Message, MessageId, MessageStore, ReceivedMessage};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mycelium-message-store-{name}-{}",
+            MessageId::new().as_hex()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn received(data: &[u8]) -> ReceivedMessage {
+        let pk = PublicKey::from(&SecretKey::new());
+        ReceivedMessage {
+            id: MessageId::new(),
+            is_reply: false,
+            src_ip: IpAddr::V6(pk.address()),
+            src_pk: pk,
+            dst_ip: IpAddr::V6(pk.address()),
+            dst_pk: pk,
+            topic: b"topic".to_vec(),
+            data: data.to_vec(),
+            spool_file: None,
+        }
+    }
+
+    #[test]
+    fn inbox_survives_reopen() {
+        let dir = test_dir("inbox");
+        let first = received(b"first");
+        let mut second = received(b"second");
+        second.spool_file = Some("/var/spool/mycelium/second.msg".into());
+
+        {
+            let mut store = MessageStore::open(&dir).unwrap();
+            let (inbox, outbox) = store.take_loaded();
+            assert!(inbox.is_empty());
+            assert!(outbox.is_empty());
+            store.insert_received(&first);
+            store.insert_received(&second);
+            store.remove_received(first.id);
+        }
+
+        let (inbox, _) = MessageStore::open(&dir).unwrap().take_loaded();
+        assert_eq!(inbox.len(), 1);
+        assert!(inbox[0].id == second.id);
+        assert_eq!(inbox[0].data, b"second");
+        assert_eq!(inbox[0].topic, b"topic");
+        assert_eq!(inbox[0].spool_file, second.spool_file);
+
+        // Compaction on open must not lose data.
+        let (inbox, _) = MessageStore::open(&dir).unwrap().take_loaded();
+        assert_eq!(inbox.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn outbox_survives_reopen() {
+        let dir = test_dir("outbox");
+        let created = SystemTime::now();
+        let msg = Message {
+            id: MessageId::new(),
+            src: "400::1".parse().unwrap(),
+            dst: "400::2".parse().unwrap(),
+            topic: vec![],
+            data: vec![1; 5_000],
+        };
+
+        {
+            let mut store = MessageStore::open(&dir).unwrap();
+            store.insert_outbound(&msg, true, created, created + Duration::from_secs(60));
+        }
+
+        let mut store = MessageStore::open(&dir).unwrap();
+        let (_, outbox) = store.take_loaded();
+        assert_eq!(outbox.len(), 1);
+        assert!(outbox[0].msg.id == msg.id);
+        assert!(outbox[0].reply);
+        assert_eq!(outbox[0].msg.dst, msg.dst);
+        assert_eq!(outbox[0].msg.data, msg.data);
+        assert_eq!(
+            outbox[0]
+                .deadline
+                .duration_since(outbox[0].created)
+                .unwrap(),
+            Duration::from_secs(60)
+        );
+
+        store.remove_outbound(msg.id);
+        drop(store);
+
+        let (_, outbox) = MessageStore::open(&dir).unwrap().take_loaded();
+        assert!(outbox.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_record_is_ignored() {
+        let dir = test_dir("truncated");
+        let msg = received(b"data");
+
+        {
+            let mut store = MessageStore::open(&dir).unwrap();
+            store.insert_received(&msg);
+        }
+
+        // Simulate a torn write by appending half a record to the active segment.
+        let segment = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.to_string_lossy().contains("inbox"))
+            .unwrap();
+        let mut data = std::fs::read(&segment).unwrap();
+        let half = data[..data.len() / 2].to_vec();
+        data.extend_from_slice(&half);
+        std::fs::write(&segment, data).unwrap();
+
+        let (inbox, _) = MessageStore::open(&dir).unwrap().take_loaded();
+        assert_eq!(inbox.len(), 1);
+        assert!(inbox[0].id == msg.id);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+//! Outbound messages which are streamed from a reader.
+//!
+//! Regular outbound messages are fully held in memory until they are received. A streamed message
+//! is read from its source while it is being transmitted, and only a limited window of chunks
+//! which have not been acknowledged by the receiver is kept in memory. The chunks and the checksum
+//! sent to the receiver are the same as for a regular message, so the receiver does not need to
+//! know the message is streamed.
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use tokio::sync::Notify;
+
+use super::{
+    ChunkTransmitState, MessageChecksum, TransmissionProgress, TransmissionState,
+    AVERAGE_CHUNK_SIZE, RETRANSMISSION_DELAY,
+};
+
+/// The maximum amount of unacknowledged chunks of a streamed message.
+const STREAM_WINDOW: usize = 64;
+
+/// State of a streamed outbound message.
+pub(super) struct OutboundStream {
+    /// The current state of the message.
+    pub state: TransmissionState,
+    /// Source IP (ours).
+    pub src: IpAddr,
+    /// Destination IP.
+    pub dst: IpAddr,
+    /// An optional topic of the message.
+    pub topic: Vec<u8>,
+    /// Timestamp when the message was created.
+    pub created: SystemTime,
+    /// Timestamp indicating when we stop trying to send the message.
+    pub deadline: SystemTime,
+    /// Length of the message.
+    pub len: u64,
+    /// Notified when the transmission task should read more data, because the receiver
+    /// acknowledged our init message or a chunk.
+    pub notify: Arc<Notify>,
+    /// Chunks read from the source which have not been acknowledged yet.
+    window: VecDeque<StreamChunk>,
+    /// Amount of bytes read from the source.
+    read: u64,
+    /// Amount of chunks which have been acknowledged and dropped from the window.
+    acked: u64,
+    /// Running checksum of the data read from the source.
+    hasher: blake3::Hasher,
+}
+
+/// A chunk of a streamed message which has not been acknowledged yet.
+pub(super) struct StreamChunk {
+    /// Index of the chunk in the chunk stream.
+    pub idx: u64,
+    /// Offset of the chunk in the message.
+    pub offset: u64,
+    /// Data of the chunk.
+    pub data: Vec<u8>,
+    /// Transmit state of the chunk.
+    state: ChunkTransmitState,
+}
+
+impl OutboundStream {
+    /// Create a new `OutboundStream` for a message of `len` bytes.
+    pub fn new(
+        src: IpAddr,
+        dst: IpAddr,
+        topic: Vec<u8>,
+        created: SystemTime,
+        deadline: SystemTime,
+        len: u64,
+    ) -> Self {
+        Self {
+            state: TransmissionState::Init,
+            src,
+            dst,
+            topic,
+            created,
+            deadline,
+            len,
+            notify: Arc::new(Notify::new()),
+            window: VecDeque::with_capacity(STREAM_WINDOW),
+            read: 0,
+            acked: 0,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// The amount of chunks the message is split in.
+    pub fn chunk_count(&self) -> u64 {
+        self.len.div_ceil(AVERAGE_CHUNK_SIZE as u64)
+    }
+
+    /// The size of the next chunk to read from the source, if there is space for it in the
+    /// window. Returns [`None`] if the receiver did not acknowledge the init message yet, the
+    /// window is full, or the full message has been read.
+    pub fn next_chunk_size(&self) -> Option<usize> {
+        if self.state != TransmissionState::InProgress
+            || self.window.len() >= STREAM_WINDOW
+            || self.read >= self.len
+        {
+            return None;
+        }
+
+        Some(u64::min(self.len - self.read, AVERAGE_CHUNK_SIZE as u64) as usize)
+    }
+
+    /// Add the next chunk read from the source to the window.
+    pub fn push_chunk(&mut self, data: Vec<u8>) {
+        self.hasher.update(&data);
+        let offset = self.read;
+        self.read += data.len() as u64;
+        self.window.push_back(StreamChunk {
+            idx: offset / AVERAGE_CHUNK_SIZE as u64,
+            offset,
+            data,
+            state: ChunkTransmitState::Started,
+        });
+    }
+
+    /// Mark the chunk with the given index as acknowledged. Acknowledged chunks at the start of
+    /// the window are dropped. Returns `true` if this freed up space in the window.
+    pub fn ack(&mut self, idx: u64) -> bool {
+        if let Some(chunk) = self.window.iter_mut().find(|chunk| chunk.idx == idx) {
+            chunk.state = ChunkTransmitState::Acked;
+        }
+
+        let mut freed = false;
+        while self
+            .window
+            .front()
+            .is_some_and(|chunk| matches!(chunk.state, ChunkTransmitState::Acked))
+        {
+            self.window.pop_front();
+            self.acked += 1;
+            freed = true;
+        }
+
+        freed
+    }
+
+    /// Chunks which need to be sent, because they have not been sent yet or have not been
+    /// acknowledged in time. The returned chunks are marked as sent.
+    pub fn due_chunks(&mut self) -> impl Iterator<Item = &StreamChunk> + '_ {
+        let now = Instant::now();
+        self.window.iter_mut().filter_map(move |chunk| {
+            let due = match chunk.state {
+                ChunkTransmitState::Started => true,
+                ChunkTransmitState::Sent(t) => now.duration_since(t) >= RETRANSMISSION_DELAY,
+                ChunkTransmitState::Acked => false,
+            };
+            if due {
+                chunk.state = ChunkTransmitState::Sent(now);
+                Some(&*chunk)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns `true` once the full message has been read and acknowledged by the receiver.
+    pub fn all_acked(&self) -> bool {
+        self.read >= self.len && self.window.is_empty()
+    }
+
+    /// The [`MessageChecksum`] of the message. This is only valid once the full message has been
+    /// read.
+    pub fn checksum(&self) -> MessageChecksum {
+        self.hasher.finalize()
+    }
+
+    /// The [`TransmissionProgress`] of the message.
+    pub fn progress(&self) -> TransmissionProgress {
+        match self.state {
+            TransmissionState::Init => TransmissionProgress::Pending,
+            TransmissionState::InProgress => {
+                let (sent, acked) =
+                    self.window
+                        .iter()
+                        .fold(
+                            (0, self.acked as usize),
+                            |(sent, acked), chunk| match chunk.state {
+                                ChunkTransmitState::Started => (sent, acked),
+                                ChunkTransmitState::Sent(_) => (sent + 1, acked),
+                                ChunkTransmitState::Acked => (sent, acked + 1),
+                            },
+                        );
+                TransmissionProgress::Sending {
+                    pending: self.chunk_count() as usize - sent - acked,
+                    sent,
+                    acked,
+                }
+            }
+            TransmissionState::Received => TransmissionProgress::Received,
+            TransmissionState::Read => TransmissionProgress::Read,
+            TransmissionState::Aborted => TransmissionProgress::Aborted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv6Addr},
+        time::SystemTime,
+    };
+
+    use super::{OutboundStream, STREAM_WINDOW};
+    use crate::message::{TransmissionProgress, TransmissionState, AVERAGE_CHUNK_SIZE};
+
+    fn stream(len: u64) -> OutboundStream {
+        let ip = IpAddr::V6(Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 1));
+        let now = SystemTime::now();
+        let mut stream = OutboundStream::new(ip, ip, vec![], now, now, len);
+        stream.state = TransmissionState::InProgress;
+        stream
+    }
+
+    #[test]
+    fn window_is_bounded() {
+        let mut stream = stream(AVERAGE_CHUNK_SIZE as u64 * 100);
+
+        for _ in 0..STREAM_WINDOW {
+            let size = stream.next_chunk_size().unwrap();
+            stream.push_chunk(vec![0; size]);
+        }
+        assert_eq!(stream.next_chunk_size(), None);
+        assert_eq!(stream.due_chunks().count(), STREAM_WINDOW);
+        // Chunks are not retransmitted immediately.
+        assert_eq!(stream.due_chunks().count(), 0);
+
+        // Acking a chunk which is not at the start of the window does not free space.
+        assert!(!stream.ack(1));
+        assert_eq!(stream.next_chunk_size(), None);
+        assert!(stream.ack(0));
+        assert_eq!(stream.next_chunk_size(), Some(AVERAGE_CHUNK_SIZE));
+
+        assert!(matches!(
+            stream.progress(),
+            TransmissionProgress::Sending {
+                pending: 36,
+                sent: 62,
+                acked: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn checksum_and_last_chunk() {
+        let len = AVERAGE_CHUNK_SIZE as u64 + 10;
+        let mut stream = stream(len);
+        let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+
+        let first = stream.next_chunk_size().unwrap();
+        stream.push_chunk(data[..first].to_vec());
+        let second = stream.next_chunk_size().unwrap();
+        assert_eq!(second, 10);
+        stream.push_chunk(data[first..].to_vec());
+        assert_eq!(stream.next_chunk_size(), None);
+        assert_eq!(stream.chunk_count(), 2);
+
+        let chunks = stream
+            .due_chunks()
+            .map(|chunk| (chunk.idx, chunk.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![(0, 0), (1, AVERAGE_CHUNK_SIZE as u64)]);
+
+        assert!(!stream.all_acked());
+        stream.ack(1);
+        stream.ack(0);
+        assert!(stream.all_acked());
+        assert_eq!(stream.checksum(), blake3::hash(&data));
+    }
+}
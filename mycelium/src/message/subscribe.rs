@@ -0,0 +1,85 @@
+use super::MessagePacket;
+
+/// A message announcing interest in a topic to a publisher, or withdrawing it.
+///
+/// The body of a subscribe message has the following structure:
+///   - 1 byte, 1 to subscribe or 0 to unsubscribe
+///   - 1 byte topic length
+///   - topic
+pub struct MessageSubscribe {
+    buffer: MessagePacket,
+}
+
+impl MessageSubscribe {
+    /// Create a new `MessageSubscribe` in the provided [`MessagePacket`].
+    pub fn new(mut buffer: MessagePacket) -> Self {
+        buffer.set_used_buffer_size(2);
+        buffer.header_mut().flags_mut().set_subscribe();
+        Self { buffer }
+    }
+
+    /// Return if this message subscribes to the topic, or withdraws a subscription.
+    pub fn subscribe(&self) -> bool {
+        self.buffer.buffer()[0] != 0
+    }
+
+    /// Return the topic of the subscription, as written in the body.
+    pub fn topic(&self) -> &[u8] {
+        let topic_len = self.buffer.buffer()[1] as usize;
+        &self.buffer.buffer()[2..2 + topic_len]
+    }
+
+    /// Set if this message subscribes to the topic, or withdraws a subscription.
+    pub fn set_subscribe(&mut self, subscribe: bool) {
+        self.buffer.buffer_mut()[0] = subscribe as u8;
+    }
+
+    /// Set the topic in the message body.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the topic is longer than 255 bytes.
+    pub fn set_topic(&mut self, topic: &[u8]) {
+        assert!(
+            topic.len() <= u8::MAX as usize,
+            "Topic can be 255 bytes long at most"
+        );
+        self.buffer.set_used_buffer_size(2 + topic.len());
+        self.buffer.buffer_mut()[1] = topic.len() as u8;
+        self.buffer.buffer_mut()[2..2 + topic.len()].copy_from_slice(topic);
+    }
+
+    /// Consumes this `MessageSubscribe`, returning the underlying [`MessagePacket`].
+    pub fn into_inner(self) -> MessagePacket {
+        self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto::PacketBuffer, message::MessagePacket};
+
+    use super::MessageSubscribe;
+
+    #[test]
+    fn subscribe_flag_set() {
+        let ms = MessageSubscribe::new(MessagePacket::new(PacketBuffer::new()));
+
+        let mp = ms.into_inner();
+        assert!(mp.header().flags().subscribe());
+    }
+
+    #[test]
+    fn write_and_read_body() {
+        let mut ms = MessageSubscribe::new(MessagePacket::new(PacketBuffer::new()));
+        ms.set_subscribe(true);
+        ms.set_topic(b"weather");
+
+        assert_eq!(&ms.buffer.buffer()[..9], b"\x01\x07weather");
+        assert!(ms.subscribe());
+        assert_eq!(ms.topic(), b"weather");
+
+        ms.set_subscribe(false);
+        assert!(!ms.subscribe());
+    }
+}
@@ -0,0 +1,276 @@
+//! Bookkeeping of topic subscriptions.
+//!
+//! A node subscribes to a topic at a publisher by periodically sending it a subscribe message.
+//! The publisher remembers the subscription for a limited time, so subscribers which disappear
+//! without unsubscribing are eventually forgotten.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Time between subscribe messages sent to a publisher for an active subscription.
+pub const SUBSCRIPTION_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time a publisher remembers a subscription without receiving a new subscribe message.
+const SUBSCRIPTION_HOLD_TIME: Duration = Duration::from_secs(60 * 3 + 30);
+
+/// Maximum amount of topics a single remote node can be subscribed to at this node.
+const MAX_TOPICS_PER_SUBSCRIBER: usize = 64;
+
+/// Maximum amount of subscriptions of remote nodes remembered at this node.
+const MAX_SUBSCRIBERS: usize = 16 * 1024;
+
+/// Topic subscriptions of this node at publishers, and of remote nodes at this node.
+#[derive(Default)]
+pub struct TopicRegistry {
+    /// Topics this node subscribed to, and the publisher the subscription is made at.
+    subscriptions: BTreeSet<(Vec<u8>, IpAddr)>,
+    /// Remote nodes subscribed to topics of this node, and the time their subscription expires.
+    subscribers: HashMap<Vec<u8>, HashMap<IpAddr, Instant>>,
+    /// The amount of topics every remote node is subscribed to.
+    topic_counts: HashMap<IpAddr, usize>,
+    /// The total amount of subscriptions of remote nodes.
+    subscriber_count: usize,
+}
+
+impl TopicRegistry {
+    /// Create a new, empty `TopicRegistry`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a subscription of this node to a topic at a publisher. Returns `false` if the
+    /// subscription already existed.
+    pub fn subscribe(&mut self, topic: Vec<u8>, publisher: IpAddr) -> bool {
+        self.subscriptions.insert((topic, publisher))
+    }
+
+    /// Remove a subscription of this node to a topic at a publisher. Returns `false` if there was
+    /// no such subscription.
+    pub fn unsubscribe(&mut self, topic: Vec<u8>, publisher: IpAddr) -> bool {
+        self.subscriptions.remove(&(topic, publisher))
+    }
+
+    /// The subscriptions of this node, as topic and publisher.
+    pub fn subscriptions(&self) -> impl Iterator<Item = &(Vec<u8>, IpAddr)> + '_ {
+        self.subscriptions.iter()
+    }
+
+    /// Add or refresh the subscription of a remote node to a topic. Existing subscriptions are
+    /// always refreshed, but new subscriptions are dropped if the remote node is subscribed to
+    /// too many topics already, or if there are too many subscriptions in total. Returns `false`
+    /// if the subscription was dropped.
+    pub fn add_subscriber(&mut self, topic: Vec<u8>, subscriber: IpAddr, now: Instant) -> bool {
+        let expires = now + SUBSCRIPTION_HOLD_TIME;
+        if let Some(existing) = self
+            .subscribers
+            .get_mut(&topic)
+            .and_then(|subscribers| subscribers.get_mut(&subscriber))
+        {
+            *existing = expires;
+            return true;
+        }
+
+        if self.subscriber_count >= MAX_SUBSCRIBERS
+            || self.topic_counts.get(&subscriber).copied().unwrap_or(0) >= MAX_TOPICS_PER_SUBSCRIBER
+        {
+            // Make room if some subscriptions expired already.
+            self.expire_subscribers(now);
+            if self.subscriber_count >= MAX_SUBSCRIBERS
+                || self.topic_counts.get(&subscriber).copied().unwrap_or(0)
+                    >= MAX_TOPICS_PER_SUBSCRIBER
+            {
+                return false;
+            }
+        }
+
+        self.subscribers
+            .entry(topic)
+            .or_default()
+            .insert(subscriber, expires);
+        *self.topic_counts.entry(subscriber).or_default() += 1;
+        self.subscriber_count += 1;
+        true
+    }
+
+    /// Remove the subscription of a remote node to a topic.
+    pub fn remove_subscriber(&mut self, topic: &[u8], subscriber: IpAddr) {
+        if let Some(subscribers) = self.subscribers.get_mut(topic) {
+            if subscribers.remove(&subscriber).is_some() {
+                self.forget_subscription(subscriber);
+            }
+            if subscribers.is_empty() {
+                self.subscribers.remove(topic);
+            }
+        }
+    }
+
+    /// The remote nodes with an active subscription to the given topic.
+    pub fn subscribers(&self, topic: &[u8], now: Instant) -> Vec<IpAddr> {
+        let mut subscribers = self
+            .subscribers
+            .get(topic)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .filter(|(_, expires)| **expires > now)
+                    .map(|(ip, _)| *ip)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        subscribers.sort();
+        subscribers
+    }
+
+    /// All topics with active remote subscriptions, and the subscribed nodes.
+    pub fn all_subscribers(&self, now: Instant) -> Vec<(Vec<u8>, Vec<IpAddr>)> {
+        let mut all = self
+            .subscribers
+            .keys()
+            .map(|topic| (topic.clone(), self.subscribers(topic, now)))
+            .filter(|(_, subscribers)| !subscribers.is_empty())
+            .collect::<Vec<_>>();
+        all.sort();
+        all
+    }
+
+    /// Forget remote subscriptions which expired.
+    pub fn expire_subscribers(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+        self.subscribers.retain(|_, subscribers| {
+            subscribers.retain(|subscriber, expires| {
+                if *expires > now {
+                    return true;
+                }
+                expired.push(*subscriber);
+                false
+            });
+            !subscribers.is_empty()
+        });
+        for subscriber in expired {
+            self.forget_subscription(subscriber);
+        }
+    }
+
+    /// Update the subscription counts after a subscription of the given remote node is removed.
+    fn forget_subscription(&mut self, subscriber: IpAddr) {
+        self.subscriber_count -= 1;
+        if let Some(count) = self.topic_counts.get_mut(&subscriber) {
+            *count -= 1;
+            if *count == 0 {
+                self.topic_counts.remove(&subscriber);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv6Addr},
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        TopicRegistry, MAX_SUBSCRIBERS, MAX_TOPICS_PER_SUBSCRIBER, SUBSCRIPTION_HOLD_TIME,
+    };
+
+    fn ip(last: u16) -> IpAddr {
+        Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, last).into()
+    }
+
+    #[test]
+    fn subscriptions_are_unique() {
+        let mut registry = TopicRegistry::new();
+
+        assert!(registry.subscribe(b"weather".to_vec(), ip(1)));
+        assert!(!registry.subscribe(b"weather".to_vec(), ip(1)));
+        assert!(registry.subscribe(b"weather".to_vec(), ip(2)));
+        assert_eq!(registry.subscriptions().count(), 2);
+
+        assert!(registry.unsubscribe(b"weather".to_vec(), ip(1)));
+        assert!(!registry.unsubscribe(b"weather".to_vec(), ip(1)));
+        assert_eq!(registry.subscriptions().count(), 1);
+    }
+
+    #[test]
+    fn subscribers_expire_unless_refreshed() {
+        let mut registry = TopicRegistry::new();
+        let now = Instant::now();
+
+        registry.add_subscriber(b"weather".to_vec(), ip(1), now);
+        registry.add_subscriber(b"weather".to_vec(), ip(2), now);
+        registry.add_subscriber(b"news".to_vec(), ip(1), now);
+        assert_eq!(registry.subscribers(b"weather", now), vec![ip(1), ip(2)]);
+
+        let later = now + SUBSCRIPTION_HOLD_TIME - Duration::from_secs(1);
+        registry.add_subscriber(b"weather".to_vec(), ip(2), later);
+
+        let expired = now + SUBSCRIPTION_HOLD_TIME;
+        assert_eq!(registry.subscribers(b"weather", expired), vec![ip(2)]);
+        assert!(registry.subscribers(b"news", expired).is_empty());
+
+        registry.expire_subscribers(expired);
+        assert_eq!(
+            registry.all_subscribers(expired),
+            vec![(b"weather".to_vec(), vec![ip(2)])]
+        );
+    }
+
+    #[test]
+    fn removed_subscribers_are_forgotten() {
+        let mut registry = TopicRegistry::new();
+        let now = Instant::now();
+
+        registry.add_subscriber(b"weather".to_vec(), ip(1), now);
+        registry.remove_subscriber(b"weather", ip(1));
+
+        assert!(registry.subscribers(b"weather", now).is_empty());
+        assert!(registry.all_subscribers(now).is_empty());
+    }
+
+    #[test]
+    fn subscriptions_per_subscriber_are_capped() {
+        let mut registry = TopicRegistry::new();
+        let now = Instant::now();
+
+        for i in 0..MAX_TOPICS_PER_SUBSCRIBER {
+            assert!(registry.add_subscriber(i.to_be_bytes().to_vec(), ip(1), now));
+        }
+        assert!(!registry.add_subscriber(b"weather".to_vec(), ip(1), now));
+        // Existing subscriptions can still be refreshed, and other nodes can still subscribe.
+        assert!(registry.add_subscriber(0usize.to_be_bytes().to_vec(), ip(1), now));
+        assert!(registry.add_subscriber(b"weather".to_vec(), ip(2), now));
+
+        // Once a subscription is removed, a new one is accepted.
+        registry.remove_subscriber(&0usize.to_be_bytes(), ip(1));
+        assert!(registry.add_subscriber(b"weather".to_vec(), ip(1), now));
+
+        // Expired subscriptions make room as well.
+        let expired = now + SUBSCRIPTION_HOLD_TIME;
+        assert!(registry.add_subscriber(b"news".to_vec(), ip(1), expired));
+        assert_eq!(registry.subscribers(b"news", expired), vec![ip(1)]);
+    }
+
+    #[test]
+    fn total_subscriptions_are_capped() {
+        let mut registry = TopicRegistry::new();
+        let now = Instant::now();
+
+        for i in 0..MAX_SUBSCRIBERS {
+            let subscriber = ip((i / MAX_TOPICS_PER_SUBSCRIBER) as u16);
+            let topic = (i % MAX_TOPICS_PER_SUBSCRIBER).to_be_bytes().to_vec();
+            assert!(registry.add_subscriber(topic, subscriber, now));
+        }
+        assert!(!registry.add_subscriber(b"weather".to_vec(), ip(u16::MAX), now));
+
+        registry.expire_subscribers(now + SUBSCRIPTION_HOLD_TIME);
+        assert!(registry.add_subscriber(
+            b"weather".to_vec(),
+            ip(u16::MAX),
+            now + SUBSCRIPTION_HOLD_TIME
+        ));
+    }
+}
@@ -2,7 +2,7 @@
 //! interest is the [`Metrics`] trait. Users can provide their own implementation of this, or use
 //! the default provided implementation to disable gathering metrics.
 
-use crate::peer_manager::PeerType;
+use crate::peer_manager::{LinkQuality, PeerType};
 
 /// The collection of all metrics exported by a [`mycelium node`](crate::Node). It is up to the
 /// user to provide an implementation which implements the methods for metrics they are interested
@@ -76,6 +76,17 @@ pub trait Metrics {
     #[inline]
     fn router_peer_died(&self) {}
 
+    /// The [`LinkQuality`] of a [`Peer`](crate::peer::Peer) was updated after a Hello/IHU
+    /// exchange. The peer is identified by its connection identifier.
+    #[inline]
+    fn router_peer_link_quality(&self, _peer: &str, _link_quality: LinkQuality) {}
+
+    /// A [`Peer`](crate::peer::Peer) was removed from the [`Router`](crate::router::Router), so
+    /// its [`LinkQuality`] is no longer tracked. The peer is identified by its connection
+    /// identifier.
+    #[inline]
+    fn router_peer_link_quality_removed(&self, _peer: &str) {}
+
     /// The [`Router`](crate::router::Router) ran a route selection procedure.
     #[inline]
     fn router_route_selection_ran(&self) {}
@@ -118,6 +129,11 @@ pub trait Metrics {
     #[inline]
     fn router_route_packet_no_route(&self) {}
 
+    /// The [`Router`](crate::router::Router) refused to forward a packet from a peer for another
+    /// node, because it is a leaf node.
+    #[inline]
+    fn router_route_packet_transit_prohibited(&self) {}
+
     /// The [`Router`](crate::router::Router) replied to a seqno request with a local route, which
     /// is more recent (bigger seqno) than the request.
     #[inline]
@@ -168,6 +184,19 @@ pub trait Metrics {
     #[inline]
     fn router_update_denied_by_filter(&self) {}
 
+    /// An update was denied by the configured [`RoutePolicy`](crate::policy::RoutePolicy).
+    #[inline]
+    fn router_update_denied_by_policy(&self) {}
+
+    /// An update was received with an origin signature which is not valid for the announced
+    /// route.
+    #[inline]
+    fn router_update_invalid_signature(&self) {}
+
+    /// An update without origin signature was received, while origin signatures are required.
+    #[inline]
+    fn router_update_unsigned(&self) {}
+
     /// An update was accepted by the router filters, but was otherwise unfeasible or a retraction,
     /// for an unknown subnet.
     #[inline]
@@ -1,21 +1,57 @@
 use bytes::{Buf, BufMut, BytesMut};
 pub use control::ControlPacket;
 pub use data::DataPacket;
+pub use handshake::{Capabilities, Handshake};
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug, trace};
+
+use crate::{
+    babel::{self, auth},
+    link_auth::LinkAuth,
+};
 
 mod control;
 mod data;
+mod handshake;
 
-/// Current version of the protocol being used.
+/// Highest version of the protocol supported by this node.
 const PROTOCOL_VERSION: u8 = 1;
+/// Lowest version of the protocol supported by this node. This version is used until a higher
+/// version is negotiated with a [`Handshake`].
+const MIN_PROTOCOL_VERSION: u8 = 1;
 
 /// The size of a `Packet` header on the wire, in bytes.
 const PACKET_HEADER_SIZE: usize = 4;
 
+/// Flag set in the third byte of the `Packet` header if the sender understands sub-TLVs in
+/// control packets. Older nodes ignore this byte, and never set it.
+const FLAG_SUB_TLVS: u8 = 0b0000_0001;
+/// Flag set in the third byte of the `Packet` header if the sender can decode control packets
+/// holding multiple TLVs.
+const FLAG_MULTI_TLV: u8 = 0b0000_0010;
+/// Flag set in the third byte of the `Packet` header if the sender supports the [`Handshake`].
+const FLAG_HANDSHAKE: u8 = 0b0000_0100;
+/// Flags set in the header of every `Packet` we send.
+const LOCAL_FLAGS: u8 = FLAG_SUB_TLVS | FLAG_MULTI_TLV | FLAG_HANDSHAKE;
+
+/// Maximum size of the TLVs which are combined in a single control packet. Larger batches are
+/// split over multiple packets.
+const MAX_CONTROL_BODY_SIZE: usize = 8 * 1024;
+
 #[derive(Debug, Clone)]
 pub enum Packet {
     DataPacket(DataPacket),
     ControlPacket(ControlPacket),
+    /// Multiple control packets, which are combined in as few packets as possible if the remote
+    /// supports it. This is never produced by the decoder, which yields the individual
+    /// [`ControlPacket`]s instead.
+    ControlPackets(Vec<ControlPacket>),
+    /// The [`Handshake`] of a node, which is sent once the remote indicated it supports it.
+    Handshake(Handshake),
+    /// A control packet without TLVs, which only carries the pending authentication TLVs of the
+    /// link. The decoder produces this once the remote waits for these TLVs, so it can be sent
+    /// back. It is not sent on links without authentication.
+    Auth,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -23,12 +59,52 @@ pub enum Packet {
 pub enum PacketType {
     DataPacket = 0,
     ControlPacket = 1,
+    Handshake = 2,
 }
 
 pub struct Codec {
     packet_type: Option<PacketType>,
     data_packet_codec: data::Codec,
     control_packet_codec: control::Codec,
+    handshake_codec: handshake::Codec,
+    /// Protocol version used for the packets we send.
+    version: u8,
+    /// Set once the remote indicated it supports the [`Handshake`].
+    remote_handshake: bool,
+    /// Set once our [`Handshake`] is sent.
+    handshake_sent: bool,
+    /// The [`Handshake`] received from the remote, if any.
+    remote_handshake_received: Option<Handshake>,
+    /// Set once the remote indicated it understands sub-TLVs. Until then, sub-TLVs are removed
+    /// from outgoing control packets.
+    remote_sub_tlvs: bool,
+    /// Set once the remote indicated it can decode control packets with multiple TLVs. Until
+    /// then, every TLV is sent in a separate control packet.
+    remote_multi_tlv: bool,
+    /// Length of the trailer of the control packet which is being decoded.
+    trailer_len: usize,
+    /// Authentication state of control packets, if the link uses authentication.
+    auth: Option<LinkAuth>,
+}
+
+/// The size on the wire of the [`Packet`] at the start of `src`, including the header and a
+/// possible trailer. Returns [`None`] if the buffer does not hold enough of the packet to know its
+/// size yet, or if the packet has an unknown type.
+///
+/// This allows splitting a stream of packets without decoding them.
+pub fn wire_size(src: &[u8]) -> Option<usize> {
+    if src.len() < PACKET_HEADER_SIZE {
+        return None;
+    }
+    let body = &src[PACKET_HEADER_SIZE..];
+    let body_size = match src[1] {
+        0 => data::wire_size(body)?,
+        1 => babel::packet_len(body)? + src[3] as usize,
+        2 => handshake::wire_size(body)?,
+        _ => return None,
+    };
+
+    Some(PACKET_HEADER_SIZE + body_size)
 }
 
 impl Codec {
@@ -37,8 +113,76 @@ impl Codec {
             packet_type: None,
             data_packet_codec: data::Codec::new(),
             control_packet_codec: control::Codec::new(),
+            handshake_codec: handshake::Codec::new(),
+            version: MIN_PROTOCOL_VERSION,
+            remote_handshake: false,
+            handshake_sent: false,
+            remote_handshake_received: None,
+            remote_sub_tlvs: false,
+            remote_multi_tlv: false,
+            trailer_len: 0,
+            auth: None,
+        }
+    }
+
+    /// Create a new `Codec` which authenticates control packets with the given key.
+    pub fn with_auth(key: [u8; 32]) -> Self {
+        Codec {
+            auth: Some(LinkAuth::new(key)),
+            ..Self::new()
         }
     }
+
+    /// Returns `true` if our [`Handshake`] should be sent, because the remote indicated it supports
+    /// it.
+    pub fn handshake_pending(&self) -> bool {
+        self.remote_handshake && !self.handshake_sent
+    }
+
+    /// The [`Handshake`] to send to the remote.
+    pub fn local_handshake(&self) -> Handshake {
+        Handshake::local(self.auth.is_some())
+    }
+
+    /// Merge a [`Handshake`] of the remote with the one it sent earlier. Handshakes are not
+    /// authenticated, so on authenticated links a later handshake can't remove capabilities, as
+    /// it might be injected to disable features such as signed updates.
+    fn merge_handshake(&mut self, handshake: Handshake) -> Handshake {
+        let handshake = match self.remote_handshake_received {
+            Some(previous) if self.auth.is_some() => {
+                if !handshake.capabilities.contains(previous.capabilities) {
+                    debug!("Ignoring capabilities dropped by handshake on authenticated link");
+                }
+                Handshake {
+                    version: handshake.version.max(previous.version),
+                    capabilities: handshake.capabilities.union(previous.capabilities),
+                }
+            }
+            _ => handshake,
+        };
+        self.remote_handshake_received = Some(handshake);
+
+        handshake
+    }
+
+    /// Apply the [`Handshake`] of the remote. Features it supports are used from now on.
+    fn apply_handshake(&mut self, handshake: &Handshake) {
+        self.version = handshake
+            .version
+            .clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+        if handshake.capabilities.contains(Capabilities::SUB_TLVS) {
+            self.remote_sub_tlvs = true;
+        }
+        if handshake.capabilities.contains(Capabilities::MULTI_TLV) {
+            self.remote_multi_tlv = true;
+        }
+    }
+
+    /// Returns `true` if a [`Packet::Auth`] should be sent as soon as possible, to complete the
+    /// authentication of the link.
+    pub fn auth_pending(&self) -> bool {
+        self.auth.as_ref().is_some_and(LinkAuth::pending_send)
+    }
 }
 
 impl Decoder for Codec {
@@ -46,31 +190,82 @@ impl Decoder for Codec {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            // The remaining TLVs of a decoded control packet are returned first.
+            if let Some(p) = self.control_packet_codec.next_pending() {
+                return Ok(Some(Packet::ControlPacket(p)));
+            }
+
+            // Then control packets which passed authentication.
+            if let Some(auth) = &mut self.auth {
+                if let Some(mut packet) = auth.next_accepted() {
+                    if let Some(p) = self.control_packet_codec.decode(&mut packet)? {
+                        return Ok(Some(Packet::ControlPacket(p)));
+                    }
+                    continue;
+                }
+            }
+
+            match self.decode_packet(src)? {
+                Decoded::Packet(packet) => return Ok(Some(packet)),
+                Decoded::Incomplete => return Ok(None),
+                Decoded::Consumed if self.auth_pending() => return Ok(Some(Packet::Auth)),
+                Decoded::Consumed => continue,
+            }
+        }
+    }
+}
+
+/// Result of decoding a single packet from the wire.
+enum Decoded {
+    /// A packet was decoded.
+    Packet(Packet),
+    /// There are not enough bytes to decode a packet yet.
+    Incomplete,
+    /// A packet was consumed, but it does not produce a packet right now. It was dropped, or held
+    /// by the link authentication.
+    Consumed,
+}
+
+impl Codec {
+    fn decode_packet(&mut self, src: &mut BytesMut) -> Result<Decoded, std::io::Error> {
         // Determine the packet_type
         let packet_type = if let Some(packet_type) = self.packet_type {
             packet_type
         } else {
             // Check we can read the header
             if src.remaining() <= PACKET_HEADER_SIZE {
-                return Ok(None);
+                return Ok(Decoded::Incomplete);
             }
 
             let mut header = [0; PACKET_HEADER_SIZE];
             header.copy_from_slice(&src[..PACKET_HEADER_SIZE]);
             src.advance(PACKET_HEADER_SIZE);
 
-            // For now it's a hard error to not follow the 1 defined protocol version
-            if header[0] != PROTOCOL_VERSION {
+            // It's a hard error to use a protocol version we don't support
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&header[0]) {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
                     "Unknown protocol version",
                 ));
             };
 
+            if header[2] & FLAG_SUB_TLVS != 0 {
+                self.remote_sub_tlvs = true;
+            }
+            if header[2] & FLAG_MULTI_TLV != 0 {
+                self.remote_multi_tlv = true;
+            }
+            if header[2] & FLAG_HANDSHAKE != 0 {
+                self.remote_handshake = true;
+            }
+            self.trailer_len = header[3] as usize;
+
             let packet_type_byte = header[1];
             let packet_type = match packet_type_byte {
                 0 => PacketType::DataPacket,
                 1 => PacketType::ControlPacket,
+                2 => PacketType::Handshake,
                 _ => {
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
@@ -90,20 +285,47 @@ impl Decoder for Codec {
                 match self.data_packet_codec.decode(src) {
                     Ok(Some(p)) => {
                         self.packet_type = None; // Reset state
-                        Ok(Some(Packet::DataPacket(p)))
+                        Ok(Decoded::Packet(Packet::DataPacket(p)))
                     }
-                    Ok(None) => Ok(None),
+                    Ok(None) => Ok(Decoded::Incomplete),
                     Err(e) => Err(e),
                 }
             }
+            PacketType::Handshake => match self.handshake_codec.decode(src)? {
+                Some(handshake) => {
+                    self.packet_type = None; // Reset state
+                    let handshake = self.merge_handshake(handshake);
+                    self.apply_handshake(&handshake);
+                    Ok(Decoded::Packet(Packet::Handshake(handshake)))
+                }
+                None => Ok(Decoded::Incomplete),
+            },
             PacketType::ControlPacket => {
-                match self.control_packet_codec.decode(src) {
-                    Ok(Some(p)) => {
-                        self.packet_type = None; // Reset state
-                        Ok(Some(Packet::ControlPacket(p)))
-                    }
-                    Ok(None) => Ok(None),
-                    Err(e) => Err(e),
+                // Control packets are only decoded once they are fully received, including the
+                // trailer, so the packet is consumed even if it holds no valid TLVs.
+                let Some(len) = babel::packet_len(src) else {
+                    return Ok(Decoded::Incomplete);
+                };
+                if src.remaining() < len + self.trailer_len {
+                    return Ok(Decoded::Incomplete);
+                }
+                let mut packet = src.split_to(len);
+                let trailer = src.split_to(self.trailer_len);
+                self.packet_type = None; // Reset state
+
+                // Authenticated packets are verified as a whole, including the trailer.
+                if let Some(auth) = &mut self.auth {
+                    auth.open(packet, &trailer);
+                    return Ok(Decoded::Consumed);
+                }
+                if !trailer.is_empty() {
+                    trace!("Dropping authenticated control packet on link without key");
+                    return Ok(Decoded::Consumed);
+                }
+
+                match self.control_packet_codec.decode(&mut packet)? {
+                    Some(p) => Ok(Decoded::Packet(Packet::ControlPacket(p))),
+                    None => Ok(Decoded::Consumed),
                 }
             }
         }
@@ -116,14 +338,87 @@ impl Encoder<Packet> for Codec {
     fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
         match item {
             Packet::DataPacket(datapacket) => {
-                dst.put_slice(&[PROTOCOL_VERSION, 0, 0, 0]);
+                dst.put_slice(&[self.version, 0, LOCAL_FLAGS, 0]);
                 self.data_packet_codec.encode(datapacket, dst)
             }
-            Packet::ControlPacket(controlpacket) => {
-                dst.put_slice(&[PROTOCOL_VERSION, 1, 0, 0]);
+            Packet::ControlPacket(mut controlpacket) => {
+                if !self.remote_sub_tlvs {
+                    controlpacket.clear_sub_tlvs();
+                }
+                if let Some(auth) = &mut self.auth {
+                    let mut packet = BytesMut::new();
+                    self.control_packet_codec
+                        .encode(controlpacket, &mut packet)?;
+                    dst.put_slice(&[self.version, 1, LOCAL_FLAGS, auth::TRAILER_SIZE as u8]);
+                    auth.seal(Some(&packet), dst);
+                    return Ok(());
+                }
+                dst.put_slice(&[self.version, 1, LOCAL_FLAGS, 0]);
                 self.control_packet_codec.encode(controlpacket, dst)
             }
+            Packet::ControlPackets(mut controlpackets) => {
+                if !self.remote_multi_tlv {
+                    for controlpacket in controlpackets {
+                        self.encode(Packet::ControlPacket(controlpacket), dst)?;
+                    }
+                    return Ok(());
+                }
+                if !self.remote_sub_tlvs {
+                    for controlpacket in &mut controlpackets {
+                        controlpacket.clear_sub_tlvs();
+                    }
+                }
+
+                let mut batch = Vec::new();
+                let mut batch_size = 0;
+                for controlpacket in controlpackets {
+                    // TLV header is not included in the wire size.
+                    let size = controlpacket.wire_size() as usize + 2;
+                    if !batch.is_empty() && batch_size + size > MAX_CONTROL_BODY_SIZE {
+                        self.encode_control_batch(std::mem::take(&mut batch), dst)?;
+                        batch_size = 0;
+                    }
+                    batch_size += size;
+                    batch.push(controlpacket);
+                }
+                if !batch.is_empty() {
+                    self.encode_control_batch(batch, dst)?;
+                }
+
+                Ok(())
+            }
+            Packet::Handshake(handshake) => {
+                dst.put_slice(&[self.version, 2, LOCAL_FLAGS, 0]);
+                self.handshake_sent = true;
+                self.handshake_codec.encode(handshake, dst)
+            }
+            Packet::Auth => {
+                if let Some(auth) = &mut self.auth {
+                    dst.put_slice(&[self.version, 1, LOCAL_FLAGS, auth::TRAILER_SIZE as u8]);
+                    auth.seal(None, dst);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Codec {
+    /// Encode multiple control packets in a single packet.
+    fn encode_control_batch(
+        &mut self,
+        batch: Vec<ControlPacket>,
+        dst: &mut BytesMut,
+    ) -> Result<(), std::io::Error> {
+        if let Some(auth) = &mut self.auth {
+            let mut packet = BytesMut::new();
+            self.control_packet_codec.encode(batch, &mut packet)?;
+            dst.put_slice(&[self.version, 1, LOCAL_FLAGS, auth::TRAILER_SIZE as u8]);
+            auth.seal(Some(&packet), dst);
+            return Ok(());
         }
+        dst.put_slice(&[self.version, 1, LOCAL_FLAGS, 0]);
+        self.control_packet_codec.encode(batch, dst)
     }
 }
 
@@ -132,3 +427,226 @@ impl Default for Codec {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::babel::{self, Tlv};
+
+    use super::{Capabilities, Codec, DataPacket, Handshake, Packet, PACKET_HEADER_SIZE};
+
+    fn hello_tlv() -> Tlv {
+        babel::Hello::new_unicast(1.into(), 400)
+            .with_timestamp(42)
+            .into()
+    }
+
+    fn hello() -> Packet {
+        Packet::ControlPacket(hello_tlv())
+    }
+
+    fn decode_timestamp(codec: &mut Codec, buf: &mut BytesMut) -> Option<u32> {
+        match codec.decode(buf).unwrap() {
+            Some(Packet::ControlPacket(Tlv::Hello(hello))) => hello.timestamp(),
+            _ => panic!("Expected a hello"),
+        }
+    }
+
+    #[test]
+    fn sub_tlvs_only_sent_to_capable_remote() {
+        let mut local = Codec::new();
+        let mut remote = Codec::new();
+        let mut buf = BytesMut::new();
+
+        // The remote did not announce support for sub-TLVs yet.
+        local.encode(hello(), &mut buf).unwrap();
+        assert_eq!(decode_timestamp(&mut remote, &mut buf), None);
+
+        // Any packet from the remote announces support.
+        remote.encode(hello(), &mut buf).unwrap();
+        decode_timestamp(&mut local, &mut buf);
+
+        local.encode(hello(), &mut buf).unwrap();
+        assert_eq!(decode_timestamp(&mut remote, &mut buf), Some(42));
+    }
+
+    #[test]
+    fn authenticated_control_packets() {
+        let mut local = Codec::with_auth([1; 32]);
+        let mut remote = Codec::with_auth([1; 32]);
+        let mut buf = BytesMut::new();
+
+        // The hello is held until the challenge of the remote is answered, which is requested by
+        // decoding it.
+        assert!(local.auth_pending());
+        local.encode(hello(), &mut buf).unwrap();
+        assert!(matches!(
+            remote.decode(&mut buf).unwrap(),
+            Some(Packet::Auth)
+        ));
+        assert!(buf.is_empty());
+
+        remote.encode(Packet::Auth, &mut buf).unwrap();
+        assert!(matches!(
+            local.decode(&mut buf).unwrap(),
+            Some(Packet::Auth)
+        ));
+
+        // The hello was sent before the remote announced support for sub-TLVs.
+        local.encode(Packet::Auth, &mut buf).unwrap();
+        assert_eq!(decode_timestamp(&mut remote, &mut buf), None);
+        assert!(remote.decode(&mut buf).unwrap().is_none());
+
+        // A remote without key drops authenticated packets, but stays in sync with the stream.
+        let mut plain = Codec::new();
+        local.encode(hello(), &mut buf).unwrap();
+        remote.encode(hello(), &mut buf).unwrap();
+        assert!(plain.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn control_packets_batched_for_capable_remote() {
+        let mut local = Codec::new();
+        let mut remote = Codec::new();
+        let mut buf = BytesMut::new();
+
+        // Without knowing the capabilities of the remote, every TLV is sent separately.
+        local
+            .encode(
+                Packet::ControlPackets(vec![hello_tlv(), hello_tlv()]),
+                &mut buf,
+            )
+            .unwrap();
+        assert!(remote.decode(&mut buf).unwrap().is_some());
+        assert!(remote.decode(&mut buf).unwrap().is_some());
+        assert!(remote.decode(&mut buf).unwrap().is_none());
+
+        remote.encode(hello(), &mut buf).unwrap();
+        decode_timestamp(&mut local, &mut buf);
+
+        local
+            .encode(
+                Packet::ControlPackets(vec![hello_tlv(), hello_tlv()]),
+                &mut buf,
+            )
+            .unwrap();
+        // A single packet and babel header, followed by both TLVs.
+        let tlv_size = 2 + hello_tlv().wire_size() as usize;
+        assert_eq!(buf.len(), PACKET_HEADER_SIZE + 4 + 2 * tlv_size);
+        assert_eq!(decode_timestamp(&mut remote, &mut buf), Some(42));
+        assert_eq!(decode_timestamp(&mut remote, &mut buf), Some(42));
+        assert!(remote.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn handshake_enables_capabilities() {
+        let mut local = Codec::new();
+        let mut remote = Codec::new();
+        let mut buf = BytesMut::new();
+
+        // The handshake is only sent once the remote indicated it supports it.
+        assert!(!local.handshake_pending());
+        remote.encode(hello(), &mut buf).unwrap();
+        decode_timestamp(&mut local, &mut buf);
+        assert!(local.handshake_pending());
+
+        let handshake = local.local_handshake();
+        local
+            .encode(Packet::Handshake(handshake), &mut buf)
+            .unwrap();
+        assert!(!local.handshake_pending());
+        match remote.decode(&mut buf).unwrap() {
+            Some(Packet::Handshake(handshake)) => {
+                assert_eq!(handshake, Handshake::local(false));
+                assert!(!handshake.capabilities.contains(Capabilities::LINK_AUTH));
+            }
+            _ => panic!("Expected a handshake"),
+        }
+        assert!(remote.remote_sub_tlvs);
+        assert!(remote.remote_multi_tlv);
+        assert!(remote.handshake_pending());
+    }
+
+    #[test]
+    fn handshake_cannot_drop_capabilities_on_authenticated_link() {
+        let mut local = Codec::with_auth([1; 32]);
+        let mut remote = Codec::with_auth([1; 32]);
+        let mut buf = BytesMut::new();
+
+        let handshake = local.local_handshake();
+        assert!(handshake.capabilities.contains(Capabilities::LINK_AUTH));
+        local
+            .encode(Packet::Handshake(handshake), &mut buf)
+            .unwrap();
+        match remote.decode(&mut buf).unwrap() {
+            Some(Packet::Handshake(received)) => assert_eq!(received, handshake),
+            _ => panic!("Expected a handshake"),
+        }
+
+        // A later handshake without signed updates, as an attacker could inject, doesn't disable
+        // them.
+        let injected = Handshake {
+            version: 1,
+            capabilities: Capabilities::SUB_TLVS,
+        };
+        local.encode(Packet::Handshake(injected), &mut buf).unwrap();
+        match remote.decode(&mut buf).unwrap() {
+            Some(Packet::Handshake(received)) => assert_eq!(received, handshake),
+            _ => panic!("Expected a handshake"),
+        }
+    }
+
+    #[test]
+    fn wire_size_matches_encoded_packets() {
+        let mut codec = Codec::new();
+        let packets = [
+            hello(),
+            Packet::Handshake(Handshake::local(false)),
+            Packet::DataPacket(DataPacket {
+                raw_data: vec![1; 100],
+                hop_limit: 64,
+                src_ip: "400::1".parse().unwrap(),
+                dst_ip: "400::2".parse().unwrap(),
+                flow: 7,
+            }),
+        ];
+        for packet in packets {
+            let mut buf = BytesMut::new();
+            codec.encode(packet, &mut buf).unwrap();
+            assert_eq!(super::wire_size(&buf), Some(buf.len()));
+            assert_eq!(super::wire_size(&buf[..PACKET_HEADER_SIZE - 1]), None);
+        }
+    }
+
+    #[test]
+    fn data_packet_flow_roundtrip() {
+        let mut local = Codec::new();
+        let mut remote = Codec::new();
+        let mut buf = BytesMut::new();
+
+        local
+            .encode(
+                Packet::DataPacket(DataPacket {
+                    raw_data: vec![1; 10],
+                    hop_limit: 64,
+                    src_ip: "400::1".parse().unwrap(),
+                    dst_ip: "400::2".parse().unwrap(),
+                    flow: 0xAB,
+                }),
+                &mut buf,
+            )
+            .unwrap();
+        match remote.decode(&mut buf).unwrap() {
+            Some(Packet::DataPacket(dp)) => {
+                assert_eq!(dp.flow, 0xAB);
+                assert_eq!(dp.hop_limit, 64);
+                assert_eq!(dp.raw_data, vec![1; 10]);
+            }
+            _ => panic!("Expected a data packet"),
+        }
+    }
+}
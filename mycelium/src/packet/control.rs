@@ -18,13 +18,27 @@ impl ControlPacket {
     pub fn new_hello(dest_peer: &Peer, interval: Duration) -> Self {
         let tlv: babel::Tlv =
             babel::Hello::new_unicast(dest_peer.hello_seqno(), (interval.as_millis() / 10) as u16)
+                .with_timestamp(babel::timestamp())
                 .into();
         dest_peer.increment_hello_seqno();
         tlv
     }
 
-    pub fn new_ihu(rx_cost: Metric, interval: Duration, dest_address: Option<IpAddr>) -> Self {
-        babel::Ihu::new(rx_cost, (interval.as_millis() / 10) as u16, dest_address).into()
+    /// Create a new IHU in reply to the given hello. If the hello carries a timestamp, it is
+    /// echoed so the receiver can calculate the round trip time of the link.
+    pub fn new_ihu(
+        rx_cost: Metric,
+        interval: Duration,
+        dest_address: Option<IpAddr>,
+        hello: &babel::Hello,
+    ) -> Self {
+        let ihu = babel::Ihu::new(rx_cost, (interval.as_millis() / 10) as u16, dest_address);
+        match (hello.timestamp(), hello.received_at()) {
+            (Some(origin), Some(receive)) => ihu
+                .with_timestamps(origin, receive, babel::timestamp())
+                .into(),
+            _ => ihu.into(),
+        }
     }
 
     pub fn new_update(
@@ -44,6 +58,11 @@ impl Codec {
             codec: babel::Codec::new(),
         }
     }
+
+    /// Get the next control packet of a previously decoded packet, if there is one.
+    pub fn next_pending(&mut self) -> Option<ControlPacket> {
+        self.codec.next_pending()
+    }
 }
 
 impl Decoder for Codec {
@@ -62,3 +81,15 @@ impl Encoder<ControlPacket> for Codec {
         self.codec.encode(message, buf)
     }
 }
+
+impl Encoder<Vec<ControlPacket>> for Codec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        messages: Vec<ControlPacket>,
+        buf: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        self.codec.encode(messages, buf)
+    }
+}
@@ -16,6 +16,10 @@ pub struct DataPacket {
     pub hop_limit: u8,
     pub src_ip: Ipv6Addr,
     pub dst_ip: Ipv6Addr,
+    /// Identifier of the flow this packet is part of, set by the source from the header of the
+    /// packet before it is encrypted. Forwarding nodes use this to keep packets of the same flow
+    /// on the same route. 0 if the flow is not known.
+    pub flow: u8,
 }
 
 pub struct Codec {
@@ -29,6 +33,19 @@ pub struct Codec {
 struct HeaderValues {
     len: u16,
     hop_limit: u8,
+    flow: u8,
+}
+
+/// The size on the wire of the data packet at the start of `src`, excluding the packet header.
+/// Returns [`None`] if the buffer does not hold the data packet header yet.
+pub fn wire_size(src: &[u8]) -> Option<usize> {
+    if src.len() < DATA_PACKET_HEADER_SIZE {
+        return None;
+    }
+    let raw_header = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+    let data_len = ((raw_header >> 8) & DATA_PACKET_LEN_MASK) as usize;
+
+    Some(DATA_PACKET_HEADER_SIZE + 16 + 16 + data_len)
 }
 
 impl Codec {
@@ -47,7 +64,11 @@ impl Decoder for Codec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // Determine the length of the data
-        let HeaderValues { len, hop_limit } = if let Some(header_vals) = self.header_vals {
+        let HeaderValues {
+            len,
+            hop_limit,
+            flow,
+        } = if let Some(header_vals) = self.header_vals {
             header_vals
         } else {
             // Check we have enough data to decode
@@ -59,9 +80,12 @@ impl Decoder for Codec {
             // Hop limit is the last 8 bits.
             let hop_limit = (raw_header & 0xFF) as u8;
             let data_len = ((raw_header >> 8) & DATA_PACKET_LEN_MASK) as u16;
+            // Flow is the first 8 bits.
+            let flow = (raw_header >> 24) as u8;
             let header_vals = HeaderValues {
                 len: data_len,
                 hop_limit,
+                flow,
             };
 
             self.header_vals = Some(header_vals);
@@ -127,6 +151,7 @@ impl Decoder for Codec {
             hop_limit,
             dst_ip: dest_ip,
             src_ip,
+            flow,
         }))
     }
 }
@@ -137,6 +162,8 @@ impl Encoder<DataPacket> for Codec {
     fn encode(&mut self, item: DataPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
         dst.reserve(item.raw_data.len() + DATA_PACKET_HEADER_SIZE + 16 + 16);
         let mut raw_header = 0;
+        // Add the flow
+        raw_header |= (item.flow as u32) << 24;
         // Add length of the data
         raw_header |= (item.raw_data.len() as u32) << 8;
         // And hop limit
@@ -0,0 +1,209 @@
+//! The handshake exchanged at the start of a connection with a peer.
+//!
+//! Nodes indicate they support the handshake with a flag in the header of every packet. Once a
+//! node sees this flag on a packet of the remote, it sends its own handshake. Older nodes never
+//! set the flag, and as such never receive a handshake.
+
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Size of the handshake body which is understood by this version. Newer versions can append
+/// fields, which are ignored.
+const HANDSHAKE_BODY_SIZE: u16 = 6;
+
+/// Optional features of the protocol which are supported by a node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// The node understands sub-TLVs in control packets.
+    pub const SUB_TLVS: Self = Self(1 << 0);
+    /// The node can decode control packets holding multiple TLVs, with compressed updates.
+    pub const MULTI_TLV: Self = Self(1 << 1);
+    /// The node signs the routes it originates, and verifies and forwards the signatures of
+    /// other nodes.
+    pub const SIGNED_UPDATES: Self = Self(1 << 2);
+    /// The node can authenticate control packets on links.
+    pub const LINK_AUTH: Self = Self(1 << 3);
+
+    /// Names of the known capabilities.
+    const NAMES: [(Self, &'static str); 4] = [
+        (Self::SUB_TLVS, "subTlvs"),
+        (Self::MULTI_TLV, "multiTlv"),
+        (Self::SIGNED_UPDATES, "signedUpdates"),
+        (Self::LINK_AUTH, "linkAuth"),
+    ];
+
+    /// The capabilities supported by this node. [`Capabilities::LINK_AUTH`] is only set if the
+    /// link is authenticated.
+    pub const fn local(link_auth: bool) -> Self {
+        let capabilities = Self(Self::SUB_TLVS.0 | Self::MULTI_TLV.0 | Self::SIGNED_UPDATES.0);
+        if link_auth {
+            capabilities.union(Self::LINK_AUTH)
+        } else {
+            capabilities
+        }
+    }
+
+    /// The capabilities which are set in either `self` or `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns `true` if all capabilities in `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The names of the known capabilities which are set. Unknown capabilities are skipped.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .filter(move |(cap, _)| self.contains(*cap))
+            .map(|(_, name)| name)
+    }
+}
+
+impl From<u32> for Capabilities {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Capabilities> for u32 {
+    fn from(value: Capabilities) -> Self {
+        value.0
+    }
+}
+
+/// The handshake a node sends to its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    /// Highest protocol version supported by the node.
+    pub version: u8,
+    /// Capabilities of the node.
+    pub capabilities: Capabilities,
+}
+
+impl Handshake {
+    /// The handshake of this node, for a link which is authenticated if `link_auth` is set.
+    pub fn local(link_auth: bool) -> Self {
+        Self {
+            version: super::PROTOCOL_VERSION,
+            capabilities: Capabilities::local(link_auth),
+        }
+    }
+}
+
+/// The size on the wire of the handshake at the start of `src`, excluding the packet header.
+/// Returns [`None`] if the buffer does not hold the length of the handshake yet.
+pub fn wire_size(src: &[u8]) -> Option<usize> {
+    if src.len() < 2 {
+        return None;
+    }
+
+    Some(2 + u16::from_be_bytes([src[0], src[1]]) as usize)
+}
+
+pub struct Codec {
+    body_len: Option<u16>,
+}
+
+impl Codec {
+    pub fn new() -> Self {
+        Codec { body_len: None }
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Handshake;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let body_len = if let Some(body_len) = self.body_len {
+            body_len
+        } else {
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            let body_len = src.get_u16();
+            self.body_len = Some(body_len);
+            body_len
+        };
+
+        if src.len() < body_len as usize {
+            return Ok(None);
+        }
+        let mut body = src.split_to(body_len as usize);
+        // Reset state
+        self.body_len = None;
+
+        if body_len < HANDSHAKE_BODY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Handshake body too short",
+            ));
+        }
+
+        let version = body.get_u8();
+        // Reserved byte
+        let _ = body.get_u8();
+        let capabilities = body.get_u32().into();
+
+        Ok(Some(Handshake {
+            version,
+            capabilities,
+        }))
+    }
+}
+
+impl Encoder<Handshake> for Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Handshake, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_u16(HANDSHAKE_BODY_SIZE);
+        dst.put_u8(item.version);
+        dst.put_u8(0);
+        dst.put_u32(item.capabilities.into());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{Capabilities, Codec, Handshake};
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = BytesMut::new();
+        Codec::new()
+            .encode(Handshake::local(true), &mut buf)
+            .unwrap();
+        assert_eq!(
+            Codec::new().decode(&mut buf).unwrap(),
+            Some(Handshake::local(true))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let mut buf = BytesMut::from(&[0, 8, 3, 0, 0, 0, 0, 0b11, 9, 9, 1][..]);
+        let handshake = Codec::new().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(handshake.version, 3);
+        assert!(handshake.capabilities.contains(Capabilities::MULTI_TLV));
+        assert!(!handshake.capabilities.contains(Capabilities::LINK_AUTH));
+        assert_eq!(
+            handshake.capabilities.names().collect::<Vec<_>>(),
+            vec!["subTlvs", "multiTlv"]
+        );
+        // Only the handshake is consumed.
+        assert_eq!(&buf[..], &[1]);
+    }
+}
@@ -2,10 +2,12 @@ use futures::{SinkExt, StreamExt};
 use std::{
     error::Error,
     io,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc, RwLock, Weak,
     },
+    time::Duration,
 };
 use tokio::{
     select,
@@ -16,7 +18,9 @@ use tracing::{debug, error, info, trace};
 
 use crate::{
     connection::{self, Connection},
-    packet::{self, Packet},
+    link_auth::LinkKeys,
+    packet::{self, Handshake, Packet},
+    peer_manager::{LinkQuality, PeerSettings},
 };
 use crate::{
     packet::{ControlPacket, DataPacket},
@@ -27,6 +31,11 @@ use crate::{
 /// received.
 const PACKET_COALESCE_WINDOW: usize = 50;
 
+/// The maximum amount of control packets which are sent together, if they are ready when the
+/// first one is received. Control packets are combined in as few packets as possible, if the
+/// peer supports it.
+const CONTROL_PACKET_BATCH_SIZE: usize = 256;
+
 /// The default link cost assigned to new peers before their actual cost is known.
 ///
 /// In theory, the best value would be U16::MAX - 1, however this value would take too long to be
@@ -42,11 +51,30 @@ const PACKET_COALESCE_WINDOW: usize = 50;
 /// selected as hop.
 const DEFAULT_LINK_COST: u16 = 1000;
 
+/// Round trip time below which no delay based cost is added to a link. Differences in round trip
+/// time below this are mostly noise.
+const RTT_MIN: Duration = Duration::from_millis(10);
+/// Round trip time at which the delay based cost of a link reaches [`MAX_RTT_PENALTY`].
+const RTT_MAX: Duration = Duration::from_millis(1010);
+/// The maximum delay based cost of a link. Between [`RTT_MIN`] and [`RTT_MAX`], the cost
+/// increases linearly, by 1 per millisecond.
+const MAX_RTT_PENALTY: u16 = 1000;
+
+/// Minimum change of the smoothed link cost before the link cost used for route selection is
+/// updated.
+const LINK_COST_HYSTERESIS_MIN: u16 = 5;
+/// Change of the smoothed link cost, in percent of the current link cost, before the link cost
+/// used for route selection is updated, if this is larger than [`LINK_COST_HYSTERESIS_MIN`].
+const LINK_COST_HYSTERESIS_PERCENT: u32 = 10;
+
 /// Multiplier for smoothed metric calculation of the existing smoothed metric.
 const EXISTING_METRIC_FACTOR: u32 = 9;
 /// Divisor for smoothed metric calcuation of the combined metric
 const TOTAL_METRIC_DIVISOR: u32 = 10;
 
+/// Amount of recent Hello TLVs used to calculate the hello loss of a [`Peer`].
+const HELLO_HISTORY_SIZE: u32 = u16::BITS;
+
 #[derive(Debug, Clone)]
 /// A peer represents a directly connected participant in the network.
 pub struct Peer {
@@ -67,6 +95,7 @@ impl Peer {
         dead_peer_sink: mpsc::Sender<Peer>,
         bytes_written: Arc<AtomicU64>,
         bytes_read: Arc<AtomicU64>,
+        link_keys: &LinkKeys,
     ) -> Result<Self, io::Error> {
         // Wrap connection so we can get access to the counters.
         let connection = connection::Tracked::new(bytes_read, bytes_written, connection);
@@ -85,6 +114,7 @@ impl Peer {
                 to_peer_control,
                 connection_identifier: connection.identifier()?,
                 static_link_cost: connection.static_link_cost()?,
+                remote_address: connection.remote_address(),
                 death_notifier,
                 alive: AtomicBool::new(true),
             }),
@@ -92,12 +122,24 @@ impl Peer {
 
         // Framed for peer
         // Used to send and receive packets from a TCP stream
-        let mut framed = Framed::new(connection, packet::Codec::new());
+        let codec = match link_keys.key_for(peer.inner.remote_address) {
+            Some(key) => packet::Codec::with_auth(key),
+            None => packet::Codec::new(),
+        };
+        let mut framed = Framed::new(connection, codec);
 
         {
             let peer = peer.clone();
 
             tokio::spawn(async move {
+                // Challenge the remote right away, so its control packets are accepted as soon as
+                // possible.
+                if framed.codec().auth_pending() {
+                    if let Err(e) = framed.send(Packet::Auth).await {
+                        error!("Failed to send authentication packet to connection: {e}");
+                    }
+                }
+
                 loop {
                     select! {
                         // Received over the TCP stream
@@ -122,6 +164,32 @@ impl Peer {
                                             }
 
                                         }
+                                        // Never produced by the decoder.
+                                        Packet::ControlPackets(_) => {}
+                                        Packet::Handshake(handshake) => {
+                                            debug!(
+                                                peer = peer.connection_identifier(),
+                                                version = handshake.version,
+                                                "Received handshake",
+                                            );
+                                            peer.inner.state.write().unwrap().handshake = Some(handshake);
+                                        }
+                                        Packet::Auth => {
+                                            if let Err(e) = framed.send(Packet::Auth).await {
+                                                error!("Failed to send authentication packet to connection: {e}");
+                                                break
+                                            }
+                                        }
+                                    }
+
+                                    // Reply with our handshake once the remote indicated it
+                                    // supports it.
+                                    if framed.codec().handshake_pending() {
+                                        let handshake = framed.codec().local_handshake();
+                                        if let Err(e) = framed.send(Packet::Handshake(handshake)).await {
+                                            error!("Failed to send handshake to connection: {e}");
+                                            break
+                                        }
                                     }
                                 }
                                 Some(Err(e)) => {
@@ -163,27 +231,21 @@ impl Peer {
                         }
 
                         Some(packet) = from_routing_control.recv() => {
-                            if let Err(e) = framed.feed(Packet::ControlPacket(packet)).await {
-                                error!("Failed to feed control packet to connection: {e}");
-                                break
-                            }
-
-                            for _ in 1..PACKET_COALESCE_WINDOW {
+                            let mut batch = vec![packet];
+                            while batch.len() < CONTROL_PACKET_BATCH_SIZE {
                                 // There can be 2 cases of errors here, empty channel and no more
                                 // senders. In both cases we don't really care at this point.
                                 if let Ok(packet) = from_routing_control.try_recv() {
-                                    if let Err(e) = framed.feed(Packet::ControlPacket(packet)).await {
-                                        error!("Failed to feed data packet to connection: {e}");
-                                        break
-                                    }
+                                    batch.push(packet);
                                 } else {
-                                    // No packets ready, flush currently buffered ones
+                                    // No packets ready, send the ones we have
                                     break
                                 }
                             }
+                            trace!(packets = batch.len(), "Sending batch of control packets to peer");
 
-                            if let Err(e) = framed.flush().await {
-                                error!("Failed to flush buffered peer connection control packets: {e}");
+                            if let Err(e) = framed.send(Packet::ControlPackets(batch)).await {
+                                error!("Failed to send control packets to connection: {e}");
                                 break
                             }
                         }
@@ -243,23 +305,41 @@ impl Peer {
     /// Get the cost to use the peer, i.e. the additional impact on the [`crate::metric::Metric`]
     /// for using this `Peer`.
     ///
-    /// This is a smoothed value, which is calculated over the recent history of link cost.
+    /// This is a smoothed value, which is calculated over the recent history of link cost. If the
+    /// [`PeerSettings`] of the peer override the cost, the configured cost is used instead. The
+    /// configured penalty is always added. The cost is never infinite.
     pub fn link_cost(&self) -> u16 {
-        self.inner.state.read().unwrap().link_cost + self.inner.static_link_cost
+        let state = self.inner.state.read().unwrap();
+        state
+            .settings
+            .cost
+            .unwrap_or(state.link_cost.saturating_add(self.inner.static_link_cost))
+            .saturating_add(state.settings.penalty)
+            // The infinite metric would make the peer unreachable.
+            .min(u16::MAX - 1)
     }
 
-    /// Sets the link cost based on the provided value.
+    /// Sets the link cost based on a new round trip time sample of the link.
     ///
-    /// The link cost is not set to the given value, but rather to an average of recent values.
-    /// This makes sure short-lived, hard spikes of the link cost of a peer don't influence the
-    /// routing.
-    pub fn set_link_cost(&self, new_link_cost: u16) {
-        // Calculate new link cost by multiplying (i.e. scaling) old and new link cost and
-        // averaging them.
-        let mut inner = self.inner.state.write().unwrap();
-        inner.link_cost = (((inner.link_cost as u32) * EXISTING_METRIC_FACTOR
-            + (new_link_cost as u32) * (TOTAL_METRIC_DIVISOR - EXISTING_METRIC_FACTOR))
-            / TOTAL_METRIC_DIVISOR) as u16;
+    /// The round trip time is converted to a delay based cost, in the same way as [RFC
+    /// 9616](https://datatracker.ietf.org/doc/html/rfc9616#section-4.2). The link cost is not set
+    /// to this cost, but rather to an average of recent values. This makes sure short-lived, hard
+    /// spikes of the link cost of a peer don't influence the routing. Additionally, the link cost
+    /// used for route selection only follows the average once it changed significantly, so small
+    /// fluctuations don't cause routes to flap.
+    pub fn set_link_cost(&self, rtt: Duration) {
+        self.inner.state.write().unwrap().update_link_cost(rtt)
+    }
+
+    /// Apply the link [`PeerSettings`] configured for this `Peer`.
+    pub fn set_settings(&self, settings: PeerSettings) {
+        self.inner.state.write().unwrap().settings = settings;
+    }
+
+    /// Returns `true` if routes through this `Peer` must only be used if there are no routes
+    /// through other peers.
+    pub fn backup(&self) -> bool {
+        self.inner.state.read().unwrap().settings.backup
     }
 
     /// Identifier for the connection to the `Peer`.
@@ -267,6 +347,11 @@ impl Peer {
         &self.inner.connection_identifier
     }
 
+    /// The address of the remote end of the connection to this `Peer`, if there is one.
+    pub fn remote_address(&self) -> Option<SocketAddr> {
+        self.inner.remote_address
+    }
+
     pub fn time_last_received_ihu(&self) -> tokio::time::Instant {
         self.inner.state.read().unwrap().time_last_received_ihu
     }
@@ -275,6 +360,40 @@ impl Peer {
         self.inner.state.write().unwrap().time_last_received_ihu = time
     }
 
+    /// Record that a Hello TLV was sent to this `Peer` at the given time. If the previous Hello
+    /// has not been answered yet, it is considered lost.
+    pub fn record_hello_sent(&self, time: tokio::time::Instant) {
+        self.inner
+            .state
+            .write()
+            .unwrap()
+            .link_quality
+            .hello_sent(time)
+    }
+
+    /// Record that an IHU TLV was received from this `Peer` at the given time. If there is an
+    /// outstanding Hello, the round trip time of the exchange is returned.
+    pub fn record_ihu_received(&self, time: tokio::time::Instant) -> Option<Duration> {
+        self.inner
+            .state
+            .write()
+            .unwrap()
+            .link_quality
+            .ihu_received(time)
+    }
+
+    /// Get the [`LinkQuality`] of the connection to this `Peer`, as measured by the Hello/IHU
+    /// exchanges.
+    pub fn link_quality(&self) -> LinkQuality {
+        self.inner.state.read().unwrap().link_quality.link_quality()
+    }
+
+    /// Get the [`Handshake`] the remote sent, if any. Nodes running an older version don't send a
+    /// handshake, in which case only the features they indicate in the packet headers are used.
+    pub fn handshake(&self) -> Option<Handshake> {
+        self.inner.state.read().unwrap().handshake
+    }
+
     /// Notify this `Peer` that it died.
     ///
     /// While some [`Connection`] types can immediately detect that the connection itself is
@@ -346,6 +465,8 @@ struct PeerInner {
     /// Static cost of using this link, to be added to the announced metric for routes through this
     /// Peer.
     static_link_cost: u16,
+    /// Address of the remote end of the connection, if it is backed by a network socket.
+    remote_address: Option<SocketAddr>,
     /// Channel to notify the connection of its decease.
     death_notifier: Arc<Notify>,
     /// Keep track if the connection is alive.
@@ -356,8 +477,91 @@ struct PeerInner {
 struct PeerState {
     hello_seqno: SeqNo,
     time_last_received_hello: tokio::time::Instant,
+    /// Link cost used for route selection.
     link_cost: u16,
+    /// Average of the recent delay based costs of the link.
+    smoothed_link_cost: u16,
     time_last_received_ihu: tokio::time::Instant,
+    link_quality: LinkQualityTracker,
+    /// The [`Handshake`] of the remote, if it sent one.
+    handshake: Option<Handshake>,
+    /// Link settings configured by the operator.
+    settings: PeerSettings,
+}
+
+/// Tracks the round trip time, jitter and loss of the Hello/IHU exchanges with a [`Peer`].
+///
+/// Since a peer replies to every Hello with an IHU, and there is only a single Hello in flight at
+/// any time, the round trip time is the time between sending a Hello and receiving the next IHU.
+/// The smoothed round trip time and jitter are calculated in the same way as the smoothed RTT and
+/// RTT variation in [RFC 6298](https://datatracker.ietf.org/doc/html/rfc6298#section-2).
+#[derive(Debug, Default)]
+struct LinkQualityTracker {
+    /// Time the last Hello was sent, if it has not been answered yet.
+    hello_outstanding: Option<tokio::time::Instant>,
+    /// Outcome of the most recent Hellos, the lowest bit is the most recent one. A set bit means
+    /// the Hello was answered.
+    hello_history: u16,
+    /// Amount of Hellos tracked in `hello_history`.
+    hello_history_len: u32,
+    /// Smoothed round trip time.
+    srtt: Option<Duration>,
+    /// Smoothed mean deviation of the round trip time.
+    rtt_var: Duration,
+}
+
+impl LinkQualityTracker {
+    /// Record a new Hello, sent at the given time.
+    fn hello_sent(&mut self, time: tokio::time::Instant) {
+        if self.hello_outstanding.replace(time).is_some() {
+            self.push_hello_outcome(false);
+        }
+    }
+
+    /// Record an IHU, received at the given time. Returns the round trip time if there was an
+    /// outstanding Hello.
+    fn ihu_received(&mut self, time: tokio::time::Instant) -> Option<Duration> {
+        let sent = self.hello_outstanding.take()?;
+        let rtt = time.duration_since(sent);
+        self.push_hello_outcome(true);
+
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rtt_var = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rtt_var = (self.rtt_var * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+
+        Some(rtt)
+    }
+
+    /// Add the outcome of a Hello to the history.
+    fn push_hello_outcome(&mut self, answered: bool) {
+        self.hello_history = (self.hello_history << 1) | answered as u16;
+        self.hello_history_len = (self.hello_history_len + 1).min(HELLO_HISTORY_SIZE);
+    }
+
+    /// Get the current [`LinkQuality`].
+    fn link_quality(&self) -> LinkQuality {
+        let hello_loss = if self.hello_history_len == 0 {
+            None
+        } else {
+            let mask = (u32::MAX >> (u32::BITS - self.hello_history_len)) as u16;
+            let lost = self.hello_history_len - (self.hello_history & mask).count_ones();
+            Some((lost * 100 / self.hello_history_len) as u8)
+        };
+
+        LinkQuality {
+            rtt: self.srtt,
+            jitter: self.srtt.map(|_| self.rtt_var),
+            hello_loss,
+        }
+    }
 }
 
 impl PeerState {
@@ -374,8 +578,161 @@ impl PeerState {
         Self {
             hello_seqno,
             link_cost,
+            smoothed_link_cost: link_cost,
             time_last_received_ihu,
             time_last_received_hello,
+            link_quality: LinkQualityTracker::default(),
+            handshake: None,
+            settings: PeerSettings::default(),
+        }
+    }
+
+    /// Update the link cost with a new round trip time sample.
+    fn update_link_cost(&mut self, rtt: Duration) {
+        // Calculate new link cost by multiplying (i.e. scaling) old and new link cost and
+        // averaging them.
+        self.smoothed_link_cost = (((self.smoothed_link_cost as u32) * EXISTING_METRIC_FACTOR
+            + (rtt_cost(rtt) as u32) * (TOTAL_METRIC_DIVISOR - EXISTING_METRIC_FACTOR))
+            / TOTAL_METRIC_DIVISOR) as u16;
+
+        let threshold = LINK_COST_HYSTERESIS_MIN
+            .max((self.link_cost as u32 * LINK_COST_HYSTERESIS_PERCENT / 100) as u16);
+        if self.smoothed_link_cost.abs_diff(self.link_cost) >= threshold {
+            self.link_cost = self.smoothed_link_cost;
+        }
+    }
+}
+
+/// Calculate the delay based cost of a link with the given round trip time.
+fn rtt_cost(rtt: Duration) -> u16 {
+    if rtt <= RTT_MIN {
+        0
+    } else if rtt >= RTT_MAX {
+        MAX_RTT_PENALTY
+    } else {
+        ((rtt - RTT_MIN).as_micros() * MAX_RTT_PENALTY as u128 / (RTT_MAX - RTT_MIN).as_micros())
+            as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{rtt_cost, LinkQualityTracker, PeerState, DEFAULT_LINK_COST, MAX_RTT_PENALTY};
+
+    #[test]
+    fn no_link_quality_without_exchanges() {
+        let tracker = LinkQualityTracker::default();
+        let quality = tracker.link_quality();
+
+        assert_eq!(quality.rtt, None);
+        assert_eq!(quality.jitter, None);
+        assert_eq!(quality.hello_loss, None);
+    }
+
+    #[test]
+    fn rtt_and_jitter_are_smoothed() {
+        let mut tracker = LinkQualityTracker::default();
+        let start = tokio::time::Instant::now();
+
+        tracker.hello_sent(start);
+        assert_eq!(
+            tracker.ihu_received(start + Duration::from_millis(80)),
+            Some(Duration::from_millis(80))
+        );
+        let quality = tracker.link_quality();
+        assert_eq!(quality.rtt, Some(Duration::from_millis(80)));
+        assert_eq!(quality.jitter, Some(Duration::from_millis(40)));
+
+        let start = start + Duration::from_secs(20);
+        tracker.hello_sent(start);
+        tracker.ihu_received(start + Duration::from_millis(160));
+        let quality = tracker.link_quality();
+        assert_eq!(quality.rtt, Some(Duration::from_millis(90)));
+        assert_eq!(quality.jitter, Some(Duration::from_millis(50)));
+        assert_eq!(quality.hello_loss, Some(0));
+    }
+
+    #[test]
+    fn unanswered_hellos_are_lost() {
+        let mut tracker = LinkQualityTracker::default();
+        let start = tokio::time::Instant::now();
+
+        // 1 answered hello, then 3 lost ones, and a final answered one.
+        tracker.hello_sent(start);
+        tracker.ihu_received(start + Duration::from_millis(10));
+        for _ in 0..4 {
+            tracker.hello_sent(start);
+        }
+        tracker.ihu_received(start + Duration::from_millis(10));
+
+        assert_eq!(tracker.link_quality().hello_loss, Some(60));
+
+        // An IHU without outstanding hello is ignored.
+        assert_eq!(tracker.ihu_received(start), None);
+        assert_eq!(tracker.link_quality().hello_loss, Some(60));
+    }
+
+    #[test]
+    fn hello_history_is_bounded() {
+        let mut tracker = LinkQualityTracker::default();
+        let start = tokio::time::Instant::now();
+
+        for _ in 0..100 {
+            tracker.hello_sent(start);
+        }
+        tracker.ihu_received(start);
+        assert_eq!(tracker.link_quality().hello_loss, Some(93));
+
+        for _ in 0..16 {
+            tracker.hello_sent(start);
+            tracker.ihu_received(start);
+        }
+        assert_eq!(tracker.link_quality().hello_loss, Some(0));
+    }
+
+    #[test]
+    fn rtt_cost_is_bounded() {
+        assert_eq!(rtt_cost(Duration::from_millis(2)), 0);
+        assert_eq!(rtt_cost(Duration::from_millis(10)), 0);
+        assert_eq!(rtt_cost(Duration::from_millis(110)), 100);
+        assert_eq!(rtt_cost(Duration::from_millis(1010)), MAX_RTT_PENALTY);
+        assert_eq!(rtt_cost(Duration::from_secs(5)), MAX_RTT_PENALTY);
+    }
+
+    #[test]
+    fn link_cost_converges_to_rtt_cost() {
+        let mut state = PeerState::new();
+        assert_eq!(state.link_cost, DEFAULT_LINK_COST);
+
+        for _ in 0..100 {
+            state.update_link_cost(Duration::from_millis(60));
+        }
+
+        assert!(state.link_cost.abs_diff(50) <= 10);
+    }
+
+    #[test]
+    fn link_cost_ignores_small_fluctuations() {
+        let mut state = PeerState::new();
+        for _ in 0..100 {
+            state.update_link_cost(Duration::from_millis(210));
+        }
+        let link_cost = state.link_cost;
+
+        // Alternate between slightly better and slightly worse samples, the smoothed cost stays
+        // within the hysteresis, so the link cost used for routing doesn't change.
+        for i in 0..100 {
+            let rtt = if i % 2 == 0 { 195 } else { 225 };
+            state.update_link_cost(Duration::from_millis(rtt));
+            assert_eq!(state.link_cost, link_cost);
+        }
+
+        // A sustained change is picked up.
+        for _ in 0..50 {
+            state.update_link_cost(Duration::from_millis(410));
         }
+        assert!(state.link_cost > link_cost + 100);
     }
 }
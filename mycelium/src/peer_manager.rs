@@ -1,5 +1,6 @@
 use crate::connection::Quic;
 use crate::endpoint::{Endpoint, Protocol};
+use crate::link_auth::{LinkKey, LinkKeys};
 use crate::metrics::Metrics;
 use crate::peer::{Peer, PeerRef};
 use crate::router::Router;
@@ -30,6 +31,10 @@ use tokio::task::AbortHandle;
 use tokio::time::MissedTickBehavior;
 use tracing::{debug, error, info, instrument, trace, warn};
 
+pub use cache::PeerCache;
+
+mod cache;
+
 /// Magic bytes to identify a multicast UDP packet used in link local peer discovery.
 const MYCELIUM_MULTICAST_DISCOVERY_MAGIC: &[u8; 8] = b"mycelium";
 /// Size of a peer discovery beacon.
@@ -53,6 +58,8 @@ const INBOUND_QUIC_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 /// these in parralel. For now, 10 in parallel should be sufficient, though this can be
 /// increased/decreased based on observations.
 const MAX_INBOUND_CONCURRENT_QUICK_HANDSHAKES: usize = 10;
+/// The time between writing changes in the peer cache to disk.
+const PEER_CACHE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
 
 /// The PeerManager creates new peers by connecting to configured addresses, and setting up the
 /// connection. Once a connection is established, the created [`Peer`] is handed over to the
@@ -75,10 +82,26 @@ pub enum PeerType {
     Inbound,
 }
 
+/// Settings which change the cost of the link to a peer, as configured by the operator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PeerSettings {
+    /// Use this cost for the link instead of the cost measured from the round trip time and the
+    /// kind of connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<u16>,
+    /// Cost added to the cost of the link.
+    pub penalty: u16,
+    /// Only use routes through this peer if there is no route through any other peer.
+    pub backup: bool,
+}
+
 /// Local info about a peer.
 struct PeerInfo {
     /// Details how we found out about this peer.
     pt: PeerType,
+    /// Link settings applied to connections with this peer.
+    settings: PeerSettings,
     /// Are we currently connecting to this peer?
     connecting: bool,
     /// The [`PeerRef`] used to check liveliness.
@@ -126,6 +149,40 @@ pub struct PeerStats {
     pub tx_bytes: u64,
     /// Amount of bytes received from this [`Peer`].
     pub rx_bytes: u64,
+    /// Smoothed round trip time to this [`Peer`] in milliseconds, if it is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<u64>,
+    /// Jitter of the round trip time to this [`Peer`] in milliseconds, if it is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<u64>,
+    /// Percentage of recent Hello TLVs sent to this [`Peer`] which were not answered, if any
+    /// Hello has been sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hello_loss: Option<u8>,
+    /// Highest protocol version supported by this [`Peer`], if it sent a handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u8>,
+    /// Names of the optional protocol features supported by this [`Peer`], if it sent a
+    /// handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+    /// Cost of the link to this [`Peer`] used for route selection, if it is connected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_cost: Option<u16>,
+    /// Link [`settings`](PeerSettings) configured for this [`Peer`].
+    #[serde(default)]
+    pub settings: PeerSettings,
+}
+
+/// Quality of the link to a [`Peer`], as measured by the Hello/IHU exchanges with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkQuality {
+    /// Smoothed round trip time, if at least one Hello was answered.
+    pub rtt: Option<Duration>,
+    /// Smoothed mean deviation of the round trip time, if at least one Hello was answered.
+    pub jitter: Option<Duration>,
+    /// Percentage of recent Hellos which were not answered, if any Hello has been sent.
+    pub hello_loss: Option<u8>,
 }
 
 impl PeerInfo {
@@ -164,6 +221,10 @@ struct Inner<M> {
     private_network_config: Option<(String, [u8; 32])>,
     metrics: M,
     firewall_mark: Option<u32>,
+    /// Cache of peers learned at runtime, if one is configured.
+    peer_cache: Option<Mutex<PeerCache>>,
+    /// Keys used to authenticate control packets on links with peers.
+    link_keys: LinkKeys,
 }
 
 impl<M> PeerManager<M>
@@ -181,8 +242,15 @@ where
         private_network_config: Option<(String, PrivateNetworkKey)>,
         metrics: M,
         firewall_mark: Option<u32>,
+        peer_cache: Option<PeerCache>,
+        link_keys: Vec<LinkKey>,
+        peer_settings: HashMap<Endpoint, PeerSettings>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let is_private_net = private_network_config.is_some();
+        let link_keys = LinkKeys::new(
+            &link_keys,
+            private_network_config.as_ref().map(|(_, key)| key),
+        );
 
         // Currently we don't support Quic when a private network is used.
         let quic_socket = if !is_private_net {
@@ -199,40 +267,68 @@ where
             None
         };
 
+        let mut peers = static_peers_sockets
+            .into_iter()
+            // These peers are not alive, but we say they are because the reconnect
+            // loop will perform the actual check and figure out they are dead, then
+            // (re)connect.
+            .map(|s| {
+                (
+                    s,
+                    PeerInfo {
+                        pt: PeerType::Static,
+                        settings: peer_settings.get(&s).copied().unwrap_or_default(),
+                        connecting: false,
+                        pr: PeerRef::new(),
+                        connection_attempts: 0,
+                        con_traffic: ConnectionTraffic {
+                            tx_bytes: Arc::new(AtomicU64::new(0)),
+                            rx_bytes: Arc::new(AtomicU64::new(0)),
+                        },
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        // Seed the peers we learned during previous runs. Statically configured peers take
+        // precedence.
+        if let Some(peer_cache) = &peer_cache {
+            for (endpoint, entry) in peer_cache.entries() {
+                if let Entry::Vacant(e) = peers.entry(*endpoint) {
+                    debug!(peer.endpoint=%endpoint, peer.successes=entry.successes, "Adding cached peer");
+                    e.insert(PeerInfo {
+                        pt: entry.pt.clone(),
+                        // Configured settings take precedence over the cached ones.
+                        settings: peer_settings
+                            .get(endpoint)
+                            .copied()
+                            .unwrap_or(entry.settings),
+                        connecting: false,
+                        pr: PeerRef::new(),
+                        connection_attempts: 0,
+                        con_traffic: ConnectionTraffic {
+                            tx_bytes: Arc::new(AtomicU64::new(0)),
+                            rx_bytes: Arc::new(AtomicU64::new(0)),
+                        },
+                    });
+                }
+            }
+        }
+
         // Set the initially configured peer count in metrics.
-        metrics.peer_manager_known_peers(static_peers_sockets.len());
+        metrics.peer_manager_known_peers(peers.len());
 
         let mut peer_manager = PeerManager {
             inner: Arc::new(Inner {
                 router: Mutex::new(router),
-                peers: Mutex::new(
-                    static_peers_sockets
-                        .into_iter()
-                        // These peers are not alive, but we say they are because the reconnect
-                        // loop will perform the actual check and figure out they are dead, then
-                        // (re)connect.
-                        .map(|s| {
-                            (
-                                s,
-                                PeerInfo {
-                                    pt: PeerType::Static,
-                                    connecting: false,
-                                    pr: PeerRef::new(),
-                                    connection_attempts: 0,
-                                    con_traffic: ConnectionTraffic {
-                                        tx_bytes: Arc::new(AtomicU64::new(0)),
-                                        rx_bytes: Arc::new(AtomicU64::new(0)),
-                                    },
-                                },
-                            )
-                        })
-                        .collect(),
-                ),
+                peers: Mutex::new(peers),
                 tcp_listen_port,
                 quic_socket,
                 private_network_config,
                 metrics,
                 firewall_mark,
+                peer_cache: peer_cache.map(Mutex::new),
+                link_keys,
             }),
             abort_handles: vec![],
         };
@@ -266,25 +362,61 @@ where
             peer_manager.abort_handles.push(handle.abort_handle());
         }
 
+        if peer_manager.inner.peer_cache.is_some() {
+            let handle = tokio::spawn(peer_manager.inner.clone().flush_peer_cache());
+            peer_manager.abort_handles.push(handle.abort_handle());
+        }
+
         Ok(peer_manager)
     }
 
-    /// Add a new peer to the system.
+    /// Add a new peer to the system, with the given link [`settings`](PeerSettings).
     ///
     /// The peer starts of as a dead peer, and connecting is handled in the reconnect loop.
     ///
     /// # Errors
     ///
     /// This function returns an error if the [`Endpoint`] is already known.
-    pub fn add_peer(&self, peer: Endpoint) -> Result<(), PeerExists> {
+    pub fn add_peer(&self, peer: Endpoint, settings: PeerSettings) -> Result<(), PeerExists> {
+        self.insert_peer(peer, settings, true)
+    }
+
+    /// Add a new peer from the configuration, with the given link [`settings`](PeerSettings).
+    /// Unlike [`PeerManager::add_peer`], the peer is not recorded in the peer cache, since it is
+    /// added from the configuration again when the node restarts.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the [`Endpoint`] is already known.
+    pub fn add_static_peer(
+        &self,
+        peer: Endpoint,
+        settings: PeerSettings,
+    ) -> Result<(), PeerExists> {
+        self.insert_peer(peer, settings, false)
+    }
+
+    fn insert_peer(
+        &self,
+        peer: Endpoint,
+        settings: PeerSettings,
+        cache: bool,
+    ) -> Result<(), PeerExists> {
         let mut peer_map = self.inner.peers.lock().unwrap();
         if peer_map.contains_key(&peer) {
             return Err(PeerExists);
         }
+        if let Some(peer_cache) = self.inner.peer_cache.as_ref().filter(|_| cache) {
+            peer_cache
+                .lock()
+                .unwrap()
+                .insert(peer, PeerType::Static, settings);
+        }
         peer_map.insert(
             peer,
             PeerInfo {
                 pt: PeerType::Static,
+                settings,
                 connecting: false,
                 pr: PeerRef::new(),
                 connection_attempts: 0,
@@ -298,6 +430,30 @@ where
         Ok(())
     }
 
+    /// Change the link [`settings`](PeerSettings) of a peer. If the peer is connected, the
+    /// settings apply to the existing connection immediately.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no peer identified by the given [`Endpoint`].
+    pub fn set_peer_settings(
+        &self,
+        endpoint: &Endpoint,
+        settings: PeerSettings,
+    ) -> Result<(), PeerNotFound> {
+        let mut peer_map = self.inner.peers.lock().unwrap();
+        let pi = peer_map.get_mut(endpoint).ok_or(PeerNotFound)?;
+        pi.settings = settings;
+        if let Some(peer_cache) = &self.inner.peer_cache {
+            peer_cache.lock().unwrap().set_settings(endpoint, settings);
+        }
+        if let Some(peer) = pi.pr.upgrade() {
+            peer.set_settings(settings);
+        }
+
+        Ok(())
+    }
+
     /// Delete a peer from the system.
     ///
     /// The peer will be disconnected if it is currently connected.
@@ -307,6 +463,9 @@ where
     /// Returns an error if there is no peer identified by the given [`Endpoint`].
     pub fn delete_peer(&self, endpoint: &Endpoint) -> Result<(), PeerNotFound> {
         let mut peer_map = self.inner.peers.lock().unwrap();
+        if let Some(peer_cache) = &self.inner.peer_cache {
+            peer_cache.lock().unwrap().remove(endpoint);
+        }
         peer_map.remove(endpoint).ok_or(PeerNotFound).map(|pi| {
             // Make sure we kill the peer connection if one exists
             if let Some(peer) = pi.pr.upgrade() {
@@ -327,12 +486,26 @@ where
             } else {
                 ConnectionState::Dead
             };
+            let peer = peer_info.pr.upgrade();
+            let link_quality = peer
+                .as_ref()
+                .map(|peer| peer.link_quality())
+                .unwrap_or_default();
+            let link_cost = peer.as_ref().map(|peer| peer.link_cost());
+            let handshake = peer.and_then(|peer| peer.handshake());
             pi.push(PeerStats {
                 endpoint: *endpoint,
                 pt: peer_info.pt.clone(),
                 connection_state,
                 tx_bytes: peer_info.written(),
                 rx_bytes: peer_info.read(),
+                rtt: link_quality.rtt.map(|rtt| rtt.as_millis() as u64),
+                jitter: link_quality.jitter.map(|jitter| jitter.as_millis() as u64),
+                hello_loss: link_quality.hello_loss,
+                protocol_version: handshake.map(|h| h.version),
+                capabilities: handshake.map(|h| h.capabilities.names().map(String::from).collect()),
+                link_cost,
+                settings: peer_info.settings,
             });
         }
         pi
@@ -345,6 +518,12 @@ impl<M> Drop for PeerManager<M> {
         for ah in &self.abort_handles {
             ah.abort();
         }
+        // Make sure the latest changes to the peer cache are persisted.
+        if let Some(peer_cache) = &self.inner.peer_cache {
+            if let Err(e) = peer_cache.lock().unwrap().flush() {
+                error!(err=%e, "Failed to write peer cache");
+            }
+        }
     }
 }
 
@@ -378,10 +557,15 @@ where
                             // We did find a new Peer, insert into router and keep track of it
                             // Use fully qualified call to aid compiler in type inference.
                             pi.pr = Peer::refer(&peer);
+                            peer.set_settings(pi.settings);
                             self.router.lock().unwrap().add_peer_interface(peer);
 
                             // We successfully connected, reset the connection_attempts counter to 0
                             pi.connection_attempts = 0;
+
+                            if let Some(peer_cache) = &self.peer_cache {
+                                peer_cache.lock().unwrap().record_success(&endpoint);
+                            }
                         } else {
                             // Only log with error level on the first connection failure, to avoid spamming the logs
                             if pi.connection_attempts == 0 {
@@ -424,6 +608,35 @@ where
         }
     }
 
+    /// Periodically write changes in the peer cache to disk.
+    async fn flush_peer_cache(self: Arc<Self>) {
+        let Some(peer_cache) = &self.peer_cache else {
+            return;
+        };
+
+        let mut flush_interval = tokio::time::interval(PEER_CACHE_FLUSH_INTERVAL);
+        flush_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            flush_interval.tick().await;
+
+            let (path, content) = {
+                let mut peer_cache = peer_cache.lock().unwrap();
+                let Some(content) = peer_cache.take_changes() else {
+                    continue;
+                };
+                (peer_cache.path().to_path_buf(), content)
+            };
+            let res = tokio::task::spawn_blocking(move || cache::write_cache(&path, &content))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            if let Err(e) = res {
+                error!(err=%e, "Failed to write peer cache");
+                peer_cache.lock().unwrap().mark_dirty();
+            }
+        }
+    }
+
     /// Create a new connection to a remote peer
     #[instrument(skip_all, fields(endpoint.proto=%endpoint.proto(), endpoint.address=%endpoint.address()))]
     async fn connect_peer(
@@ -535,6 +748,7 @@ where
                             dead_peer_sink,
                             ct.tx_bytes,
                             ct.rx_bytes,
+                            &self.link_keys,
                         )
                     } else {
                         Peer::new(
@@ -544,6 +758,7 @@ where
                             dead_peer_sink,
                             ct.tx_bytes,
                             ct.rx_bytes,
+                            &self.link_keys,
                         )
                     }
                 };
@@ -556,6 +771,7 @@ where
                     dead_peer_sink,
                     ct.tx_bytes,
                     ct.rx_bytes,
+                    &self.link_keys,
                 );
 
                 match res {
@@ -635,6 +851,7 @@ where
                                 dead_peer_sink,
                                 ct.tx_bytes,
                                 ct.rx_bytes,
+                                &self.link_keys,
                             )
                         };
                         match res {
@@ -750,6 +967,7 @@ where
                                 dead_peer_sink.clone(),
                                 tx_bytes.clone(),
                                 rx_bytes.clone(),
+                                &self.link_keys,
                             )
                         } else {
                             Peer::new(
@@ -759,6 +977,7 @@ where
                                 dead_peer_sink.clone(),
                                 tx_bytes.clone(),
                                 rx_bytes.clone(),
+                                &self.link_keys,
                             )
                         };
 
@@ -770,6 +989,7 @@ where
                             dead_peer_sink.clone(),
                             tx_bytes.clone(),
                             rx_bytes.clone(),
+                            &self.link_keys,
                         );
 
                         let new_peer = match new_peer {
@@ -864,6 +1084,7 @@ where
                             dead_peer_sink.clone(),
                             tx_bytes.clone(),
                             rx_bytes.clone(),
+                            &self.link_keys,
                         ) {
                             Ok(peer) => peer,
                             Err(e) => {
@@ -917,8 +1138,18 @@ where
         }
         // Only if we don't know it yet.
         if let Entry::Vacant(e) = peers.entry(endpoint) {
+            if discovery_type == PeerType::LinkLocalDiscovery {
+                if let Some(peer_cache) = &self.peer_cache {
+                    peer_cache.lock().unwrap().insert(
+                        endpoint,
+                        PeerType::LinkLocalDiscovery,
+                        PeerSettings::default(),
+                    );
+                }
+            }
             e.insert(PeerInfo {
                 pt: discovery_type,
+                settings: PeerSettings::default(),
                 connecting: false,
                 pr: if let Some(p) = &peer {
                     p.refer()
@@ -940,6 +1171,7 @@ where
                 endpoint,
                 PeerInfo {
                     pt: discovery_type,
+                    settings: PeerSettings::default(),
                     connecting: false,
                     pr: if let Some(p) = &peer {
                         p.refer()
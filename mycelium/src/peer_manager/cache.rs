@@ -0,0 +1,385 @@
+//! Persistent cache of peers learned while the node is running.
+//!
+//! Peers added through the API and peers found through link local discovery are recorded in the
+//! cache together with the last time a connection to them was established and the amount of
+//! successful connections; peers from the configuration are not. When the node starts, the cached
+//! peers are added to the set of known peers again. Entries which have not been seen for
+//! [`PEER_CACHE_EXPIRY`] are dropped.
+//!
+//! The cache is a plain text file, with one peer per line in the format
+//! `<last seen> <successes> <type> <endpoint> [settings]`, where `last seen` is a unix timestamp in
+//! seconds. The link settings of the peer follow as `cost=<cost>`, `penalty=<penalty>` and
+//! `backup`, if they are not the default.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{debug, warn};
+
+use crate::endpoint::Endpoint;
+
+use super::{PeerSettings, PeerType};
+
+/// Amount of time after which a peer which has not been seen is removed from the cache.
+const PEER_CACHE_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+/// Header written at the start of the cache file.
+const PEER_CACHE_HEADER: &str = "# mycelium peer cache";
+
+/// A persistent cache of peers added at runtime.
+pub struct PeerCache {
+    path: PathBuf,
+    entries: HashMap<Endpoint, CacheEntry>,
+    /// Set if the entries changed since the cache was last written to disk.
+    dirty: bool,
+}
+
+/// Info about a single cached peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct CacheEntry {
+    /// How the peer was learned.
+    pub pt: PeerType,
+    /// Last time a connection to the peer was established, or the time it was added if there
+    /// never was a connection.
+    pub last_seen: SystemTime,
+    /// Amount of successful connections to the peer.
+    pub successes: u64,
+    /// Link settings of the peer.
+    pub settings: PeerSettings,
+}
+
+impl PeerCache {
+    /// Open the peer cache at the given path. If the file does not exist yet, an empty cache is
+    /// returned, and the file is created the first time the cache is written.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut cache = PeerCache {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+            dirty: false,
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e),
+        };
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match decode_line(line) {
+                Some((endpoint, entry)) => {
+                    cache.entries.insert(endpoint, entry);
+                }
+                None => warn!(line, "Skipping malformed peer cache entry"),
+            }
+        }
+
+        cache.expire(SystemTime::now());
+        debug!(peers = cache.entries.len(), "Loaded peer cache");
+
+        Ok(cache)
+    }
+
+    /// Iterate over all cached peers.
+    pub(super) fn entries(&self) -> impl Iterator<Item = (&Endpoint, &CacheEntry)> {
+        self.entries.iter()
+    }
+
+    /// Record a new peer with the given link settings. Nothing happens if the peer is already
+    /// cached.
+    pub(super) fn insert(&mut self, endpoint: Endpoint, pt: PeerType, settings: PeerSettings) {
+        self.entries.entry(endpoint).or_insert_with(|| {
+            self.dirty = true;
+            CacheEntry {
+                pt,
+                last_seen: SystemTime::now(),
+                successes: 0,
+                settings,
+            }
+        });
+    }
+
+    /// Change the link settings of a peer. Nothing happens if the peer is not cached.
+    pub(super) fn set_settings(&mut self, endpoint: &Endpoint, settings: PeerSettings) {
+        if let Some(entry) = self.entries.get_mut(endpoint) {
+            if entry.settings != settings {
+                entry.settings = settings;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Remove a peer from the cache.
+    pub(super) fn remove(&mut self, endpoint: &Endpoint) {
+        if self.entries.remove(endpoint).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Record a successful connection to a peer. Nothing happens if the peer is not cached.
+    pub(super) fn record_success(&mut self, endpoint: &Endpoint) {
+        if let Some(entry) = self.entries.get_mut(endpoint) {
+            entry.last_seen = SystemTime::now();
+            entry.successes += 1;
+            self.dirty = true;
+        }
+    }
+
+    /// Write the cache to disk, if it changed since it was last written. Expired entries are
+    /// removed first.
+    pub(super) fn flush(&mut self) -> io::Result<()> {
+        let Some(content) = self.take_changes() else {
+            return Ok(());
+        };
+        if let Err(e) = write_cache(&self.path, &content) {
+            self.dirty = true;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Encode the cache if it changed since it was last written, and mark it as written. Expired
+    /// entries are removed first. The caller must write the content to [`PeerCache::path`], and
+    /// call [`PeerCache::mark_dirty`] if that fails.
+    pub(super) fn take_changes(&mut self) -> Option<String> {
+        self.expire(SystemTime::now());
+        if !self.dirty {
+            return None;
+        }
+
+        let mut content = String::from(PEER_CACHE_HEADER);
+        content.push('\n');
+        for (endpoint, entry) in &self.entries {
+            content.push_str(&encode_line(endpoint, entry));
+            content.push('\n');
+        }
+        self.dirty = false;
+
+        Some(content)
+    }
+
+    /// Mark the cache as changed, so it is written again on the next flush.
+    pub(super) fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// The path of the cache file.
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove all entries which have not been seen for longer than [`PEER_CACHE_EXPIRY`].
+    fn expire(&mut self, now: SystemTime) {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| {
+            now.duration_since(entry.last_seen)
+                .map(|age| age <= PEER_CACHE_EXPIRY)
+                // Last seen is in the future, likely because the clock changed. Keep the entry.
+                .unwrap_or(true)
+        });
+        if self.entries.len() != before {
+            debug!(
+                expired = before - self.entries.len(),
+                "Removed expired entries from peer cache"
+            );
+            self.dirty = true;
+        }
+    }
+}
+
+/// Write the encoded cache to disk.
+pub(super) fn write_cache(path: &Path, content: &str) -> io::Result<()> {
+    // Write to a temporary file first, so a crash while writing does not corrupt the existing
+    // cache.
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Encode a cache entry as a single line.
+fn encode_line(endpoint: &Endpoint, entry: &CacheEntry) -> String {
+    let mut line = format!(
+        "{} {} {} {}://{}",
+        entry
+            .last_seen
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        entry.successes,
+        match entry.pt {
+            PeerType::LinkLocalDiscovery => "discovered",
+            // Inbound peers are never cached, since we can't connect to them.
+            PeerType::Static | PeerType::Inbound => "static",
+        },
+        endpoint.proto().to_string().to_lowercase(),
+        endpoint.address(),
+    );
+    if let Some(cost) = entry.settings.cost {
+        line.push_str(&format!(" cost={cost}"));
+    }
+    if entry.settings.penalty != 0 {
+        line.push_str(&format!(" penalty={}", entry.settings.penalty));
+    }
+    if entry.settings.backup {
+        line.push_str(" backup");
+    }
+
+    line
+}
+
+/// Decode a single line of the cache file. Returns [`None`] if the line is malformed.
+fn decode_line(line: &str) -> Option<(Endpoint, CacheEntry)> {
+    let mut parts = line.split_whitespace();
+    let last_seen = UNIX_EPOCH + Duration::from_secs(parts.next()?.parse().ok()?);
+    let successes = parts.next()?.parse().ok()?;
+    let pt = match parts.next()? {
+        "static" => PeerType::Static,
+        "discovered" => PeerType::LinkLocalDiscovery,
+        _ => return None,
+    };
+    let endpoint = parts.next()?.parse().ok()?;
+    let mut settings = PeerSettings::default();
+    for part in parts {
+        match part.split_once('=') {
+            Some(("cost", cost)) => settings.cost = Some(cost.parse().ok()?),
+            Some(("penalty", penalty)) => settings.penalty = penalty.parse().ok()?,
+            None if part == "backup" => settings.backup = true,
+            _ => return None,
+        }
+    }
+
+    Some((
+        endpoint,
+        CacheEntry {
+            pt,
+            last_seen,
+            successes,
+            settings,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::endpoint::Endpoint;
+
+    use super::{PeerCache, PeerSettings, PeerType, PEER_CACHE_EXPIRY};
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mycelium-peer-cache-{name}-{}",
+            rand::random::<u64>()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn peers_survive_reopen() {
+        let path = test_path("reopen");
+        let static_peer: Endpoint = "tcp://192.0.2.1:9651".parse().unwrap();
+        let discovered_peer: Endpoint = "quic://[fe80::1%2]:9651".parse().unwrap();
+
+        {
+            let mut cache = PeerCache::open(&path).unwrap();
+            assert_eq!(cache.entries().count(), 0);
+            cache.insert(static_peer, PeerType::Static, PeerSettings::default());
+            cache.insert(
+                discovered_peer,
+                PeerType::LinkLocalDiscovery,
+                PeerSettings::default(),
+            );
+            cache.set_settings(
+                &static_peer,
+                PeerSettings {
+                    cost: Some(50),
+                    penalty: 10,
+                    backup: true,
+                },
+            );
+            cache.record_success(&static_peer);
+            cache.record_success(&static_peer);
+            cache.flush().unwrap();
+        }
+
+        let cache = PeerCache::open(&path).unwrap();
+        assert_eq!(cache.entries().count(), 2);
+        let entry = &cache.entries[&static_peer];
+        assert_eq!(entry.pt, PeerType::Static);
+        assert_eq!(entry.successes, 2);
+        assert_eq!(
+            entry.settings,
+            PeerSettings {
+                cost: Some(50),
+                penalty: 10,
+                backup: true,
+            }
+        );
+        let entry = &cache.entries[&discovered_peer];
+        assert_eq!(entry.pt, PeerType::LinkLocalDiscovery);
+        assert_eq!(entry.successes, 0);
+        assert_eq!(entry.settings, PeerSettings::default());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn removed_peers_are_forgotten() {
+        let path = test_path("remove");
+        let peer: Endpoint = "tcp://192.0.2.1:9651".parse().unwrap();
+
+        {
+            let mut cache = PeerCache::open(&path).unwrap();
+            cache.insert(peer, PeerType::Static, PeerSettings::default());
+            cache.flush().unwrap();
+            cache.remove(&peer);
+            cache.flush().unwrap();
+        }
+
+        assert_eq!(PeerCache::open(&path).unwrap().entries().count(), 0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn stale_and_malformed_entries_are_dropped() {
+        let path = test_path("stale");
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let stale = now - PEER_CACHE_EXPIRY - Duration::from_secs(60);
+        std::fs::write(
+            &path,
+            format!(
+                "# mycelium peer cache\n\
+                 {} 3 static tcp://192.0.2.1:9651\n\
+                 {} 1 static tcp://192.0.2.2:9651\n\
+                 not a valid line\n",
+                now.as_secs(),
+                stale.as_secs()
+            ),
+        )
+        .unwrap();
+
+        let cache = PeerCache::open(&path).unwrap();
+        let entries = cache.entries().map(|(ep, _)| *ep).collect::<Vec<_>>();
+        assert_eq!(entries, vec!["tcp://192.0.2.1:9651".parse().unwrap()]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,362 @@
+//! Declarative policies for routes received from peers.
+//!
+//! Where [`RouteUpdateFilter`](crate::filters::RouteUpdateFilter)s enforce the fixed invariants of
+//! the network, a [`RoutePolicy`] allows an operator to decide which routes are accepted by the
+//! local node, and how attractive routes learned through specific peers are. A policy consists of
+//! an ordered list of [`PolicyRule`]s, of which the first matching rule decides if a route is
+//! accepted, and a list of [`MetricPenalty`]s, which are added to the metric of every route learned
+//! through a matching peer.
+
+use core::fmt;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
+
+use crate::{crypto::PublicKey, endpoint::Endpoint, metric::Metric, subnet::Subnet};
+
+/// A set of rules used to judge routes received from peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutePolicy {
+    /// Action to take for routes which don't match any rule.
+    #[serde(default, rename = "default")]
+    pub default_action: PolicyAction,
+    /// Rules to evaluate, in order. The first rule which matches decides the action.
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+    /// Penalties added to the metric of routes received from specific peers.
+    #[serde(default)]
+    pub penalties: Vec<MetricPenalty>,
+}
+
+/// The action to take for a route matched by a [`PolicyRule`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    /// Accept the route.
+    #[default]
+    Allow,
+    /// Reject the route.
+    Deny,
+}
+
+/// A single rule in a [`RoutePolicy`]. A rule matches a route if all of its configured selectors
+/// match. A rule without any selectors matches every route.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    /// Action to take if the rule matches.
+    pub action: PolicyAction,
+    /// Match routes for subnets contained in this subnet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<Subnet>,
+    /// Match routes originated by the router with this public key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub router: Option<PublicKey>,
+    /// Match routes received from this peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerSelector>,
+}
+
+/// A penalty added to the metric of all routes received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricPenalty {
+    /// The peer to which the penalty applies.
+    pub peer: PeerSelector,
+    /// The amount added to the metric of routes received from the peer.
+    pub penalty: u16,
+}
+
+/// Identifies a peer by the remote address of its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSelector {
+    /// Matches all connections with a remote on this IP.
+    Ip(IpAddr),
+    /// Matches only connections with this exact remote address.
+    Socket(SocketAddr),
+}
+
+/// Error returned when parsing an invalid [`PeerSelector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerSelectorParseError;
+
+impl RoutePolicy {
+    /// Check if a route for `subnet`, originated by `router` and received from a peer with
+    /// the given remote address, is allowed by this policy.
+    pub fn allows(&self, subnet: Subnet, router: &PublicKey, peer: Option<SocketAddr>) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(subnet, router, peer))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default_action)
+            == PolicyAction::Allow
+    }
+
+    /// The total penalty to apply to routes received from a peer with the given remote address.
+    /// The penalty is never infinite, so a penalized route is not turned into a retraction.
+    pub fn penalty(&self, peer: Option<SocketAddr>) -> Metric {
+        let Some(peer) = peer else {
+            return Metric::new(0);
+        };
+        self.penalties
+            .iter()
+            .filter(|p| p.peer.matches(peer))
+            .fold(Metric::new(0), |total, p| {
+                total + Metric::new(p.penalty.min(u16::MAX - 1))
+            })
+    }
+}
+
+impl PolicyRule {
+    /// Check if this rule matches the given route.
+    fn matches(&self, subnet: Subnet, router: &PublicKey, peer: Option<SocketAddr>) -> bool {
+        if let Some(rule_subnet) = self.subnet {
+            if !rule_subnet.contains_subnet(&subnet) {
+                return false;
+            }
+        }
+        if let Some(rule_router) = self.router {
+            if &rule_router != router {
+                return false;
+            }
+        }
+        if let Some(rule_peer) = self.peer {
+            if !peer.is_some_and(|peer| rule_peer.matches(peer)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl PeerSelector {
+    /// Check if the given remote address is selected.
+    pub fn matches(&self, remote: SocketAddr) -> bool {
+        match self {
+            PeerSelector::Ip(ip) => normalize_ip(*ip) == normalize_ip(remote.ip()),
+            PeerSelector::Socket(addr) => {
+                normalize_ip(addr.ip()) == normalize_ip(remote.ip()) && addr.port() == remote.port()
+            }
+        }
+    }
+}
+
+/// Converts IPv4 mapped IPv6 addresses to plain IPv4 addresses, so both forms match each other.
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+impl FromStr for PeerSelector {
+    type Err = PeerSelectorParseError;
+
+    /// Parse a `PeerSelector`. This can be an [`Endpoint`] (`tcp://[2001:db8::1]:9651`), a
+    /// [`SocketAddr`], or an [`IpAddr`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(endpoint) = s.parse::<Endpoint>() {
+            return Ok(PeerSelector::Socket(endpoint.address()));
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(PeerSelector::Socket(addr));
+        }
+        s.parse()
+            .map(PeerSelector::Ip)
+            .map_err(|_| PeerSelectorParseError)
+    }
+}
+
+impl fmt::Display for PeerSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerSelector::Ip(ip) => ip.fmt(f),
+            PeerSelector::Socket(addr) => addr.fmt(f),
+        }
+    }
+}
+
+impl Serialize for PeerSelector {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+struct PeerSelectorVisitor;
+
+impl<'de> Visitor<'de> for PeerSelectorVisitor {
+    type Value = PeerSelector;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("An endpoint, socket address or IP address")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerSelector {
+    fn deserialize<D>(deserializer: D) -> Result<PeerSelector, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PeerSelectorVisitor)
+    }
+}
+
+impl fmt::Display for PeerSelectorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid peer, expected an endpoint, socket address or IP address")
+    }
+}
+
+impl std::error::Error for PeerSelectorParseError {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{
+        crypto::{PublicKey, SecretKey},
+        metric::Metric,
+        subnet::Subnet,
+    };
+
+    use super::{MetricPenalty, PeerSelector, PolicyAction, PolicyRule, RoutePolicy};
+
+    fn rule(action: PolicyAction) -> PolicyRule {
+        PolicyRule {
+            action,
+            subnet: None,
+            router: None,
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn default_policy_allows_everything() {
+        let policy = RoutePolicy::default();
+        let pk = PublicKey::from(&SecretKey::new());
+
+        assert!(policy.allows("400::/64".parse().unwrap(), &pk, None));
+        assert_eq!(
+            policy.penalty(Some("[2001:db8::1]:9651".parse().unwrap())),
+            Metric::new(0)
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let other_pk = PublicKey::from(&SecretKey::new());
+        let policy = RoutePolicy {
+            default_action: PolicyAction::Deny,
+            rules: vec![
+                PolicyRule {
+                    subnet: Some("5a0::/16".parse().unwrap()),
+                    ..rule(PolicyAction::Deny)
+                },
+                PolicyRule {
+                    router: Some(pk),
+                    ..rule(PolicyAction::Allow)
+                },
+            ],
+            penalties: vec![],
+        };
+
+        let subnet: Subnet = "400:1::/64".parse().unwrap();
+        let denied_subnet: Subnet = "5a0:1::/64".parse().unwrap();
+
+        assert!(policy.allows(subnet, &pk, None));
+        assert!(!policy.allows(denied_subnet, &pk, None));
+        assert!(!policy.allows(subnet, &other_pk, None));
+    }
+
+    #[test]
+    fn peer_rules_and_penalties() {
+        let pk = PublicKey::from(&SecretKey::new());
+        let peer: SocketAddr = "[2001:db8::1]:9651".parse().unwrap();
+        let other_peer: SocketAddr = "192.0.2.1:9651".parse().unwrap();
+        let policy = RoutePolicy {
+            default_action: PolicyAction::Allow,
+            rules: vec![PolicyRule {
+                peer: Some("192.0.2.1".parse().unwrap()),
+                ..rule(PolicyAction::Deny)
+            }],
+            penalties: vec![
+                MetricPenalty {
+                    peer: "tcp://[2001:db8::1]:9651".parse().unwrap(),
+                    penalty: 100,
+                },
+                MetricPenalty {
+                    peer: "2001:db8::1".parse().unwrap(),
+                    penalty: 50,
+                },
+            ],
+        };
+        let subnet: Subnet = "400:1::/64".parse().unwrap();
+
+        assert!(policy.allows(subnet, &pk, Some(peer)));
+        assert!(!policy.allows(subnet, &pk, Some(other_peer)));
+        // Rules on a peer never match routes without a known peer address.
+        assert!(policy.allows(subnet, &pk, None));
+
+        assert_eq!(policy.penalty(Some(peer)), Metric::new(150));
+        assert_eq!(policy.penalty(Some(other_peer)), Metric::new(0));
+    }
+
+    #[test]
+    fn penalty_is_never_infinite() {
+        let peer: SocketAddr = "[2001:db8::1]:9651".parse().unwrap();
+        let policy = RoutePolicy {
+            default_action: PolicyAction::Allow,
+            rules: vec![],
+            penalties: vec![MetricPenalty {
+                peer: "2001:db8::1".parse().unwrap(),
+                penalty: u16::MAX,
+            }],
+        };
+
+        let penalty = policy.penalty(Some(peer));
+        assert!(!penalty.is_infinite());
+        assert!(!(Metric::new(100) + penalty).is_infinite());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match() {
+        let selector: PeerSelector = "192.0.2.1".parse().unwrap();
+
+        assert!(selector.matches("[::ffff:192.0.2.1]:1234".parse().unwrap()));
+        assert!(selector.matches("192.0.2.1:1234".parse().unwrap()));
+        assert!(!selector.matches("192.0.2.2:1234".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_peer_selector() {
+        assert_eq!(
+            "quic://192.0.2.1:9651".parse(),
+            Ok(PeerSelector::Socket("192.0.2.1:9651".parse().unwrap()))
+        );
+        assert_eq!(
+            "[2001:db8::1]:9651".parse(),
+            Ok(PeerSelector::Socket("[2001:db8::1]:9651".parse().unwrap()))
+        );
+        assert_eq!(
+            "2001:db8::1".parse(),
+            Ok(PeerSelector::Ip("2001:db8::1".parse().unwrap()))
+        );
+        assert!("not a peer".parse::<PeerSelector>().is_err());
+    }
+}
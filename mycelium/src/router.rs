@@ -1,28 +1,38 @@
 use crate::{
     babel::{self, Hello, Ihu, RouteRequest, SeqNoRequest, Update},
-    crypto::{PacketBuffer, PublicKey, SecretKey, SharedSecret},
+    connection::Connection,
+    crypto::{PacketBuffer, PublicKey, SecretKey, SharedSecret, Signature},
+    damping::{DampedRoute, DampingConfig, RouteDamping},
     filters::RouteUpdateFilter,
+    gateway,
+    link_auth::LinkKeys,
     metric::Metric,
     metrics::Metrics,
-    packet::{ControlPacket, DataPacket},
+    packet::{Capabilities, ControlPacket, DataPacket},
     peer::Peer,
+    policy::RoutePolicy,
     router_id::RouterId,
     routing_table::{RouteEntry, RouteKey, RouteList, RoutingTable},
     seqno_cache::{SeqnoCache, SeqnoRequestCacheKey},
     sequence_number::SeqNo,
     source_table::{FeasibilityDistance, SourceKey, SourceTable},
     subnet::Subnet,
+    succession::{PreviousKey, Succession, SuccessionTable},
 };
+use arc_swap::{ArcSwap, ArcSwapOption};
 use etherparse::{
     icmpv6::{DestUnreachableCode, TimeExceededCode},
     Icmpv6Type,
 };
 use std::{
+    collections::HashMap,
     error::Error,
+    fmt,
     hash::{Hash, Hasher},
-    net::IpAddr,
-    sync::{Arc, RwLock},
-    time::{Duration, Instant},
+    io,
+    net::{IpAddr, Ipv6Addr},
+    sync::{atomic::AtomicU64, Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
 use tracing::{debug, error, info, trace, warn};
@@ -52,8 +62,13 @@ const SEQNO_BUMP_TIMEOUT: Duration = Duration::from_secs(4);
 /// Metric change of more than 10 is considered a large change.
 const BIG_METRIC_CHANGE_TRESHOLD: Metric = Metric::new(10);
 
-/// The amount a metric of a route needs to improve before we will consider switching to it.
-const SIGNIFICANT_METRIC_IMPROVEMENT: Metric = Metric::new(10);
+/// The default amount a metric of a route needs to improve before we will consider switching to
+/// it.
+pub const DEFAULT_SELECTION_HYSTERESIS: u16 = 10;
+
+/// The largest selection hysteresis which can be used. Higher values would be an infinite
+/// [`Metric`].
+pub const MAX_SELECTION_HYSTERESIS: u16 = u16::MAX - 1;
 
 /// Hold retracted routes for 1 minute before purging them from the [`RoutingTable`].
 const RETRACTED_ROUTE_HOLD_TIME: Duration = Duration::from_secs(60);
@@ -61,6 +76,22 @@ const RETRACTED_ROUTE_HOLD_TIME: Duration = Duration::from_secs(60);
 /// The interval specified in updates if the update won't be repeated.
 const INTERVAL_NOT_REPEATING: Duration = Duration::from_millis(0);
 
+/// The amount of origin or gateway signatures remembered for a single source, for the most
+/// recently received sequence numbers. Routes for a source can have different sequence numbers,
+/// and we need the signature matching the sequence number of the selected route when propagating
+/// it.
+const SIGNATURES_PER_SOURCE: usize = 4;
+
+/// The key this node used before a key rotation. The subnet of the previous key is announced until
+/// the end of the grace period of the rotation.
+struct PreviousIdentity {
+    keypair: (SecretKey, PublicKey),
+    router_id: RouterId,
+    subnet: Subnet,
+    succession: Succession,
+    expires: Instant,
+}
+
 pub struct Router<M> {
     routing_table: RoutingTable,
     peer_interfaces: Arc<RwLock<Vec<Peer>>>,
@@ -68,13 +99,41 @@ pub struct Router<M> {
     // Router SeqNo and last time it was bumped
     router_seqno: Arc<RwLock<(SeqNo, Instant)>>,
     static_routes: Vec<Subnet>,
+    /// Signatures of gateways over the prefixes they announce, for the most recent sequence
+    /// numbers of every prefix, including the signatures over our own static routes which are
+    /// not derived from our key. These are attached to updates for those prefixes, so other
+    /// nodes can verify them.
+    gateway_signatures: Arc<RwLock<HashMap<(Subnet, PublicKey), Vec<(SeqNo, Signature)>>>>,
+    /// Signatures of the origin of routes over the announced subnet and sequence number, for the
+    /// most recent sequence numbers of every source, with the origin flags they cover. This
+    /// includes the signatures over our own routes. These are attached to updates, so other nodes
+    /// can verify the route is actually announced by the router in the update.
+    origin_signatures: Arc<RwLock<HashMap<SourceKey, Vec<(SeqNo, u8, Signature)>>>>,
+    /// Reject updates which don't carry a valid origin signature.
+    require_origin_signatures: bool,
+    /// Maximum amount the metric of a route can be higher than the metric of the selected route,
+    /// for the route to be used for multipath forwarding. Multipath forwarding is disabled if
+    /// this is not set.
+    multipath_tolerance: Option<Metric>,
+    /// The amount the metric of a route needs to improve before we switch to it.
+    selection_hysteresis: Metric,
+    /// Flap history of sources, if route flap damping is enabled.
+    route_damping: Option<Arc<RouteDamping>>,
+    /// Leaf nodes don't forward traffic for other nodes, and don't advertise routes to them.
+    leaf: bool,
     router_id: RouterId,
     node_keypair: (SecretKey, PublicKey),
+    /// The previous key of this node, if it rotated its key.
+    previous_identity: Arc<ArcSwapOption<PreviousIdentity>>,
+    /// Successions of the keys of other nodes which rotated their key.
+    successions: Arc<SuccessionTable>,
     router_data_tx: Sender<DataPacket>,
     router_control_tx: UnboundedSender<(ControlPacket, Peer)>,
     node_tun: UnboundedSender<DataPacket>,
     node_tun_subnet: Subnet,
     update_filters: Arc<Vec<Box<dyn RouteUpdateFilter + Send + Sync>>>,
+    /// Operator defined policy applied to updates which passed the update filters.
+    route_policy: Arc<ArcSwap<RoutePolicy>>,
     /// Channel injected into peers, so they can notify the router if they exit.
     dead_peer_sink: mpsc::Sender<Peer>,
     /// Channel to notify the router of expired SourceKey's.
@@ -93,6 +152,7 @@ where
     /// # Panics
     ///
     /// If update_workers is not in the range of [1..255], this will panic.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         update_workers: usize,
         node_tun: UnboundedSender<DataPacket>,
@@ -100,6 +160,11 @@ where
         static_routes: Vec<Subnet>,
         node_keypair: (SecretKey, PublicKey),
         update_filters: Vec<Box<dyn RouteUpdateFilter + Send + Sync>>,
+        require_origin_signatures: bool,
+        multipath_tolerance: Option<u16>,
+        selection_hysteresis: u16,
+        route_damping: Option<DampingConfig>,
+        leaf: bool,
         metrics: M,
     ) -> Result<Self, Box<dyn Error>> {
         // We could use a NonZeroU8 here, but for now just handle this manually as this might get
@@ -107,6 +172,7 @@ where
         if !(1..255).contains(&update_workers) {
             panic!("update workers must be at least 1 and at most 255");
         }
+        validate_selection_hysteresis(selection_hysteresis)?;
 
         // Tx is passed onto each new peer instance. This enables peers to send control packets to the router.
         let (router_control_tx, router_control_rx) = mpsc::unbounded_channel();
@@ -128,8 +194,17 @@ where
             source_table: Arc::new(RwLock::new(SourceTable::new())),
             router_seqno: Arc::new(RwLock::new((SeqNo::new(), Instant::now()))),
             static_routes,
+            gateway_signatures: Arc::new(RwLock::new(HashMap::new())),
+            origin_signatures: Arc::new(RwLock::new(HashMap::new())),
+            require_origin_signatures,
+            multipath_tolerance: multipath_tolerance.map(Metric::from),
+            selection_hysteresis: Metric::from(selection_hysteresis),
+            route_damping: route_damping.map(|config| Arc::new(RouteDamping::new(config))),
+            leaf,
             router_id,
             node_keypair,
+            previous_identity: Arc::new(ArcSwapOption::empty()),
+            successions: Arc::new(SuccessionTable::new()),
             router_data_tx,
             router_control_tx,
             node_tun,
@@ -138,6 +213,7 @@ where
             expired_source_key_sink,
             seqno_cache,
             update_filters: Arc::new(update_filters),
+            route_policy: Arc::new(ArcSwap::from_pointee(RoutePolicy::default())),
             update_workers,
             metrics,
         };
@@ -211,6 +287,37 @@ where
         }
     }
 
+    /// Create a new [`Peer`] on an established connection, and add it to the router. Unlike peers
+    /// managed by the [`PeerManager`](crate::peer_manager::PeerManager), the connection is not
+    /// reestablished if it fails. This is mainly useful to connect routers which run in the same
+    /// process.
+    pub fn add_connection<C>(&self, connection: C, link_keys: &LinkKeys) -> Result<(), io::Error>
+    where
+        C: Connection + Unpin + Send + 'static,
+    {
+        let peer = Peer::new(
+            self.router_data_tx(),
+            self.router_control_tx(),
+            connection,
+            self.dead_peer_sink.clone(),
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicU64::new(0)),
+            link_keys,
+        )?;
+        self.add_peer_interface(peer);
+
+        Ok(())
+    }
+
+    /// Get the damping state of all routes which flapped recently. This is empty if route flap
+    /// damping is disabled.
+    pub fn damped_routes(&self) -> Vec<DampedRoute> {
+        self.route_damping
+            .as_ref()
+            .map(|damping| damping.damped_routes())
+            .unwrap_or_default()
+    }
+
     /// Get the public key used by the router
     pub fn node_public_key(&self) -> PublicKey {
         self.node_keypair.1
@@ -229,6 +336,46 @@ where
             .map(|rl| rl[0].source().router_id().to_pubkey())
     }
 
+    /// Checks if a packet from the node owning `sender` may use `src` as source address. This is
+    /// the case if `src` is in the /64 of `sender`, or if the best routes to both addresses are
+    /// announced by the same node, i.e. the sender is a gateway for `src`.
+    pub fn is_valid_source(&self, sender: Ipv6Addr, src: Ipv6Addr) -> bool {
+        if Subnet::new(sender.into(), 64)
+            .expect("64 is a valid IPv6 prefix size; qed")
+            .contains_ip(src.into())
+        {
+            return true;
+        }
+
+        match (self.get_pubkey(sender.into()), self.get_pubkey(src.into())) {
+            (Some(sender_key), Some(src_key)) => sender_key == src_key,
+            _ => false,
+        }
+    }
+
+    /// Checks if the node which owns the given address announced, in the signed routes for the
+    /// address, that it requires data packets to be encrypted with a session key.
+    pub fn requires_data_session(&self, ip: IpAddr) -> bool {
+        let Some(source) = self.routing_table.best_routes(ip).and_then(|rl| {
+            if rl.is_empty() {
+                None
+            } else {
+                Some(rl[0].source())
+            }
+        }) else {
+            return false;
+        };
+        self.origin_signatures
+            .read()
+            .unwrap()
+            .get(&source)
+            .is_some_and(|signatures| {
+                signatures
+                    .iter()
+                    .any(|(_, flags, _)| flags & babel::ORIGIN_FLAG_DATA_SESSIONS != 0)
+            })
+    }
+
     /// Gets the cached [`SharedSecret`] for the remote.
     pub fn get_shared_secret_from_dest(&self, dest: IpAddr) -> Option<SharedSecret> {
         self.routing_table
@@ -244,6 +391,168 @@ where
         self.get_shared_secret_from_dest(dest.address().into())
     }
 
+    /// Set the key this node used before its current key. Until the end of the grace period of
+    /// the key rotation, the subnet of the previous key is announced as well, and packets for it
+    /// are delivered to this node.
+    pub fn set_previous_key(&self, previous_key: PreviousKey) {
+        let public_key = PublicKey::from(&previous_key.key);
+        let subnet = Subnet::new(
+            Subnet::new(public_key.address().into(), 64)
+                .expect("64 is a valid IPv6 prefix size; qed")
+                .network(),
+            64,
+        )
+        .expect("64 is a valid IPv6 prefix size; qed");
+        let remaining = previous_key
+            .succession
+            .grace_period_end()
+            .and_then(|end| end.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default();
+
+        info!(%subnet, "Announcing subnet of previous key for {}s", remaining.as_secs());
+        self.previous_identity
+            .store(Some(Arc::new(PreviousIdentity {
+                keypair: (previous_key.key, public_key),
+                router_id: RouterId::new(public_key),
+                subnet,
+                succession: previous_key.succession,
+                expires: Instant::now() + remaining,
+            })));
+
+        self.propagate_static_routes_to_peers();
+    }
+
+    /// Get the previous key of this node, if it is still in the grace period of a key rotation.
+    fn previous_identity(&self) -> Option<Arc<PreviousIdentity>> {
+        self.previous_identity
+            .load_full()
+            .filter(|pi| pi.expires > Instant::now())
+    }
+
+    /// Checks if the given address is in the subnet of the previous key of this node, while it is
+    /// still announced.
+    pub fn is_previous_address(&self, ip: IpAddr) -> bool {
+        self.previous_identity()
+            .is_some_and(|pi| pi.subnet.contains_ip(ip))
+    }
+
+    /// Get the [`SharedSecret`] of the previous key of this node with a remote, while the subnet
+    /// of the previous key is still announced.
+    pub fn previous_shared_secret(&self, remote: &PublicKey) -> Option<(Succession, SharedSecret)> {
+        self.previous_identity()
+            .map(|pi| (pi.succession, pi.keypair.0.shared_secret(remote)))
+    }
+
+    /// Get the [`Succession`] of the key which owns the given address, if that key has been
+    /// replaced. This includes the previous key of this node.
+    pub fn key_succession(&self, ip: IpAddr) -> Option<Succession> {
+        if let Some(pi) = self.previous_identity.load_full() {
+            if pi.subnet.contains_ip(ip) {
+                return Some(pi.succession);
+            }
+        }
+        self.successions.get(ip)
+    }
+
+    /// Get the current key of the node which owned the given address before it rotated its key,
+    /// if the rotation is known.
+    pub fn successor(&self, ip: IpAddr) -> Option<PublicKey> {
+        if let Some(pi) = self.previous_identity.load_full() {
+            if pi.subnet.contains_ip(ip) {
+                return Some(self.node_keypair.1);
+            }
+        }
+        self.successions.successor(ip)
+    }
+
+    /// Remember the [`Succession`] of the key of another node. The signature must have been
+    /// verified already. Returns `true` if the succession was remembered.
+    pub fn add_succession(&self, succession: Succession) -> bool {
+        self.successions.insert(succession)
+    }
+
+    /// Get the [`RoutePolicy`] currently used by the `Router`.
+    pub fn route_policy(&self) -> RoutePolicy {
+        RoutePolicy::clone(&self.route_policy.load())
+    }
+
+    /// Replace the [`RoutePolicy`] used by the `Router`.
+    ///
+    /// Existing routes which are denied by the new policy are retracted. Afterwards, a full route
+    /// table dump is requested from all peers, so routes which are now allowed are learned, and
+    /// metric penalties are applied to all routes.
+    pub fn set_route_policy(&self, policy: RoutePolicy) {
+        info!(
+            rules = policy.rules.len(),
+            penalties = policy.penalties.len(),
+            "Applying new route policy"
+        );
+        self.route_policy.store(Arc::new(policy));
+        let policy = self.route_policy.load();
+
+        // Scope for routing table write access.
+        let subnets_to_select = {
+            let mut rt_write = self.routing_table.write();
+            let mut rt_write = rt_write.iter_mut();
+
+            let mut subnets_to_select = Vec::new();
+
+            while let Some((subnet, mut rl)) = rt_write.next() {
+                rl.update_routes(|routes, eres, ct| {
+                    let denied = routes
+                        .iter()
+                        .filter(|re| {
+                            !policy.allows(
+                                subnet,
+                                &re.source().router_id().to_pubkey(),
+                                re.neighbour().remote_address(),
+                            )
+                        })
+                        .map(|re| re.neighbour().clone())
+                        .collect::<Vec<_>>();
+
+                    for neighbour in denied {
+                        let Some(mut re) = routes.iter_mut().find(|re| re.neighbour() == &neighbour)
+                        else {
+                            continue;
+                        };
+
+                        debug!(%subnet, peer = neighbour.connection_identifier(), "Route denied by new route policy");
+
+                        if re.selected() {
+                            subnets_to_select.push(subnet);
+
+                            // Don't clear selected flag yet, running route selection does that for us.
+                            re.set_metric(Metric::infinite());
+                            re.set_expires(
+                                tokio::time::Instant::now() + RETRACTED_ROUTE_HOLD_TIME,
+                                eres.clone(),
+                                ct.clone(),
+                            );
+                        } else {
+                            routes.remove(&neighbour);
+                        }
+                    }
+                });
+            }
+
+            subnets_to_select
+        };
+
+        for subnet in subnets_to_select {
+            self.route_selection(subnet);
+        }
+
+        for peer in self.peer_interfaces() {
+            if let Err(e) = peer.send_control_packet(RouteRequest::new(None).into()) {
+                error!(
+                    "Failed to request route table dump from {}: {e}",
+                    peer.connection_identifier()
+                );
+            }
+        }
+    }
+
     /// Get a reference to this `Router`s' dead peer sink.
     pub fn dead_peer_sink(&self) -> &mpsc::Sender<Peer> {
         &self.dead_peer_sink
@@ -257,6 +566,8 @@ where
         );
         self.peer_interfaces.write().unwrap().retain(|p| p != peer);
         self.metrics.router_peer_removed();
+        self.metrics
+            .router_peer_link_quality_removed(peer.connection_identifier());
     }
 
     /// Get a list of all selected route entries.
@@ -391,7 +702,14 @@ where
             }
 
             routes.set_selected(new_selected.neighbour());
+
+            // The route switched to a different next hop.
+            if route_list[0].selected() && self.record_route_flap(route_list[0].source()) {
+                debug!(subnet = %subnet, "Not sending triggered update for suppressed route");
+                return;
+            }
         } else if !route_list.is_empty() && route_list[0].selected() {
+            self.record_route_flap(route_list[0].source());
             // This means we went from a selected route to a non-selected route. Unselect route and
             // trigger update.
             // At this point we also send a seqno request to all peers which advertised this route
@@ -414,6 +732,7 @@ where
         while let Some(sk) = expired_source_key_stream.recv().await {
             debug!("Removing expired source entry {sk}");
             self.source_table.write().unwrap().remove(&sk);
+            self.origin_signatures.write().unwrap().remove(&sk);
             self.metrics.router_source_key_expired();
         }
         warn!("Expired source key processing halted");
@@ -692,11 +1011,12 @@ where
     }
 
     /// Handle a received hello TLV
-    fn handle_incoming_hello(&self, _: babel::Hello, source_peer: Peer) {
+    fn handle_incoming_hello(&self, hello: babel::Hello, source_peer: Peer) {
         self.metrics.router_process_hello();
         // Upon receiving and Hello message from a peer, this node has to send a IHU back
         // TODO: properly calculate RX cost, for now just set the link cost.
-        let ihu = ControlPacket::new_ihu(source_peer.link_cost().into(), IHU_INTERVAL, None);
+        let ihu =
+            ControlPacket::new_ihu(source_peer.link_cost().into(), IHU_INTERVAL, None, &hello);
         if source_peer.send_control_packet(ihu).is_err() {
             trace!(
                 "Failed to send IHU reply to peer: {}",
@@ -706,18 +1026,26 @@ where
     }
 
     /// Handle a received IHU TLV
-    fn handle_incoming_ihu(&self, _: babel::Ihu, source_peer: Peer) {
+    fn handle_incoming_ihu(&self, ihu: babel::Ihu, source_peer: Peer) {
         self.metrics.router_process_ihu();
-        // reset the IHU timer associated with the peer
-        // measure time between Hello and and IHU and set the link cost
-        let time_diff = tokio::time::Instant::now()
-            .duration_since(source_peer.time_last_received_hello())
-            .as_millis();
-
-        source_peer.set_link_cost(time_diff as u16);
-
         // set the last_received_ihu for this peer
-        source_peer.set_time_last_received_ihu(tokio::time::Instant::now());
+        let now = tokio::time::Instant::now();
+        source_peer.set_time_last_received_ihu(now);
+
+        let measured_rtt = source_peer.record_ihu_received(now);
+        if measured_rtt.is_some() {
+            self.metrics.router_peer_link_quality(
+                source_peer.connection_identifier(),
+                source_peer.link_quality(),
+            );
+        }
+
+        // Prefer the round trip time calculated from the timestamps in the IHU, as it excludes the
+        // time the remote needed to reply. Fall back to the time between sending the last Hello
+        // and receiving this IHU if the remote does not send timestamps.
+        if let Some(rtt) = ihu.rtt().or(measured_rtt) {
+            source_peer.set_link_cost(rtt);
+        }
     }
 
     /// Process a route request. We reply with an Update if we have a selected route for the
@@ -745,16 +1073,16 @@ where
                 babel::Update::new(
                     advertised_update_interval(sre),
                     sre.seqno(),
-                    sre.metric() + Metric::from(sre.neighbour().link_cost()),
+                    self.advertised_metric(sre),
                     subnet,
                     sre.source().router_id(),
                 )
             }
             // Could be a request for a static route/subnet.
-            else if let Some(static_route) = self
-                .static_routes
-                .iter()
-                .find(|sr| sr.contains_subnet(&subnet))
+            else if let Some((static_route, router_id)) = self
+                .local_routes()
+                .into_iter()
+                .find(|(sr, _)| sr.contains_subnet(&subnet))
             {
                 trace!(
                     "Advertising static route {static_route} in response to route request for {subnet}"
@@ -763,8 +1091,8 @@ where
                     UPDATE_INTERVAL, // Static route is advertised with the default interval
                     self.router_seqno.read().unwrap().0, // Updates receive the seqno of the router
                     Metric::from(0), // Static route has no further hop costs
-                    *static_route,
-                    self.router_id,
+                    static_route,
+                    router_id,
                 )
             }
             // If the requested route is not present, send a retraction
@@ -861,9 +1189,10 @@ where
         // prefix is part of our static routes, if the router id is our own, and if the
         // requested seqno is greater than our own.
         let (router_seqno, last_seqno_bump) = *self.router_seqno.read().unwrap();
-        if seqno_request.router_id() == self.router_id
-            && seqno_request.seqno().gt(&router_seqno)
-            && self.static_routes.contains(&seqno_request.prefix())
+        if seqno_request.seqno().gt(&router_seqno)
+            && self
+                .local_routes()
+                .contains(&(seqno_request.prefix(), seqno_request.router_id()))
         {
             if last_seqno_bump.elapsed() >= SEQNO_BUMP_TIMEOUT {
                 trace!("Ignoring seqno bump request which happened too fast");
@@ -905,7 +1234,7 @@ where
             return;
         }
 
-        if seqno_request.router_id() != self.router_id {
+        if !self.is_local_router_id(seqno_request.router_id()) {
             seqno_request.decrement_hop_count();
 
             let srck = SeqnoRequestCacheKey {
@@ -1011,16 +1340,35 @@ where
             // Infinite metrics are technically feasible, but for route selection we explicitly
             // don't want infinite metrics as those routes are unreachable.
             .filter(|re| !re.metric().is_infinite() && source_table.route_feasible(re))
-            .min_by_key(|re| re.metric() + Metric::from(re.neighbour().link_cost()));
+            // Routes through backup peers are only selected if there is no other route.
+            .min_by_key(|re| {
+                (
+                    re.neighbour().backup(),
+                    re.metric() + Metric::from(re.neighbour().link_cost()),
+                )
+            });
 
         if let (Some(best), Some(current)) = (best, current) {
+            let different =
+                best.source() != current.source() || best.neighbour() != current.neighbour();
+            // Suppressed routes keep their current next hop as long as it is usable.
+            if different
+                && !current.metric().is_infinite()
+                && source_table.route_feasible(current)
+                && self.route_suppressed(&current.source())
+            {
+                debug!(source = %current.source(), "maintaining currently selected route since the route is suppressed");
+                return Some(current);
+            }
             // If we swap to an actually different route, only do so if the metric is
-            // significantly better OR if it is directly connected (metric 0).
-            if (best.source() != current.source() || best.neighbour() != current.neighbour())
+            // significantly better OR if it is directly connected (metric 0), or if it moves the
+            // route away from a backup peer.
+            if different
                 && !(best.metric() + Metric::from(best.neighbour().link_cost())
                     < current.metric() + Metric::from(current.neighbour().link_cost())
-                        - SIGNIFICANT_METRIC_IMPROVEMENT
-                    || best.metric().is_direct())
+                        - self.selection_hysteresis
+                    || best.metric().is_direct()
+                    || (current.neighbour().backup() && !best.neighbour().backup()))
             {
                 debug!("maintaining currently selected route since new route is not significantly better");
                 return Some(current);
@@ -1031,7 +1379,7 @@ where
     }
 
     /// Handle a received update TLV
-    fn handle_incoming_update(&self, update: babel::Update, source_peer: Peer) {
+    fn handle_incoming_update(&self, mut update: babel::Update, source_peer: Peer) {
         self.metrics.router_process_update();
         // Check if we actually allow this update based on filters.
         for filter in &*self.update_filters {
@@ -1042,6 +1390,53 @@ where
             }
         }
 
+        // Verify the update is announced by the router it claims to come from. This must happen
+        // before the update can affect the source table or the routing table. Retractions only
+        // affect routes through the peer which sends them, so they don't need to be signed.
+        if !update.metric().is_infinite() {
+            match update.origin_signature() {
+                Some(signature) => {
+                    if update
+                        .router_id()
+                        .to_pubkey()
+                        .verify(&update.origin_signature_data(), signature)
+                        .is_err()
+                    {
+                        debug!(subnet = %update.subnet(), router_id = %update.router_id(), "Update has invalid origin signature");
+                        self.metrics.router_update_invalid_signature();
+                        return;
+                    }
+                }
+                None if self.require_origin_signatures => {
+                    debug!(subnet = %update.subnet(), router_id = %update.router_id(), "Update without origin signature");
+                    self.metrics.router_update_unsigned();
+                    return;
+                }
+                None => {}
+            }
+        }
+
+        // Then check the update against the configured route policy. Retractions are always
+        // allowed, so routes which were accepted earlier can still be withdrawn.
+        {
+            let policy = self.route_policy.load();
+            if !update.metric().is_infinite()
+                && !policy.allows(
+                    update.subnet(),
+                    &update.router_id().to_pubkey(),
+                    source_peer.remote_address(),
+                )
+            {
+                debug!(subnet = %update.subnet(), "Update denied by route policy");
+                self.metrics.router_update_denied_by_policy();
+                return;
+            }
+            let penalty = policy.penalty(source_peer.remote_address());
+            if !penalty.is_direct() {
+                update.set_metric(update.metric() + penalty);
+            }
+        }
+
         let metric = update.metric();
         let router_id = update.router_id();
         let seqno = update.seqno();
@@ -1052,6 +1447,25 @@ where
             return;
         }
 
+        // Remember the signature of gateway announcements, so it can be attached when the route
+        // is propagated. The update filters already verified the signature in this case.
+        if !metric.is_infinite() && !subnet.contains_ip(router_id.to_pubkey().address().into()) {
+            if let Some(signature) = update.gateway_signature() {
+                self.remember_gateway_signature((subnet, router_id.to_pubkey()), seqno, *signature);
+            }
+        }
+
+        // Remember the origin signature, so it can be attached when the route is propagated. The
+        // signature has been verified already.
+        if let Some(signature) = update.origin_signature() {
+            self.remember_origin_signature(
+                SourceKey::new(subnet, router_id),
+                seqno,
+                update.origin_flags(),
+                *signature,
+            );
+        }
+
         // We accepted the update, check if we have a seqno request sent for this update
         let interested_peers = self.seqno_cache.remove(&SeqnoRequestCacheKey {
             router_id,
@@ -1231,7 +1645,8 @@ where
         // - seqno increase (unless it is requested by a peer)
         let trigger_update = match (&old_selected_route, new_selected_route) {
             (Some(old_route), Some(new_route)) => {
-                if new_route.neighbour() != old_route.neighbour() {
+                let next_hop_changed = new_route.neighbour() != old_route.neighbour();
+                if next_hop_changed {
                     debug!(
                         subnet = %subnet,
                         old_next_hop = old_route.neighbour().connection_identifier(),
@@ -1239,9 +1654,23 @@ where
                         "Selected route changed next-hop",
                     );
                 }
+                let suppressed = if new_route.source() != old_route.source() {
+                    // The old source lost the route. The update announces the new source, so
+                    // its own flap history decides if the update is suppressed.
+                    self.record_route_flap(old_route.source());
+                    self.route_suppressed(&new_route.source())
+                } else if next_hop_changed {
+                    self.record_route_flap(new_route.source())
+                } else {
+                    self.route_suppressed(&new_route.source())
+                };
                 // Router id changed.
-                new_route.source().router_id() != old_route.source().router_id()
-                    || new_route.metric().delta(&old_route.metric()) > BIG_METRIC_CHANGE_TRESHOLD
+                let significant = new_route.source().router_id() != old_route.source().router_id()
+                    || new_route.metric().delta(&old_route.metric()) > BIG_METRIC_CHANGE_TRESHOLD;
+                if significant && suppressed {
+                    debug!(subnet = %subnet, "Not sending triggered update for suppressed route");
+                }
+                significant && !suppressed
             }
             (None, Some(new_route)) => {
                 info!(
@@ -1257,6 +1686,8 @@ where
                     peer = old_route.neighbour().connection_identifier(),
                     "Lost route",
                 );
+                // Losing a route is always propagated, so peers don't keep using it.
+                self.record_route_flap(old_route.source());
                 true
             }
             (None, None) => false,
@@ -1276,6 +1707,27 @@ where
         }
     }
 
+    /// Returns `true` if the routes of the source are suppressed by route flap damping.
+    fn route_suppressed(&self, source: &SourceKey) -> bool {
+        self.route_damping
+            .as_ref()
+            .is_some_and(|damping| damping.suppressed(source))
+    }
+
+    /// Record a flap of the route of the source for route flap damping. Returns `true` if the
+    /// routes of the source are suppressed.
+    fn record_route_flap(&self, source: SourceKey) -> bool {
+        let Some(damping) = &self.route_damping else {
+            return false;
+        };
+        let suppressed = damping.record_flap(source);
+        if suppressed {
+            debug!(%source, "Route flaps too often, suppressing it");
+        }
+
+        suppressed
+    }
+
     /// Trigger an update for the given [`Subnet`]. If `peers` is [`None`], send the update to all
     /// peers the `Router` knows.
     fn trigger_update(&self, subnet: Subnet, peers: Option<Vec<Peer>>) {
@@ -1379,15 +1831,74 @@ where
         }
     }
 
-    /// Checks if a route key is an exact match for a static route.
+    /// Checks if a route key is an exact match for a static route, or for the subnet of the
+    /// previous key of this node.
     #[inline]
     fn is_static_subnet(&self, subnet: Subnet) -> bool {
         self.static_routes.contains(&subnet)
+            || self
+                .previous_identity()
+                .is_some_and(|pi| pi.subnet == subnet)
     }
 
-    pub fn route_packet(&self, mut data_packet: DataPacket) {
-        let node_tun_subnet = self.node_tun_subnet();
+    /// The subnets announced by this node, with the [`RouterId`] they are announced with. During
+    /// the grace period of a key rotation, this includes the subnet of the previous key.
+    fn local_routes(&self) -> Vec<(Subnet, RouterId)> {
+        let mut routes = self
+            .static_routes
+            .iter()
+            .map(|sr| (*sr, self.router_id))
+            .collect::<Vec<_>>();
+        if let Some(pi) = self.previous_identity() {
+            routes.push((pi.subnet, pi.router_id));
+        }
+        routes
+    }
 
+    /// Checks if the given [`RouterId`] belongs to this node.
+    fn is_local_router_id(&self, router_id: RouterId) -> bool {
+        router_id == self.router_id
+            || self
+                .previous_identity()
+                .is_some_and(|pi| pi.router_id == router_id)
+    }
+
+    /// Checks if the given address is part of a prefix this node is a gateway for.
+    pub fn is_gateway_address(&self, ip: Ipv6Addr) -> bool {
+        self.gateway_prefix_len(ip).is_some()
+    }
+
+    /// Get the prefix length of the most specific prefix this node is a gateway for which
+    /// contains the given address.
+    fn gateway_prefix_len(&self, ip: Ipv6Addr) -> Option<u8> {
+        self.static_routes
+            .iter()
+            .filter(|sr| *sr != &self.node_tun_subnet && sr.contains_ip(ip.into()))
+            .map(|sr| sr.prefix_len())
+            .max()
+    }
+
+    /// Checks if a packet for the given destination must be delivered locally because this node
+    /// is a gateway for it. More specific routes in the routing table take precedence over the
+    /// gateway prefix.
+    fn is_gateway_destination(&self, ip: Ipv6Addr) -> bool {
+        let Some(prefix_len) = self.gateway_prefix_len(ip) else {
+            return false;
+        };
+        self.routing_table
+            .selected_route(ip.into())
+            .map_or(true, |re| re.source().subnet().prefix_len() < prefix_len)
+    }
+
+    /// Checks if a packet for the given destination is delivered to this node, instead of being
+    /// forwarded to a peer.
+    fn is_local_destination(&self, ip: Ipv6Addr) -> bool {
+        self.node_tun_subnet().contains_ip(ip.into())
+            || self.is_previous_address(ip.into())
+            || self.is_gateway_destination(ip)
+    }
+
+    pub fn route_packet(&self, mut data_packet: DataPacket) {
         trace!(
             "Incoming data packet {} -> {}",
             data_packet.src_ip,
@@ -1401,15 +1912,16 @@ where
         }
         data_packet.hop_limit -= 1;
 
-        if node_tun_subnet.contains_ip(data_packet.dst_ip.into()) {
+        if self.is_local_destination(data_packet.dst_ip) {
             self.metrics.router_route_packet_local();
             if let Err(e) = self.node_tun().send(data_packet) {
                 error!("Error sending data packet to TUN interface: {:?}", e);
             }
         } else {
-            match self.routing_table.selected_route(data_packet.dst_ip.into()) {
+            match self.forwarding_route(&data_packet) {
                 Some(route_entry) => {
                     self.metrics.router_route_packet_forward();
+                    route_entry.record_forwarded(data_packet.raw_data.len());
                     if let Err(e) = route_entry.neighbour().send_data_packet(data_packet) {
                         error!(
                             "Error sending data packet to peer {}: {:?}",
@@ -1426,9 +1938,58 @@ where
         }
     }
 
+    /// Get the route a data packet is forwarded over. Without multipath forwarding, this is the
+    /// selected route for the destination.
+    ///
+    /// With multipath forwarding, all feasible routes with a metric close enough to the metric of
+    /// the selected route are candidates. Feasible routes are advertised by neighbours which are
+    /// closer to the destination than this node, so forwarding over them can't cause a loop. The
+    /// route is chosen based on the flow of the packet, so all packets of a flow take the same
+    /// route as long as the candidates don't change.
+    fn forwarding_route(&self, data_packet: &DataPacket) -> Option<RouteEntry> {
+        let Some(tolerance) = self.multipath_tolerance else {
+            return self.routing_table.selected_route(data_packet.dst_ip.into());
+        };
+
+        let routes = self.routing_table.best_routes(data_packet.dst_ip.into())?;
+        let selected = routes.selected()?;
+        let max_metric =
+            selected.metric() + Metric::from(selected.neighbour().link_cost()) + tolerance;
+
+        let candidates = {
+            let source_table = self.source_table.read().unwrap();
+            routes
+                .iter()
+                .filter(|re| {
+                    re.selected()
+                        || (!re.metric().is_infinite()
+                            && re.neighbour().alive()
+                            && !re.neighbour().backup()
+                            && source_table.route_feasible(re)
+                            && re.metric() + Metric::from(re.neighbour().link_cost()) <= max_metric)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if candidates.len() == 1 {
+            return Some(selected.clone());
+        }
+
+        let mut hasher = std::hash::DefaultHasher::new();
+        (data_packet.src_ip, data_packet.dst_ip, data_packet.flow).hash(&mut hasher);
+        let idx = (hasher.finish() % candidates.len() as u64) as usize;
+
+        Some(candidates[idx].clone())
+    }
+
     /// Handle a received data packet.
     async fn handle_incoming_data_packet(self, mut router_data_rx: Receiver<DataPacket>) {
         while let Some(data_packet) = router_data_rx.recv().await {
+            if self.leaf && !self.is_local_destination(data_packet.dst_ip) {
+                self.metrics.router_route_packet_transit_prohibited();
+                self.transit_prohibited(data_packet);
+                continue;
+            }
             self.route_packet(data_packet);
         }
         warn!("Router data receiver stream ended");
@@ -1456,6 +2017,19 @@ where
         )
     }
 
+    /// Handle a packet from a peer for another node, if this node is a leaf.
+    fn transit_prohibited(&self, data_packet: DataPacket) {
+        trace!(
+            "Refusing to forward data packet for {} as leaf node",
+            data_packet.dst_ip
+        );
+
+        self.oob_icmp(
+            Icmpv6Type::DestinationUnreachable(DestUnreachableCode::Prohibited),
+            data_packet,
+        )
+    }
+
     /// Send an oob icmp packet of the specified type in reply to the given DataPakcet.
     fn oob_icmp(&self, icmp_type: Icmpv6Type, mut data_packet: DataPacket) {
         let src_ip = if let IpAddr::V6(ip) = self.node_tun_subnet.address() {
@@ -1510,6 +2084,7 @@ where
             src_ip,
             hop_limit: 64,
             raw_data: enc,
+            flow: 0,
         });
     }
 
@@ -1538,6 +2113,13 @@ where
         loop {
             tokio::time::sleep(ROUTE_PROPAGATION_INTERVAL).await;
 
+            // Leaf nodes only advertise their own routes, which are propagated as static routes.
+            // Peers learn that other routes are unreachable through this node from triggered
+            // updates and route table dumps, so there is no need to repeat that periodically.
+            if self.leaf {
+                continue;
+            }
+
             trace!("Propagating selected routes");
 
             let start = Instant::now();
@@ -1645,7 +2227,11 @@ where
 
             for peer in self.peer_interfaces.read().unwrap().iter() {
                 let hello = ControlPacket::new_hello(peer, hello_interval);
-                peer.set_time_last_received_hello(tokio::time::Instant::now());
+                let now = tokio::time::Instant::now();
+                peer.set_time_last_received_hello(now);
+                peer.record_hello_sent(now);
+                self.metrics
+                    .router_peer_link_quality(peer.connection_identifier(), peer.link_quality());
 
                 if peer.send_control_packet(hello).is_err() {
                     trace!(
@@ -1660,7 +2246,7 @@ where
     /// Send a control packet to a peer.
     ///
     /// Errors are not propagated to the caller.
-    fn send_update(&self, peer: &Peer, update: Update) {
+    fn send_update(&self, peer: &Peer, mut update: Update) {
         trace!("Sending update to peer");
 
         // Sanity check, verify what we are doing is actually usefull
@@ -1670,6 +2256,21 @@ where
             return;
         }
 
+        // Announcements of gateway prefixes must carry the signature of the gateway.
+        if !update.metric().is_infinite() {
+            if let Some(signature) = self.gateway_signature(&update) {
+                update.set_gateway_signature(Some(signature));
+            }
+
+            // Peers which told us they don't handle signed updates have no use for the signature.
+            // Peers without handshake might still forward it.
+            if peer.handshake().map_or(true, |h| {
+                h.capabilities.contains(Capabilities::SIGNED_UPDATES)
+            }) {
+                self.sign_origin(&mut update);
+            }
+        }
+
         if peer
             .send_control_packet(ControlPacket::Update(update))
             .is_err()
@@ -1682,15 +2283,138 @@ where
         }
     }
 
+    /// Get the gateway signature for an update of a prefix outside of the overlay. For the
+    /// prefixes we are a gateway for, the update is signed if we did not sign it already. For
+    /// other prefixes, the signature received from the gateway is used, if we have it for the
+    /// sequence number of the update.
+    fn gateway_signature(&self, update: &Update) -> Option<Signature> {
+        let key = (update.subnet(), update.router_id().to_pubkey());
+        let signature = self
+            .gateway_signatures
+            .read()
+            .unwrap()
+            .get(&key)
+            .and_then(|signatures| {
+                signatures
+                    .iter()
+                    .find(|(seqno, _)| *seqno == update.seqno())
+                    .map(|(_, signature)| *signature)
+            });
+        if signature.is_some() {
+            return signature;
+        }
+
+        // Static routes which don't contain our own address are prefixes we are a gateway for.
+        if update.router_id() != self.router_id
+            || !self.static_routes.contains(&update.subnet())
+            || update
+                .subnet()
+                .contains_ip(self.node_keypair.1.address().into())
+        {
+            return None;
+        }
+        let signature = self.node_keypair.0.sign(&gateway::announcement_data(
+            update.subnet(),
+            &self.node_keypair.1,
+            update.seqno(),
+        ));
+        self.remember_gateway_signature(key, update.seqno(), signature);
+        Some(signature)
+    }
+
+    /// Remember a gateway signature for the given prefix and sequence number. Only the
+    /// signatures for the most recent sequence numbers are kept.
+    fn remember_gateway_signature(
+        &self,
+        key: (Subnet, PublicKey),
+        seqno: SeqNo,
+        signature: Signature,
+    ) {
+        let mut gateway_signatures = self.gateway_signatures.write().unwrap();
+        let signatures = gateway_signatures.entry(key).or_default();
+        if signatures.iter().any(|(s, _)| *s == seqno) {
+            return;
+        }
+        if signatures.len() >= SIGNATURES_PER_SOURCE {
+            signatures.remove(0);
+        }
+        signatures.push((seqno, signature));
+    }
+
+    /// Attach the origin signature and origin flags to an update. For our own routes, the update
+    /// is signed if we did not sign it already. For other routes, the signature received from the
+    /// origin is used, if we have it for the sequence number of the update.
+    fn sign_origin(&self, update: &mut Update) {
+        let source_key = SourceKey::new(update.subnet(), update.router_id());
+        let known = self
+            .origin_signatures
+            .read()
+            .unwrap()
+            .get(&source_key)
+            .and_then(|signatures| {
+                signatures
+                    .iter()
+                    .find(|(seqno, _, _)| *seqno == update.seqno())
+                    .map(|(_, flags, signature)| (*flags, *signature))
+            });
+        if let Some((flags, signature)) = known {
+            update.set_origin_flags(flags);
+            update.set_origin_signature(Some(signature));
+            return;
+        }
+
+        let signature = if update.router_id() == self.router_id {
+            // We only receive data packets encrypted with a session key from nodes which support
+            // it, so announce that nodes which know this must not accept anything else.
+            update.set_origin_flags(babel::ORIGIN_FLAG_DATA_SESSIONS);
+            self.node_keypair.0.sign(&update.origin_signature_data())
+        } else {
+            match self.previous_identity() {
+                Some(pi) if pi.router_id == update.router_id() => {
+                    update.set_origin_flags(0);
+                    pi.keypair.0.sign(&update.origin_signature_data())
+                }
+                _ => return,
+            }
+        };
+        self.remember_origin_signature(
+            source_key,
+            update.seqno(),
+            update.origin_flags(),
+            signature,
+        );
+        update.set_origin_signature(Some(signature));
+    }
+
+    /// Remember an origin signature, and the origin flags it covers, for the given source and
+    /// sequence number. Only the signatures for the most recent sequence numbers are kept.
+    fn remember_origin_signature(
+        &self,
+        source_key: SourceKey,
+        seqno: SeqNo,
+        flags: u8,
+        signature: Signature,
+    ) {
+        let mut origin_signatures = self.origin_signatures.write().unwrap();
+        let signatures = origin_signatures.entry(source_key).or_default();
+        if signatures.iter().any(|(s, _, _)| *s == seqno) {
+            return;
+        }
+        if signatures.len() >= SIGNATURES_PER_SOURCE {
+            signatures.remove(0);
+        }
+        signatures.push((seqno, flags, signature));
+    }
+
     /// Propagate the static routes to a single peer
     fn propagate_static_route_to_peer(&self, peer: &Peer) {
-        for sr in self.static_routes.iter() {
+        for (sr, router_id) in self.local_routes() {
             let update = babel::Update::new(
                 UPDATE_INTERVAL,
                 self.router_seqno.read().unwrap().0, // updates receive the seqno of the router
                 Metric::from(0),                     // Static route has no further hop costs
-                *sr,
-                self.router_id,
+                sr,
+                router_id,
             );
             self.update_source_table(&update);
 
@@ -1707,7 +2431,7 @@ where
                 let update = babel::Update::new(
                     advertised_update_interval(&sre),
                     sre.seqno(),
-                    sre.metric() + Metric::from(sre.neighbour().link_cost()),
+                    self.advertised_metric(&sre),
                     sre.source().subnet(),
                     sre.source().router_id(),
                 );
@@ -1756,6 +2480,17 @@ where
         };
     }
 
+    /// The metric advertised to peers for a selected route. This is the metric of the route plus
+    /// the cost of the link to the next hop. Leaf nodes don't forward traffic for other nodes, so
+    /// they advertise all selected routes as unreachable.
+    fn advertised_metric(&self, route: &RouteEntry) -> Metric {
+        if self.leaf {
+            Metric::infinite()
+        } else {
+            route.metric() + Metric::from(route.neighbour().link_cost())
+        }
+    }
+
     /// Propagate all selected routes to all peers known in the router.
     fn propagate_selected_routes_to_peer(&self, peer: &Peer) {
         for (subnet, sre) in self
@@ -1764,24 +2499,23 @@ where
             .iter()
             .filter_map(|(subnet, route_list)| route_list.selected().map(|sr| (subnet, sr.clone())))
         {
-            let neigh_link_cost = Metric::from(sre.neighbour().link_cost());
             // Don't send updates for a route to the next hop of the route, as that peer will never
             // select the route through us (that would caus a routing loop). The protocol can
             // handle this just fine, leaving this out is essentially an easy optimization.
             if peer == sre.neighbour() {
                 continue;
             }
+            let metric = self.advertised_metric(&sre);
             let update = babel::Update::new(
                 advertised_update_interval(&sre),
                 sre.seqno(),
-                // the cost of the route is the cost of the route + the cost of the link to the next-hop
-                sre.metric() + neigh_link_cost,
+                metric,
                 subnet,
                 sre.source().router_id(),
             );
             debug!(
                 subnet = %subnet,
-                metric = %sre.metric() + neigh_link_cost,
+                metric = %metric,
                 seqno = %sre.seqno(),
                 peer = peer.connection_identifier(),
                 "Propagating route update",
@@ -1808,13 +2542,23 @@ where
             source_table: self.source_table.clone(),
             router_seqno: self.router_seqno.clone(),
             static_routes: self.static_routes.clone(),
+            gateway_signatures: self.gateway_signatures.clone(),
+            origin_signatures: self.origin_signatures.clone(),
+            require_origin_signatures: self.require_origin_signatures,
+            multipath_tolerance: self.multipath_tolerance,
+            selection_hysteresis: self.selection_hysteresis,
+            route_damping: self.route_damping.clone(),
+            leaf: self.leaf,
             router_id: self.router_id,
             node_keypair: self.node_keypair.clone(),
+            previous_identity: self.previous_identity.clone(),
+            successions: self.successions.clone(),
             router_data_tx: self.router_data_tx.clone(),
             router_control_tx: self.router_control_tx.clone(),
             node_tun: self.node_tun.clone(),
             node_tun_subnet: self.node_tun_subnet,
             update_filters: self.update_filters.clone(),
+            route_policy: self.route_policy.clone(),
             dead_peer_sink: self.dead_peer_sink.clone(),
             expired_source_key_sink: self.expired_source_key_sink.clone(),
             seqno_cache: self.seqno_cache.clone(),
@@ -1824,6 +2568,21 @@ where
     }
 }
 
+/// Error returned when the selection hysteresis exceeds [`MAX_SELECTION_HYSTERESIS`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSelectionHysteresis;
+
+/// Check if the given selection hysteresis can be used.
+pub fn validate_selection_hysteresis(
+    selection_hysteresis: u16,
+) -> Result<(), InvalidSelectionHysteresis> {
+    if selection_hysteresis > MAX_SELECTION_HYSTERESIS {
+        return Err(InvalidSelectionHysteresis);
+    }
+
+    Ok(())
+}
+
 /// Calculate the hold time for a [`RouteEntry`] from an [`Update`](babel::Update) .
 fn route_hold_time(update: &babel::Update) -> Duration {
     // According to https://datatracker.ietf.org/doc/html/rfc8966#section-appendix.b a good value
@@ -1853,6 +2612,17 @@ fn advertised_update_interval(sre: &RouteEntry) -> Duration {
     }
 }
 
+impl fmt::Display for InvalidSelectionHysteresis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid selection hysteresis, expected at most {MAX_SELECTION_HYSTERESIS}"
+        )
+    }
+}
+
+impl std::error::Error for InvalidSelectionHysteresis {}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -1864,8 +2634,8 @@ mod tests {
     use tokio::sync::mpsc;
 
     use crate::{
-        babel::Update, crypto::PublicKey, metric::Metric, peer::Peer, router_id::RouterId,
-        sequence_number::SeqNo, source_table::SourceKey, subnet::Subnet,
+        babel::Update, crypto::PublicKey, link_auth::LinkKeys, metric::Metric, peer::Peer,
+        router_id::RouterId, sequence_number::SeqNo, source_table::SourceKey, subnet::Subnet,
     };
 
     #[test]
@@ -1912,6 +2682,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn selection_hysteresis_must_be_finite() {
+        assert_eq!(
+            super::validate_selection_hysteresis(super::DEFAULT_SELECTION_HYSTERESIS),
+            Ok(())
+        );
+        assert_eq!(
+            super::validate_selection_hysteresis(super::MAX_SELECTION_HYSTERESIS),
+            Ok(())
+        );
+        assert_eq!(
+            super::validate_selection_hysteresis(u16::MAX),
+            Err(super::InvalidSelectionHysteresis)
+        );
+        // The largest allowed hysteresis is a finite metric, which can be subtracted.
+        assert!(!Metric::from(super::MAX_SELECTION_HYSTERESIS).is_infinite());
+        assert_eq!(
+            Metric::new(10) - Metric::from(super::MAX_SELECTION_HYSTERESIS),
+            Metric::new(0)
+        );
+    }
+
     #[tokio::test]
     async fn calculate_advertised_update_interval() {
         // Set up a dummy peer since that is needed to create a `RouteEntry`
@@ -1926,6 +2718,7 @@ mod tests {
             dead_peer_sink,
             Arc::new(AtomicU64::new(0)),
             Arc::new(AtomicU64::new(0)),
+            &LinkKeys::default(),
         )
         .expect("Can create a dummy peer");
         let subnet = Subnet::new(IpAddr::V6(Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 0)), 64)
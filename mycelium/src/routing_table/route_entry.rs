@@ -1,3 +1,8 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
 use tokio::time::Instant;
 
 use crate::{
@@ -15,6 +20,16 @@ pub struct RouteEntry {
     seqno: SeqNo,
     selected: bool,
     expires: Instant,
+    /// Traffic forwarded over this route. This is shared between clones of the `RouteEntry`, so
+    /// it is kept when the entry is updated in the routing table.
+    traffic: Arc<RouteTraffic>,
+}
+
+/// Counters for the traffic forwarded over a route.
+#[derive(Default)]
+struct RouteTraffic {
+    packets: AtomicU64,
+    bytes: AtomicU64,
 }
 
 impl RouteEntry {
@@ -34,6 +49,7 @@ impl RouteEntry {
             seqno,
             selected,
             expires,
+            traffic: Arc::new(RouteTraffic::default()),
         }
     }
 
@@ -68,6 +84,24 @@ impl RouteEntry {
         self.expires
     }
 
+    /// Return the amount of data packets forwarded over this `RouteEntry`.
+    pub fn forwarded_packets(&self) -> u64 {
+        self.traffic.packets.load(Ordering::Relaxed)
+    }
+
+    /// Return the amount of bytes of data forwarded over this `RouteEntry`.
+    pub fn forwarded_bytes(&self) -> u64 {
+        self.traffic.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Record that a data packet with the given size is forwarded over this `RouteEntry`.
+    pub fn record_forwarded(&self, bytes: usize) {
+        self.traffic.packets.fetch_add(1, Ordering::Relaxed);
+        self.traffic
+            .bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
     /// Set the [`SourceKey`] for this `RouteEntry`.
     pub fn set_source(&mut self, source: SourceKey) {
         self.source = source;
@@ -81,6 +115,8 @@ impl RouteEntry {
     /// Sets the [`neighbour`](Peer) for this `RouteEntry`.
     pub fn set_neighbour(&mut self, neighbour: Peer) {
         self.neighbour = neighbour;
+        // Traffic is counted per next hop.
+        self.traffic = Arc::new(RouteTraffic::default());
     }
 
     /// Sets the [`Metric`] for this `RouteEntry`.
@@ -115,6 +151,8 @@ impl std::fmt::Debug for RouteEntry {
             .field("seqno", &self.seqno)
             .field("selected", &self.selected)
             .field("expires", &self.expires)
+            .field("forwarded_packets", &self.forwarded_packets())
+            .field("forwarded_bytes", &self.forwarded_bytes())
             .finish()
     }
 }
@@ -0,0 +1,882 @@
+//! Ephemeral session keys for data packets between nodes.
+//!
+//! Data packets are encrypted with the [`SharedSecret`] computed from the static keys of the
+//! source and destination, using a random nonce. Such packets can be replayed, and anyone who
+//! learns a node key can decrypt all past traffic of that node. To avoid this, nodes which both
+//! support it agree on session keys with a handshake, in which both sides contribute an ephemeral
+//! key. The handshake messages are data packets encrypted with the static [`SharedSecret`], which
+//! authenticates them, and the session keys are derived from both the static and the ephemeral
+//! secrets.
+//!
+//! Packets encrypted with a session key use a nonce consisting of the index of the session chosen
+//! by the receiver, followed by a counter. The receiver uses the index to find the key, and keeps
+//! a sliding window of received counters to drop replayed packets. Sessions are replaced by a new
+//! handshake every few minutes, after which the old keys are forgotten.
+//!
+//! The handshake also selects the [`Cipher`] used for the session. ChaCha20-Poly1305 is used if
+//! either node prefers it, since it is fast on all hardware, while AES-GCM is slow on hardware
+//! without AES instructions.
+//!
+//! Nodes which don't support sessions ignore the handshake, in which case the static
+//! [`SharedSecret`] keeps being used, with AES-GCM.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, trace};
+
+use crate::crypto::{
+    self, Cipher, DecryptionError, Nonce, PacketBuffer, PublicKey, SecretKey, SharedSecret,
+};
+
+/// Type of a handshake message starting a session.
+const HANDSHAKE_INIT: u8 = 1;
+
+/// Type of a handshake message answering a [`HANDSHAKE_INIT`].
+const HANDSHAKE_RESPONSE: u8 = 2;
+
+/// Size of a handshake init: type, sender index, timestamp, ephemeral public key and preferred
+/// cipher.
+const HANDSHAKE_INIT_SIZE: usize = 1 + 4 + 8 + 32 + 1;
+
+/// Size of a handshake response: type, sender index, receiver index, ephemeral public key and
+/// selected cipher.
+const HANDSHAKE_RESPONSE_SIZE: usize = 1 + 4 + 4 + 32 + 1;
+
+/// Context used to derive session keys from the handshake secrets.
+const SESSION_KEY_CONTEXT: &str = "mycelium 2024 data plane session keys";
+
+/// Age of a session after which the node which initiated it starts a new handshake.
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// Age of a session after which the node which responded to its handshake starts a new
+/// handshake. This is later than [`REKEY_AFTER_TIME`], so usually only one side rekeys.
+const RESPONDER_REKEY_AFTER_TIME: Duration = Duration::from_secs(150);
+
+/// Age of a session after which its keys are forgotten.
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// Amount of packets after which a session key is no longer used.
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
+/// Minimum time between handshakes started with the same node.
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum time between handshakes started with a node which does not answer them, usually
+/// because it does not support sessions.
+const MAX_HANDSHAKE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Time after which all state for a node which we don't exchange packets with is removed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Maximum amount of sessions kept per node.
+const MAX_SESSIONS: usize = 3;
+
+/// Amount of 64 bit words in a [`ReplayWindow`].
+const REPLAY_WINDOW_WORDS: usize = 32;
+
+/// Amount of counters below the highest received counter which are accepted by a
+/// [`ReplayWindow`]. One word is reserved to advance the window without clearing valid bits.
+const REPLAY_WINDOW_SIZE: u64 = (REPLAY_WINDOW_WORDS as u64 - 1) * 64;
+
+/// Keeps track of the sessions with all nodes we exchange data packets with.
+pub(crate) struct SessionManager {
+    /// State per remote node, keyed by the static [`SharedSecret`] with the node, since that
+    /// uniquely identifies it.
+    remotes: Mutex<HashMap<[u8; 32], RemoteSessions>>,
+    /// Timestamp of the last handshake init we sent.
+    last_timestamp: AtomicU64,
+    /// The cipher we prefer for new sessions.
+    preferred_cipher: Cipher,
+}
+
+/// Sessions and handshake state for a single remote node.
+struct RemoteSessions {
+    /// Established sessions, newest last.
+    sessions: Vec<Session>,
+    /// The handshake we started, if it was not answered yet.
+    pending: Option<PendingHandshake>,
+    /// Earliest time at which a new handshake may be started.
+    next_handshake: Instant,
+    /// Amount of handshakes which were not answered since the last completed one.
+    unanswered: u32,
+    /// Timestamp of the last accepted handshake init of the remote, to drop replayed inits.
+    last_init_timestamp: u64,
+    /// Last time a packet was sent to or received from the remote.
+    last_used: Instant,
+}
+
+/// A handshake which is waiting for a response.
+struct PendingHandshake {
+    /// Index chosen by us for the session.
+    local_index: u32,
+    /// The ephemeral key we sent.
+    ephemeral: SecretKey,
+}
+
+/// Keys and state of a single session.
+struct Session {
+    /// Index chosen by us, which the remote sets in the nonce of packets it sends.
+    local_index: u32,
+    /// Index chosen by the remote, which we set in the nonce of packets we send.
+    remote_index: u32,
+    send_key: SharedSecret,
+    receive_key: SharedSecret,
+    /// The cipher used with the keys of this session.
+    cipher: Cipher,
+    /// Counter of the next packet we send.
+    send_counter: u64,
+    replay_window: ReplayWindow,
+    created: Instant,
+    /// Set if we started the handshake of this session.
+    initiator: bool,
+    /// Set once a packet is received on this session. The node which answered the handshake only
+    /// sends packets on the session once this is set, since it knows the remote has the keys.
+    received: bool,
+}
+
+/// How a received data packet was protected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protection {
+    /// The packet was encrypted with the static [`SharedSecret`], which always uses AES-GCM.
+    Static,
+    /// The packet was encrypted with a session key, using the given cipher.
+    Session(Cipher),
+}
+
+/// Error returned when a received data packet can't be decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReceiveError {
+    /// The packet was already received on its session, or is older than the replay window.
+    Replayed,
+    /// The packet could not be decrypted, for instance because it uses a session we don't know.
+    Decryption,
+}
+
+/// Sliding window of received packet counters, following
+/// [RFC 6479](https://datatracker.ietf.org/doc/html/rfc6479).
+struct ReplayWindow {
+    /// Highest counter received so far.
+    last: u64,
+    bitmap: [u64; REPLAY_WINDOW_WORDS],
+}
+
+impl SessionManager {
+    /// Create a new `SessionManager` without any sessions, which proposes the given cipher for
+    /// new sessions.
+    pub fn new(preferred_cipher: Cipher) -> Self {
+        Self {
+            remotes: Mutex::new(HashMap::new()),
+            last_timestamp: AtomicU64::new(0),
+            preferred_cipher,
+        }
+    }
+
+    /// Encrypt a packet for the node with which we share the given static secret. The newest
+    /// usable session is used if there is one, otherwise the packet is encrypted with the static
+    /// secret. The version of the user data header is set to the one of the cipher which is used.
+    ///
+    /// If a new session is needed, a handshake message is returned as well, which must be sent to
+    /// the remote encrypted with the static secret.
+    pub fn encrypt(
+        &self,
+        shared_secret: &SharedSecret,
+        mut packet: PacketBuffer,
+    ) -> (Vec<u8>, Option<PacketBuffer>) {
+        let now = Instant::now();
+        let mut remotes = self.remotes.lock().unwrap();
+        let remote = remotes
+            .entry(**shared_secret)
+            .or_insert_with(|| RemoteSessions::new(now));
+        remote.last_used = now;
+
+        let session = remote
+            .sessions
+            .iter_mut()
+            .rev()
+            .find(|session| session.can_send(now));
+        let rekey = session
+            .as_ref()
+            .map_or(true, |session| session.needs_rekey(now));
+        let data = match session {
+            Some(session) => {
+                let mut nonce = [0; 12];
+                nonce[..4].copy_from_slice(&session.remote_index.to_be_bytes());
+                nonce[4..].copy_from_slice(&session.send_counter.to_be_bytes());
+                session.send_counter += 1;
+                packet.header_mut()[0] = session.cipher.header_version();
+                session
+                    .send_key
+                    .encrypt_with_nonce(packet, session.cipher, nonce)
+            }
+            None => {
+                packet.header_mut()[0] = Cipher::Aes256Gcm.header_version();
+                shared_secret.encrypt(packet)
+            }
+        };
+
+        let handshake = if rekey {
+            self.start_handshake(remote, now)
+        } else {
+            None
+        };
+
+        (data, handshake)
+    }
+
+    /// Decrypt a packet received from the node with which we share the given static secret.
+    /// Packets sent on a session are decrypted with its key, and dropped if they are replayed.
+    /// Other packets are decrypted with the static secret.
+    pub fn decrypt(
+        &self,
+        shared_secret: &SharedSecret,
+        data: Vec<u8>,
+    ) -> Result<(PacketBuffer, Protection), ReceiveError> {
+        let now = Instant::now();
+        let Some((index, counter)) = crypto::nonce(&data).map(split_nonce) else {
+            return Err(ReceiveError::Decryption);
+        };
+
+        let mut remotes = self.remotes.lock().unwrap();
+        let Some(remote) = remotes.get_mut(&**shared_secret) else {
+            drop(remotes);
+            return Ok((shared_secret.decrypt(data)?, Protection::Static));
+        };
+        remote.last_used = now;
+
+        // A random nonce of a packet encrypted with the static secret can match the index of a
+        // session, in which case the packet is lost. Given the size of the index, this is
+        // exceedingly rare.
+        let Some(session) = remote
+            .sessions
+            .iter_mut()
+            .find(|session| session.local_index == index && !session.expired(now))
+        else {
+            drop(remotes);
+            return Ok((shared_secret.decrypt(data)?, Protection::Static));
+        };
+
+        if !session.replay_window.check(counter) {
+            trace!(counter, "Dropping replayed data packet");
+            return Err(ReceiveError::Replayed);
+        }
+        let packet = session.receive_key.decrypt_with(data, session.cipher)?;
+        session.replay_window.update(counter);
+        session.received = true;
+
+        Ok((packet, Protection::Session(session.cipher)))
+    }
+
+    /// Decrypt a packet we sent to the node with which we share the given static secret. This is
+    /// needed for ICMP errors about such packets, which contain the original encrypted packet.
+    pub fn decrypt_sent(
+        &self,
+        shared_secret: &SharedSecret,
+        data: Vec<u8>,
+    ) -> Result<PacketBuffer, DecryptionError> {
+        let Some((index, _)) = crypto::nonce(&data).map(split_nonce) else {
+            return Err(DecryptionError);
+        };
+
+        let send_key = self
+            .remotes
+            .lock()
+            .unwrap()
+            .get(&**shared_secret)
+            .and_then(|remote| {
+                remote
+                    .sessions
+                    .iter()
+                    .find(|session| session.remote_index == index)
+            })
+            .map(|session| (session.send_key.clone(), session.cipher));
+
+        match send_key {
+            Some((key, cipher)) => key
+                .decrypt_with(data.clone(), cipher)
+                .or_else(|_| shared_secret.decrypt(data)),
+            None => shared_secret.decrypt(data),
+        }
+    }
+
+    /// Check if data packets from the node with which we share the given static secret must be
+    /// encrypted with a session key. This is the case once the remote sent packets on a session
+    /// which is still valid, since it then supports sessions.
+    pub fn requires_session(&self, shared_secret: &SharedSecret) -> bool {
+        let now = Instant::now();
+        self.remotes
+            .lock()
+            .unwrap()
+            .get(&**shared_secret)
+            .is_some_and(|remote| {
+                remote
+                    .sessions
+                    .iter()
+                    .any(|session| session.received && !session.expired(now))
+            })
+    }
+
+    /// Start a handshake with the node with which we share the given static secret, unless one
+    /// was started recently. This is used if the remote sends packets we can't decrypt, which
+    /// happens if it uses a session we forgot about, for instance because we restarted.
+    pub fn initiate(&self, shared_secret: &SharedSecret) -> Option<PacketBuffer> {
+        let now = Instant::now();
+        let mut remotes = self.remotes.lock().unwrap();
+        let remote = remotes
+            .entry(**shared_secret)
+            .or_insert_with(|| RemoteSessions::new(now));
+
+        self.start_handshake(remote, now)
+    }
+
+    /// Handle a handshake message received from the node with which we share the given static
+    /// secret. If a reply must be sent to the remote, it is returned. Like the handshake messages
+    /// returned by [`SessionManager::encrypt`], it must be encrypted with the static secret.
+    pub fn handle_handshake(
+        &self,
+        shared_secret: &SharedSecret,
+        message: &[u8],
+    ) -> Option<PacketBuffer> {
+        let now = Instant::now();
+        let mut remotes = self.remotes.lock().unwrap();
+        let remote = remotes
+            .entry(**shared_secret)
+            .or_insert_with(|| RemoteSessions::new(now));
+        remote.last_used = now;
+
+        match message.first() {
+            Some(&HANDSHAKE_INIT) if message.len() >= HANDSHAKE_INIT_SIZE => {
+                let remote_index = u32::from_be_bytes(message[1..5].try_into().unwrap());
+                let timestamp = u64::from_be_bytes(message[5..13].try_into().unwrap());
+                let remote_ephemeral =
+                    PublicKey::from(<[u8; 32]>::try_from(&message[13..45]).unwrap());
+                // Unknown ciphers are treated as having no preference.
+                let cipher = if Cipher::from_id(message[45]) == Some(Cipher::ChaCha20Poly1305) {
+                    Cipher::ChaCha20Poly1305
+                } else {
+                    self.preferred_cipher
+                };
+
+                if timestamp <= remote.last_init_timestamp {
+                    debug!("Dropping replayed session handshake");
+                    return None;
+                }
+                remote.last_init_timestamp = timestamp;
+
+                let ephemeral = SecretKey::new();
+                let local_ephemeral = PublicKey::from(&ephemeral);
+                let (initiator_key, responder_key) = derive_keys(
+                    shared_secret,
+                    &ephemeral.shared_secret(&remote_ephemeral),
+                    &remote_ephemeral,
+                    &local_ephemeral,
+                );
+                let local_index = remote.new_index();
+                remote.add_session(Session::new(
+                    local_index,
+                    remote_index,
+                    responder_key,
+                    initiator_key,
+                    cipher,
+                    false,
+                    now,
+                ));
+                trace!(local_index, remote_index, %cipher, "Accepted session handshake");
+
+                let mut response = PacketBuffer::new();
+                let buf = response.buffer_mut();
+                buf[0] = HANDSHAKE_RESPONSE;
+                buf[1..5].copy_from_slice(&local_index.to_be_bytes());
+                buf[5..9].copy_from_slice(&remote_index.to_be_bytes());
+                buf[9..41].copy_from_slice(local_ephemeral.as_bytes());
+                buf[41] = cipher.id();
+                response.set_size(HANDSHAKE_RESPONSE_SIZE);
+
+                Some(response)
+            }
+            Some(&HANDSHAKE_RESPONSE) if message.len() >= HANDSHAKE_RESPONSE_SIZE => {
+                let remote_index = u32::from_be_bytes(message[1..5].try_into().unwrap());
+                let local_index = u32::from_be_bytes(message[5..9].try_into().unwrap());
+                let remote_ephemeral =
+                    PublicKey::from(<[u8; 32]>::try_from(&message[9..41]).unwrap());
+                let Some(cipher) = Cipher::from_id(message[41]) else {
+                    debug!("Dropping session handshake response with unknown cipher");
+                    return None;
+                };
+
+                if !matches!(remote.pending, Some(ref pending) if pending.local_index == local_index)
+                {
+                    debug!("Dropping session handshake response for unknown handshake");
+                    return None;
+                }
+                let pending = remote.pending.take()?;
+
+                let (initiator_key, responder_key) = derive_keys(
+                    shared_secret,
+                    &pending.ephemeral.shared_secret(&remote_ephemeral),
+                    &PublicKey::from(&pending.ephemeral),
+                    &remote_ephemeral,
+                );
+                remote.add_session(Session::new(
+                    local_index,
+                    remote_index,
+                    initiator_key,
+                    responder_key,
+                    cipher,
+                    true,
+                    now,
+                ));
+                remote.unanswered = 0;
+                remote.next_handshake = now + REKEY_TIMEOUT;
+                trace!(local_index, remote_index, %cipher, "Completed session handshake");
+
+                None
+            }
+            _ => {
+                debug!("Dropping malformed session handshake");
+                None
+            }
+        }
+    }
+
+    /// Remove expired sessions, and all state of nodes we did not exchange packets with for a
+    /// while.
+    pub fn remove_expired(&self) {
+        let now = Instant::now();
+        let mut remotes = self.remotes.lock().unwrap();
+        remotes.retain(|_, remote| {
+            remote.sessions.retain(|session| !session.expired(now));
+            now.duration_since(remote.last_used) < IDLE_TIMEOUT
+        });
+    }
+
+    /// Periodically remove expired sessions, so their keys are forgotten even if the remote is no
+    /// longer used.
+    pub async fn sweep_sessions(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REKEY_TIMEOUT);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+            self.remove_expired();
+        }
+    }
+
+    /// Start a new handshake with a remote, unless one was started recently. Returns the
+    /// handshake init to send.
+    fn start_handshake(&self, remote: &mut RemoteSessions, now: Instant) -> Option<PacketBuffer> {
+        if now < remote.next_handshake {
+            return None;
+        }
+
+        // Back off if the remote does not answer, which is the case for nodes which don't
+        // support sessions.
+        if remote.pending.is_some() {
+            remote.unanswered = remote.unanswered.saturating_add(1);
+        }
+        remote.next_handshake = now
+            + REKEY_TIMEOUT
+                .saturating_mul(1 << remote.unanswered.min(16))
+                .min(MAX_HANDSHAKE_INTERVAL);
+
+        let ephemeral = SecretKey::new();
+        let local_index = remote.new_index();
+        let mut init = PacketBuffer::new();
+        let buf = init.buffer_mut();
+        buf[0] = HANDSHAKE_INIT;
+        buf[1..5].copy_from_slice(&local_index.to_be_bytes());
+        buf[5..13].copy_from_slice(&self.next_timestamp().to_be_bytes());
+        buf[13..45].copy_from_slice(PublicKey::from(&ephemeral).as_bytes());
+        buf[45] = self.preferred_cipher.id();
+        init.set_size(HANDSHAKE_INIT_SIZE);
+
+        remote.pending = Some(PendingHandshake {
+            local_index,
+            ephemeral,
+        });
+        trace!(local_index, "Starting session handshake");
+
+        Some(init)
+    }
+
+    /// Get a timestamp for a handshake init, which is higher than all timestamps we sent before.
+    fn next_timestamp(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        // If the clock did not advance, just increment the previous value.
+        let previous = self
+            .last_timestamp
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .expect("Update closure always returns a value; qed");
+        now.max(previous + 1)
+    }
+}
+
+impl RemoteSessions {
+    /// Create the state for a new remote, without sessions.
+    fn new(now: Instant) -> Self {
+        Self {
+            sessions: Vec::new(),
+            pending: None,
+            next_handshake: now,
+            unanswered: 0,
+            last_init_timestamp: 0,
+            last_used: now,
+        }
+    }
+
+    /// Pick a random index for a new session, which is not used by another session.
+    fn new_index(&self) -> u32 {
+        loop {
+            let index = rand::random();
+            if !self
+                .sessions
+                .iter()
+                .any(|session| session.local_index == index)
+            {
+                return index;
+            }
+        }
+    }
+
+    /// Add a new session, removing the oldest one if there are too many.
+    fn add_session(&mut self, session: Session) {
+        if self.sessions.len() >= MAX_SESSIONS {
+            self.sessions.remove(0);
+        }
+        self.sessions.push(session);
+    }
+}
+
+impl Session {
+    /// Create a new session with the given keys.
+    fn new(
+        local_index: u32,
+        remote_index: u32,
+        send_key: SharedSecret,
+        receive_key: SharedSecret,
+        cipher: Cipher,
+        initiator: bool,
+        now: Instant,
+    ) -> Self {
+        Self {
+            local_index,
+            remote_index,
+            send_key,
+            receive_key,
+            cipher,
+            send_counter: 0,
+            replay_window: ReplayWindow::new(),
+            created: now,
+            initiator,
+            received: false,
+        }
+    }
+
+    /// Check if the keys of this session may no longer be used.
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.created) >= REJECT_AFTER_TIME
+    }
+
+    /// Check if packets can be sent on this session.
+    fn can_send(&self, now: Instant) -> bool {
+        (self.initiator || self.received)
+            && !self.expired(now)
+            && self.send_counter < REJECT_AFTER_MESSAGES
+    }
+
+    /// Check if a new session should be negotiated to replace this one.
+    fn needs_rekey(&self, now: Instant) -> bool {
+        let rekey_after = if self.initiator {
+            REKEY_AFTER_TIME
+        } else {
+            RESPONDER_REKEY_AFTER_TIME
+        };
+        now.duration_since(self.created) >= rekey_after
+    }
+}
+
+impl Protection {
+    /// The cipher the packet was encrypted with.
+    pub fn cipher(self) -> Cipher {
+        match self {
+            Protection::Static => Cipher::Aes256Gcm,
+            Protection::Session(cipher) => cipher,
+        }
+    }
+}
+
+impl ReplayWindow {
+    /// Create a new `ReplayWindow` in which no counter was received.
+    fn new() -> Self {
+        Self {
+            last: 0,
+            bitmap: [0; REPLAY_WINDOW_WORDS],
+        }
+    }
+
+    /// Check if a packet with this counter may be accepted, i.e. it is not too old and it was not
+    /// received before.
+    fn check(&self, counter: u64) -> bool {
+        if counter > self.last {
+            return true;
+        }
+        if self.last - counter >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+        let (word, bit) = Self::position(counter);
+        self.bitmap[word] & bit == 0
+    }
+
+    /// Mark a counter as received. This must only be called after a successful
+    /// [`ReplayWindow::check`].
+    fn update(&mut self, counter: u64) {
+        if counter > self.last {
+            let current_block = self.last / 64;
+            let new_block = counter / 64;
+            let blocks = (new_block - current_block).min(REPLAY_WINDOW_WORDS as u64);
+            for block in current_block + 1..=current_block + blocks {
+                self.bitmap[(block % REPLAY_WINDOW_WORDS as u64) as usize] = 0;
+            }
+            self.last = counter;
+        }
+        let (word, bit) = Self::position(counter);
+        self.bitmap[word] |= bit;
+    }
+
+    /// The word and bit in the bitmap for a counter.
+    fn position(counter: u64) -> (usize, u64) {
+        (
+            ((counter / 64) % REPLAY_WINDOW_WORDS as u64) as usize,
+            1 << (counter % 64),
+        )
+    }
+}
+
+/// Split a nonce of a session packet in the session index and packet counter.
+fn split_nonce(nonce: Nonce) -> (u32, u64) {
+    (
+        u32::from_be_bytes(nonce[..4].try_into().unwrap()),
+        u64::from_be_bytes(nonce[4..].try_into().unwrap()),
+    )
+}
+
+/// Derive the keys of a session from the static and ephemeral secrets, and the ephemeral public
+/// keys of both sides. Returns the key used by the initiator to send, and the key used by the
+/// responder to send.
+fn derive_keys(
+    static_secret: &SharedSecret,
+    ephemeral_secret: &SharedSecret,
+    initiator_ephemeral: &PublicKey,
+    responder_ephemeral: &PublicKey,
+) -> (SharedSecret, SharedSecret) {
+    let mut hasher = blake3::Hasher::new_derive_key(SESSION_KEY_CONTEXT);
+    hasher.update(&**static_secret);
+    hasher.update(&**ephemeral_secret);
+    hasher.update(initiator_ephemeral.as_bytes());
+    hasher.update(responder_ephemeral.as_bytes());
+    let mut keys = [0; 64];
+    hasher.finalize_xof().fill(&mut keys);
+
+    let mut initiator_key = [0; 32];
+    let mut responder_key = [0; 32];
+    initiator_key.copy_from_slice(&keys[..32]);
+    responder_key.copy_from_slice(&keys[32..]);
+    (initiator_key.into(), responder_key.into())
+}
+
+impl From<DecryptionError> for ReceiveError {
+    fn from(_: DecryptionError) -> Self {
+        Self::Decryption
+    }
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replayed => f.write_str("Packet was already received"),
+            Self::Decryption => f.write_str("Failed to decrypt packet"),
+        }
+    }
+}
+
+impl Error for ReceiveError {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::crypto::{Cipher, PacketBuffer, SecretKey, SharedSecret};
+
+    use super::{Protection, ReceiveError, ReplayWindow, SessionManager, REPLAY_WINDOW_SIZE};
+
+    fn packet(data: &[u8]) -> PacketBuffer {
+        let mut pb = PacketBuffer::new();
+        pb.buffer_mut()[..data.len()].copy_from_slice(data);
+        pb.set_size(data.len());
+        pb
+    }
+
+    fn shared_secret() -> SharedSecret {
+        SecretKey::new().shared_secret(&(&SecretKey::new()).into())
+    }
+
+    /// Run a full handshake between 2 managers, started by `initiator`.
+    fn handshake(ss: &SharedSecret, initiator: &SessionManager, responder: &SessionManager) {
+        let (_, init) = initiator.encrypt(ss, packet(b"first"));
+        let init = init.expect("Handshake is started for a new remote");
+        let response = responder
+            .handle_handshake(ss, &init)
+            .expect("Init is answered");
+        assert!(initiator.handle_handshake(ss, &response).is_none());
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new();
+
+        for counter in [0, 1, 5, 3, 200] {
+            assert!(window.check(counter));
+            window.update(counter);
+            assert!(!window.check(counter));
+        }
+        assert!(window.check(4));
+        assert!(window.check(2));
+
+        window.update(200 + REPLAY_WINDOW_SIZE);
+        assert!(!window.check(200));
+        assert!(window.check(201));
+        assert!(!window.check(200 + REPLAY_WINDOW_SIZE));
+    }
+
+    #[tokio::test]
+    async fn session_packets_roundtrip_and_replays_are_dropped() {
+        let ss = shared_secret();
+        let local = SessionManager::new(Cipher::Aes256Gcm);
+        let remote = SessionManager::new(Cipher::Aes256Gcm);
+
+        handshake(&ss, &local, &remote);
+
+        // The initiator uses the session right away.
+        let (data, handshake) = local.encrypt(&ss, packet(b"hello"));
+        assert!(handshake.is_none());
+        let (decrypted, protection) = remote.decrypt(&ss, data.clone()).unwrap();
+        assert_eq!(&*decrypted, b"hello");
+        assert_eq!(protection, Protection::Session(Cipher::Aes256Gcm));
+        assert_eq!(
+            remote.decrypt(&ss, data).map(|_| ()),
+            Err(ReceiveError::Replayed)
+        );
+
+        // The session is not the static key.
+        let (data, _) = local.encrypt(&ss, packet(b"hello"));
+        assert!(ss.decrypt(data).is_err());
+
+        // The responder uses the session once it received a packet on it.
+        let (data, _) = remote.encrypt(&ss, packet(b"reply"));
+        let (decrypted, protection) = local.decrypt(&ss, data).unwrap();
+        assert_eq!(&*decrypted, b"reply");
+        assert_eq!(protection, Protection::Session(Cipher::Aes256Gcm));
+
+        assert!(remote.requires_session(&ss));
+        assert!(local.requires_session(&ss));
+    }
+
+    #[tokio::test]
+    async fn chacha20_poly1305_used_if_either_side_prefers_it() {
+        for (initiator_cipher, responder_cipher, expected) in [
+            (Cipher::Aes256Gcm, Cipher::Aes256Gcm, Cipher::Aes256Gcm),
+            (
+                Cipher::ChaCha20Poly1305,
+                Cipher::Aes256Gcm,
+                Cipher::ChaCha20Poly1305,
+            ),
+            (
+                Cipher::Aes256Gcm,
+                Cipher::ChaCha20Poly1305,
+                Cipher::ChaCha20Poly1305,
+            ),
+        ] {
+            let ss = shared_secret();
+            let local = SessionManager::new(initiator_cipher);
+            let remote = SessionManager::new(responder_cipher);
+
+            handshake(&ss, &local, &remote);
+
+            let (data, _) = local.encrypt(&ss, packet(b"hello"));
+            let (decrypted, protection) = remote.decrypt(&ss, data).unwrap();
+            assert_eq!(protection, Protection::Session(expected));
+            assert_eq!(decrypted.header()[0], expected.header_version());
+        }
+    }
+
+    #[tokio::test]
+    async fn static_key_used_without_answer() {
+        let ss = shared_secret();
+        let local = SessionManager::new(Cipher::Aes256Gcm);
+        let remote = SessionManager::new(Cipher::Aes256Gcm);
+
+        let (data, init) = local.encrypt(&ss, packet(b"hello"));
+        assert!(init.is_some());
+        let (decrypted, protection) = remote.decrypt(&ss, data).unwrap();
+        assert_eq!(&*decrypted, b"hello");
+        assert_eq!(protection, Protection::Static);
+        assert!(!remote.requires_session(&ss));
+
+        // No new handshake is started right away.
+        let (_, init) = local.encrypt(&ss, packet(b"hello"));
+        assert!(init.is_none());
+    }
+
+    #[tokio::test]
+    async fn replayed_handshakes_are_ignored() {
+        let ss = shared_secret();
+        let local = SessionManager::new(Cipher::Aes256Gcm);
+        let remote = SessionManager::new(Cipher::Aes256Gcm);
+
+        let (_, init) = local.encrypt(&ss, packet(b"first"));
+        let init = init.unwrap();
+        let response = remote.handle_handshake(&ss, &init).unwrap();
+        assert!(remote.handle_handshake(&ss, &init).is_none());
+        local.handle_handshake(&ss, &response);
+        // The pending handshake is completed, so the response can't be used again.
+        let (data, _) = local.encrypt(&ss, packet(b"hello"));
+        assert_ne!(remote.decrypt(&ss, data).unwrap().1, Protection::Static);
+        assert!(local.handle_handshake(&ss, &response).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_are_rekeyed_and_expire() {
+        let ss = shared_secret();
+        let local = SessionManager::new(Cipher::Aes256Gcm);
+        let remote = SessionManager::new(Cipher::Aes256Gcm);
+
+        handshake(&ss, &local, &remote);
+
+        tokio::time::advance(Duration::from_secs(121)).await;
+        let (data, init) = local.encrypt(&ss, packet(b"hello"));
+        assert!(init.is_some());
+        // The old session is still used until the new one is established.
+        assert_ne!(remote.decrypt(&ss, data).unwrap().1, Protection::Static);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        local.remove_expired();
+        remote.remove_expired();
+        assert!(!remote.requires_session(&ss));
+        let (data, _) = local.encrypt(&ss, packet(b"hello"));
+        assert_eq!(remote.decrypt(&ss, data).unwrap().1, Protection::Static);
+    }
+}
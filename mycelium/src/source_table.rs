@@ -170,6 +170,7 @@ mod tests {
     use crate::{
         babel,
         crypto::SecretKey,
+        link_auth::LinkKeys,
         metric::Metric,
         peer::Peer,
         router_id::RouterId,
@@ -382,6 +383,7 @@ mod tests {
             dead_peer_sink,
             Arc::new(AtomicU64::new(0)),
             Arc::new(AtomicU64::new(0)),
+            &LinkKeys::default(),
         )
         .expect("Can create a dummy peer");
 
@@ -427,6 +429,7 @@ mod tests {
             dead_peer_sink,
             Arc::new(AtomicU64::new(0)),
             Arc::new(AtomicU64::new(0)),
+            &LinkKeys::default(),
         )
         .expect("Can create a dummy peer");
 
@@ -472,6 +475,7 @@ mod tests {
             dead_peer_sink,
             Arc::new(AtomicU64::new(0)),
             Arc::new(AtomicU64::new(0)),
+            &LinkKeys::default(),
         )
         .expect("Can create a dummy peer");
 
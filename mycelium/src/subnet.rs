@@ -9,9 +9,10 @@
 //! might not be optimal for other uses.
 
 use core::fmt;
-use std::{hash::Hash, net::IpAddr};
+use std::{hash::Hash, net::IpAddr, str::FromStr};
 
 use ipnet::IpNet;
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
 
 /// Representation of a subnet. A subnet can be either IPv4 or IPv6.
 #[derive(Debug, Clone, Copy, Eq, PartialOrd, Ord)]
@@ -25,6 +26,10 @@ pub struct Subnet {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PrefixLenError;
 
+/// An error returned when parsing a [`Subnet`] from a string which is not in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubnetParseError;
+
 impl Subnet {
     /// Create a new `Subnet` from the given [`IpAddr`] and prefix length.
     pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Subnet, PrefixLenError> {
@@ -170,6 +175,65 @@ impl Hash for Subnet {
     }
 }
 
+impl FromStr for Subnet {
+    type Err = SubnetParseError;
+
+    /// Parse a `Subnet` in CIDR notation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use mycelium::subnet::Subnet;
+    /// use std::net::Ipv6Addr;
+    ///
+    /// let subnet: Subnet = "400::/7".parse().unwrap();
+    ///
+    /// assert_eq!(subnet.prefix_len(), 7);
+    /// assert_eq!(subnet.network(), Ipv6Addr::new(0x400, 0, 0, 0, 0, 0, 0, 0));
+    /// assert!("400::".parse::<Subnet>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            inner: s.parse().map_err(|_| SubnetParseError)?,
+        })
+    }
+}
+
+impl Serialize for Subnet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+struct SubnetVisitor;
+
+impl<'de> Visitor<'de> for SubnetVisitor {
+    type Value = Subnet;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("A subnet in CIDR notation")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subnet {
+    fn deserialize<D>(deserializer: D) -> Result<Subnet, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(SubnetVisitor)
+    }
+}
+
 impl fmt::Display for PrefixLenError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("Invalid prefix length for this address")
@@ -178,6 +242,14 @@ impl fmt::Display for PrefixLenError {
 
 impl std::error::Error for PrefixLenError {}
 
+impl fmt::Display for SubnetParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid subnet, expected CIDR notation")
+    }
+}
+
+impl std::error::Error for SubnetParseError {}
+
 #[cfg(test)]
 mod tests {
     use std::net::{Ipv4Addr, Ipv6Addr};
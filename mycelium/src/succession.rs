@@ -0,0 +1,389 @@
+//! Key succession allows a node to replace its key, and thus its overlay address, without
+//! breaking all its contacts.
+//!
+//! When a node rotates its key, the previous key signs a statement naming the new key as its
+//! successor. For a grace period, the node keeps announcing the subnet of the previous key.
+//! Packets sent to the previous address are answered with the signed statement, so the sender
+//! learns the new key, and can use it from then on.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    crypto::{InvalidSignature, PublicKey, SecretKey, Signature, SIGNATURE_SIZE},
+    subnet::Subnet,
+};
+
+/// Domain separation for the data signed by the previous key, so the signature can't be used in a
+/// different context.
+const SUCCESSION_DOMAIN: &[u8] = b"mycelium key succession v1";
+
+/// Size of an encoded [`Succession`].
+pub const SUCCESSION_SIZE: usize = 32 + 32 + 8 + SIGNATURE_SIZE;
+
+/// Time after a key rotation during which the subnet of the previous key is still announced.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Time after a key rotation during which successions learned from other nodes are remembered.
+const SUCCESSION_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Maximum time the issued time of a succession learned from another node can be ahead of our
+/// clock.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum amount of successions learned from other nodes which are remembered.
+const MAX_SUCCESSIONS: usize = 1024;
+
+/// Maximum amount of successions followed to find the current key of a node which rotated its key
+/// multiple times.
+const MAX_SUCCESSION_CHAIN: usize = 8;
+
+/// A statement signed by a previous key of a node, naming the key which replaces it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Succession {
+    previous: PublicKey,
+    successor: PublicKey,
+    /// Time the key was rotated, in seconds since the unix epoch.
+    issued: u64,
+    signature: Signature,
+}
+
+/// A previous key of a node, together with the [`Succession`] naming the current key of the node.
+#[derive(Clone)]
+pub struct PreviousKey {
+    /// The secret key the node used before its current key.
+    pub key: SecretKey,
+    /// The statement naming the current key as successor of `key`.
+    pub succession: Succession,
+}
+
+/// Successions learned from other nodes, indexed by the previous key.
+pub(crate) struct SuccessionTable {
+    successions: RwLock<HashMap<PublicKey, Succession>>,
+}
+
+impl Succession {
+    /// Create a new `Succession`, signed by the previous key, naming `successor` as its
+    /// replacement.
+    pub fn new(previous: &SecretKey, successor: PublicKey) -> Self {
+        let issued = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time is after the unix epoch; qed")
+            .as_secs();
+        let previous_pk = PublicKey::from(previous);
+        let signature = previous.sign(&signature_data(&previous_pk, &successor, issued));
+
+        Self {
+            previous: previous_pk,
+            successor,
+            issued,
+            signature,
+        }
+    }
+
+    /// The key which was replaced.
+    pub fn previous(&self) -> PublicKey {
+        self.previous
+    }
+
+    /// The key which replaces the previous key.
+    pub fn successor(&self) -> PublicKey {
+        self.successor
+    }
+
+    /// The time the key was rotated, or [`None`] if the time can't be represented.
+    pub fn issued(&self) -> Option<SystemTime> {
+        UNIX_EPOCH.checked_add(Duration::from_secs(self.issued))
+    }
+
+    /// The end of the grace period, after which the subnet of the previous key is no longer
+    /// announced. This is [`None`] if the time can't be represented.
+    pub fn grace_period_end(&self) -> Option<SystemTime> {
+        self.issued()?.checked_add(GRACE_PERIOD)
+    }
+
+    /// Verify the signature of the previous key over this `Succession`.
+    pub fn verify(&self) -> Result<(), InvalidSignature> {
+        self.previous.verify(
+            &signature_data(&self.previous, &self.successor, self.issued),
+            &self.signature,
+        )
+    }
+
+    /// Encode this `Succession`.
+    pub fn to_bytes(&self) -> [u8; SUCCESSION_SIZE] {
+        let mut bytes = [0; SUCCESSION_SIZE];
+        bytes[..32].copy_from_slice(self.previous.as_bytes());
+        bytes[32..64].copy_from_slice(self.successor.as_bytes());
+        bytes[64..72].copy_from_slice(&self.issued.to_be_bytes());
+        bytes[72..].copy_from_slice(self.signature.as_bytes());
+        bytes
+    }
+
+    /// Decode a `Succession` previously encoded with [`Succession::to_bytes`]. This returns
+    /// [`None`] if there are not enough bytes. The signature is not verified.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < SUCCESSION_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            previous: <[u8; 32]>::try_from(&bytes[..32]).ok()?.into(),
+            successor: <[u8; 32]>::try_from(&bytes[32..64]).ok()?.into(),
+            issued: u64::from_be_bytes(bytes[64..72].try_into().ok()?),
+            signature: <[u8; SIGNATURE_SIZE]>::try_from(&bytes[72..SUCCESSION_SIZE])
+                .ok()?
+                .into(),
+        })
+    }
+}
+
+impl PreviousKey {
+    /// Encode this `PreviousKey`, as the secret key followed by the encoded [`Succession`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(32 + SUCCESSION_SIZE);
+        bytes.extend_from_slice(self.key.as_bytes());
+        bytes.extend_from_slice(&self.succession.to_bytes());
+        bytes
+    }
+
+    /// Decode a `PreviousKey` previously encoded with [`PreviousKey::to_bytes`]. This returns
+    /// [`None`] if the data is not valid, or if the succession is not signed by the key.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let key = SecretKey::from(<[u8; 32]>::try_from(bytes.get(..32)?).ok()?);
+        let succession = Succession::from_bytes(&bytes[32..])?;
+        if succession.previous() != PublicKey::from(&key) || succession.verify().is_err() {
+            return None;
+        }
+
+        Some(Self { key, succession })
+    }
+}
+
+impl SuccessionTable {
+    /// Create a new, empty `SuccessionTable`.
+    pub fn new() -> Self {
+        Self {
+            successions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Remember a [`Succession`]. The signature must have been verified already. Returns `true`
+    /// if the succession was remembered.
+    ///
+    /// Only the first succession of a key is remembered, until it expires. Whoever holds a
+    /// compromised previous key could otherwise replace the successor with a key of their own.
+    /// Successions issued in the future are rejected, so they can't be kept around forever.
+    pub fn insert(&self, succession: Succession) -> bool {
+        let now = SystemTime::now();
+        if succession
+            .issued()
+            .map_or(true, |issued| issued > now + MAX_CLOCK_SKEW)
+        {
+            return false;
+        }
+
+        let mut successions = self.successions.write().unwrap();
+        successions.retain(|_, s| {
+            s.issued()
+                .and_then(|issued| issued.checked_add(SUCCESSION_RETENTION))
+                .is_some_and(|end| end > now)
+        });
+        if successions.contains_key(&succession.previous()) {
+            return false;
+        }
+
+        if successions.len() >= MAX_SUCCESSIONS {
+            if let Some(oldest) = successions
+                .values()
+                .min_by_key(|s| s.issued)
+                .map(Succession::previous)
+            {
+                successions.remove(&oldest);
+            }
+        }
+
+        successions.insert(succession.previous(), succession);
+        true
+    }
+
+    /// Get the [`Succession`] of the key owning the given address, if it is known.
+    pub fn get(&self, ip: IpAddr) -> Option<Succession> {
+        self.successions
+            .read()
+            .unwrap()
+            .values()
+            .find(|s| owns_address(&s.previous(), ip))
+            .copied()
+    }
+
+    /// Get the current key of the node which previously owned the given address. If the node
+    /// rotated its key multiple times, the successions are followed to the last known key.
+    pub fn successor(&self, ip: IpAddr) -> Option<PublicKey> {
+        let successions = self.successions.read().unwrap();
+        let mut current = successions
+            .values()
+            .find(|s| owns_address(&s.previous(), ip))?
+            .successor();
+        for _ in 1..MAX_SUCCESSION_CHAIN {
+            match successions.get(&current) {
+                Some(s) => current = s.successor(),
+                None => break,
+            }
+        }
+
+        Some(current)
+    }
+}
+
+/// Checks if the given address is in the subnet of the key.
+fn owns_address(key: &PublicKey, ip: IpAddr) -> bool {
+    Subnet::new(key.address().into(), 64)
+        .expect("64 is a valid IPv6 prefix size; qed")
+        .contains_ip(ip)
+}
+
+/// The data signed by the previous key.
+fn signature_data(previous: &PublicKey, successor: &PublicKey, issued: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SUCCESSION_DOMAIN.len() + 32 + 32 + 8);
+    data.extend_from_slice(SUCCESSION_DOMAIN);
+    data.extend_from_slice(previous.as_bytes());
+    data.extend_from_slice(successor.as_bytes());
+    data.extend_from_slice(&issued.to_be_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::crypto::{PublicKey, SecretKey};
+
+    use super::{signature_data, PreviousKey, Succession, SuccessionTable};
+
+    /// Create a `Succession` with the given issued time.
+    fn succession_issued_at(previous: &SecretKey, successor: PublicKey, issued: u64) -> Succession {
+        let previous_pk = PublicKey::from(previous);
+        Succession {
+            previous: previous_pk,
+            successor,
+            issued,
+            signature: previous.sign(&signature_data(&previous_pk, &successor, issued)),
+        }
+    }
+
+    #[test]
+    fn succession_roundtrip() {
+        let previous = SecretKey::new();
+        let successor = PublicKey::from(&SecretKey::new());
+
+        let succession = Succession::new(&previous, successor);
+        assert!(succession.verify().is_ok());
+        assert_eq!(succession.previous(), PublicKey::from(&previous));
+        assert_eq!(succession.successor(), successor);
+
+        let decoded = Succession::from_bytes(&succession.to_bytes()).unwrap();
+        assert_eq!(decoded, succession);
+        assert!(decoded.verify().is_ok());
+    }
+
+    #[test]
+    fn succession_is_bound_to_successor() {
+        let previous = SecretKey::new();
+        let succession = Succession::new(&previous, PublicKey::from(&SecretKey::new()));
+
+        let mut bytes = succession.to_bytes();
+        bytes[32..64].copy_from_slice(PublicKey::from(&SecretKey::new()).as_bytes());
+        assert!(Succession::from_bytes(&bytes).unwrap().verify().is_err());
+    }
+
+    #[test]
+    fn previous_key_requires_matching_succession() {
+        let previous = SecretKey::new();
+        let other = SecretKey::new();
+        let succession = Succession::new(&previous, PublicKey::from(&SecretKey::new()));
+
+        let pk = PreviousKey {
+            key: previous,
+            succession,
+        };
+        assert!(PreviousKey::from_bytes(&pk.to_bytes()).is_some());
+
+        let pk = PreviousKey {
+            key: other,
+            succession,
+        };
+        assert!(PreviousKey::from_bytes(&pk.to_bytes()).is_none());
+    }
+
+    #[test]
+    fn successions_are_followed() {
+        let first = SecretKey::new();
+        let second = SecretKey::new();
+        let third = PublicKey::from(&SecretKey::new());
+        let table = SuccessionTable::new();
+
+        let first_address = PublicKey::from(&first).address().into();
+        assert!(table.successor(first_address).is_none());
+
+        assert!(table.insert(Succession::new(&first, PublicKey::from(&second))));
+        assert_eq!(
+            table.successor(first_address),
+            Some(PublicKey::from(&second))
+        );
+
+        assert!(table.insert(Succession::new(&second, third)));
+        assert_eq!(table.successor(first_address), Some(third));
+        assert_eq!(
+            table.get(first_address).map(|s| s.successor()),
+            Some(PublicKey::from(&second))
+        );
+    }
+
+    #[test]
+    fn first_succession_is_kept() {
+        let previous = SecretKey::new();
+        let successor = PublicKey::from(&SecretKey::new());
+        let hijacker = PublicKey::from(&SecretKey::new());
+        let table = SuccessionTable::new();
+        let address = PublicKey::from(&previous).address().into();
+
+        let first = Succession::new(&previous, successor);
+        assert!(table.insert(first));
+        assert!(!table.insert(first));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(!table.insert(succession_issued_at(&previous, hijacker, now + 60)));
+        assert_eq!(table.successor(address), Some(successor));
+    }
+
+    #[test]
+    fn future_successions_are_rejected() {
+        let table = SuccessionTable::new();
+
+        let succession = succession_issued_at(
+            &SecretKey::new(),
+            PublicKey::from(&SecretKey::new()),
+            u64::MAX,
+        );
+        assert!(succession.verify().is_ok());
+        assert!(succession.issued().is_none());
+        assert!(succession.grace_period_end().is_none());
+        assert!(!table.insert(succession));
+
+        let in_a_day = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        let succession = succession_issued_at(
+            &SecretKey::new(),
+            PublicKey::from(&SecretKey::new()),
+            in_a_day.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        );
+        assert!(!table.insert(succession));
+    }
+}
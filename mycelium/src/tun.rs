@@ -16,6 +16,11 @@ pub struct TunConfig {
     pub name: String,
     pub node_subnet: Subnet,
     pub route_subnet: Subnet,
+    /// IPv4 address of the node and the prefix length of the IPv4 overlay range, if the IPv4
+    /// overlay is enabled.
+    pub ipv4_subnet: Option<Subnet>,
+    /// Prefixes reachable through trusted gateways, which are routed over the TUN interface.
+    pub gateway_routes: Vec<Subnet>,
 }
 
 #[cfg(any(
@@ -28,6 +28,10 @@ const LINK_MTU: i32 = 1400;
 // TODO: figure out structure and values, but for now this seems to work.
 const HEADER: [u8; 4] = [0, 0, 0, 30];
 
+/// The 4 byte packet header written before an IPv4 packet is sent on the TUN. The last byte is
+/// the address family of the packet (AF_INET).
+const HEADER_IPV4: [u8; 4] = [0, 0, 0, 2];
+
 const IN6_IFF_NODAD: u32 = 0x0020; // netinet6/in6_var.h
 const IN6_IFF_SECURED: u32 = 0x0400; // netinet6/in6_var.h
 const ND6_INFINITE_LIFETIME: u32 = 0xFFFFFFFF; // netinet6/nd6.h
@@ -92,6 +96,15 @@ pub async fn new(
     };
     let iface = Iface::by_name(&tun_name)?;
     iface.add_address(tun_config.node_subnet, tun_config.route_subnet)?;
+    if let Some(ipv4_subnet) = tun_config.ipv4_subnet {
+        add_ipv4_address(&tun_name, ipv4_subnet)?;
+    }
+    // Failing to add a gateway route is not fatal, the overlay itself still works.
+    for route in tun_config.gateway_routes {
+        if let Err(e) = add_ipv6_route(&tun_name, route) {
+            warn!("Failed to add route for gateway prefix {route} to TUN interface: {e}");
+        }
+    }
 
     let (tun_sink, mut sink_receiver) = mpsc::channel::<PacketBuffer>(1000);
     let (tun_stream, stream_receiver) = mpsc::unbounded_channel();
@@ -112,7 +125,12 @@ pub async fn new(
                         None => return,
                         Some(data) => {
                             // We need to append a 4 byte header here
-                            if let Err(e) = tun.write_vectored(&[IoSlice::new(&HEADER), IoSlice::new(&data)]).await {
+                            let header = if data.first().map(|b| b >> 4) == Some(4) {
+                                &HEADER_IPV4
+                            } else {
+                                &HEADER
+                            };
+                            if let Err(e) = tun.write_vectored(&[IoSlice::new(header), IoSlice::new(&data)]).await {
                                 error!("Failed to send data to tun interface {e}");
                             }
                         }
@@ -337,6 +355,81 @@ impl Iface {
         }
     }
 }
+/// Add an IPv4 address to an interface by shelling out to `ifconfig`.
+fn add_ipv4_address(name: &str, subnet: Subnet) -> Result<(), io::Error> {
+    let address = subnet.address().to_string();
+    let exit_code = std::process::Command::new("ifconfig")
+        .args([
+            name,
+            "inet",
+            &address,
+            &address,
+            "netmask",
+            &subnet.mask().to_string(),
+            "alias",
+        ])
+        .spawn()?
+        .wait()?;
+
+    match exit_code.code() {
+        Some(0) => {}
+        Some(x) => return Err(io::Error::from_raw_os_error(x)),
+        None => warn!("Failed to determine `ifconfig` exit status"),
+    }
+
+    // Since utun is a point to point interface, no route is added for the subnet automatically.
+    let exit_code = std::process::Command::new("route")
+        .args([
+            "-q",
+            "-n",
+            "add",
+            "-inet",
+            &subnet.network().to_string(),
+            "-netmask",
+            &subnet.mask().to_string(),
+            "-interface",
+            name,
+        ])
+        .spawn()?
+        .wait()?;
+
+    match exit_code.code() {
+        Some(0) => Ok(()),
+        Some(x) => Err(io::Error::from_raw_os_error(x)),
+        None => {
+            warn!("Failed to determine `route` exit status");
+            Ok(())
+        }
+    }
+}
+
+/// Add a route for an IPv6 subnet over an interface by shelling out to `route`.
+fn add_ipv6_route(name: &str, subnet: Subnet) -> Result<(), io::Error> {
+    let exit_code = std::process::Command::new("route")
+        .args([
+            "-q",
+            "-n",
+            "add",
+            "-inet6",
+            &subnet.network().to_string(),
+            "-prefixlen",
+            &subnet.prefix_len().to_string(),
+            "-interface",
+            name,
+        ])
+        .spawn()?
+        .wait()?;
+
+    match exit_code.code() {
+        Some(0) => Ok(()),
+        Some(x) => Err(io::Error::from_raw_os_error(x)),
+        None => {
+            warn!("Failed to determine `route` exit status");
+            Ok(())
+        }
+    }
+}
+
 // Create a socket to talk to the kernel.
 fn random_socket() -> Result<std::net::UdpSocket, std::io::Error> {
     std::net::UdpSocket::bind("[::1]:0")
@@ -1,12 +1,12 @@
 //! Linux specific tun interface setup.
 
-use std::io;
+use std::{io, net::IpAddr};
 
 use futures::{Sink, Stream, TryStreamExt};
 use rtnetlink::Handle;
 use tokio::{select, sync::mpsc};
 use tokio_tun::{Tun, TunBuilder};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::crypto::PacketBuffer;
 use crate::subnet::Subnet;
@@ -63,6 +63,20 @@ pub async fn new(
         return Err(e);
     }
 
+    if let Some(ipv4_subnet) = tun_config.ipv4_subnet {
+        if let Err(e) = add_address(handle.clone(), tun_index, ipv4_subnet).await {
+            error!("Failed to add IPv4 address {ipv4_subnet} to TUN interface: {e}");
+            return Err(e);
+        }
+    }
+
+    // Failing to add a gateway route is not fatal, the overlay itself still works.
+    for route in tun_config.gateway_routes {
+        if let Err(e) = add_route(handle.clone(), tun_index, route).await {
+            warn!("Failed to add route for gateway prefix {route} to TUN interface: {e}");
+        }
+    }
+
     // We are done with our netlink connection, abort the task so we can properly clean up.
     netlink_task_handle.abort();
 
@@ -155,3 +169,26 @@ async fn add_address(
         .execute()
         .await?)
 }
+
+/// Add a route for an IPv6 subnet over an interface.
+async fn add_route(
+    handle: Handle,
+    link_index: u32,
+    subnet: Subnet,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let IpAddr::V6(network) = subnet.network() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only IPv6 routes are supported",
+        )
+        .into());
+    };
+    Ok(handle
+        .route()
+        .add()
+        .v6()
+        .destination_prefix(network, subnet.prefix_len())
+        .output_interface(link_index)
+        .execute()
+        .await?)
+}
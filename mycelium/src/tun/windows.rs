@@ -45,6 +45,15 @@ pub async fn new(
         tun_config.node_subnet,
         tun_config.route_subnet,
     )?;
+    if let Some(ipv4_subnet) = tun_config.ipv4_subnet {
+        add_ipv4_address(&tun_config.name, ipv4_subnet)?;
+    }
+    // Failing to add a gateway route is not fatal, the overlay itself still works.
+    for route in tun_config.gateway_routes {
+        if let Err(e) = add_ipv6_route(&tun_config.name, route) {
+            warn!("Failed to add route for gateway prefix {route} to TUN interface: {e}");
+        }
+    }
     // Build 2 separate sessions - one for receiving, one for sending.
     let rx_session = Arc::new(tun.start_session(wintun::MAX_RING_CAPACITY)?);
     let tx_session = rx_session.clone();
@@ -139,6 +148,56 @@ fn add_address(adapter_name: &str, subnet: Subnet, route_subnet: Subnet) -> Resu
     }
 }
 
+/// Set an IPv4 address on an interface by shelling out to `netsh`
+fn add_ipv4_address(adapter_name: &str, subnet: Subnet) -> Result<(), io::Error> {
+    let exit_code = std::process::Command::new("netsh")
+        .args([
+            "interface",
+            "ipv4",
+            "set",
+            "address",
+            adapter_name,
+            "static",
+            &subnet.address().to_string(),
+            &subnet.mask().to_string(),
+        ])
+        .spawn()?
+        .wait()?;
+
+    match exit_code.code() {
+        Some(0) => Ok(()),
+        Some(x) => Err(io::Error::from_raw_os_error(x)),
+        None => {
+            warn!("Failed to determine `netsh` exit status");
+            Ok(())
+        }
+    }
+}
+
+/// Add a route for an IPv6 subnet over an interface by shelling out to `netsh`
+fn add_ipv6_route(adapter_name: &str, subnet: Subnet) -> Result<(), io::Error> {
+    let exit_code = std::process::Command::new("netsh")
+        .args([
+            "interface",
+            "ipv6",
+            "add",
+            "route",
+            &format!("{}/{}", subnet.network(), subnet.prefix_len()),
+            adapter_name,
+        ])
+        .spawn()?
+        .wait()?;
+
+    match exit_code.code() {
+        Some(0) => Ok(()),
+        Some(x) => Err(io::Error::from_raw_os_error(x)),
+        None => {
+            warn!("Failed to determine `netsh` exit status");
+            Ok(())
+        }
+    }
+}
+
 fn set_adapter_mtu(name: &str, mtu: usize) -> Result<(), io::Error> {
     let args = &[
         "interface",
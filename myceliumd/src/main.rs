@@ -1,6 +1,9 @@
+use std::collections::HashMap;
 use std::io;
 use std::net::Ipv4Addr;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
 use std::{
     error::Error,
     net::{IpAddr, SocketAddr},
@@ -10,18 +13,31 @@ use std::{fmt::Display, str::FromStr};
 
 use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Deserializer};
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 #[cfg(target_family = "unix")]
 use tokio::signal::{self, unix::SignalKind};
+use tokio::sync::{mpsc, Mutex};
 use tracing::{debug, error, info, warn};
 
 use crypto::PublicKey;
+use mycelium::damping::DampingConfig;
 use mycelium::endpoint::Endpoint;
+use mycelium::firewall::FirewallPolicy;
+use mycelium::gateway::TrustedGateway;
+use mycelium::link_auth::LinkKey;
+use mycelium::metrics::Metrics;
+use mycelium::peer_manager::{PeerExists, PeerNotFound, PeerSettings};
+use mycelium::policy::RoutePolicy;
+use mycelium::router::DEFAULT_SELECTION_HYSTERESIS;
+use mycelium::subnet::Subnet;
+use mycelium::succession::{PreviousKey, Succession, GRACE_PERIOD};
 use mycelium::{crypto, Node};
+use mycelium_api::ReloadRequest;
+use mycelium_cli::{KeyFormat, KEY_PASSPHRASE_ENV};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
+use zeroize::Zeroizing;
 
 /// The default port on the underlay to listen on for incoming TCP connections.
 const DEFAULT_TCP_LISTEN_PORT: u16 = 9651;
@@ -82,6 +98,12 @@ struct Cli {
     #[arg(short = 'k', long = "key-file", global = true)]
     key_file: Option<PathBuf>,
 
+    /// Path of a file holding the passphrase of the key file. If this is not set, the passphrase
+    /// is read from the MYCELIUM_KEY_PASSPHRASE environment variable, or from the terminal if
+    /// the key file is encrypted. New key files are encrypted if a passphrase is given.
+    #[arg(long = "key-passphrase-file", global = true)]
+    key_passphrase_file: Option<PathBuf>,
+
     // Configuration file
     #[arg(short = 'c', long = "config-file", global = true)]
     config_file: Option<PathBuf>,
@@ -135,6 +157,94 @@ pub enum Command {
         #[command(subcommand)]
         command: RoutesCommand,
     },
+
+    /// Send probes to a node in the overlay and report the replies
+    Ping {
+        /// Amount of probes to send.
+        #[arg(short = 'c', long = "count", default_value_t = 4)]
+        count: u16,
+        /// Amount of seconds to wait for the reply to a probe.
+        #[arg(long = "timeout", default_value_t = 2)]
+        timeout: u64,
+        /// Output in json format.
+        #[arg(long = "json")]
+        json: bool,
+        /// Node to ping, either a hex encoded public key, or an IPv6 address in the 400::/7
+        /// range.
+        destination: String,
+    },
+
+    /// Trace the path to a node in the overlay
+    Traceroute {
+        /// Maximum amount of hops to probe.
+        #[arg(short = 'm', long = "max-hops", default_value_t = 30)]
+        max_hops: u8,
+        /// Amount of seconds to wait for the reply to a probe.
+        #[arg(long = "timeout", default_value_t = 2)]
+        timeout: u64,
+        /// Output in json format.
+        #[arg(long = "json")]
+        json: bool,
+        /// Node to trace, either a hex encoded public key, or an IPv6 address in the 400::/7
+        /// range.
+        destination: String,
+    },
+
+    /// Actions on the node key
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommand {
+    /// Replace the node key with a new key.
+    ///
+    /// The current key signs a statement naming the new key as its successor, and is kept next
+    /// to the key file. After the node is restarted, it keeps announcing the subnet of the
+    /// current key for a grace period, and nodes sending packets to it learn the new key.
+    Rotate {
+        /// Output in json format.
+        #[arg(long = "json")]
+        json: bool,
+        /// Rotate the key even if the grace period of a previous rotation did not end yet. The
+        /// subnet of the key which was replaced then is no longer announced.
+        #[arg(long = "force")]
+        force: bool,
+    },
+    /// Print the node key.
+    ///
+    /// Anyone with the exported key can impersonate the node, store it safely.
+    Export {
+        /// Encoding of the key, either "hex", "pem" (PKCS#8) or "mnemonic" (24 words).
+        #[arg(long = "format", default_value_t = KeyFormat::Hex)]
+        format: KeyFormat,
+    },
+    /// Write a key previously exported with `key export` to the key file.
+    Import {
+        /// Encoding of the key, either "hex", "pem" (PKCS#8) or "mnemonic" (24 words). The
+        /// encoding is detected if this is not set.
+        #[arg(long = "format")]
+        format: Option<KeyFormat>,
+        /// Replace the key file if it exists.
+        #[arg(long = "force", default_value_t = false)]
+        force: bool,
+        /// File holding the key. The key is read from stdin if this is not set.
+        input: Option<PathBuf>,
+    },
+    /// Encrypt the key file with a passphrase, or change the passphrase of an encrypted key
+    /// file.
+    ///
+    /// The current passphrase of an encrypted key file is taken from the passphrase file or the
+    /// MYCELIUM_KEY_PASSPHRASE environment variable. The new passphrase is taken from the new
+    /// passphrase file or the MYCELIUM_NEW_KEY_PASSPHRASE environment variable if set, otherwise
+    /// it is read from the terminal.
+    Encrypt {
+        /// File holding the new passphrase.
+        #[arg(long = "new-passphrase-file")]
+        new_passphrase_file: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -189,7 +299,18 @@ pub enum PeersCommand {
         json: bool,
     },
     /// Add peer(s)
-    Add { peers: Vec<String> },
+    Add {
+        peers: Vec<String>,
+        /// Use this link cost for the peers, instead of the cost measured on the link
+        #[arg(long = "cost")]
+        cost: Option<u16>,
+        /// Cost added to the link cost of the peers
+        #[arg(long = "penalty", default_value_t = 0)]
+        penalty: u16,
+        /// Only use routes through the peers if there is no route through any other peer
+        #[arg(long = "backup", default_value_t = false)]
+        backup: bool,
+    },
     /// Remove peer(s)
     Remove { peers: Vec<String> },
 }
@@ -210,7 +331,7 @@ pub enum RoutesCommand {
     },
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct NodeArguments {
     /// Peers to connect to.
     #[arg(long = "peers", num_args = 1..)]
@@ -284,11 +405,97 @@ pub struct NodeArguments {
     /// increased to process updates in parallel.
     #[arg(long = "update-workers", default_value_t = 1)]
     update_workers: usize,
+
+    /// Directory in which the message inbox and outbox are persisted.
+    ///
+    /// If this is set, received messages which have not been read yet, and sent messages which
+    /// have not been fully transmitted yet, survive a restart of the node. If this is not set,
+    /// messages are only kept in memory.
+    #[arg(long = "message-store")]
+    message_store: Option<PathBuf>,
+
+    /// Directory in which large received messages are written.
+    ///
+    /// If this is set, received messages which are too large to comfortably keep in memory are
+    /// written to a file in this directory while they are received. If this is not set, all
+    /// messages are kept in memory.
+    #[arg(long = "message-spool")]
+    message_spool: Option<PathBuf>,
+
+    /// File in which peers added at runtime are remembered.
+    ///
+    /// If this is set, peers added through the API and peers found through link local discovery
+    /// are added again when the node restarts. Peers which have not been seen for a week are
+    /// forgotten.
+    #[arg(long = "peer-cache")]
+    peer_cache: Option<PathBuf>,
+
+    /// Private IPv4 range used to carry IPv4 traffic over the overlay, e.g. 10.64.0.0/10.
+    ///
+    /// If this is set, the node gets an IPv4 address in this range derived from its overlay
+    /// subnet, and IPv4 packets for addresses in the range are sent to the node owning them. All
+    /// nodes must use the same range.
+    #[arg(long = "ipv4-overlay")]
+    ipv4_overlay: Option<Subnet>,
+
+    /// IPv6 prefix outside of the overlay to announce as a gateway, e.g. an internal network.
+    ///
+    /// Only nodes which trust this node for the prefix will use the announced route. The node
+    /// delivers traffic for the prefix to its TUN interface, forwarding it further must be
+    /// configured on the host. Can be passed multiple times.
+    #[arg(long = "gateway-prefix")]
+    gateway_prefixes: Vec<Subnet>,
+
+    /// Only accept route announcements which are signed by the node which originated them.
+    ///
+    /// Announcements of this node are always signed, and signatures on received announcements
+    /// are always verified. With this set, unsigned announcements are rejected as well, so only
+    /// enable it once all nodes sign their announcements.
+    #[arg(long = "require-signed-updates", default_value_t = false)]
+    require_signed_updates: bool,
+
+    /// Spread traffic to a destination over multiple routes.
+    ///
+    /// Flows are distributed over all loop free routes with a metric which is at most this much
+    /// higher than the metric of the selected route. Packets of the same flow always take the
+    /// same route. Multipath forwarding is disabled if this is not set.
+    #[arg(long = "multipath-tolerance")]
+    multipath_tolerance: Option<u16>,
+
+    /// Amount the metric of a route must improve before switching to it.
+    ///
+    /// Higher values make route selection more stable, at the cost of sometimes using a slightly
+    /// worse route. The value can be at most 65534.
+    #[arg(long = "selection-hysteresis")]
+    selection_hysteresis: Option<u16>,
+
+    /// Damp routes which flap often.
+    ///
+    /// Routes of nodes which change often keep their current next hop, and changes in their
+    /// metric are not propagated immediately, until they have been stable for a while. The
+    /// damping parameters can be tuned in the config file.
+    #[arg(long = "route-damping", default_value_t = false)]
+    route_damping: bool,
+
+    /// Run as a leaf node.
+    ///
+    /// Leaf nodes only announce their own subnet, and refuse to forward traffic between other
+    /// nodes. Use this for nodes on metered or slow connections.
+    #[arg(long = "leaf", default_value_t = false)]
+    leaf: bool,
+
+    /// Cipher to propose when negotiating session keys with other nodes.
+    ///
+    /// Either "aes-gcm" or "chacha20-poly1305". ChaCha20-Poly1305 is used if either side prefers
+    /// it. By default, AES-256-GCM is preferred if the CPU has hardware support for it.
+    #[arg(long = "preferred-cipher")]
+    preferred_cipher: Option<crypto::Cipher>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MergedNodeConfig {
     peers: Vec<Endpoint>,
+    peer_settings: HashMap<Endpoint, PeerSettings>,
     tcp_listen_port: u16,
     disable_quic: bool,
     quic_listen_port: u16,
@@ -300,12 +507,27 @@ pub struct MergedNodeConfig {
     metrics_api_address: Option<SocketAddr>,
     firewall_mark: Option<u32>,
     update_workers: usize,
+    message_store: Option<PathBuf>,
+    message_spool: Option<PathBuf>,
+    peer_cache: Option<PathBuf>,
+    ipv4_overlay: Option<Subnet>,
+    route_policy: RoutePolicy,
+    gateway_prefixes: Vec<Subnet>,
+    trusted_gateways: Vec<TrustedGateway>,
+    require_signed_updates: bool,
+    multipath_tolerance: Option<u16>,
+    selection_hysteresis: u16,
+    route_damping: Option<DampingConfig>,
+    leaf: bool,
+    firewall: Option<FirewallPolicy>,
+    preferred_cipher: Option<crypto::Cipher>,
+    link_keys: Vec<LinkKey>,
 }
 
 #[derive(Debug, Deserialize, Default)]
 struct MyceliumConfig {
-    #[serde(deserialize_with = "deserialize_optional_endpoint_str_from_toml")]
-    peers: Option<Vec<Endpoint>>,
+    #[serde(deserialize_with = "deserialize_optional_peers_from_toml")]
+    peers: Option<Vec<ConfiguredPeer>>,
     tcp_listen_port: Option<u16>,
     disable_quic: Option<bool>,
     quic_listen_port: Option<u16>,
@@ -317,67 +539,36 @@ struct MyceliumConfig {
     metrics_api_address: Option<SocketAddr>,
     firewall_mark: Option<u32>,
     update_workers: Option<usize>,
+    message_store: Option<PathBuf>,
+    message_spool: Option<PathBuf>,
+    peer_cache: Option<PathBuf>,
+    ipv4_overlay: Option<Subnet>,
+    route_policy: Option<RoutePolicy>,
+    gateway_prefixes: Option<Vec<Subnet>>,
+    trusted_gateways: Option<Vec<TrustedGateway>>,
+    require_signed_updates: Option<bool>,
+    multipath_tolerance: Option<u16>,
+    selection_hysteresis: Option<u16>,
+    route_damping: Option<DampingConfig>,
+    leaf: Option<bool>,
+    firewall: Option<FirewallPolicy>,
+    preferred_cipher: Option<crypto::Cipher>,
+    link_keys: Option<Vec<LinkKey>>,
+}
+
+/// A statically configured peer in the configuration file, with its link settings.
+#[derive(Debug)]
+struct ConfiguredPeer {
+    endpoint: Endpoint,
+    settings: PeerSettings,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    // Init default configuration
-    let mut mycelium_config = MyceliumConfig::default();
-
     // Load configuration file
-    if let Some(config_file_path) = &cli.config_file {
-        if Path::new(config_file_path).exists() {
-            let config = config::Config::builder()
-                .add_source(config::File::new(
-                    config_file_path.to_str().unwrap(),
-                    config::FileFormat::Toml,
-                ))
-                .build()?;
-
-            mycelium_config = config.try_deserialize()?;
-        } else {
-            let error_msg = format!("Config file {:?} not found", config_file_path);
-            return Err(io::Error::new(io::ErrorKind::NotFound, error_msg).into());
-        }
-    } else if let Some(mut conf) = dirs::config_dir() {
-        // Windows: %APPDATA%/ThreeFold Tech/Mycelium/mycelium.conf
-        #[cfg(target_os = "windows")]
-        {
-            conf = conf
-                .join("ThreeFold Tech")
-                .join("Mycelium")
-                .join("mycelium.toml")
-        };
-        // Linux: $HOME/.config/mycelium/mycelium.conf
-        #[cfg(target_os = "linux")]
-        {
-            conf = conf.join("mycelium").join("mycelium.toml")
-        };
-        // MacOS: $HOME/Library/Application Support/ThreeFold Tech/Mycelium/mycelium.conf
-        #[cfg(target_os = "macos")]
-        {
-            conf = conf
-                .join("ThreeFold Tech")
-                .join("Mycelium")
-                .join("mycelium.toml")
-        };
-
-        if conf.exists() {
-            info!(
-                conf_dir = conf.to_str().unwrap(),
-                "Mycelium is starting with configuration file",
-            );
-            let config = config::Config::builder()
-                .add_source(config::File::new(
-                    conf.to_str().unwrap(),
-                    config::FileFormat::Toml,
-                ))
-                .build()?;
-            mycelium_config = config.try_deserialize()?;
-        }
-    }
+    let mycelium_config = load_config(cli.config_file.as_deref())?;
 
     let level = if cli.silent {
         tracing::Level::ERROR
@@ -404,26 +595,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let key_path = cli
         .key_file
         .unwrap_or_else(|| PathBuf::from(DEFAULT_KEY_FILE));
+    let mut key_passphrase = KeyPassphrase::new(cli.key_passphrase_file);
 
     match cli.command {
         None => {
+            let cli_args = cli.node_args.clone();
             let merged_config = merge_config(cli.node_args, mycelium_config);
+            let reload_context = ReloadContext {
+                cli_args,
+                config_file: cli.config_file,
+                peers: merged_config.peers.clone(),
+                peer_settings: merged_config.peer_settings.clone(),
+            };
+            let (reload_tx, reload_rx) = mpsc::channel(1);
 
-            let node_keys = get_node_keys(&key_path).await?;
+            let node_keys = get_node_keys(&key_path, &mut key_passphrase).await?;
             let node_secret_key = if let Some((node_secret_key, _)) = node_keys {
                 node_secret_key
             } else {
                 warn!("Node key file {key_path:?} not found, generating new keys");
                 let secret_key = crypto::SecretKey::new();
-                save_key_file(&secret_key, &key_path).await?;
+                save_key_file(&secret_key, &key_path, &mut key_passphrase).await?;
                 secret_key
             };
+            let previous_key =
+                load_previous_key_file(&previous_key_path(&key_path), &mut key_passphrase).await?;
 
-            let _api = if let Some(metrics_api_addr) = merged_config.metrics_api_address {
+            if let Some(metrics_api_addr) = merged_config.metrics_api_address {
                 let metrics = mycelium_metrics::PrometheusExporter::new();
                 let config = mycelium::Config {
                     node_key: node_secret_key,
+                    previous_key,
                     peers: merged_config.peers,
+                    peer_settings: merged_config.peer_settings,
                     no_tun: merged_config.no_tun,
                     tcp_listen_port: merged_config.tcp_listen_port,
                     quic_listen_port: if merged_config.disable_quic {
@@ -441,14 +645,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     metrics: metrics.clone(),
                     firewall_mark: merged_config.firewall_mark,
                     update_workers: merged_config.update_workers,
+                    message_store: merged_config.message_store,
+                    message_spool: merged_config.message_spool,
+                    peer_cache: merged_config.peer_cache,
+                    ipv4_overlay: merged_config.ipv4_overlay,
+                    route_policy: merged_config.route_policy,
+                    gateway_prefixes: merged_config.gateway_prefixes,
+                    trusted_gateways: merged_config.trusted_gateways,
+                    require_signed_updates: merged_config.require_signed_updates,
+                    multipath_tolerance: merged_config.multipath_tolerance,
+                    selection_hysteresis: merged_config.selection_hysteresis,
+                    route_damping: merged_config.route_damping,
+                    leaf: merged_config.leaf,
+                    firewall: merged_config.firewall,
+                    preferred_cipher: merged_config.preferred_cipher,
+                    link_keys: merged_config.link_keys,
                 };
                 metrics.spawn(metrics_api_addr);
-                let node = Node::new(config).await?;
-                mycelium_api::Http::spawn(node, merged_config.api_addr)
+                let node = Arc::new(Mutex::new(Node::new(config).await?));
+                let _api = mycelium_api::Http::spawn(
+                    node.clone(),
+                    merged_config.api_addr,
+                    Some(reload_tx),
+                );
+                run_until_stopped(node, reload_context, reload_rx).await;
             } else {
                 let config = mycelium::Config {
                     node_key: node_secret_key,
+                    previous_key,
                     peers: merged_config.peers,
+                    peer_settings: merged_config.peer_settings,
                     no_tun: merged_config.no_tun,
                     tcp_listen_port: merged_config.tcp_listen_port,
                     quic_listen_port: if merged_config.disable_quic {
@@ -466,34 +692,34 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     metrics: mycelium_metrics::NoMetrics,
                     firewall_mark: merged_config.firewall_mark,
                     update_workers: merged_config.update_workers,
+                    message_store: merged_config.message_store,
+                    message_spool: merged_config.message_spool,
+                    peer_cache: merged_config.peer_cache,
+                    ipv4_overlay: merged_config.ipv4_overlay,
+                    route_policy: merged_config.route_policy,
+                    gateway_prefixes: merged_config.gateway_prefixes,
+                    trusted_gateways: merged_config.trusted_gateways,
+                    require_signed_updates: merged_config.require_signed_updates,
+                    multipath_tolerance: merged_config.multipath_tolerance,
+                    selection_hysteresis: merged_config.selection_hysteresis,
+                    route_damping: merged_config.route_damping,
+                    leaf: merged_config.leaf,
+                    firewall: merged_config.firewall,
+                    preferred_cipher: merged_config.preferred_cipher,
+                    link_keys: merged_config.link_keys,
                 };
-                let node = Node::new(config).await?;
-                mycelium_api::Http::spawn(node, merged_config.api_addr)
-            };
-
-            // TODO: put in dedicated file so we can only rely on certain signals on unix platforms
-            #[cfg(target_family = "unix")]
-            {
-                let mut sigint = signal::unix::signal(SignalKind::interrupt())
-                    .expect("Can install SIGINT handler");
-                let mut sigterm = signal::unix::signal(SignalKind::terminate())
-                    .expect("Can install SIGTERM handler");
-
-                tokio::select! {
-                    _ = sigint.recv() => { }
-                    _ = sigterm.recv() => { }
-                }
-            }
-            #[cfg(not(target_family = "unix"))]
-            {
-                if let Err(e) = tokio::signal::ctrl_c().await {
-                    error!("Failed to wait for SIGINT: {e}");
-                }
+                let node = Arc::new(Mutex::new(Node::new(config).await?));
+                let _api = mycelium_api::Http::spawn(
+                    node.clone(),
+                    merged_config.api_addr,
+                    Some(reload_tx),
+                );
+                run_until_stopped(node, reload_context, reload_rx).await;
             }
         }
         Some(cmd) => match cmd {
             Command::Inspect { json, key } => {
-                let node_keys = get_node_keys(&key_path).await?;
+                let node_keys = get_node_keys(&key_path, &mut key_passphrase).await?;
                 let key = if let Some(key) = key {
                     PublicKey::try_from(key.as_str())?
                 } else if let Some((_, node_pub_key)) = node_keys {
@@ -552,8 +778,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 PeersCommand::List { json } => {
                     return mycelium_cli::list_peers(cli.node_args.api_addr, json).await;
                 }
-                PeersCommand::Add { peers } => {
-                    return mycelium_cli::add_peers(cli.node_args.api_addr, peers).await;
+                PeersCommand::Add {
+                    peers,
+                    cost,
+                    penalty,
+                    backup,
+                } => {
+                    return mycelium_cli::add_peers(
+                        cli.node_args.api_addr,
+                        peers,
+                        PeerSettings {
+                            cost,
+                            penalty,
+                            backup,
+                        },
+                    )
+                    .await;
                 }
                 PeersCommand::Remove { peers } => {
                     return mycelium_cli::remove_peers(cli.node_args.api_addr, peers).await;
@@ -567,17 +807,404 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     return mycelium_cli::list_fallback_routes(cli.node_args.api_addr, json).await;
                 }
             },
+            Command::Ping {
+                count,
+                timeout,
+                json,
+                destination,
+            } => {
+                return mycelium_cli::ping(
+                    cli.node_args.api_addr,
+                    destination,
+                    count,
+                    timeout,
+                    json,
+                )
+                .await;
+            }
+            Command::Traceroute {
+                max_hops,
+                timeout,
+                json,
+                destination,
+            } => {
+                return mycelium_cli::traceroute(
+                    cli.node_args.api_addr,
+                    destination,
+                    max_hops,
+                    timeout,
+                    json,
+                )
+                .await;
+            }
+            Command::Key { command } => match command {
+                KeyCommand::Rotate { json, force } => {
+                    let Some((previous_key, _)) =
+                        get_node_keys(&key_path, &mut key_passphrase).await?
+                    else {
+                        error!("No key to rotate found at {key_path:?}");
+                        return Err(
+                            io::Error::new(io::ErrorKind::NotFound, "key file not found").into(),
+                        );
+                    };
+                    // Only one previous key is kept, rotating again would stop announcing the
+                    // subnet of the key replaced by the last rotation.
+                    let previous_path = previous_key_path(&key_path);
+                    if !force
+                        && load_previous_key_file(&previous_path, &mut key_passphrase)
+                            .await?
+                            .is_some()
+                    {
+                        error!("Grace period of the previous rotation did not end yet, use --force to rotate anyway");
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "grace period of previous key did not end",
+                        )
+                        .into());
+                    }
+                    let key = crypto::SecretKey::new();
+                    let succession = Succession::new(&previous_key, PublicKey::from(&key));
+                    // Save the previous key first, so the current key is not lost if this fails.
+                    save_previous_key_file(
+                        &PreviousKey {
+                            key: previous_key,
+                            succession,
+                        },
+                        &previous_path,
+                        &mut key_passphrase,
+                    )
+                    .await?;
+                    save_key_file(&key, &key_path, &mut key_passphrase).await?;
+                    mycelium_cli::inspect(PublicKey::from(&key), json)?;
+                    if !json {
+                        println!(
+                            "Restart the node to use the new key. The subnet of the previous key is announced for {} days.",
+                            GRACE_PERIOD.as_secs() / (24 * 60 * 60)
+                        );
+                    }
+
+                    return Ok(());
+                }
+                KeyCommand::Export { format } => {
+                    let Some((key, _)) = get_node_keys(&key_path, &mut key_passphrase).await?
+                    else {
+                        error!("No key to export found at {key_path:?}");
+                        return Err(
+                            io::Error::new(io::ErrorKind::NotFound, "key file not found").into(),
+                        );
+                    };
+                    println!("{}", *mycelium_cli::export_key(&key, format));
+
+                    return Ok(());
+                }
+                KeyCommand::Import {
+                    format,
+                    force,
+                    input,
+                } => {
+                    if key_path.exists() && !force {
+                        error!("Key file {key_path:?} already exists, use --force to replace it");
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "key file already exists",
+                        )
+                        .into());
+                    }
+                    let input = Zeroizing::new(match input {
+                        Some(path) => tokio::fs::read_to_string(path).await?,
+                        None => io::read_to_string(io::stdin())?,
+                    });
+                    let key = mycelium_cli::import_key(&input, format)?;
+                    save_key_file(&key, &key_path, &mut key_passphrase).await?;
+                    mycelium_cli::inspect(PublicKey::from(&key), false)?;
+
+                    return Ok(());
+                }
+                KeyCommand::Encrypt {
+                    new_passphrase_file,
+                } => {
+                    let Some((key, _)) = get_node_keys(&key_path, &mut key_passphrase).await?
+                    else {
+                        error!("No key to encrypt found at {key_path:?}");
+                        return Err(
+                            io::Error::new(io::ErrorKind::NotFound, "key file not found").into(),
+                        );
+                    };
+                    // The previous key must be read with the current passphrase.
+                    let previous_path = previous_key_path(&key_path);
+                    let previous_key = if previous_path.exists() {
+                        Some(read_secret_file(&previous_path, &mut key_passphrase).await?)
+                    } else {
+                        None
+                    };
+
+                    key_passphrase.passphrase = Some(mycelium_cli::new_key_passphrase(
+                        new_passphrase_file.as_deref(),
+                    )?);
+
+                    save_key_file(&key, &key_path, &mut key_passphrase).await?;
+                    if let Some(previous_key) = previous_key {
+                        save_secret_file(&previous_key, &previous_path, &mut key_passphrase)
+                            .await?;
+                    }
+
+                    return Ok(());
+                }
+            },
         },
     }
 
     Ok(())
 }
 
+/// State needed to reload the configuration of a running node.
+struct ReloadContext {
+    /// Arguments passed on the command line. These take precedence over the configuration file.
+    cli_args: NodeArguments,
+    /// Path of the configuration file passed on the command line, if any.
+    config_file: Option<PathBuf>,
+    /// Static peers of the currently applied configuration.
+    peers: Vec<Endpoint>,
+    /// Link settings of peers in the currently applied configuration.
+    peer_settings: HashMap<Endpoint, PeerSettings>,
+}
+
+impl ReloadContext {
+    /// Reload the configuration file, and apply the settings which can be changed on a running
+    /// node. Static peers which were added to the configuration are connected, static peers which
+    /// were removed from it are disconnected, the link settings of static peers are updated, and
+    /// the route policy is replaced. Other settings only take effect after a restart.
+    async fn reload<M>(&mut self, node: &Mutex<Node<M>>) -> Result<(), Box<dyn Error>>
+    where
+        M: Metrics + Clone + Send + Sync + 'static,
+    {
+        let file_config = load_config(self.config_file.as_deref())?;
+        let merged_config = merge_config(self.cli_args.clone(), file_config);
+
+        let node = node.lock().await;
+        for endpoint in self
+            .peers
+            .iter()
+            .filter(|endpoint| !merged_config.peers.contains(endpoint))
+        {
+            match node.remove_peer(*endpoint) {
+                Ok(()) => info!(peer.endpoint=%endpoint, "Removed static peer"),
+                Err(PeerNotFound) => debug!(peer.endpoint=%endpoint, "Static peer already removed"),
+            }
+        }
+        for endpoint in merged_config.peers.iter() {
+            let settings = merged_config
+                .peer_settings
+                .get(endpoint)
+                .copied()
+                .unwrap_or_default();
+            if !self.peers.contains(endpoint) {
+                match node.add_static_peer(*endpoint, settings) {
+                    Ok(()) => info!(peer.endpoint=%endpoint, "Added static peer"),
+                    Err(PeerExists) => {
+                        debug!(peer.endpoint=%endpoint, "Static peer already exists")
+                    }
+                }
+            } else if self
+                .peer_settings
+                .get(endpoint)
+                .copied()
+                .unwrap_or_default()
+                != settings
+            {
+                match node.set_peer_settings(*endpoint, settings) {
+                    Ok(()) => {
+                        info!(peer.endpoint=%endpoint, "Updated link settings of static peer")
+                    }
+                    Err(PeerNotFound) => {
+                        debug!(peer.endpoint=%endpoint, "Static peer was removed")
+                    }
+                }
+            }
+        }
+        self.peers = merged_config.peers;
+        self.peer_settings = merged_config.peer_settings;
+
+        if node.route_policy() != merged_config.route_policy {
+            info!("Applying updated route policy");
+            node.set_route_policy(merged_config.route_policy);
+        }
+
+        if node.firewall_policy() != merged_config.firewall {
+            info!("Applying updated firewall policy");
+            node.set_firewall_policy(merged_config.firewall);
+        }
+
+        Ok(())
+    }
+}
+
+/// Wait until the process is asked to stop. In the meantime, the configuration is reloaded when
+/// this is requested through the API, or, on unix platforms, by sending SIGHUP to the process.
+async fn run_until_stopped<M>(
+    node: Arc<Mutex<Node<M>>>,
+    mut reload_context: ReloadContext,
+    mut reload_rx: mpsc::Receiver<ReloadRequest>,
+) where
+    M: Metrics + Clone + Send + Sync + 'static,
+{
+    // TODO: put in dedicated file so we can only rely on certain signals on unix platforms
+    #[cfg(target_family = "unix")]
+    {
+        let mut sigint =
+            signal::unix::signal(SignalKind::interrupt()).expect("Can install SIGINT handler");
+        let mut sigterm =
+            signal::unix::signal(SignalKind::terminate()).expect("Can install SIGTERM handler");
+        let mut sighup =
+            signal::unix::signal(SignalKind::hangup()).expect("Can install SIGHUP handler");
+
+        loop {
+            tokio::select! {
+                _ = sigint.recv() => break,
+                _ = sigterm.recv() => break,
+                _ = sighup.recv() => {
+                    info!("Received SIGHUP, reloading configuration");
+                    if let Err(e) = reload_context.reload(&node).await {
+                        error!(err=%e, "Failed to reload configuration");
+                    }
+                }
+                Some(reply) = reload_rx.recv() => {
+                    info!("Reloading configuration");
+                    let res = reload_context.reload(&node).await.map_err(|e| e.to_string());
+                    if let Err(e) = &res {
+                        error!(err=%e, "Failed to reload configuration");
+                    }
+                    let _ = reply.send(res);
+                }
+            }
+        }
+    }
+    #[cfg(not(target_family = "unix"))]
+    {
+        loop {
+            tokio::select! {
+                res = tokio::signal::ctrl_c() => {
+                    if let Err(e) = res {
+                        error!("Failed to wait for SIGINT: {e}");
+                    }
+                    break;
+                }
+                Some(reply) = reload_rx.recv() => {
+                    info!("Reloading configuration");
+                    let res = reload_context.reload(&node).await.map_err(|e| e.to_string());
+                    if let Err(e) = &res {
+                        error!(err=%e, "Failed to reload configuration");
+                    }
+                    let _ = reply.send(res);
+                }
+            }
+        }
+    }
+}
+
+/// Load the configuration file. If no path is given, the default configuration file for the
+/// platform is used if it exists. If there is no configuration file, the default configuration is
+/// returned.
+fn load_config(config_file_path: Option<&Path>) -> Result<MyceliumConfig, Box<dyn Error>> {
+    // Init default configuration
+    let mut mycelium_config = MyceliumConfig::default();
+
+    if let Some(config_file_path) = config_file_path {
+        if Path::new(config_file_path).exists() {
+            let config = config::Config::builder()
+                .add_source(config::File::new(
+                    config_file_path.to_str().unwrap(),
+                    config::FileFormat::Toml,
+                ))
+                .build()?;
+
+            mycelium_config = config.try_deserialize()?;
+        } else {
+            let error_msg = format!("Config file {:?} not found", config_file_path);
+            return Err(io::Error::new(io::ErrorKind::NotFound, error_msg).into());
+        }
+    } else if let Some(mut conf) = dirs::config_dir() {
+        // Windows: %APPDATA%/ThreeFold Tech/Mycelium/mycelium.conf
+        #[cfg(target_os = "windows")]
+        {
+            conf = conf
+                .join("ThreeFold Tech")
+                .join("Mycelium")
+                .join("mycelium.toml")
+        };
+        // Linux: $HOME/.config/mycelium/mycelium.conf
+        #[cfg(target_os = "linux")]
+        {
+            conf = conf.join("mycelium").join("mycelium.toml")
+        };
+        // MacOS: $HOME/Library/Application Support/ThreeFold Tech/Mycelium/mycelium.conf
+        #[cfg(target_os = "macos")]
+        {
+            conf = conf
+                .join("ThreeFold Tech")
+                .join("Mycelium")
+                .join("mycelium.toml")
+        };
+
+        if conf.exists() {
+            info!(
+                conf_dir = conf.to_str().unwrap(),
+                "Loading configuration file",
+            );
+            let config = config::Config::builder()
+                .add_source(config::File::new(
+                    conf.to_str().unwrap(),
+                    config::FileFormat::Toml,
+                ))
+                .build()?;
+            mycelium_config = config.try_deserialize()?;
+        }
+    }
+
+    Ok(mycelium_config)
+}
+
+/// Passphrase used to decrypt and encrypt key files. It is only read once it is needed, and then
+/// reused for all key files.
+struct KeyPassphrase {
+    /// File holding the passphrase, if set on the command line.
+    file: Option<PathBuf>,
+    passphrase: Option<Zeroizing<String>>,
+}
+
+impl KeyPassphrase {
+    fn new(file: Option<PathBuf>) -> Self {
+        Self {
+            file,
+            passphrase: None,
+        }
+    }
+
+    /// Get the passphrase from the passphrase file or the environment. If neither is set and
+    /// `prompt` is true, it is read from the terminal.
+    fn get(&mut self, prompt: bool) -> io::Result<Option<&str>> {
+        if self.passphrase.is_none() {
+            self.passphrase = mycelium_cli::key_passphrase(self.file.as_deref(), prompt)?;
+        }
+
+        Ok(self
+            .passphrase
+            .as_ref()
+            .map(|passphrase| passphrase.as_str()))
+    }
+}
+
 async fn get_node_keys(
     key_path: &PathBuf,
+    passphrase: &mut KeyPassphrase,
 ) -> Result<Option<(crypto::SecretKey, crypto::PublicKey)>, io::Error> {
     if key_path.exists() {
-        let sk = load_key_file(key_path).await?;
+        let data = read_secret_file(key_path, passphrase).await?;
+        let secret_bytes = <[u8; 32]>::try_from(data.get(..32).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "key file is too short")
+        })?)
+        .expect("Slice is 32 bytes; qed");
+        let sk = crypto::SecretKey::from(secret_bytes);
         let pk = crypto::PublicKey::from(&sk);
         debug!("Loaded key file at {key_path:?}");
         Ok(Some((sk, pk)))
@@ -586,18 +1213,95 @@ async fn get_node_keys(
     }
 }
 
-async fn load_key_file<T>(path: &Path) -> Result<T, io::Error>
-where
-    T: From<[u8; 32]>,
-{
-    let mut file = File::open(path).await?;
-    let mut secret_bytes = [0u8; 32];
-    file.read_exact(&mut secret_bytes).await?;
+async fn save_key_file(
+    key: &crypto::SecretKey,
+    path: &Path,
+    passphrase: &mut KeyPassphrase,
+) -> io::Result<()> {
+    save_secret_file(key.as_bytes(), path, passphrase).await
+}
+
+/// Path of the file holding the previous key of the node, next to the key file.
+fn previous_key_path(key_path: &Path) -> PathBuf {
+    let mut path = key_path.as_os_str().to_owned();
+    path.push(".previous");
+    PathBuf::from(path)
+}
+
+/// Load the previous key of the node, if the key was rotated and the grace period of the rotation
+/// did not end yet.
+async fn load_previous_key_file(
+    path: &Path,
+    passphrase: &mut KeyPassphrase,
+) -> Result<Option<PreviousKey>, io::Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = read_secret_file(path, passphrase).await?;
+    let Some(previous_key) = PreviousKey::from_bytes(&data) else {
+        warn!("Ignoring invalid previous key file {path:?}");
+        return Ok(None);
+    };
+    if previous_key
+        .succession
+        .grace_period_end()
+        .map_or(true, |end| end <= SystemTime::now())
+    {
+        debug!("Grace period of previous key in {path:?} ended");
+        return Ok(None);
+    }
+
+    debug!("Loaded previous key file at {path:?}");
+    Ok(Some(previous_key))
+}
+
+async fn save_previous_key_file(
+    previous_key: &PreviousKey,
+    path: &Path,
+    passphrase: &mut KeyPassphrase,
+) -> io::Result<()> {
+    save_secret_file(&previous_key.to_bytes(), path, passphrase).await
+}
 
-    Ok(T::from(secret_bytes))
+/// Read a file holding secret data, decrypting it if it is encrypted.
+async fn read_secret_file(
+    path: &Path,
+    passphrase: &mut KeyPassphrase,
+) -> io::Result<Zeroizing<Vec<u8>>> {
+    let data = Zeroizing::new(tokio::fs::read(path).await?);
+    if !mycelium_cli::is_encrypted(&data) {
+        return Ok(data);
+    }
+
+    let Some(passphrase) = passphrase.get(true)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "key file {path:?} is encrypted, set {KEY_PASSPHRASE_ENV} or use --key-passphrase-file"
+            ),
+        ));
+    };
+    mycelium_cli::decrypt_secret(&data, passphrase.as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-async fn save_key_file(key: &crypto::SecretKey, path: &Path) -> io::Result<()> {
+/// Write secret data to a file, which is only readable by the owner if supported. The data is
+/// encrypted if a passphrase is given.
+async fn save_secret_file(
+    data: &[u8],
+    path: &Path,
+    passphrase: &mut KeyPassphrase,
+) -> io::Result<()> {
+    let encrypted;
+    let data = match passphrase.get(false)? {
+        Some(passphrase) => {
+            encrypted = mycelium_cli::encrypt_secret(data, passphrase.as_bytes());
+            &encrypted[..]
+        }
+        None => data,
+    };
+
     #[cfg(target_family = "unix")]
     {
         use tokio::fs::OpenOptions;
@@ -609,23 +1313,29 @@ async fn save_key_file(key: &crypto::SecretKey, path: &Path) -> io::Result<()> {
             .mode(0o600) // rw by the owner, not readable by group or others
             .open(path)
             .await?;
-        file.write_all(key.as_bytes()).await?;
+        file.write_all(data).await?;
     }
     #[cfg(not(target_family = "unix"))]
     {
-        let mut file = File::create(path).await?;
-        file.write_all(key.as_bytes()).await?;
+        let mut file = tokio::fs::File::create(path).await?;
+        file.write_all(data).await?;
     }
 
     Ok(())
 }
 
 fn merge_config(cli_args: NodeArguments, file_config: MyceliumConfig) -> MergedNodeConfig {
+    let file_peers = file_config.peers.unwrap_or_default();
     MergedNodeConfig {
+        // Link settings from the config file also apply to peers passed on the command line.
+        peer_settings: file_peers
+            .iter()
+            .map(|peer| (peer.endpoint, peer.settings))
+            .collect(),
         peers: if !cli_args.static_peers.is_empty() {
             cli_args.static_peers
         } else {
-            file_config.peers.unwrap_or_default()
+            file_peers.into_iter().map(|peer| peer.endpoint).collect()
         },
         tcp_listen_port: if cli_args.tcp_listen_port != DEFAULT_TCP_LISTEN_PORT {
             cli_args.tcp_listen_port
@@ -675,36 +1385,87 @@ fn merge_config(cli_args: NodeArguments, file_config: MyceliumConfig) -> MergedN
         } else {
             file_config.update_workers.unwrap_or(1)
         },
+        message_store: cli_args.message_store.or(file_config.message_store),
+        message_spool: cli_args.message_spool.or(file_config.message_spool),
+        peer_cache: cli_args.peer_cache.or(file_config.peer_cache),
+        ipv4_overlay: cli_args.ipv4_overlay.or(file_config.ipv4_overlay),
+        route_policy: file_config.route_policy.unwrap_or_default(),
+        gateway_prefixes: if !cli_args.gateway_prefixes.is_empty() {
+            cli_args.gateway_prefixes
+        } else {
+            file_config.gateway_prefixes.unwrap_or_default()
+        },
+        trusted_gateways: file_config.trusted_gateways.unwrap_or_default(),
+        require_signed_updates: cli_args.require_signed_updates
+            || file_config.require_signed_updates.unwrap_or(false),
+        multipath_tolerance: cli_args
+            .multipath_tolerance
+            .or(file_config.multipath_tolerance),
+        selection_hysteresis: cli_args
+            .selection_hysteresis
+            .or(file_config.selection_hysteresis)
+            .unwrap_or(DEFAULT_SELECTION_HYSTERESIS),
+        route_damping: file_config.route_damping.or(if cli_args.route_damping {
+            Some(DampingConfig::default())
+        } else {
+            None
+        }),
+        leaf: cli_args.leaf || file_config.leaf.unwrap_or(false),
+        firewall: file_config.firewall,
+        preferred_cipher: cli_args.preferred_cipher.or(file_config.preferred_cipher),
+        link_keys: file_config.link_keys.unwrap_or_default(),
     }
 }
 
-/// Deserialize an optional list of endpoints from TOML format. The endpoints can be provided
-/// either as a list `[...]`, or in case there is only 1 endpoint, it can also be provided as a
-/// single string element. If no value is provided, it returns None.
-fn deserialize_optional_endpoint_str_from_toml<'de, D>(
+/// Deserialize an optional list of peers from TOML format. The peers can be provided either as a
+/// list `[...]`, or in case there is only 1 peer, it can also be provided as a single string
+/// element. Peers in a list are either an endpoint string, or a table with an `endpoint` and the
+/// link settings of the peer. If no value is provided, it returns None.
+fn deserialize_optional_peers_from_toml<'de, D>(
     deserializer: D,
-) -> Result<Option<Vec<Endpoint>>, D::Error>
+) -> Result<Option<Vec<ConfiguredPeer>>, D::Error>
 where
     D: Deserializer<'de>,
 {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PeerEntry {
+        Endpoint(String),
+        WithSettings {
+            endpoint: String,
+            #[serde(flatten)]
+            settings: PeerSettings,
+        },
+    }
+
     #[derive(Deserialize)]
     #[serde(untagged)]
     enum StringOrVec {
         String(String),
-        Vec(Vec<String>),
+        Vec(Vec<PeerEntry>),
+    }
+
+    fn parse<E: serde::de::Error>(
+        endpoint: &str,
+        settings: PeerSettings,
+    ) -> Result<ConfiguredPeer, E> {
+        Ok(ConfiguredPeer {
+            endpoint: <Endpoint as std::str::FromStr>::from_str(endpoint)
+                .map_err(serde::de::Error::custom)?,
+            settings,
+        })
     }
 
     Ok(match Option::<StringOrVec>::deserialize(deserializer)? {
         Some(StringOrVec::Vec(v)) => Some(
             v.into_iter()
-                .map(|s| {
-                    <Endpoint as std::str::FromStr>::from_str(&s).map_err(serde::de::Error::custom)
+                .map(|entry| match entry {
+                    PeerEntry::Endpoint(s) => parse(&s, PeerSettings::default()),
+                    PeerEntry::WithSettings { endpoint, settings } => parse(&endpoint, settings),
                 })
                 .collect::<Result<Vec<_>, _>>()?,
         ),
-        Some(StringOrVec::String(s)) => Some(vec![
-            <Endpoint as std::str::FromStr>::from_str(&s).map_err(serde::de::Error::custom)?
-        ]),
+        Some(StringOrVec::String(s)) => Some(vec![parse(&s, PeerSettings::default())?]),
         None => None,
     })
 }